@@ -0,0 +1,223 @@
+use axum::{
+    Router,
+    routing::get,
+    extract::{Query, Request, State},
+    Json,
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::jwt_utils::extract_token;
+use crate::subscribers::ConnectionId;
+use crate::Subscribers;
+use tracing::warn;
+
+/// Page size used when a request does not set `limit`.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+/// Largest page a request may ask for.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Query parameters shared by the admin endpoints
+#[derive(Deserialize)]
+pub struct AdminQuery {
+    /// Required by `/admin/sessions` and `/admin/subscribers`: the topic to inspect
+    pub topic: Option<String>,
+    /// Limits `/admin/subscribers` to one session
+    pub session_id: Option<String>,
+    /// Return only totals instead of a page of entries
+    #[serde(default)]
+    pub summary: bool,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl AdminQuery {
+    fn page<T>(&self, entries: Vec<T>) -> Page<T> {
+        let total = entries.len();
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        let items = entries.into_iter().skip(self.offset).take(limit).collect();
+        Page { total, offset: self.offset, limit, items }
+    }
+}
+
+/// Totals across every topic, or across the sessions of one topic
+#[derive(Serialize)]
+pub struct SummaryResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Number of topics; omitted when summarizing a single topic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topics: Option<usize>,
+    pub sessions: usize,
+    pub subscribers: usize,
+}
+
+/// Subscription counts for one topic
+#[derive(Serialize)]
+pub struct TopicEntry {
+    pub topic: String,
+    pub sessions: usize,
+    pub subscribers: usize,
+}
+
+/// Subscription count for one session of a topic
+#[derive(Serialize)]
+pub struct SessionEntry {
+    pub session_id: String,
+    pub subscribers: usize,
+}
+
+/// One live subscription to a topic, with the connection that holds it
+#[derive(Serialize)]
+pub struct SubscriberEntry {
+    pub session_id: String,
+    pub connection_id: ConnectionId,
+    /// Remote address of the connection; omitted for in-process connections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    pub options: Vec<String>,
+}
+
+/// One page of entries; `total` counts all entries, not just this page
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub items: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+// Define a unified API response for the summary, page and error cases
+enum AdminResponse {
+    Summary(SummaryResponse),
+    Topics(Page<TopicEntry>),
+    Sessions(Page<SessionEntry>),
+    Subscribers(Page<SubscriberEntry>),
+    Error(StatusCode, String),
+}
+
+impl IntoResponse for AdminResponse {
+    fn into_response(self) -> Response {
+        match self {
+            AdminResponse::Summary(summary) => (StatusCode::OK, Json(summary)).into_response(),
+            AdminResponse::Topics(page) => (StatusCode::OK, Json(page)).into_response(),
+            AdminResponse::Sessions(page) => (StatusCode::OK, Json(page)).into_response(),
+            AdminResponse::Subscribers(page) => (StatusCode::OK, Json(page)).into_response(),
+            AdminResponse::Error(status, error) => (status, Json(ErrorResponse { error })).into_response(),
+        }
+    }
+}
+
+/// Creates read-only endpoints reporting subscription counts for operators.
+///
+/// - `GET /admin/topics?summary=true` returns totals for the endpoint.
+/// - `GET /admin/topics?offset=0&limit=100` returns a page of per-topic counts, sorted by topic.
+/// - `GET /admin/sessions?topic=<topic>[&summary=true]` does the same for the sessions of one topic.
+/// - `GET /admin/subscribers?topic=<topic>[&session_id=<session>]` returns a page of the topic's
+///   subscribers with their connection id and peer address, sorted by session.
+///
+/// Apart from `/admin/subscribers`, only counts are reported, so a topic with hundreds of
+/// thousands of subscribers costs no more than one with a handful. The endpoints are unauthenticated; mount them on an internal listener,
+/// or use [`admin_api_router_with_token`].
+pub fn admin_api_router<S>(subscribers: Subscribers) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let sessions_subscribers = subscribers.clone();
+    let listed_subscribers = subscribers.clone();
+    Router::new()
+        .route("/admin/topics", get(
+            move |State(_): State<S>, Query(query): Query<AdminQuery>| async move {
+                let counts = subscribers.topic_counts();
+                if query.summary {
+                    return AdminResponse::Summary(SummaryResponse {
+                        topic: None,
+                        topics: Some(counts.len()),
+                        sessions: counts.iter().map(|c| c.sessions).sum(),
+                        subscribers: counts.iter().map(|c| c.subscribers).sum(),
+                    });
+                }
+                let entries = counts
+                    .into_iter()
+                    .map(|c| TopicEntry { topic: c.topic, sessions: c.sessions, subscribers: c.subscribers })
+                    .collect();
+                AdminResponse::Topics(query.page(entries))
+            }
+        ))
+        .route("/admin/sessions", get(
+            move |State(_): State<S>, Query(query): Query<AdminQuery>| async move {
+                let Some(topic) = query.topic.clone() else {
+                    return AdminResponse::Error(StatusCode::BAD_REQUEST, "Missing topic parameter".to_string());
+                };
+                let counts = sessions_subscribers.session_counts(&topic);
+                if query.summary {
+                    return AdminResponse::Summary(SummaryResponse {
+                        topic: Some(topic),
+                        topics: None,
+                        sessions: counts.len(),
+                        subscribers: counts.iter().map(|(_, count)| count).sum(),
+                    });
+                }
+                let entries = counts
+                    .into_iter()
+                    .map(|(session_id, subscribers)| SessionEntry { session_id, subscribers })
+                    .collect();
+                AdminResponse::Sessions(query.page(entries))
+            }
+        ))
+        .route("/admin/subscribers", get(
+            move |State(_): State<S>, Query(query): Query<AdminQuery>| async move {
+                let Some(topic) = query.topic.as_deref() else {
+                    return AdminResponse::Error(StatusCode::BAD_REQUEST, "Missing topic parameter".to_string());
+                };
+                let entries = listed_subscribers.subscriber_infos(topic)
+                    .into_iter()
+                    .filter(|info| query.session_id.as_ref().is_none_or(|session| *session == info.session_id))
+                    .map(|info| SubscriberEntry {
+                        session_id: info.session_id,
+                        connection_id: info.connection_id,
+                        peer: info.peer.map(|peer| peer.to_string()),
+                        options: info.options,
+                    })
+                    .collect();
+                AdminResponse::Subscribers(query.page(entries))
+            }
+        ))
+}
+
+/// The endpoints of [`admin_api_router`], answering only requests that carry
+/// `Authorization: Bearer <token>`; any other request gets `401`.
+pub fn admin_api_router_with_token<S>(subscribers: Subscribers, token: &str) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let token: Arc<str> = Arc::from(token);
+    admin_api_router(subscribers).route_layer(middleware::from_fn(move |request: Request, next: Next| {
+        let token = token.clone();
+        async move {
+            let presented = request.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(extract_token);
+            if !presented.is_some_and(|presented| tokens_match(presented, &token)) {
+                warn!("[admin] Rejected request to {} without a valid admin token", request.uri().path());
+                return AdminResponse::Error(StatusCode::UNAUTHORIZED, "Invalid admin token".to_string()).into_response();
+            }
+            next.run(request).await
+        }
+    }))
+}
+
+// Compares without stopping at the first differing byte, so timing does not reveal the token
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented.bytes().zip(expected.bytes()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
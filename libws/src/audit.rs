@@ -0,0 +1,55 @@
+// src/audit.rs
+use std::fmt;
+use crate::timestamp::now_rfc3339;
+use tracing::info;
+
+/// What happened to a topic in a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopicAuditKind {
+    /// The first subscriber created the topic in the session.
+    Created,
+    /// The last subscriber left and the topic was removed from the session.
+    Removed,
+    /// A publish was refused because the topic had more subscribers than `ConnectionConfig::max_fan_out`.
+    FanOutRejected,
+}
+
+/// A topic lifecycle or capacity event, emitted when a topic/session key enters or leaves the
+/// subscriber map or a publish to it is refused for its fan-out.
+#[derive(Clone, Debug)]
+pub struct TopicAuditEvent {
+    pub kind: TopicAuditKind,
+    pub topic: String,
+    pub session_id: String,
+    /// Client name (or authenticated user) whose action caused the change.
+    pub actor: String,
+    /// RFC 3339 time of the change.
+    pub at: String,
+}
+
+/// Receives topic audit events, for example to forward them to a security log.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: &TopicAuditEvent);
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditSink")
+    }
+}
+
+/// Logs the event and forwards it to the sink, if any.
+pub(crate) fn emit(sink: Option<&dyn AuditSink>, kind: TopicAuditKind, topic: &str, session_id: &str, actor: &str) {
+    let event = TopicAuditEvent {
+        kind,
+        topic: topic.to_string(),
+        session_id: session_id.to_string(),
+        actor: actor.to_string(),
+        at: now_rfc3339(),
+    };
+    info!("[audit] topic {:?}: topic={}, session={}, actor={}, at={}",
+        event.kind, event.topic, event.session_id, event.actor, event.at);
+    if let Some(sink) = sink {
+        sink.record(&event);
+    }
+}
@@ -0,0 +1,95 @@
+// src/authorizer.rs
+use std::fmt;
+use std::sync::Arc;
+use crate::jwt_utils::Claims;
+
+/// Decides which topics a connection may subscribe to and publish on, from its token claims.
+/// Anonymous connections pass `None`, so an authorizer can keep topics to logged-in users.
+///
+/// `ConnectionConfig::authorizer` is consulted before a subscription is added or restored and
+/// before a publish or direct message is fanned out, after `topic_policies` allowed it. One
+/// implementation covers both directions, so it can share state such as an access list between
+/// them; [`TopicHooks`] builds one from a closure per direction.
+pub trait Authorizer: Send + Sync {
+    fn can_subscribe(&self, claims: Option<&Claims>, topic: &str) -> bool;
+
+    fn can_publish(&self, claims: Option<&Claims>, topic: &str) -> bool;
+}
+
+impl fmt::Debug for dyn Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authorizer")
+    }
+}
+
+/// Decides from a connection's token claims whether it may use a topic. Anonymous connections
+/// pass `None`. The default allows everything. Give one per direction to
+/// [`TopicHooks`] to use them as the endpoint's authorizer.
+#[derive(Clone)]
+pub struct TopicAuthorizer(Arc<TopicCheck>);
+
+type TopicCheck = dyn Fn(Option<&Claims>, &str) -> bool + Send + Sync;
+
+impl TopicAuthorizer {
+    pub fn new<F>(check: F) -> Self
+    where
+        F: Fn(Option<&Claims>, &str) -> bool + Send + Sync + 'static,
+    {
+        TopicAuthorizer(Arc::new(check))
+    }
+
+    /// Whether the claims are allowed on the topic.
+    pub fn allows(&self, claims: Option<&Claims>, topic: &str) -> bool {
+        (self.0)(claims, topic)
+    }
+}
+
+impl Default for TopicAuthorizer {
+    fn default() -> Self {
+        TopicAuthorizer::new(|_, _| true)
+    }
+}
+
+impl fmt::Debug for TopicAuthorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TopicAuthorizer")
+    }
+}
+
+/// An [`Authorizer`] made of two closures, one for subscribes and one for publishes. Either
+/// left at its default allows everything.
+#[derive(Clone, Debug, Default)]
+pub struct TopicHooks {
+    pub subscribe: TopicAuthorizer,
+    pub publish: TopicAuthorizer,
+}
+
+impl TopicHooks {
+    pub fn new(subscribe: TopicAuthorizer, publish: TopicAuthorizer) -> Self {
+        TopicHooks { subscribe, publish }
+    }
+}
+
+impl Authorizer for TopicHooks {
+    fn can_subscribe(&self, claims: Option<&Claims>, topic: &str) -> bool {
+        self.subscribe.allows(claims, topic)
+    }
+
+    fn can_publish(&self, claims: Option<&Claims>, topic: &str) -> bool {
+        self.publish.allows(claims, topic)
+    }
+}
+
+/// Allows every connection on every topic (the default).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn can_subscribe(&self, _claims: Option<&Claims>, _topic: &str) -> bool {
+        true
+    }
+
+    fn can_publish(&self, _claims: Option<&Claims>, _topic: &str) -> bool {
+        true
+    }
+}
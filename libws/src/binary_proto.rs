@@ -0,0 +1,183 @@
+// src/binary_proto.rs
+//! Binary framing for carrying raw payloads (protobuf, images, ...) on a topic without
+//! base64-encoding them into a JSON string.
+//!
+//! Every binary WebSocket message is one frame. Lengths are big-endian:
+//!
+//! ```text
+//! +--------+-----------+-------+-------------+------------+---------+
+//! | opcode | topic len | topic | session len | session id | payload |
+//! | 1 byte | 2 bytes   | UTF-8 | 2 bytes     | UTF-8      | rest    |
+//! +--------+-----------+-------+-------------+------------+---------+
+//! ```
+//!
+//! Opcodes are `0x01` subscribe, `0x02` unsubscribe and `0x03` publish. Subscribe and
+//! unsubscribe frames carry no payload, and an empty session id means the connection's
+//! registered session. A binary subscribe registers the connection for binary delivery: the
+//! server sends it a publish frame for every message on the topic. Text subscribers of the
+//! same topic receive binary publishes as the usual JSON envelope with a base64 `payload`
+//! and `"encoding": "base64"`.
+use std::fmt;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde_json::Value;
+use crate::message_envelope;
+use crate::retained;
+use crate::timestamp::now_rfc3339;
+use tracing::warn;
+
+/// Subscription option that asks for binary publish frames instead of JSON envelopes.
+pub const BINARY_OPTION: &str = "binary";
+
+/// `encoding` value marking an envelope whose payload is base64-encoded bytes.
+pub const BASE64_ENCODING: &str = "base64";
+
+/// Operation carried by a binary frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Subscribe = 0x01,
+    Unsubscribe = 0x02,
+    Publish = 0x03,
+}
+
+impl Opcode {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Opcode::Subscribe),
+            0x02 => Some(Opcode::Unsubscribe),
+            0x03 => Some(Opcode::Publish),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded binary frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BinaryFrame {
+    pub opcode: Opcode,
+    pub topic: String,
+    pub session_id: String,
+    pub payload: Vec<u8>,
+}
+
+/// Why a binary frame could not be encoded or decoded.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame ended before its header was complete.
+    Truncated,
+    /// The first byte is not a known opcode.
+    UnknownOpcode(u8),
+    /// The topic or session id is not valid UTF-8.
+    InvalidUtf8,
+    /// The topic or session id is longer than a 2-byte length allows.
+    FieldTooLong,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "frame is shorter than its header"),
+            FrameError::UnknownOpcode(opcode) => write!(f, "unknown opcode 0x{:02x}", opcode),
+            FrameError::InvalidUtf8 => write!(f, "topic or session id is not valid UTF-8"),
+            FrameError::FieldTooLong => write!(f, "topic or session id exceeds {} bytes", u16::MAX),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl BinaryFrame {
+    /// A subscribe frame for the topic.
+    pub fn subscribe(topic: &str, session_id: &str) -> Self {
+        BinaryFrame::new(Opcode::Subscribe, topic, session_id, Vec::new())
+    }
+
+    /// An unsubscribe frame for the topic.
+    pub fn unsubscribe(topic: &str, session_id: &str) -> Self {
+        BinaryFrame::new(Opcode::Unsubscribe, topic, session_id, Vec::new())
+    }
+
+    /// A publish frame carrying the payload.
+    pub fn publish(topic: &str, session_id: &str, payload: Vec<u8>) -> Self {
+        BinaryFrame::new(Opcode::Publish, topic, session_id, payload)
+    }
+
+    fn new(opcode: Opcode, topic: &str, session_id: &str, payload: Vec<u8>) -> Self {
+        BinaryFrame { opcode, topic: topic.to_string(), session_id: session_id.to_string(), payload }
+    }
+
+    /// Serializes the frame into its wire format.
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let mut bytes = Vec::with_capacity(5 + self.topic.len() + self.session_id.len() + self.payload.len());
+        bytes.push(self.opcode as u8);
+        for field in [&self.topic, &self.session_id] {
+            let len = u16::try_from(field.len()).map_err(|_| FrameError::FieldTooLong)?;
+            bytes.extend_from_slice(&len.to_be_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+
+    /// Parses a frame from its wire format.
+    pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+        let (&opcode, rest) = bytes.split_first().ok_or(FrameError::Truncated)?;
+        let opcode = Opcode::from_byte(opcode).ok_or(FrameError::UnknownOpcode(opcode))?;
+        let (topic, rest) = read_field(rest)?;
+        let (session_id, payload) = read_field(rest)?;
+        Ok(BinaryFrame { opcode, topic, session_id, payload: payload.to_vec() })
+    }
+}
+
+// Reads one length-prefixed UTF-8 field, returning it and the remaining bytes
+fn read_field(bytes: &[u8]) -> Result<(String, &[u8]), FrameError> {
+    let (len, rest) = bytes.split_first_chunk::<2>().ok_or(FrameError::Truncated)?;
+    let len = u16::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(FrameError::Truncated);
+    }
+    let (field, rest) = rest.split_at(len);
+    let field = String::from_utf8(field.to_vec()).map_err(|_| FrameError::InvalidUtf8)?;
+    Ok((field, rest))
+}
+
+/// Builds the JSON envelope for a binary publish, with the payload base64-encoded.
+pub(crate) fn publish_envelope(publisher: &str, topic: &str, session_id: &str, payload: &[u8], correlation_id: &str, seq: Option<u64>) -> String {
+    let envelope = message_envelope(publisher, topic, &Value::from(STANDARD.encode(payload)), &now_rfc3339(), session_id, correlation_id, seq);
+    let mut envelope: Value = serde_json::from_str(&envelope).unwrap_or_default();
+    envelope["encoding"] = Value::from(BASE64_ENCODING);
+    envelope.to_string()
+}
+
+/// Converts a delivered JSON envelope into the publish frame sent to binary subscribers.
+pub(crate) fn delivery_frame(envelope: &str) -> Option<Vec<u8>> {
+    let mut envelope: Value = serde_json::from_str(envelope).ok()?;
+    if !crate::compression::inflate_envelope(&mut envelope) {
+        return None;
+    }
+    // Text payloads arrive as their bytes, structured ones as their JSON text, and encrypted
+    // ones as the nonce and ciphertext that `enc_utils::decrypt` takes
+    let payload = match &envelope["payload"] {
+        Value::String(payload) if envelope["encoding"] == BASE64_ENCODING || envelope["encoding"] == crate::session_crypto::AES_GCM_ENCODING => {
+            STANDARD.decode(payload).ok()?
+        }
+        Value::String(payload) => payload.as_bytes().to_vec(),
+        payload => payload.to_string().into_bytes(),
+    };
+    BinaryFrame::publish(envelope["topic"].as_str()?, envelope["session_id"].as_str()?, payload)
+        .encode()
+        .ok()
+}
+
+/// The frame written for an envelope delivered to a binary subscription, or `None` if it has
+/// none and is dropped.
+pub(crate) fn subscriber_frame(envelope: &str) -> Option<Vec<u8>> {
+    // Binary frames have no tombstone opcode
+    if retained::is_tombstone(envelope) {
+        return None;
+    }
+    let frame = delivery_frame(envelope);
+    if frame.is_none() {
+        warn!("[binary] Dropping undeliverable envelope: {}", envelope);
+    }
+    frame
+}
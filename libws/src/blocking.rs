@@ -0,0 +1,152 @@
+// src/blocking.rs
+use std::sync::mpsc::{self, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::runtime::{Builder, Handle};
+use tokio::sync::oneshot;
+use crate::ws_client::{IncomingMessage, WsClient, WsError};
+
+// Sender shared by every topic handler; taken when the connection ends so `recv` stops waiting
+type SharedSender = Arc<Mutex<Option<Sender<IncomingMessage>>>>;
+
+/// A [`WsClient`] for synchronous code. It owns a current-thread runtime driven by one
+/// background thread, which keeps the receive task running between calls. Messages on
+/// subscribed topics are queued for [`recv`](Self::recv) instead of being passed to callbacks.
+///
+/// The methods block the calling thread, so they must not be called from async code.
+pub struct BlockingWsClient {
+    client: Option<WsClient>,
+    handle: Handle,
+    stop: Option<oneshot::Sender<()>>,
+    driver: Option<JoinHandle<()>>,
+    sender: SharedSender,
+    messages: Receiver<IncomingMessage>,
+}
+
+impl BlockingWsClient {
+    /// Connects and registers the client name, as [`WsClient::connect`] does.
+    pub fn connect(client_name: &str, ws_url: &str) -> Result<Self, WsError> {
+        let session_id = format!("session-{}", client_name);
+        Self::connect_with_session(client_name, &session_id, ws_url)
+    }
+
+    /// Connects with a specific session ID, as [`WsClient::connect_with_session`] does.
+    pub fn connect_with_session(client_name: &str, session_id: &str, ws_url: &str) -> Result<Self, WsError> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| WsError::Transport(format!("cannot start the client runtime: {}", e)))?;
+        let handle = runtime.handle().clone();
+        let (stop, stopped) = oneshot::channel::<()>();
+        // Only the thread inside `Runtime::block_on` drives a current-thread runtime's IO and timers
+        let driver = std::thread::Builder::new()
+            .name("ws-blocking".to_string())
+            .spawn(move || {
+                let _ = runtime.block_on(stopped);
+            })
+            .map_err(|e| WsError::Transport(format!("cannot start the client thread: {}", e)))?;
+
+        let mut client = match handle.block_on(WsClient::connect_with_session(client_name, session_id, ws_url)) {
+            Ok(client) => client,
+            Err(e) => {
+                let _ = stop.send(());
+                let _ = driver.join();
+                return Err(e);
+            }
+        };
+        let (sender, messages) = mpsc::channel();
+        let sender: SharedSender = Arc::new(Mutex::new(Some(sender)));
+        let closed = sender.clone();
+        client.on_close(move |_, _| {
+            closed.lock().unwrap().take();
+        });
+
+        Ok(BlockingWsClient {
+            client: Some(client),
+            handle,
+            stop: Some(stop),
+            driver: Some(driver),
+            sender,
+            messages,
+        })
+    }
+
+    fn client(&mut self) -> &mut WsClient {
+        self.client.as_mut().expect("client is only taken on drop")
+    }
+
+    /// Subscribes to a topic and queues its messages for [`recv`](Self::recv). Returns once the
+    /// server acknowledges the subscribe.
+    pub fn subscribe(&mut self, subscriber_name: &str, topic: &str, payload: &str) -> Result<(), WsError> {
+        let sender = self.sender.clone();
+        let handle = self.handle.clone();
+        let client = self.client();
+        client.on_message_full(topic, move |message| {
+            if let Some(sender) = sender.lock().unwrap().as_ref() {
+                let _ = sender.send(message);
+            }
+        });
+        let result = handle.block_on(client.subscribe(subscriber_name, topic, payload));
+        if result.is_err() {
+            client.clear_handlers(topic);
+        }
+        result
+    }
+
+    /// Unsubscribes from a topic. Messages already queued stay queued.
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<(), WsError> {
+        let handle = self.handle.clone();
+        let client = self.client();
+        client.clear_handlers(topic);
+        handle.block_on(client.unsubscribe(topic))
+    }
+
+    /// Publishes a text payload to a topic within the client's session.
+    pub fn publish(&mut self, publisher_name: &str, topic: &str, payload: &str, timestamp: &str) -> Result<(), WsError> {
+        let handle = self.handle.clone();
+        handle.block_on(self.client().publish(publisher_name, topic, payload, timestamp))
+    }
+
+    /// Waits for the next message on a subscribed topic. Fails once the connection has ended
+    /// and every queued message has been received.
+    pub fn recv(&self) -> Result<IncomingMessage, RecvError> {
+        self.messages.recv()
+    }
+
+    /// Waits up to `timeout` for the next message on a subscribed topic.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<IncomingMessage, RecvTimeoutError> {
+        self.messages.recv_timeout(timeout)
+    }
+
+    /// The next queued message, without waiting.
+    pub fn try_recv(&self) -> Result<IncomingMessage, TryRecvError> {
+        self.messages.try_recv()
+    }
+
+    /// Closes the connection, as [`WsClient::close`] does.
+    pub fn close(&mut self) -> Result<Option<u16>, WsError> {
+        let handle = self.handle.clone();
+        handle.block_on(self.client().close())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.as_ref().is_some_and(WsClient::is_connected)
+    }
+}
+
+impl Drop for BlockingWsClient {
+    /// Drops the client inside its runtime, then stops the runtime thread.
+    fn drop(&mut self) {
+        {
+            let _runtime = self.handle.enter();
+            self.client.take();
+        }
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(driver) = self.driver.take() {
+            let _ = driver.join();
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// src/capabilities.rs
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::conn_config::ConnectionConfig;
+
+/// Optional protocol features a server may offer. Advertised to every connection in the
+/// `server_hello` frame so clients can check for what they rely on before using it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Binary subscribe/unsubscribe/publish frames (see `binary_proto`).
+    BinaryFrames,
+    /// `delta` subscriptions that deliver only changed top-level fields.
+    Delta,
+    /// Resume tokens that restore subscriptions and replay missed messages.
+    Resume,
+    /// `subscribe-self` and `publish-to:` direct messaging.
+    DirectMessages,
+    /// `queue-depth` send queue reports.
+    QueueDepth,
+    /// Acknowledgements for subscribe, unsubscribe and publish.
+    Acks,
+    /// Join and leave events for the connections in a session.
+    Presence,
+    /// End-to-end encrypted message payloads.
+    Encryption,
+}
+
+impl Capability {
+    /// Name used on the wire.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::BinaryFrames => "binary_frames",
+            Capability::Delta => "delta",
+            Capability::Resume => "resume",
+            Capability::DirectMessages => "direct_messages",
+            Capability::QueueDepth => "queue_depth",
+            Capability::Acks => "acks",
+            Capability::Presence => "presence",
+            Capability::Encryption => "encryption",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Capabilities a connection accepted with this configuration supports.
+pub fn advertised(config: &ConnectionConfig) -> Vec<Capability> {
+    let mut capabilities = vec![
+        Capability::BinaryFrames,
+        Capability::Delta,
+        Capability::DirectMessages,
+        Capability::QueueDepth,
+        Capability::Acks,
+        Capability::Presence,
+    ];
+    if config.resume_token_interval.is_some() {
+        capabilities.push(Capability::Resume);
+    }
+    capabilities
+}
+
+/// Capabilities that change how the server treats one connection, so a client turns them on
+/// with `negotiate:` instead of them applying to everyone.
+pub const NEGOTIABLE: [Capability; 2] = [Capability::Acks, Capability::Presence];
+
+/// Parses `negotiate:` flags into the features to enable and the names this server cannot enable.
+pub(crate) fn negotiate(config: &ConnectionConfig, flags: &str) -> (Vec<Capability>, Vec<String>) {
+    let advertised = advertised(config);
+    let mut enabled = Vec::new();
+    let mut unsupported = Vec::new();
+    for flag in flags.split(',').map(str::trim).filter(|flag| !flag.is_empty()) {
+        match serde_json::from_value::<Capability>(Value::from(flag)) {
+            Ok(feature) if NEGOTIABLE.contains(&feature) && advertised.contains(&feature) => {
+                if !enabled.contains(&feature) {
+                    enabled.push(feature);
+                }
+            }
+            _ => unsupported.push(flag.to_string()),
+        }
+    }
+    (enabled, unsupported)
+}
+
+/// First frame sent on every connection.
+pub(crate) fn server_hello(config: &ConnectionConfig) -> Value {
+    json!({"type": "server_hello", "capabilities": advertised(config)})
+}
+
+/// Reads the capabilities out of a `server_hello` frame. Names this version does not know are skipped,
+/// so newer servers can advertise more without breaking older clients.
+pub fn from_server_hello(frame: &Value) -> Option<Vec<Capability>> {
+    if frame["type"] != "server_hello" {
+        return None;
+    }
+    let names = frame["capabilities"].as_array()?;
+    Some(names.iter().filter_map(|name| serde_json::from_value(name.clone()).ok()).collect())
+}
+
+/// Why a server does not satisfy a client's required capabilities.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// No `server_hello` arrived, so the server's capabilities are unknown.
+    NotAdvertised,
+    /// The server advertised its capabilities and these required ones were missing.
+    Missing(Vec<Capability>),
+}
+
+impl fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapabilityError::NotAdvertised => write!(f, "server did not advertise its capabilities"),
+            CapabilityError::Missing(missing) => {
+                let names: Vec<&str> = missing.iter().map(Capability::as_str).collect();
+                write!(f, "server lacks required capabilities: {}", names.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CapabilityError {}
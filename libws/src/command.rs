@@ -0,0 +1,243 @@
+// src/command.rs
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::error_frame::ErrorCode;
+use crate::publisher_filter;
+
+/// A command sent by a client as a JSON text frame, tagged by its `"op"` field, e.g.
+/// `{"op":"subscribe","topic":"a|b","session_id":"s1"}`. Topics and sessions are plain JSON
+/// strings, so they may contain characters such as `|` or `:` that the legacy prefix
+/// commands use as delimiters.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientCommand {
+    /// Names the connection; ignored on authenticated connections.
+    RegisterName { name: String },
+    /// Moves the connection to another session; ignored when the token carries one.
+    RegisterSession { session_id: String },
+    /// Subscribes to a topic or wildcard pattern.
+    Subscribe {
+        #[serde(default)]
+        topic: String,
+        /// Session to subscribe in; the connection's own when absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        /// Delivery options such as `unordered`, `delta` or `binary`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        options: Vec<String>,
+        /// Client-chosen id that asks for an ack and is echoed on any error.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// Removes this connection's subscription to a topic.
+    Unsubscribe {
+        #[serde(default)]
+        topic: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// Publishes a message to a topic's subscribers.
+    Publish(PublishCommand),
+    /// Asks for the session's encryption key, wrapped for this base64 public key.
+    KeyExchange {
+        public_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// Asks who is connected in a session; answered with `presence_list`.
+    ListPresence {
+        /// Session to list; the connection's own when absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
+    /// Asks how many live subscribers a publish to the topic would reach; answered with `subscriber_count`.
+    HasSubscribers {
+        #[serde(default)]
+        topic: String,
+        /// Session to count in; the connection's own when absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// Registers a message to publish to the topic in the connection's session if the connection
+    /// ends without the client closing it, replacing any earlier will.
+    SetWill {
+        #[serde(default)]
+        topic: String,
+        #[serde(default)]
+        payload: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// Asks the server for a `pong` reply.
+    Ping,
+}
+
+/// Body of a publish; also the JSON accepted after the legacy `publish-json:` prefix.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PublishCommand {
+    #[serde(default)]
+    pub topic: String,
+    #[serde(default)]
+    pub payload: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// Session to publish in; the connection's own when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// Kept on every delivery; the server assigns one when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Topic the publisher awaits a reply on, passed to subscribers unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Keep this message as the topic's retained message; an empty payload clears it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retain: bool,
+    /// `gzip` when `payload` is the base64 of the real payload's gzipped JSON text, `aes-256-gcm`
+    /// when it is the base64 of its JSON text encrypted under the session key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// Deliver to the topic's subscribers in every session; only for tokens with the `admin` role.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub broadcast: bool,
+    /// Refuse the publish unless at least this many subscribers in the session would receive it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_subscribers: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+impl ClientCommand {
+    /// Parses a text frame as a command. JSON objects are read as tagged commands; the legacy
+    /// `register-name:`, `register-session:`, `subscribe:`, `unsubscribe:`, `publish-json:` and
+    /// `ping` forms are still accepted for one release, as are `list-presence:`,
+    /// `has-subscribers:` and `set-will:`. Returns `None` for any other frame.
+    pub fn parse(text: &str) -> Option<Result<ClientCommand, serde_json::Error>> {
+        if text.trim_start().starts_with('{') {
+            return Some(serde_json::from_str(text));
+        }
+        Self::parse_legacy(text)
+    }
+
+    fn parse_legacy(text: &str) -> Option<Result<ClientCommand, serde_json::Error>> {
+        let optional = |part: Option<&str>| part.filter(|part| !part.is_empty()).map(str::to_string);
+        let command = if let Some(rest) = text.strip_prefix("register-name:") {
+            ClientCommand::RegisterName { name: rest.trim().to_string() }
+        } else if let Some(rest) = text.strip_prefix("register-session:") {
+            ClientCommand::RegisterSession { session_id: rest.trim().to_string() }
+        } else if let Some(rest) = text.strip_prefix("subscribe:") {
+            // subscribe:<topic>[|<session>[|<options>[|<id>]]]
+            let parts: Vec<&str> = rest.trim().split('|').collect();
+            ClientCommand::Subscribe {
+                topic: parts[0].to_string(),
+                session_id: optional(parts.get(1).copied()),
+                options: parts.get(2).map(|options| publisher_filter::split_options(options)).unwrap_or_default(),
+                id: optional(parts.get(3).copied()),
+            }
+        } else if let Some(rest) = text.strip_prefix("unsubscribe:") {
+            // unsubscribe:<topic>[|<session>[|<id>]]
+            let parts: Vec<&str> = rest.trim().split('|').collect();
+            ClientCommand::Unsubscribe {
+                topic: parts[0].to_string(),
+                session_id: optional(parts.get(1).copied()),
+                id: optional(parts.get(2).copied()),
+            }
+        } else if let Some(rest) = text.strip_prefix("publish-json:") {
+            return Some(serde_json::from_str(rest).map(ClientCommand::Publish));
+        } else if let Some(rest) = text.strip_prefix("list-presence:") {
+            // list-presence:[<session>]
+            ClientCommand::ListPresence { session_id: optional(Some(rest.trim())) }
+        } else if let Some(rest) = text.strip_prefix("has-subscribers:") {
+            // has-subscribers:<topic>[|<session>[|<id>]]
+            let parts: Vec<&str> = rest.trim().split('|').collect();
+            ClientCommand::HasSubscribers {
+                topic: parts[0].to_string(),
+                session_id: optional(parts.get(1).copied()),
+                id: optional(parts.get(2).copied()),
+            }
+        } else if let Some(rest) = text.strip_prefix("set-will:") {
+            // set-will:<topic>|<payload>; the payload is the rest of the frame, `|` included
+            let (topic, payload) = rest.split_once('|').unwrap_or((rest, ""));
+            ClientCommand::SetWill { topic: topic.trim().to_string(), payload: Value::from(payload), id: None }
+        } else if text == "ping" {
+            ClientCommand::Ping
+        } else {
+            return None;
+        };
+        Some(Ok(command))
+    }
+
+    /// The command as a JSON text frame.
+    pub fn to_frame(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Which command an ack confirms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckOp {
+    Subscribe,
+    Unsubscribe,
+    Publish,
+    SetWill,
+}
+
+/// A control frame sent by the server, tagged by its `"type"` field. Published messages are
+/// delivered as bare envelopes and are not part of this enum.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Confirms a subscribe, unsubscribe or publish.
+    Ack {
+        op: AckOp,
+        topic: String,
+        session_id: String,
+        /// How many subscribers a publish reached.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delivered: Option<usize>,
+        /// Id the acknowledged command carried.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// Refuses a frame; `fields` carries context such as `topic`, `detail` or `id`.
+    Error {
+        code: ErrorCode,
+        #[serde(flatten)]
+        fields: Map<String, Value>,
+    },
+    /// Answers `list_presence`: the names connected in the session, sorted.
+    PresenceList { session: String, members: Vec<String> },
+    /// Answers `has_subscribers`: how many live connections a publish to the topic in the session
+    /// would reach, wildcard subscribers included.
+    SubscriberCount {
+        topic: String,
+        session_id: String,
+        count: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// The heartbeat interval the server settled on.
+    Heartbeat { interval_ms: u64 },
+    /// Answers a key exchange: the session key encrypted under the ECDH secret of the
+    /// client's public key and the server keypair, base64-encoded.
+    SessionKey {
+        session_id: String,
+        wrapped_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+}
+
+impl ServerMessage {
+    /// The message as a JSON value.
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,63 @@
+// src/compression.rs
+use std::io::{Read, Write};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::Value;
+
+/// `encoding` value marking a payload sent as the base64 of its gzipped JSON text.
+pub const GZIP_ENCODING: &str = "gzip";
+
+/// Largest payload, as JSON text, that a compressed payload may inflate to.
+pub const MAX_INFLATED_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Gzips a payload's JSON text and base64-encodes the result so it fits in a text frame.
+pub fn compress_payload(payload: &Value) -> Value {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing into a Vec cannot fail
+    let _ = encoder.write_all(payload.to_string().as_bytes());
+    Value::from(STANDARD.encode(encoder.finish().unwrap_or_default()))
+}
+
+/// The value a compressed payload carries, or `None` when it is not base64 of gzipped JSON
+/// text or would inflate past [`MAX_INFLATED_BYTES`].
+pub fn inflate_payload(payload: &Value) -> Option<Value> {
+    inflate_payload_within(payload, MAX_INFLATED_BYTES)
+}
+
+/// Like [`inflate_payload`], but gives up once the JSON text passes `max_bytes`, so a small
+/// frame cannot make the server inflate more than it would accept uncompressed.
+pub fn inflate_payload_within(payload: &Value, max_bytes: u64) -> Option<Value> {
+    let compressed = STANDARD.decode(payload.as_str()?).ok()?;
+    let mut text = String::new();
+    GzDecoder::new(compressed.as_slice()).take(max_bytes + 1).read_to_string(&mut text).ok()?;
+    if text.len() as u64 > max_bytes {
+        return None;
+    }
+    serde_json::from_str(&text).ok()
+}
+
+/// Replaces the payload of an envelope marked `"encoding":"gzip"` with the value it carries and
+/// drops the marker. Other envelopes are left alone. Returns false when the payload could not
+/// be inflated.
+pub fn inflate_envelope(envelope: &mut Value) -> bool {
+    if envelope["encoding"] != GZIP_ENCODING {
+        return true;
+    }
+    let Some(payload) = inflate_payload(&envelope["payload"]) else {
+        return false;
+    };
+    envelope["payload"] = payload;
+    if let Some(fields) = envelope.as_object_mut() {
+        fields.remove("encoding");
+    }
+    true
+}
+
+/// Marks a built envelope as carrying a compressed payload.
+pub(crate) fn with_encoding(envelope: &str, encoding: &str) -> String {
+    let mut envelope: Value = serde_json::from_str(envelope).unwrap_or_default();
+    envelope["encoding"] = Value::from(encoding);
+    envelope.to_string()
+}
@@ -0,0 +1,280 @@
+// src/conn_config.rs
+use std::sync::Arc;
+use std::time::Duration;
+use zeroize::Zeroizing;
+use crate::audit::AuditSink;
+use crate::authorizer::{AllowAll, Authorizer};
+use crate::connections::ConnectionRegistry;
+use crate::enc_api_route::EncApiState;
+use crate::jwt_api_route::JwtState;
+use crate::history::MessageHistory;
+use crate::interceptor::MessageInterceptor;
+use crate::jwt_utils::{load_jwt_key, Claims, JwtKeyError, TokenValidation, JWT_KEY_LEN};
+use crate::metrics::Metrics;
+use crate::parked::ParkedSessions;
+use crate::presence::PresenceRoster;
+use crate::rate_limit::RateLimit;
+use crate::retained::RetainedMessages;
+use crate::send_queue::SendQueueLimit;
+use crate::session_crypto::SessionKeys;
+use crate::subprotocol::WireFormat;
+use crate::topic_pattern;
+use crate::transfer::SubscriptionTransfers;
+
+/// Restricts what clients may do on topics matching a pattern.
+#[derive(Clone, Debug)]
+pub struct TopicPolicy {
+    /// Topic pattern; `*` matches one `/`-separated segment.
+    pub pattern: String,
+    /// Whether clients may publish to matching topics.
+    pub can_publish: bool,
+    /// Whether clients may subscribe to matching topics.
+    pub can_subscribe: bool,
+}
+
+impl TopicPolicy {
+    /// Topics that clients may only subscribe to (server-pushed).
+    pub fn subscribe_only(pattern: &str) -> Self {
+        TopicPolicy { pattern: pattern.to_string(), can_publish: false, can_subscribe: true }
+    }
+
+    /// Topics that clients may only publish to (ingest).
+    pub fn publish_only(pattern: &str) -> Self {
+        TopicPolicy { pattern: pattern.to_string(), can_publish: true, can_subscribe: false }
+    }
+}
+
+/// What the server does with a text frame that is not a known command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownCommandPolicy {
+    /// Log it and carry on (the default).
+    #[default]
+    Ignore,
+    /// Reply with `{"type":"error","code":"unknown_command","command":...}`.
+    ErrorReply,
+    /// Reply with the error, and close the connection (code 1008) once it has sent this many unknown commands.
+    DisconnectAfter(u32),
+}
+
+/// Default for [`ConnectionConfig::max_message_bytes`]: 1 MiB.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+/// Per-endpoint settings applied to every connection accepted by `handle_socket_with_config`.
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// Token issuer whose signing key validates tokens on this endpoint, normally the state
+    /// given to `jwt_api_router`, so tokens minted at `/auth/token` are accepted here.
+    /// `None` loads the key with `jwt_utils::load_jwt_key` for each connection.
+    pub jwt: Option<JwtState>,
+    /// Expected JWT `aud` claim. When set, tokens minted for other audiences are rejected,
+    /// overriding the audience of the `jwt` issuer.
+    pub expected_audience: Option<String>,
+    /// How often an authenticated connection re-checks its token expiry. `None` disables the check.
+    pub reauth_check_interval: Option<Duration>,
+    /// How long an expired connection may take to send `authenticate:<token>` before it is closed.
+    pub reauth_grace: Duration,
+    /// Maximum lifetime of a connection. When it elapses the server sends `lifetime_exceeded`
+    /// and closes the socket so the client reconnects. `None` lets connections live indefinitely.
+    pub max_connection_lifetime: Option<Duration>,
+    /// Default interval between server-initiated pings. `None` sends no pings unless a client asks.
+    pub heartbeat_interval: Option<Duration>,
+    /// Shortest ping interval a client may negotiate with `register-heartbeat:`.
+    pub heartbeat_min_interval: Duration,
+    /// Longest ping interval a client may negotiate with `register-heartbeat:`.
+    pub heartbeat_max_interval: Duration,
+    /// While pings are being sent, a connection that answers no ping (and sends nothing else)
+    /// for this long is presumed dead: it is closed and its subscriptions are cleaned up.
+    /// `None` keeps silent connections open.
+    pub pong_timeout: Option<Duration>,
+    /// Identifier of this server instance. When set, upgrades carry a sticky routing cookie
+    /// and reconnects pinned to another instance are refused so the load balancer re-routes them.
+    pub instance_id: Option<String>,
+    /// Origins, such as `https://app.example.com`, whose pages may open a socket. Upgrades
+    /// carrying any other `Origin` are refused with 403. Requests without an `Origin`, which
+    /// browsers always send, are not affected. `None` (the default) allows every origin.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Maximum number of concurrent connections. `None` accepts every connection.
+    pub max_connections: Option<usize>,
+    /// Largest number of subscribers a single publish may be delivered to. Larger publishes are
+    /// refused with a `fan_out_too_large` error and audited. `None` allows any fan-out.
+    pub max_fan_out: Option<usize>,
+    /// How fast each connection may publish. Publishes over the limit are refused with a
+    /// `rate_limited` error. `None` (the default) does not limit publishes.
+    pub publish_rate_limit: Option<RateLimit>,
+    /// How many messages may wait to be written to each connection, and what happens to a publish
+    /// that finds the queue full. `None` leaves send queues unbounded.
+    pub send_queue_limit: Option<SendQueueLimit>,
+    /// Message formats offered as `Sec-WebSocket-Protocol` subprotocols, most preferred first.
+    /// A client asking for several gets the first offered one; a client asking for none, or only
+    /// for ones not offered, gets [`WireFormat::default`] and no protocol header.
+    pub subprotocols: Vec<WireFormat>,
+    /// Largest text or binary frame a client may send, in bytes. Larger frames, including
+    /// `register-name:` and `register-session:`, are refused with a `message_too_large` error
+    /// before they are parsed. `None` accepts frames of any size.
+    pub max_message_bytes: Option<usize>,
+    /// Close the connection (code 1009) on a frame over `max_message_bytes` instead of only refusing it.
+    pub close_on_oversized_message: bool,
+    /// Sent as `Retry-After` when a connection is refused because the endpoint is full.
+    pub overload_retry_after: Duration,
+    /// Per-topic capability rules. The first matching policy applies; unmatched topics allow everything.
+    pub topic_policies: Vec<TopicPolicy>,
+    /// Decides from the connection's claims which topics it may subscribe to and publish on,
+    /// after `topic_policies`. The default, `AllowAll`, refuses nothing; `TopicHooks` wraps a
+    /// closure per direction.
+    pub authorizer: Arc<dyn Authorizer>,
+    /// Counters shared by all connections on this endpoint.
+    pub metrics: Arc<Metrics>,
+    /// Outstanding subscription transfer tokens shared by all connections on this endpoint.
+    pub transfers: Arc<SubscriptionTransfers>,
+    /// Per-session sequence numbers and replay buffer shared by all connections on this endpoint.
+    pub history: Arc<MessageHistory>,
+    /// Last retained message per topic and session, shared by all connections on this endpoint.
+    pub retained: Arc<RetainedMessages>,
+    /// Who is connected in each session, shared by all connections on this endpoint.
+    pub presence: Arc<PresenceRoster>,
+    /// Every open connection on this endpoint, so they can be notified and closed on shutdown.
+    pub connections: Arc<ConnectionRegistry>,
+    /// How often a connection that received new messages is sent a fresh resume token.
+    /// `None` disables resume tokens.
+    pub resume_token_interval: Option<Duration>,
+    /// How long a connection that drops without closing keeps its subscriptions, buffering what
+    /// is published to them, for a reconnecting client to reattach with its session token.
+    /// `None` (the default) removes them as soon as the connection ends and issues no tokens.
+    pub session_grace: Option<Duration>,
+    /// Dropped connections waiting to be reattached, shared by all connections on this endpoint.
+    pub parked: Arc<ParkedSessions>,
+    /// How often each connection is sent `{"type":"queue_depth","depth":N}` with the number of
+    /// messages waiting in its send queue. `None` reports only when the client sends `queue-depth`.
+    pub queue_depth_interval: Option<Duration>,
+    /// Receives topic creation and removal events. They are always written to the log.
+    pub audit_sink: Option<Arc<dyn AuditSink>>,
+    /// Run in order on every client publish before it is retained or delivered; any of them
+    /// may rewrite it or drop it. Empty (the default) delivers publishes as sent.
+    pub interceptors: Vec<Arc<dyn MessageInterceptor>>,
+    /// Adds `server_latency_ms` to envelopes delivered for `publish-json:`: the time from receiving
+    /// the publish until its envelope was built, just before fan-out.
+    pub include_server_latency: bool,
+    /// How unknown commands are handled.
+    pub unknown_command_policy: UnknownCommandPolicy,
+    /// Server keypair used for key exchanges, normally the state served by `enc_api_router`.
+    /// `None` refuses key exchanges with `encryption_unavailable`.
+    pub encryption: Option<EncApiState>,
+    /// Content key of each session, handed out by key exchanges.
+    pub session_keys: Arc<SessionKeys>,
+    /// Topic patterns whose publishes must be encrypted under the session key. The server
+    /// forwards, retains and replays their ciphertext without decrypting it.
+    pub encrypted_topics: Vec<String>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            jwt: None,
+            expected_audience: None,
+            reauth_check_interval: None,
+            reauth_grace: Duration::from_secs(30),
+            max_connection_lifetime: None,
+            heartbeat_interval: None,
+            heartbeat_min_interval: Duration::from_secs(5),
+            heartbeat_max_interval: Duration::from_secs(120),
+            pong_timeout: None,
+            instance_id: None,
+            allowed_origins: None,
+            max_connections: None,
+            max_fan_out: None,
+            publish_rate_limit: None,
+            send_queue_limit: Some(SendQueueLimit::default()),
+            subprotocols: vec![WireFormat::Json, WireFormat::MessagePack],
+            max_message_bytes: Some(DEFAULT_MAX_MESSAGE_BYTES),
+            close_on_oversized_message: false,
+            overload_retry_after: Duration::from_secs(5),
+            topic_policies: Vec::new(),
+            authorizer: Arc::new(AllowAll),
+            metrics: Arc::new(Metrics::default()),
+            transfers: Arc::new(SubscriptionTransfers::default()),
+            history: Arc::new(MessageHistory::default()),
+            retained: Arc::new(RetainedMessages::default()),
+            presence: Arc::new(PresenceRoster::default()),
+            connections: Arc::new(ConnectionRegistry::default()),
+            resume_token_interval: None,
+            session_grace: None,
+            parked: Arc::new(ParkedSessions::default()),
+            queue_depth_interval: None,
+            audit_sink: None,
+            interceptors: Vec::new(),
+            include_server_latency: false,
+            unknown_command_policy: UnknownCommandPolicy::Ignore,
+            encryption: None,
+            session_keys: Arc::new(SessionKeys::default()),
+            encrypted_topics: Vec::new(),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// The key tokens are validated with: the `jwt` issuer's, else the configured one.
+    pub fn jwt_key(&self) -> Result<Arc<Zeroizing<[u8; JWT_KEY_LEN]>>, JwtKeyError> {
+        match &self.jwt {
+            Some(jwt) => Ok(jwt.secret_key.clone()),
+            None => load_jwt_key().map(Arc::new),
+        }
+    }
+
+    /// Whether the token these claims came from was revoked at the `jwt` issuer. Always false
+    /// without one, as there is no blocklist to consult.
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        self.jwt.as_ref().is_some_and(|jwt| jwt.is_revoked(claims))
+    }
+
+    /// What tokens must satisfy on this endpoint: the `jwt` issuer's leeway, audience and issuer,
+    /// else the defaults, with `expected_audience` taking precedence over the issuer's audience.
+    pub fn token_validation(&self) -> TokenValidation {
+        let mut validation = self.jwt.as_ref().map(|jwt| jwt.validation.clone()).unwrap_or_default();
+        if self.expected_audience.is_some() {
+            validation.audience = self.expected_audience.clone();
+        }
+        validation
+    }
+
+    /// Whether a page from this origin may open a socket. Origins are compared without regard to
+    /// case or a trailing slash.
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        let origin = origin.trim().trim_end_matches('/');
+        self.allowed_origins.as_ref().is_none_or(|allowed| {
+            allowed.iter().any(|allowed| allowed.trim().trim_end_matches('/').eq_ignore_ascii_case(origin))
+        })
+    }
+
+    /// Whether clients may publish to the topic.
+    pub fn can_publish(&self, topic: &str) -> bool {
+        self.topic_policy(topic).is_none_or(|policy| policy.can_publish)
+    }
+
+    /// Whether clients may subscribe to the topic.
+    pub fn can_subscribe(&self, topic: &str) -> bool {
+        self.topic_policy(topic).is_none_or(|policy| policy.can_subscribe)
+    }
+
+    /// Whether a connection with these claims may publish to the topic: both the topic policies
+    /// and the authorizer must allow it. This is the check every client publish goes through.
+    pub fn may_publish(&self, claims: Option<&Claims>, topic: &str) -> bool {
+        self.can_publish(topic) && self.authorizer.can_publish(claims, topic)
+    }
+
+    /// Whether a connection with these claims may subscribe to the topic: both the topic policies
+    /// and the authorizer must allow it. This is the check every client subscription goes through.
+    pub fn may_subscribe(&self, claims: Option<&Claims>, topic: &str) -> bool {
+        self.can_subscribe(topic) && self.authorizer.can_subscribe(claims, topic)
+    }
+
+    /// Whether publishes to the topic must be encrypted.
+    pub fn requires_encryption(&self, topic: &str) -> bool {
+        self.encrypted_topics.iter().any(|pattern| topic_pattern::matches(pattern, topic))
+    }
+
+    fn topic_policy(&self, topic: &str) -> Option<&TopicPolicy> {
+        self.topic_policies
+            .iter()
+            .find(|policy| topic_pattern::matches(&policy.pattern, topic))
+    }
+}
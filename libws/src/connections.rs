@@ -0,0 +1,83 @@
+// src/connections.rs
+use std::collections::HashMap;
+use std::sync::Mutex;
+use serde_json::json;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+use crate::subscribers::ConnectionId;
+
+/// The send queue of every open connection on an endpoint, so the server can notify them all
+/// and close them when it shuts down.
+#[derive(Debug)]
+pub struct ConnectionRegistry {
+    senders: Mutex<HashMap<ConnectionId, UnboundedSender<String>>>,
+    // Flipped once by `shutdown`; each connection closes when it sees it
+    shutting_down: watch::Sender<bool>,
+    // Number of registered connections, so shutdown can wait for them to drain
+    active: watch::Sender<usize>,
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        ConnectionRegistry {
+            senders: Mutex::default(),
+            shutting_down: watch::channel(false).0,
+            active: watch::channel(0).0,
+        }
+    }
+}
+
+impl ConnectionRegistry {
+    /// Adds a connection. The returned receiver turns true when the server shuts down; a
+    /// connection registered after that is told so straight away.
+    pub(crate) fn register(&self, id: ConnectionId, sender: UnboundedSender<String>) -> watch::Receiver<bool> {
+        let mut senders = self.senders.lock().unwrap();
+        if *self.shutting_down.borrow() {
+            let _ = sender.send(shutdown_notice());
+        }
+        senders.insert(id, sender);
+        self.active.send_replace(senders.len());
+        self.shutting_down.subscribe()
+    }
+
+    /// Removes a connection once it has ended.
+    pub(crate) fn unregister(&self, id: ConnectionId) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.remove(&id);
+        self.active.send_replace(senders.len());
+    }
+
+    /// Sends `{"type":"server_shutdown"}` to every connection and has each close with code 1001
+    /// once the notice and anything queued before it are written. Returns how many were notified.
+    pub fn shutdown(&self) -> usize {
+        let senders = self.senders.lock().unwrap();
+        let notice = shutdown_notice();
+        let notified = senders.values().filter(|sender| sender.send(notice.clone()).is_ok()).count();
+        self.shutting_down.send_replace(true);
+        notified
+    }
+
+    /// Whether [`shutdown`](Self::shutdown) has been called.
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutting_down.borrow()
+    }
+
+    /// Resolves once no connection is registered.
+    pub async fn wait_until_empty(&self) {
+        let mut active = self.active.subscribe();
+        let _ = active.wait_for(|active| *active == 0).await;
+    }
+
+    /// Number of open connections.
+    pub fn len(&self) -> usize {
+        self.senders.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn shutdown_notice() -> String {
+    json!({"type": "server_shutdown"}).to_string()
+}
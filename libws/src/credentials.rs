@@ -0,0 +1,111 @@
+// src/credentials.rs
+use std::collections::HashMap;
+use std::fmt;
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+
+/// Who a verified user is and what goes into their token.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserContext {
+    /// Becomes the token's `sub`.
+    pub user_id: String,
+    /// Becomes the token's `sid`. When `None`, the session requested by the client is used.
+    pub session_id: Option<String>,
+    /// Extra claims minted into the token, such as `roles`.
+    pub claims: Map<String, Value>,
+}
+
+impl UserContext {
+    /// A context for the user with no session and no extra claims.
+    pub fn new(user_id: &str) -> Self {
+        UserContext { user_id: user_id.to_string(), ..Default::default() }
+    }
+
+    /// Pins the user's tokens to a session, overriding the one the client asks for.
+    pub fn with_session(mut self, session_id: &str) -> Self {
+        self.session_id = Some(session_id.to_string());
+        self
+    }
+
+    /// Adds a claim to the user's tokens.
+    pub fn with_claim(mut self, name: &str, value: Value) -> Self {
+        self.claims.insert(name.to_string(), value);
+        self
+    }
+}
+
+/// Why a token request was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// The username or password is wrong.
+    InvalidCredentials,
+    /// The credential backend could not be reached.
+    Unavailable(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "invalid credentials"),
+            AuthError::Unavailable(reason) => write!(f, "credential backend unavailable: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Checks the username and password sent to `/auth/token`.
+#[async_trait]
+pub trait CredentialVerifier: Send + Sync {
+    async fn verify(&self, username: &str, password: &str) -> Result<UserContext, AuthError>;
+}
+
+/// Accepts any non-empty username and password. Only suitable for demos.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PermissiveVerifier;
+
+#[async_trait]
+impl CredentialVerifier for PermissiveVerifier {
+    async fn verify(&self, username: &str, password: &str) -> Result<UserContext, AuthError> {
+        if username.is_empty() || password.is_empty() {
+            return Err(AuthError::InvalidCredentials);
+        }
+        Ok(UserContext::new(username))
+    }
+}
+
+/// Verifies against a fixed set of users held in memory.
+///
+/// Passwords are kept as given, so load them from a secret store rather than source code.
+#[derive(Clone, Debug, Default)]
+pub struct HashMapVerifier {
+    users: HashMap<String, (String, UserContext)>,
+}
+
+impl HashMapVerifier {
+    /// Adds a user whose token carries only their username.
+    pub fn with_user(self, username: &str, password: &str) -> Self {
+        self.with_user_context(username, password, UserContext::new(username))
+    }
+
+    /// Adds a user whose token is minted from the given context.
+    pub fn with_user_context(mut self, username: &str, password: &str, context: UserContext) -> Self {
+        self.users.insert(username.to_string(), (password.to_string(), context));
+        self
+    }
+}
+
+#[async_trait]
+impl CredentialVerifier for HashMapVerifier {
+    async fn verify(&self, username: &str, password: &str) -> Result<UserContext, AuthError> {
+        match self.users.get(username) {
+            Some((expected, context)) if constant_time_eq(expected.as_bytes(), password.as_bytes()) => Ok(context.clone()),
+            _ => Err(AuthError::InvalidCredentials),
+        }
+    }
+}
+
+// Compares without stopping at the first mismatch, so timing does not reveal how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
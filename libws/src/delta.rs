@@ -0,0 +1,76 @@
+// src/delta.rs
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::retained;
+
+/// Subscription option that asks for changed top-level fields instead of full objects.
+pub const DELTA_OPTION: &str = "delta";
+
+/// Computes a JSON merge patch (RFC 7386) over top-level fields: fields whose value changed
+/// or were added, and `null` for fields that were removed.
+pub fn merge_patch(previous: &Map<String, Value>, current: &Map<String, Value>) -> Map<String, Value> {
+    let mut patch: Map<String, Value> = current
+        .iter()
+        .filter(|(key, value)| previous.get(*key) != Some(*value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    for key in previous.keys().filter(|key| !current.contains_key(*key)) {
+        patch.insert(key.clone(), Value::Null);
+    }
+    patch
+}
+
+/// Applies a top-level merge patch produced by [`merge_patch`].
+pub fn apply_merge_patch(target: &mut Map<String, Value>, patch: &Map<String, Value>) {
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            target.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// Rewrites envelopes for a delta subscription before they reach the connection's send queue.
+///
+/// The first delivery, and any whose payload is not a JSON object, carries the full payload with
+/// `"delta": false`. Later object payloads are replaced by a merge patch against the last one
+/// delivered, marked `"delta": true`. An object published as a JSON string is patched as an
+/// object and its patch sent as a string.
+pub(crate) async fn forward_deltas(mut rx: UnboundedReceiver<String>, tx: UnboundedSender<String>) {
+    let mut last: Option<Map<String, Value>> = None;
+    while let Some(envelope) = rx.recv().await {
+        let Ok(mut parsed) = serde_json::from_str::<Value>(&envelope) else {
+            let _ = tx.send(envelope);
+            continue;
+        };
+        // A tombstone means the subscriber purges its copy, so the next value is sent in full
+        if parsed["type"] == retained::TOMBSTONE_TYPE {
+            last = None;
+            if tx.send(envelope).is_err() {
+                break;
+            }
+            continue;
+        }
+        let encoded = parsed["payload"].is_string();
+        let current = match &parsed["payload"] {
+            Value::Object(payload) => Some(payload.clone()),
+            Value::String(payload) => serde_json::from_str::<Map<String, Value>>(payload).ok(),
+            _ => None,
+        };
+        let patch = match (&last, &current) {
+            (Some(previous), Some(current)) => Some(merge_patch(previous, current)),
+            _ => None,
+        };
+
+        parsed["delta"] = json!(patch.is_some());
+        if let Some(patch) = patch {
+            let patch = Value::Object(patch);
+            parsed["payload"] = if encoded { json!(patch.to_string()) } else { patch };
+        }
+        last = current;
+        if tx.send(parsed.to_string()).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,26 @@
+// src/direct.rs
+
+/// Prefix of the private topics behind direct messages. Clients cannot `subscribe:` or
+/// `publish-json:` to these topics; they use `subscribe-self` and `publish-to:` instead.
+pub const DIRECT_TOPIC_PREFIX: &str = "@direct/";
+
+/// Session that direct topics live in, so a message reaches its addressee whatever session
+/// the addressee registered.
+pub const DIRECT_SESSION: &str = "direct";
+
+/// Private topic that direct messages to `address` are delivered on.
+pub fn direct_topic(address: &str) -> String {
+    format!("{}{}", DIRECT_TOPIC_PREFIX, address)
+}
+
+/// Whether a topic is reserved for direct messages.
+pub fn is_direct_topic(topic: &str) -> bool {
+    topic.starts_with(DIRECT_TOPIC_PREFIX)
+}
+
+/// Address of a connection: its authenticated `sub`, or a random per-connection id when anonymous.
+pub(crate) fn connection_address(user_id: Option<&str>) -> String {
+    user_id
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| format!("anon-{}", crate::new_correlation_id()))
+}
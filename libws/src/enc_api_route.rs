@@ -4,13 +4,51 @@ use axum::{
     Router,
     routing::get,
     extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
-use crate::enc_utils::KeyPair;
-use std::sync::Arc;
+use crate::enc_utils::{EncryptionError, KeyPair};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+/// Cache policy for the public key; clients revalidate with `If-None-Match` once it lapses.
+const PUBLIC_KEY_CACHE_CONTROL: &str = "public, max-age=300, must-revalidate";
 
 #[derive(Clone)]
 pub struct EncApiState {
-    pub keypair: Arc<KeyPair>,
+    pub keypair: Arc<RwLock<KeyPair>>,
+}
+
+impl EncApiState {
+    pub fn new(keypair: KeyPair) -> Self {
+        EncApiState { keypair: Arc::new(RwLock::new(keypair)) }
+    }
+
+    /// Replaces the server keypair. Clients holding the old ETag will get the new key on their next fetch.
+    pub fn rotate(&self, keypair: KeyPair) {
+        *self.keypair.write().unwrap() = keypair;
+        info!("Rotated encryption key, new key id {}", self.key_id());
+    }
+
+    /// Base64 public key currently served.
+    pub fn public_key(&self) -> String {
+        self.keypair.read().unwrap().public_key.clone()
+    }
+
+    /// Stable identifier of the current key, used as its ETag.
+    pub fn key_id(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.public_key().hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+impl std::fmt::Debug for EncApiState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncApiState").field("key_id", &self.key_id()).finish()
+    }
 }
 
 /// Builds a router exposing encryption-related endpoints
@@ -21,16 +59,40 @@ where
 {
     Router::new()
         .route("/enc/public-key", get(
-            move |_: State<S>| async move {
-                // Just return the stored base64 public key directly
-                state.keypair.public_key.clone()
+            move |_: State<S>, headers: HeaderMap| async move {
+                public_key_response(&state, &headers)
             }
         ))
 }
 
+/// Serves the compressed SEC1 public key with caching headers keyed on the key id,
+/// answering 304 when the client already holds the current key.
+fn public_key_response(state: &EncApiState, headers: &HeaderMap) -> Response {
+    let public_key = state.public_key();
+    let etag = format!("\"{}\"", state.key_id());
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, PUBLIC_KEY_CACHE_CONTROL.to_string()),
+    ];
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag == etag
+        }));
+
+    if not_modified {
+        (StatusCode::NOT_MODIFIED, cache_headers).into_response()
+    } else {
+        (StatusCode::OK, cache_headers, public_key).into_response()
+    }
+}
+
 /// Create a new EncApiState with a P-256 keypair for web compatibility
-pub fn create_web_compatible_state() -> EncApiState {
-    let keypair = Arc::new(KeyPair::generate_p256());
-    println!("Generated web-compatible P-256 encryption key");
-    EncApiState { keypair }
+pub fn create_web_compatible_state() -> Result<EncApiState, EncryptionError> {
+    let state = EncApiState::new(KeyPair::generate_p256()?);
+    info!("Generated web-compatible P-256 encryption key");
+    Ok(state)
 }
@@ -1,23 +1,48 @@
 // src/enc_util.rs
 
-use aes_gcm::{Aes256Gcm, KeyInit, aead::Aead};
-use rand::{rngs::OsRng, RngCore};
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, Payload}};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 use generic_array::GenericArray;
 // Update to use new base64 API
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::fmt;
+use zeroize::Zeroizing;
 
 // P-256 imports
 use p256::{
-    ecdh::EphemeralSecret as P256Secret,
-    EncodedPoint as P256EncodedPoint, PublicKey as P256PublicKey
+    ecdh::diffie_hellman as p256_diffie_hellman,
+    EncodedPoint as P256EncodedPoint, PublicKey as P256PublicKey, SecretKey as P256SecretKey
 };
 
+/// Errors raised while generating key material
+#[derive(Debug)]
+pub enum EncryptionError {
+    /// The random number generator could not produce bytes
+    Rng(rand::Error),
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionError::Rng(e) => write!(f, "random number generator failed: {}", e),
+        }
+    }
+}
+
+impl Error for EncryptionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EncryptionError::Rng(e) => Some(e),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct KeyPair {
-    pub private_key: Vec<u8>,
+    pub private_key: Zeroizing<Vec<u8>>, // Scrubbed from memory when the keypair is dropped
     pub public_key: String, // Base64 encoded public key for serde compatibility
     pub key_type: KeyType,  // Indicates which curve is used
 }
@@ -29,57 +54,70 @@ pub enum KeyType {
 }
 
 impl KeyPair {
-    pub fn generate() -> Self {
-        // Generate a new static secret key using random_from_rng
-        let private_key = StaticSecret::random_from_rng(OsRng);
+    pub fn generate() -> Result<Self, EncryptionError> {
+        Self::generate_with_rng(&mut OsRng)
+    }
+
+    /// Generates an X25519 keypair from the given RNG, failing cleanly if it cannot supply bytes
+    pub fn generate_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Result<Self, EncryptionError> {
+        let mut secret_bytes = Zeroizing::new([0u8; 32]);
+        rng.try_fill_bytes(&mut *secret_bytes).map_err(EncryptionError::Rng)?;
+        let private_key = StaticSecret::from(*secret_bytes);
         let public_key = X25519PublicKey::from(&private_key);
         
-        KeyPair {
-            private_key: private_key.to_bytes().to_vec(),
+        Ok(KeyPair {
+            private_key: Zeroizing::new(private_key.as_bytes().to_vec()),
             public_key: serialize_public_key(&public_key),
             key_type: KeyType::X25519,
-        }
+        })
     }
 
-    pub fn generate_p256() -> Self {
-        // Generate a P-256 key for Web compatibility using a safer approach
-        let ephemeral_secret = P256Secret::random(&mut OsRng);
-        let public_key = P256PublicKey::from(&ephemeral_secret);
-        let encoded_point = P256EncodedPoint::from(public_key);
-        
-        // Create bytes to store
-        // We'll generate a new random private key and store it directly 
-        // This won't be the exact same bytes as in ephemeral_secret, but it will be a valid key
-        let mut private_bytes = [0u8; 32];
-        OsRng.fill_bytes(&mut private_bytes);
+    pub fn generate_p256() -> Result<Self, EncryptionError> {
+        Self::generate_p256_with_rng(&mut OsRng)
+    }
+
+    /// Generates a P-256 keypair for Web compatibility from the given RNG
+    pub fn generate_p256_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Result<Self, EncryptionError> {
+        // Draw candidate scalars until one is a valid non-zero key (almost always the first)
+        let (private_bytes, secret_key) = loop {
+            let mut private_bytes = Zeroizing::new([0u8; 32]);
+            rng.try_fill_bytes(&mut *private_bytes).map_err(EncryptionError::Rng)?;
+            if let Ok(secret_key) = P256SecretKey::from_slice(&*private_bytes) {
+                break (private_bytes, secret_key);
+            }
+        };
+        let encoded_point = P256EncodedPoint::from(secret_key.public_key());
         
-        KeyPair {
-            private_key: private_bytes.to_vec(),
+        Ok(KeyPair {
+            private_key: Zeroizing::new(private_bytes.to_vec()),
             public_key: BASE64.encode(encoded_point.compress().as_bytes()),
             key_type: KeyType::P256,
-        }
+        })
     }
 
     pub fn get_public_key(&self) -> Result<X25519PublicKey, Box<dyn Error>> {
         deserialize_public_key(&self.public_key)
     }
 
-    pub fn compute_shared_secret(&self, other_public_key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    /// Derives the X25519 shared secret with a peer; it is scrubbed from memory when dropped
+    pub fn compute_shared_secret(&self, other_public_key: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
         let their_public_key = deserialize_public_key(other_public_key)?;
         
         // Convert self.private_key back to StaticSecret
-        let my_private_key = StaticSecret::from(
+        let private_bytes = Zeroizing::new(
             <[u8; 32]>::try_from(&self.private_key[..]).map_err(|_| "Invalid private key length")?
         );
+        let my_private_key = StaticSecret::from(*private_bytes);
         
         // Compute the shared secret
         let shared_secret = my_private_key.diffie_hellman(&their_public_key);
         
         // Return the bytes of the shared secret
-        Ok(shared_secret.as_bytes().to_vec())
+        Ok(Zeroizing::new(shared_secret.as_bytes().to_vec()))
     }
 
-    pub fn compute_shared_secret_p256(&self, other_public_key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    /// Derives the P-256 shared secret with a peer; it is scrubbed from memory when dropped
+    pub fn compute_shared_secret_p256(&self, other_public_key: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
         // For P-256 key exchange
         if self.key_type != KeyType::P256 {
             return Err("This keypair is not a P-256 keypair".into());
@@ -93,35 +131,43 @@ impl KeyPair {
         let their_public_key = P256PublicKey::from_sec1_bytes(point.as_bytes())
             .map_err(|e| format!("Invalid P-256 public key: {}", e))?;
         
-        // Generate a new ephemeral secret for each computation
-        // This is safer than trying to reconstruct the original one
-        let ephemeral_secret = P256Secret::random(&mut OsRng);
+        // Reconstruct the secret behind our published public key; a fresh ephemeral
+        // secret here would derive a key the peer can never reproduce
+        let my_secret_key = P256SecretKey::from_slice(&self.private_key)
+            .map_err(|e| format!("Invalid P-256 private key: {}", e))?;
         
         // Compute shared secret
-        let shared_secret = ephemeral_secret.diffie_hellman(&their_public_key);
+        let shared_secret = p256_diffie_hellman(my_secret_key.to_nonzero_scalar(), their_public_key.as_affine());
         
         // Return the bytes of the shared secret
-        Ok(shared_secret.raw_secret_bytes().to_vec())
+        Ok(Zeroizing::new(shared_secret.raw_secret_bytes().to_vec()))
     }
 }
 
-fn generate_nonce() -> GenericArray<u8, typenum::U12> {
+fn generate_nonce<R: RngCore + CryptoRng>(rng: &mut R) -> Result<GenericArray<u8, typenum::U12>, EncryptionError> {
     let mut nonce = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce);
-    *GenericArray::from_slice(&nonce)
+    rng.try_fill_bytes(&mut nonce).map_err(EncryptionError::Rng)?;
+    Ok(*GenericArray::from_slice(&nonce))
+}
+
+/// Encrypts with AES-256-GCM, authenticating `aad` alongside the data. The same `aad` must be
+/// passed to `decrypt`; an empty slice binds the ciphertext to nothing beyond the key.
+pub fn encrypt(data: &[u8], shared_secret: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    encrypt_with_rng(data, shared_secret, aad, &mut OsRng)
 }
 
-pub fn encrypt(data: &[u8], shared_secret: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+/// Encrypts with a nonce drawn from the given RNG
+pub fn encrypt_with_rng<R: RngCore + CryptoRng>(data: &[u8], shared_secret: &[u8], aad: &[u8], rng: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
     // Use shared secret as AES key
-    let key_bytes = <[u8; 32]>::try_from(shared_secret).map_err(|_| "Invalid key length")?;
-    let key = Aes256Gcm::new(GenericArray::from_slice(&key_bytes));
+    let key_bytes = Zeroizing::new(<[u8; 32]>::try_from(shared_secret).map_err(|_| "Invalid key length")?);
+    let key = Aes256Gcm::new(GenericArray::from_slice(&*key_bytes));
     
-    let nonce = generate_nonce();
+    let nonce = generate_nonce(rng)?;
     
     // Encrypt the data with explicit error type annotation
-    let ciphertext = key.encrypt(&nonce, data)
+    let ciphertext = key.encrypt(&nonce, Payload { msg: data, aad })
         .map_err(|e| -> Box<dyn Error> { 
-            Box::new(std::io::Error::new(std::io::ErrorKind::Other, 
+            Box::new(std::io::Error::other(
                 format!("Encryption error: {:?}", e)))
         })?;
     
@@ -168,7 +214,9 @@ pub fn deserialize_p256_public_key(encoded: &str) -> Result<P256PublicKey, Box<d
         .map_err(|e| format!("Invalid P-256 public key: {}", e).into())
 }
 
-pub fn decrypt(encrypted_data: &[u8], shared_secret: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+/// Decrypts a nonce and ciphertext from `encrypt`. Fails when the data was tampered with or
+/// `aad` differs from what it was encrypted with.
+pub fn decrypt(encrypted_data: &[u8], shared_secret: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     if encrypted_data.len() <= 12 {
         return Err("Encrypted data too short".into());
     }
@@ -178,13 +226,13 @@ pub fn decrypt(encrypted_data: &[u8], shared_secret: &[u8]) -> Result<Vec<u8>, B
     let nonce = GenericArray::from_slice(nonce);
     
     // Use shared secret as AES key
-    let key_bytes = <[u8; 32]>::try_from(shared_secret).map_err(|_| "Invalid key length")?;
-    let key = Aes256Gcm::new(GenericArray::from_slice(&key_bytes));
+    let key_bytes = Zeroizing::new(<[u8; 32]>::try_from(shared_secret).map_err(|_| "Invalid key length")?);
+    let key = Aes256Gcm::new(GenericArray::from_slice(&*key_bytes));
     
     // Decrypt the data with explicit error type annotation
-    let plaintext = key.decrypt(nonce, ciphertext)
+    let plaintext = key.decrypt(nonce, Payload { msg: ciphertext, aad })
         .map_err(|e| -> Box<dyn Error> { 
-            Box::new(std::io::Error::new(std::io::ErrorKind::Other, 
+            Box::new(std::io::Error::other(
                 format!("Decryption error: {:?}", e)))
         })?;
     
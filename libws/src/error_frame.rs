@@ -0,0 +1,138 @@
+// src/error_frame.rs
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::command::ServerMessage;
+
+/// Why the server refused or could not handle a client frame. Sent as the `code` of an
+/// `{"type":"error"}` frame to the connection that sent it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// A text frame that is not a known command; only sent under `UnknownCommandPolicy::ErrorReply`
+    /// or `DisconnectAfter`.
+    UnknownCommand,
+    /// A `publish-json:` body that is not valid JSON.
+    BadJson,
+    /// A subscribe, unsubscribe or publish without a topic.
+    MissingTopic,
+    /// A binary frame that could not be decoded.
+    InvalidBinaryFrame,
+    /// A publish with an unknown `encoding`, or a payload that does not decode under it.
+    InvalidEncoding,
+    /// A subscribe with an option the server does not recognise.
+    InvalidSubscriptionOption,
+    /// A subscribe to a malformed wildcard pattern.
+    InvalidTopicPattern,
+    /// The topic policy forbids subscribing to the topic.
+    SubscribeNotAllowed,
+    /// The topic policy forbids publishing to the topic.
+    PublishNotAllowed,
+    /// A broadcast from a connection whose token lacks the `admin` role.
+    BroadcastNotAllowed,
+    /// A token-bound connection named another session.
+    SessionMismatch,
+    /// A publish would reach more subscribers than `max_fan_out`.
+    FanOutTooLarge,
+    /// A publish would reach fewer subscribers than its `min_subscribers`.
+    InsufficientSubscribers,
+    /// A publish beyond the connection's `publish_rate_limit`.
+    RateLimited,
+    /// A key exchange on an endpoint without a server keypair, or with an unusable public key.
+    EncryptionUnavailable,
+    /// A key exchange from a connection whose token does not carry its session.
+    KeyExchangeNotAllowed,
+    /// A publish to one of the `encrypted_topics` that is not encrypted.
+    EncryptionRequired,
+    /// No connection is subscribed to a direct message's address.
+    RecipientUnavailable,
+    /// A transfer token that is unknown, used or expired.
+    InvalidTransferToken,
+    /// A resume token that failed validation, or a session token that is unknown, used or expired.
+    InvalidResumeToken,
+    /// A frame larger than the endpoint's `max_message_bytes`; it was not parsed.
+    MessageTooLarge,
+}
+
+impl ErrorCode {
+    /// Name used on the wire.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::BadJson => "bad_json",
+            ErrorCode::MissingTopic => "missing_topic",
+            ErrorCode::InvalidBinaryFrame => "invalid_binary_frame",
+            ErrorCode::InvalidEncoding => "invalid_encoding",
+            ErrorCode::InvalidSubscriptionOption => "invalid_subscription_option",
+            ErrorCode::InvalidTopicPattern => "invalid_topic_pattern",
+            ErrorCode::SubscribeNotAllowed => "subscribe_not_allowed",
+            ErrorCode::PublishNotAllowed => "publish_not_allowed",
+            ErrorCode::BroadcastNotAllowed => "broadcast_not_allowed",
+            ErrorCode::SessionMismatch => "session_mismatch",
+            ErrorCode::FanOutTooLarge => "fan_out_too_large",
+            ErrorCode::InsufficientSubscribers => "insufficient_subscribers",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::EncryptionUnavailable => "encryption_unavailable",
+            ErrorCode::KeyExchangeNotAllowed => "key_exchange_not_allowed",
+            ErrorCode::EncryptionRequired => "encryption_required",
+            ErrorCode::RecipientUnavailable => "recipient_unavailable",
+            ErrorCode::InvalidTransferToken => "invalid_transfer_token",
+            ErrorCode::InvalidResumeToken => "invalid_resume_token",
+            ErrorCode::MessageTooLarge => "message_too_large",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Builds `{"type":"error","code":...}` with the fields of `extra`, such as `topic` or `detail`.
+/// Null fields are left out.
+pub fn error_frame(code: ErrorCode, extra: Value) -> Value {
+    let fields = match extra {
+        Value::Object(extra) => extra.into_iter().filter(|(_, value)| !value.is_null()).collect(),
+        _ => Map::new(),
+    };
+    ServerMessage::Error { code, fields }.to_value()
+}
+
+/// An error frame as received by a client.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerError {
+    /// Wire name of the error. Kept as text so codes added by newer servers still arrive.
+    pub code: String,
+    /// Explanation of what went wrong, when the server gave one.
+    #[serde(default)]
+    pub detail: Option<String>,
+    /// Topic the rejected frame was about, if any.
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Id the rejected command carried, if any.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Remaining fields, such as `command`, `session_id` or `max_fan_out`.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ServerError {
+    /// The code as an [`ErrorCode`], or `None` when this build does not know it.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        serde_json::from_value(Value::from(self.code.as_str())).ok()
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.code)?;
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
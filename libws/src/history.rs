@@ -0,0 +1,124 @@
+// src/history.rs
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use crate::topic_pattern;
+
+/// Per-session message sequencing and a bounded replay buffer.
+///
+/// Every message published into a session gets the next sequence number for that session.
+/// The most recent `retention` envelopes are kept so a reconnecting subscriber can catch up,
+/// and the most recent `topic_retention` per topic so a new subscriber can be sent them.
+#[derive(Debug, Default)]
+pub struct MessageHistory {
+    retention: usize,
+    topic_retention: usize,
+    // Each session is locked on its own so publishes to different sessions do not contend
+    sessions: Mutex<HashMap<String, Arc<Mutex<SessionHistory>>>>,
+}
+
+#[derive(Debug, Default)]
+struct SessionHistory {
+    last_seq: u64,
+    // (seq, topic, envelope), oldest first
+    messages: VecDeque<(u64, String, String)>,
+    // Per topic (seq, envelope), oldest first
+    recent: HashMap<String, VecDeque<(u64, String)>>,
+}
+
+/// Messages replayed for a subscriber catching up after a checkpoint.
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// Envelopes published after the checkpoint, in sequence order.
+    pub messages: Vec<String>,
+    /// True when some messages after the checkpoint had already been evicted.
+    pub truncated: bool,
+}
+
+impl MessageHistory {
+    /// Keeps up to `per_session` messages per session for replay.
+    pub fn with_retention(per_session: usize) -> Self {
+        MessageHistory {
+            retention: per_session,
+            ..Default::default()
+        }
+    }
+
+    /// Also keeps the last `per_topic` messages of each topic in a session, replayed to
+    /// every new subscription of that topic. 0, the default, replays nothing.
+    pub fn with_topic_retention(mut self, per_topic: usize) -> Self {
+        self.topic_retention = per_topic;
+        self
+    }
+
+    /// Assigns the next sequence number in the session, builds the envelope for it and retains it.
+    /// The envelope is handed to `deliver` before the session's next message is sequenced, so
+    /// concurrent publishers cannot deliver a session's messages out of sequence order.
+    pub(crate) fn append(&self, session_id: &str, topic: &str, build: impl FnOnce(u64) -> String, deliver: impl FnOnce(u64, &str)) {
+        let history = self.session(session_id);
+        let mut history = history.lock().unwrap();
+        history.last_seq += 1;
+        let envelope = build(history.last_seq);
+        if self.retention > 0 {
+            if history.messages.len() >= self.retention {
+                history.messages.pop_front();
+            }
+            let seq = history.last_seq;
+            history.messages.push_back((seq, topic.to_string(), envelope.clone()));
+        }
+        if self.topic_retention > 0 {
+            let seq = history.last_seq;
+            let recent = history.recent.entry(topic.to_string()).or_default();
+            if recent.len() >= self.topic_retention {
+                recent.pop_front();
+            }
+            recent.push_back((seq, envelope.clone()));
+        }
+        let seq = history.last_seq;
+        deliver(seq, &envelope);
+    }
+
+    /// Retained messages in the session on any of `topics` (exact topics or wildcard patterns) with a sequence number above `after_seq`.
+    pub fn since(&self, session_id: &str, after_seq: u64, topics: &[String]) -> Replay {
+        let Some(history) = self.sessions.lock().unwrap().get(session_id).cloned() else {
+            return Replay::default();
+        };
+        let history = history.lock().unwrap();
+        let oldest = history.messages.front().map_or(history.last_seq + 1, |(seq, _, _)| *seq);
+        Replay {
+            messages: history.messages
+                .iter()
+                .filter(|(seq, topic, _)| *seq > after_seq && topics.iter().any(|subscribed| topic_pattern::covers(subscribed, topic)))
+                .map(|(_, _, envelope)| envelope.clone())
+                .collect(),
+            truncated: history.last_seq > after_seq && oldest > after_seq + 1,
+        }
+    }
+
+    /// The last messages kept for each topic in the session covered by `pattern` (an exact topic or
+    /// a wildcard pattern), as `(topic, envelope)` in sequence order.
+    pub fn recent(&self, session_id: &str, pattern: &str) -> Vec<(String, String)> {
+        let Some(history) = self.sessions.lock().unwrap().get(session_id).cloned() else {
+            return Vec::new();
+        };
+        let history = history.lock().unwrap();
+        let mut recent: Vec<(u64, String, String)> = history.recent
+            .iter()
+            .filter(|(topic, _)| topic_pattern::covers(pattern, topic))
+            .flat_map(|(topic, messages)| {
+                messages.iter().map(move |(seq, envelope)| (*seq, topic.clone(), envelope.clone()))
+            })
+            .collect();
+        recent.sort_by_key(|(seq, _, _)| *seq);
+        recent.into_iter().map(|(_, topic, envelope)| (topic, envelope)).collect()
+    }
+
+    /// Sequence number of the latest message published in the session, 0 if none.
+    pub fn last_seq(&self, session_id: &str) -> u64 {
+        let history = self.sessions.lock().unwrap().get(session_id).cloned();
+        history.map_or(0, |history| history.lock().unwrap().last_seq)
+    }
+
+    fn session(&self, session_id: &str) -> Arc<Mutex<SessionHistory>> {
+        self.sessions.lock().unwrap().entry(session_id.to_string()).or_default().clone()
+    }
+}
@@ -0,0 +1,94 @@
+// src/interceptor.rs
+use std::fmt;
+use std::sync::Arc;
+use serde_json::Value;
+use crate::timestamp::now_rfc3339;
+
+/// A client publish on its way to the topic's subscribers, as seen by a [`MessageInterceptor`].
+/// The payload and metadata may be changed; the topic and session are fixed, as the publish was
+/// authorized for them.
+///
+/// Encrypted payloads are ciphertext here, and compressed ones are still compressed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub publisher_name: String,
+    pub payload: Value,
+    pub timestamp: String,
+    pub correlation_id: String,
+    topic: String,
+    session_id: String,
+}
+
+impl Envelope {
+    pub(crate) fn new(publisher_name: String, topic: &str, session_id: &str, payload: Value, timestamp: String, correlation_id: String) -> Self {
+        Envelope {
+            publisher_name,
+            payload,
+            timestamp,
+            correlation_id,
+            topic: topic.to_string(),
+            session_id: session_id.to_string(),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+/// What happens to a publish after an interceptor has seen it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Pass it to the next interceptor, or deliver it after the last.
+    Forward,
+    /// Discard it: later interceptors do not see it and no subscriber receives it.
+    Drop,
+}
+
+/// Inspects or rewrites each client publish before it is retained, sequenced and fanned out,
+/// for example to validate, audit or redact payloads. Interceptors in
+/// `ConnectionConfig::interceptors` run in order, each seeing the changes made by the ones before.
+pub trait MessageInterceptor: Send + Sync {
+    fn on_publish(&self, msg: &mut Envelope) -> Decision;
+}
+
+impl fmt::Debug for dyn MessageInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MessageInterceptor")
+    }
+}
+
+/// Forwards every publish unchanged.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopInterceptor;
+
+impl MessageInterceptor for NoopInterceptor {
+    fn on_publish(&self, _msg: &mut Envelope) -> Decision {
+        Decision::Forward
+    }
+}
+
+/// Replaces the publisher's `timestamp` with the server's clock, so subscribers can trust it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerTimestamp;
+
+impl MessageInterceptor for ServerTimestamp {
+    fn on_publish(&self, msg: &mut Envelope) -> Decision {
+        msg.timestamp = now_rfc3339();
+        Decision::Forward
+    }
+}
+
+/// Runs the chain on a publish, stopping at the first interceptor that drops it.
+pub(crate) fn run(interceptors: &[Arc<dyn MessageInterceptor>], msg: &mut Envelope) -> Decision {
+    for interceptor in interceptors {
+        if interceptor.on_publish(msg) == Decision::Drop {
+            return Decision::Drop;
+        }
+    }
+    Decision::Forward
+}
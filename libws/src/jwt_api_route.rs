@@ -1,22 +1,177 @@
 use axum::{
     Router,
-    routing::post,
+    routing::{get, post},
     extract::State,
     Json,
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::env;
-use crate::jwt_utils::create_token;
+use tokio::sync::Semaphore;
+use zeroize::Zeroizing;
+use crate::credentials::{AuthError, CredentialVerifier, PermissiveVerifier};
+use crate::jwt_utils::{
+    configured_jwt_key, create_refresh_token, create_token_with_issuer, extract_token, load_jwt_key, validate_refresh_token_with,
+    validate_token_with, Claims, JwtKeyError, TokenValidation, DEFAULT_LEEWAY_SECS,
+};
+use crate::revocation::{RevokedToken, TokenRevocation};
+use crate::BROADCAST_ROLE;
+use tracing::{error, info, warn};
 
 /// JWT configuration state
 #[derive(Clone)]
 pub struct JwtState {
-    pub secret_key: Arc<[u8; 32]>,
+    /// HMAC signing key, scrubbed from memory when the last clone of the state is dropped
+    pub secret_key: Arc<Zeroizing<[u8; 32]>>,
+    /// Lifetime of access tokens issued without a requested TTL
     pub token_expiration: Duration,
+    /// Longest TTL a token request may ask for; requests beyond it get 400
+    pub max_token_expiration: Duration,
+    /// Lifetime of the refresh tokens issued alongside access tokens
+    pub refresh_expiration: Duration,
+    /// Ids of tokens that were revoked, and of refresh tokens already used, until they expire.
+    /// Give the state to `ConnectionConfig::jwt` so revoked access tokens are refused on `/ws`.
+    pub revocations: TokenRevocation,
+    /// Permits for concurrent token requests; `None` leaves issuance unlimited
+    pub issuance_permits: Option<Arc<Semaphore>>,
+    /// Sent as `Retry-After` when a token request is shed because all permits are taken
+    pub issuance_retry_after: Duration,
+    /// Checks credentials and supplies the user's session and extra claims
+    pub verifier: Arc<dyn CredentialVerifier>,
+    /// Expiry leeway, and the audience and issuer stamped on issued tokens and required when validating them
+    pub validation: TokenValidation,
+}
+
+impl std::fmt::Debug for JwtState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The signing key is deliberately left out
+        f.debug_struct("JwtState")
+            .field("token_expiration", &self.token_expiration)
+            .field("max_token_expiration", &self.max_token_expiration)
+            .field("refresh_expiration", &self.refresh_expiration)
+            .field("issuance_limited", &self.issuance_permits.is_some())
+            .field("validation", &self.validation)
+            .field("revoked", &self.revocations.len())
+            .finish()
+    }
+}
+
+impl JwtState {
+    /// Limits how many token requests are processed at once; excess requests get 503
+    pub fn with_issuance_limit(mut self, max_concurrent: usize, retry_after: Duration) -> Self {
+        self.issuance_permits = Some(Arc::new(Semaphore::new(max_concurrent)));
+        self.issuance_retry_after = retry_after;
+        self
+    }
+
+    /// Caps the TTL that token requests may ask for
+    pub fn with_max_token_expiration(mut self, max: Duration) -> Self {
+        self.max_token_expiration = max;
+        self
+    }
+
+    // Lifetime of access tokens issued without a requested TTL, within the maximum
+    fn default_expiration(&self) -> Duration {
+        self.token_expiration.min(self.max_token_expiration)
+    }
+
+    /// Lifetime granted to an access token: the requested TTL in seconds, or the default
+    /// clamped to the maximum. A TTL of zero or beyond the maximum is refused.
+    pub fn granted_expiration(&self, requested_secs: Option<u64>) -> Result<Duration, String> {
+        match requested_secs {
+            None => Ok(self.default_expiration()),
+            Some(0) => Err("Requested TTL must be positive".to_string()),
+            Some(secs) if Duration::from_secs(secs) > self.max_token_expiration => Err(format!(
+                "Requested TTL exceeds the maximum of {} seconds", self.max_token_expiration.as_secs()
+            )),
+            Some(secs) => Ok(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Verifies credentials with the given backend instead of the permissive default
+    pub fn with_verifier(mut self, verifier: Arc<dyn CredentialVerifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    /// Sets the expiry leeway and the audience and issuer that issued tokens carry and validation requires
+    pub fn with_token_validation(mut self, validation: TokenValidation) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Revokes a refresh token so `/auth/refresh` rejects it. Returns false if it is not a valid refresh token.
+    pub fn revoke_refresh_token(&self, refresh_token: &str) -> bool {
+        match validate_refresh_token_with(refresh_token, &self.secret_key[..], &self.validation) {
+            Ok(claims) => self.revocations.revoke(&claims.jti.unwrap_or_default(), claims.exp),
+            Err(_) => false,
+        }
+    }
+
+    /// Revokes an access or refresh token signed by this state, returning its claims.
+    /// Fails if the token is not valid or carries no `jti`.
+    pub fn revoke_token(&self, token: &str) -> Result<Claims, String> {
+        let claims = validate_token_with(token, &self.secret_key[..], &self.validation)
+            .or_else(|_| validate_refresh_token_with(token, &self.secret_key[..], &self.validation))
+            .map_err(|e| e.to_string())?;
+        let Some(jti) = claims.jti.as_deref() else {
+            return Err("token has no jti claim".to_string());
+        };
+        self.revocations.revoke(jti, claims.exp);
+        Ok(claims)
+    }
+
+    /// Whether the token these claims came from has been revoked.
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        claims.jti.as_deref().is_some_and(|jti| self.revocations.is_revoked(jti))
+    }
+
+    // Whether the request carries a bearer token from this state granting the admin role
+    fn is_admin_request(&self, headers: &HeaderMap) -> bool {
+        headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(extract_token)
+            .and_then(|token| validate_token_with(token, &self.secret_key[..], &self.validation).ok())
+            .is_some_and(|claims| claims.has_role(BROADCAST_ROLE) && !self.is_revoked(&claims))
+    }
+
+    // Issues an access token living `expiration` and a matching refresh token
+    fn issue_tokens(&self, user_id: &str, session_id: Option<&str>, claims: Map<String, Value>, expiration: Duration) -> ApiResponse {
+        let tokens = create_token_with_issuer(
+            user_id,
+            session_id,
+            self.validation.audience.as_deref(),
+            self.validation.issuer.as_deref(),
+            claims.clone(),
+            &self.secret_key[..],
+            expiration,
+        ).and_then(|token| {
+            let refresh_token = create_refresh_token(user_id, session_id, claims, &self.secret_key[..], self.refresh_expiration)?;
+            Ok((token, refresh_token))
+        });
+        match tokens {
+            Ok((token, refresh_token)) => {
+                ApiResponse::Success(AuthResponse {
+                    token,
+                    expires_in: expiration.as_secs(),
+                    refresh_token,
+                    refresh_expires_in: self.refresh_expiration.as_secs(),
+                })
+            },
+            Err(_) => {
+                ApiResponse::Error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorResponse {
+                        error: "Failed to generate token".to_string(),
+                    }
+                )
+            }
+        }
+    }
 }
 
 /// Request payload for authentication
@@ -25,6 +180,35 @@ pub struct AuthRequest {
     pub username: String,
     pub password: String,
     pub session_id: Option<String>,
+    /// Lifetime in seconds asked for the access token, up to `JwtState::max_token_expiration`
+    pub ttl_seconds: Option<u64>,
+}
+
+/// Request payload for exchanging a refresh token
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Request payload for revoking a token: either the token itself, or (admins only) its id
+#[derive(Deserialize)]
+pub struct RevokeRequest {
+    pub token: Option<String>,
+    pub jti: Option<String>,
+    /// Expiry of the token named by `jti`; defaults to the longest an access token can live
+    pub exp: Option<u64>,
+}
+
+/// Response payload for a revoked token
+#[derive(Serialize)]
+pub struct RevokeResponse {
+    pub revoked: String,
+}
+
+/// Response payload listing the blocklist
+#[derive(Serialize)]
+pub struct RevokedListResponse {
+    pub revoked: Vec<RevokedToken>,
 }
 
 /// Response payload for successful authentication
@@ -32,6 +216,9 @@ pub struct AuthRequest {
 pub struct AuthResponse {
     pub token: String,
     pub expires_in: u64,
+    /// Exchange at `/auth/refresh` for a new access token; single use
+    pub refresh_token: String,
+    pub refresh_expires_in: u64,
 }
 
 /// Error response for failed authentication
@@ -43,7 +230,10 @@ pub struct ErrorResponse {
 // Define a unified API response to handle both success and error cases
 enum ApiResponse {
     Success(AuthResponse),
+    Revoked(RevokeResponse),
+    RevokedList(RevokedListResponse),
     Error(StatusCode, ErrorResponse),
+    Overloaded(Duration),
 }
 
 // Implement IntoResponse for our custom API response
@@ -53,9 +243,23 @@ impl IntoResponse for ApiResponse {
             ApiResponse::Success(response) => {
                 (StatusCode::OK, Json(response)).into_response()
             }
+            ApiResponse::Revoked(response) => {
+                (StatusCode::OK, Json(response)).into_response()
+            }
+            ApiResponse::RevokedList(response) => {
+                (StatusCode::OK, Json(response)).into_response()
+            }
             ApiResponse::Error(status, response) => {
                 (status, Json(response)).into_response()
             }
+            ApiResponse::Overloaded(retry_after) => {
+                let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    Json(ErrorResponse { error: "Too many concurrent token requests".to_string() }),
+                ).into_response()
+            }
         }
     }
 }
@@ -66,70 +270,146 @@ where
     S: Clone + Send + Sync + 'static,
 {
     Router::new()
-        .route("/auth/token", post(
+        .route("/auth/token", post({
+            let state = state.clone();
             move |State(_): State<S>, Json(auth_request): Json<AuthRequest>| async move {
-                // This is a simple authentication mechanism for demo purposes
-                // In a real application, you would validate credentials against a database
-                if auth_request.username.is_empty() || auth_request.password.is_empty() {
-                    return ApiResponse::Error(
-                        StatusCode::UNAUTHORIZED, 
-                        ErrorResponse {
-                            error: "Invalid credentials".to_string(),
+                // Shed load instead of queueing when the credential backend is saturated
+                let _permit = match &state.issuance_permits {
+                    Some(permits) => match permits.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            warn!("[auth] Token request shed, all issuance permits in use");
+                            return ApiResponse::Overloaded(state.issuance_retry_after);
                         }
-                    );
-                }
-
-                // Create JWT token
-                match create_token(
-                    &auth_request.username, 
-                    auth_request.session_id.as_deref(), 
-                    &state.secret_key[..],
-                    state.token_expiration
-                ) {
-                    Ok(token) => {
-                        ApiResponse::Success(AuthResponse {
-                            token,
-                            expires_in: state.token_expiration.as_secs(),
-                        })
                     },
-                    Err(_) => {
-                        ApiResponse::Error(
-                            StatusCode::INTERNAL_SERVER_ERROR,
+                    None => None,
+                };
+
+                let expiration = match state.granted_expiration(auth_request.ttl_seconds) {
+                    Ok(expiration) => expiration,
+                    Err(error) => {
+                        warn!("[auth] Token request for {} rejected: {}", auth_request.username, error);
+                        return ApiResponse::Error(StatusCode::BAD_REQUEST, ErrorResponse { error });
+                    }
+                };
+
+                let user = match state.verifier.verify(&auth_request.username, &auth_request.password).await {
+                    Ok(user) => user,
+                    Err(AuthError::InvalidCredentials) => {
+                        return ApiResponse::Error(
+                            StatusCode::UNAUTHORIZED, 
+                            ErrorResponse {
+                                error: "Invalid credentials".to_string(),
+                            }
+                        );
+                    }
+                    Err(e) => {
+                        error!("[auth] Token request for {} failed: {}", auth_request.username, e);
+                        return ApiResponse::Error(
+                            StatusCode::SERVICE_UNAVAILABLE,
                             ErrorResponse {
-                                error: "Failed to generate token".to_string(),
+                                error: "Credential backend unavailable".to_string(),
                             }
-                        )
+                        );
+                    }
+                };
+
+                // Create JWT tokens; a session chosen by the verifier wins over the requested one
+                state.issue_tokens(
+                    &user.user_id,
+                    user.session_id.as_deref().or(auth_request.session_id.as_deref()),
+                    user.claims,
+                    expiration,
+                )
+            }
+        }))
+        .route("/auth/refresh", post({
+            let state = state.clone();
+            move |State(_): State<S>, Json(refresh_request): Json<RefreshRequest>| async move {
+                let invalid = || ApiResponse::Error(
+                    StatusCode::UNAUTHORIZED,
+                    ErrorResponse {
+                        error: "Invalid refresh token".to_string(),
+                    }
+                );
+                let claims = match validate_refresh_token_with(&refresh_request.refresh_token, &state.secret_key[..], &state.validation) {
+                    Ok(claims) => claims,
+                    Err(e) => {
+                        warn!("[auth] Refresh rejected: {}", e);
+                        return invalid();
+                    }
+                };
+                // Refresh tokens rotate: each one is spent on use, so a replayed token fails
+                if !state.revocations.revoke(claims.jti.as_deref().unwrap_or_default(), claims.exp) {
+                    warn!("[auth] Refresh rejected: token for {} was already used or revoked", claims.sub);
+                    return invalid();
+                }
+                // Refreshed tokens get the default lifetime; the refresh token does not record the one requested
+                state.issue_tokens(&claims.sub, claims.sid.as_deref(), claims.extra, state.default_expiration())
+            }
+        }))
+        .route("/auth/revoke", post({
+            let state = state.clone();
+            move |State(_): State<S>, headers: HeaderMap, Json(revoke_request): Json<RevokeRequest>| async move {
+                let error = |status, error: &str| ApiResponse::Error(status, ErrorResponse { error: error.to_string() });
+                match (revoke_request.token, revoke_request.jti) {
+                    // Whoever holds a token may revoke it
+                    (Some(token), _) => match state.revoke_token(&token) {
+                        Ok(claims) => {
+                            let jti = claims.jti.unwrap_or_default();
+                            info!("[auth] Revoked token {} of {}", jti, claims.sub);
+                            ApiResponse::Revoked(RevokeResponse { revoked: jti })
+                        }
+                        Err(e) => {
+                            warn!("[auth] Revocation rejected: {}", e);
+                            error(StatusCode::BAD_REQUEST, "Invalid token")
+                        }
+                    },
+                    // Revoking by id alone takes an admin
+                    (None, Some(jti)) => {
+                        if !state.is_admin_request(&headers) {
+                            return error(StatusCode::UNAUTHORIZED, "Admin token required");
+                        }
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                        let longest = state.token_expiration.max(state.max_token_expiration);
+                        let exp = revoke_request.exp.unwrap_or(now + longest.as_secs() + state.validation.leeway_secs);
+                        state.revocations.revoke(&jti, exp);
+                        info!("[auth] Admin revoked token {}", jti);
+                        ApiResponse::Revoked(RevokeResponse { revoked: jti })
                     }
+                    (None, None) => error(StatusCode::BAD_REQUEST, "Missing token or jti"),
                 }
             }
-        ))
+        }))
+        .route("/auth/revoked", get({
+            let state = state.clone();
+            move |State(_): State<S>, headers: HeaderMap| async move {
+                if !state.is_admin_request(&headers) {
+                    return ApiResponse::Error(StatusCode::UNAUTHORIZED, ErrorResponse { error: "Admin token required".to_string() });
+                }
+                ApiResponse::RevokedList(RevokedListResponse { revoked: state.revocations.entries() })
+            }
+        }))
 }
 
 /// Creates a JWT state with reasonable defaults
+///
+/// # Panics
+///
+/// Panics when the configured signing key is not 32 bytes of base64 or hex; use
+/// `try_create_default_jwt_state` to handle that instead.
 pub fn create_default_jwt_state() -> JwtState {
-    // Create a default secret key
-    let mut secret_key = [0u8; 32];
-    
-    // Try to get JWT secret from environment variable
-    match env::var("JWT_SECRET_KEY") {
-        Ok(env_key) => {
-            // Copy bytes from environment variable, up to 32 bytes
-            let bytes = env_key.as_bytes();
-            for i in 0..std::cmp::min(bytes.len(), 32) {
-                secret_key[i] = bytes[i];
-            }
-        },
-        Err(_) => {
-            // Use default key
-            eprintln!("WARNING: Using default JWT secret key. This is insecure for production!");
-            eprintln!("Set the JWT_SECRET_KEY environment variable for better security.");
-            
-            let default_bytes = b"rusty_websocket_jwt_secret_key_32b";
-            for i in 0..32 {
-                secret_key[i] = default_bytes[i];
-            }
-        }
+    try_create_default_jwt_state().unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Creates a JWT state with reasonable defaults, signing with the same key the WebSocket
+/// endpoint validates with (see `jwt_utils::load_jwt_key`)
+pub fn try_create_default_jwt_state() -> Result<JwtState, JwtKeyError> {
+    if configured_jwt_key()?.is_none() {
+        warn!("Using default JWT secret key. This is insecure for production!");
+        warn!("Set JWT_SECRET_FILE or the JWT_SECRET_KEY environment variable for better security.");
     }
+    let secret_key = load_jwt_key()?;
     
     // Use default expiration of 1 hour (3600 seconds)
     let default_expiration = 3600;
@@ -140,12 +420,64 @@ pub fn create_default_jwt_state() -> JwtState {
         if let Ok(seconds) = val.parse::<u64>() {
             expiration_seconds = seconds;
         } else {
-            eprintln!("WARNING: Invalid JWT_EXPIRATION_SECONDS value, using default (3600)");
+            warn!("Invalid JWT_EXPIRATION_SECONDS value, using default (3600)");
+        }
+    }
+    
+    // Requested TTLs are capped at a day unless configured otherwise
+    let mut max_expiration_seconds = 24 * 3600;
+    if let Ok(val) = env::var("JWT_MAX_EXPIRATION_SECONDS") {
+        if let Ok(seconds) = val.parse::<u64>() {
+            max_expiration_seconds = seconds;
+        } else {
+            warn!("Invalid JWT_MAX_EXPIRATION_SECONDS value, using default (86400)");
         }
     }
     
-    JwtState {
+    // Refresh tokens default to 30 days
+    let mut refresh_expiration_seconds = 30 * 24 * 3600;
+    if let Ok(val) = env::var("JWT_REFRESH_EXPIRATION_SECONDS") {
+        if let Ok(seconds) = val.parse::<u64>() {
+            refresh_expiration_seconds = seconds;
+        } else {
+            warn!("Invalid JWT_REFRESH_EXPIRATION_SECONDS value, using default (2592000)");
+        }
+    }
+    
+    // Tolerate some clock skew between the issuer and validators
+    let mut leeway_secs = DEFAULT_LEEWAY_SECS;
+    if let Ok(val) = env::var("JWT_LEEWAY_SECONDS") {
+        if let Ok(seconds) = val.parse::<u64>() {
+            leeway_secs = seconds;
+        } else {
+            warn!("Invalid JWT_LEEWAY_SECONDS value, using default ({})", DEFAULT_LEEWAY_SECS);
+        }
+    }
+    let validation = TokenValidation {
+        leeway_secs,
+        audience: env::var("JWT_AUDIENCE").ok().filter(|aud| !aud.is_empty()),
+        issuer: env::var("JWT_ISSUER").ok().filter(|iss| !iss.is_empty()),
+    };
+
+    let state = JwtState {
         secret_key: Arc::new(secret_key),
         token_expiration: Duration::from_secs(expiration_seconds),
-    }
+        max_token_expiration: Duration::from_secs(max_expiration_seconds),
+        refresh_expiration: Duration::from_secs(refresh_expiration_seconds),
+        revocations: TokenRevocation::default(),
+        issuance_permits: None,
+        issuance_retry_after: Duration::from_secs(1),
+        verifier: Arc::new(PermissiveVerifier),
+        validation,
+    };
+
+    // Optionally cap concurrent token requests
+    Ok(match env::var("JWT_MAX_CONCURRENT_REQUESTS").map(|val| val.parse::<usize>()) {
+        Ok(Ok(limit)) => state.with_issuance_limit(limit, Duration::from_secs(1)),
+        Ok(Err(_)) => {
+            warn!("Invalid JWT_MAX_CONCURRENT_REQUESTS value, leaving token issuance unlimited");
+            state
+        }
+        Err(_) => state,
+    })
 }
@@ -1,7 +1,31 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::error::Error;
+use std::fmt;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+use zeroize::Zeroizing;
+use tracing::error;
+
+/// Environment variable naming a file that holds the HMAC signing secret; preferred over `JWT_SECRET_KEY`
+pub const JWT_SECRET_FILE_VAR: &str = "JWT_SECRET_FILE";
+
+/// Environment variable holding the HMAC signing secret itself
+pub const JWT_SECRET_KEY_VAR: &str = "JWT_SECRET_KEY";
+
+/// Length in bytes of the HMAC signing key
+pub const JWT_KEY_LEN: usize = 32;
+
+/// Signing key used when none is configured; insecure outside development
+const DEFAULT_JWT_KEY: &[u8; JWT_KEY_LEN] = b"rusty_websocket_default_jwt_key!";
+
+/// `typ` claim that marks a refresh token; such tokens are only accepted by `/auth/refresh`
+pub const REFRESH_TOKEN_TYPE: &str = "refresh";
+
+/// Seconds a token is still accepted past its `exp` unless configured otherwise, for clock skew
+pub const DEFAULT_LEEWAY_SECS: u64 = 60;
 
 /// Claims structure for JWT tokens
 #[derive(Debug, Serialize, Deserialize)]
@@ -11,10 +35,35 @@ pub struct Claims {
     /// Session ID to link with existing session mechanics
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sid: Option<String>,
+    /// Audience (the service this token was minted for)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Issuer (the service that minted this token)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
     /// Issued at time
     pub iat: u64,
     /// Expiration time
     pub exp: u64,
+    /// Token type; `refresh` for refresh tokens, absent for access tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    /// Unique token id, so the token can be revoked before it expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Any other claims minted into the token, such as `tenant` or `plan`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Claims {
+    /// Whether the token grants `role`, either as its `role` claim or within a `roles` array.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.extra.get("role").is_some_and(|value| value == role)
+            || self.extra.get("roles")
+                .and_then(Value::as_array)
+                .is_some_and(|roles| roles.iter().any(|value| value == role))
+    }
 }
 
 /// Creates a new JWT token
@@ -24,14 +73,105 @@ pub fn create_token(
     secret: &[u8],
     expiration: Duration,
 ) -> Result<String, Box<dyn Error>> {
+    create_token_with_audience(user_id, session_id, None, secret, expiration)
+}
+
+/// Creates a new JWT token bound to an audience
+pub fn create_token_with_audience(
+    user_id: &str,
+    session_id: Option<&str>,
+    audience: Option<&str>,
+    secret: &[u8],
+    expiration: Duration,
+) -> Result<String, Box<dyn Error>> {
+    create_token_with_claims(user_id, session_id, audience, Map::new(), secret, expiration)
+}
+
+/// Creates a new JWT token carrying additional custom claims alongside the standard ones
+pub fn create_token_with_claims(
+    user_id: &str,
+    session_id: Option<&str>,
+    audience: Option<&str>,
+    extra: Map<String, Value>,
+    secret: &[u8],
+    expiration: Duration,
+) -> Result<String, Box<dyn Error>> {
+    create_token_with_issuer(user_id, session_id, audience, None, extra, secret, expiration)
+}
+
+/// Creates a new JWT token naming its issuer in the `iss` claim, for endpoints that check it
+pub fn create_token_with_issuer(
+    user_id: &str,
+    session_id: Option<&str>,
+    audience: Option<&str>,
+    issuer: Option<&str>,
+    extra: Map<String, Value>,
+    secret: &[u8],
+    expiration: Duration,
+) -> Result<String, Box<dyn Error>> {
+    let mut claims = build_claims(user_id, session_id, audience, extra, expiration)?;
+    claims.iss = issuer.map(|iss| iss.to_string());
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )?;
+
+    Ok(token)
+}
+
+/// Creates a new JWT token signed with an RSA private key (PEM), so verifiers only need the public key
+pub fn create_token_rs256(
+    user_id: &str,
+    session_id: Option<&str>,
+    private_key_pem: &[u8],
+    expiration: Duration,
+) -> Result<String, Box<dyn Error>> {
+    let claims = build_claims(user_id, session_id, None, Map::new(), expiration)?;
+
+    let token = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(private_key_pem)?,
+    )?;
+
+    Ok(token)
+}
+
+fn build_claims(
+    user_id: &str,
+    session_id: Option<&str>,
+    audience: Option<&str>,
+    extra: Map<String, Value>,
+    expiration: Duration,
+) -> Result<Claims, Box<dyn Error>> {
     let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    
-    let claims = Claims {
+
+    Ok(Claims {
         sub: user_id.to_string(),
         sid: session_id.map(|s| s.to_string()),
+        aud: audience.map(|a| a.to_string()),
+        iss: None,
         iat: now,
         exp: now + expiration.as_secs(),
-    };
+        typ: None,
+        jti: Some(format!("{:032x}", rand::random::<u128>())),
+        extra,
+    })
+}
+
+/// Creates a long-lived refresh token that can be exchanged at `/auth/refresh` for a new
+/// access token carrying the same subject, session and custom claims
+pub fn create_refresh_token(
+    user_id: &str,
+    session_id: Option<&str>,
+    extra: Map<String, Value>,
+    secret: &[u8],
+    expiration: Duration,
+) -> Result<String, Box<dyn Error>> {
+    let mut claims = build_claims(user_id, session_id, None, extra, expiration)?;
+    claims.typ = Some(REFRESH_TOKEN_TYPE.to_string());
 
     let token = encode(
         &Header::default(),
@@ -42,22 +182,227 @@ pub fn create_token(
     Ok(token)
 }
 
+/// Validates and decodes a refresh token; access tokens are rejected
+pub fn validate_refresh_token(token: &str, secret: &[u8]) -> Result<Claims, Box<dyn Error>> {
+    validate_refresh_token_with(token, secret, &TokenValidation::default())
+}
+
+/// Validates a refresh token with the given leeway. Refresh tokens carry no audience or issuer
+/// and are only accepted by the issuer's own `/auth/refresh`, so those requirements are not checked.
+pub fn validate_refresh_token_with(token: &str, secret: &[u8], validation: &TokenValidation) -> Result<Claims, Box<dyn Error>> {
+    let refresh_validation = TokenValidation { leeway_secs: validation.leeway_secs, ..TokenValidation::default() };
+    let token_data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret),
+        &validation_for(Algorithm::HS256, &refresh_validation),
+    )?;
+
+    if token_data.claims.typ.as_deref() != Some(REFRESH_TOKEN_TYPE) || token_data.claims.jti.is_none() {
+        return Err("not a refresh token".into());
+    }
+    Ok(token_data.claims)
+}
+
+// Refresh tokens outlive access tokens, so they must never be accepted in their place
+fn access_claims(claims: Claims) -> Result<Claims, Box<dyn Error>> {
+    if claims.typ.as_deref() == Some(REFRESH_TOKEN_TYPE) {
+        return Err("refresh tokens cannot be used for access".into());
+    }
+    Ok(claims)
+}
+
 /// Validates and decodes a JWT token
 pub fn validate_token(token: &str, secret: &[u8]) -> Result<Claims, Box<dyn Error>> {
+    validate_token_for_audience(token, secret, None)
+}
+
+/// Validates and decodes a JWT token, requiring a matching `aud` claim when an audience is given
+pub fn validate_token_for_audience(
+    token: &str,
+    secret: &[u8],
+    audience: Option<&str>,
+) -> Result<Claims, Box<dyn Error>> {
+    let validation = TokenValidation { audience: audience.map(|aud| aud.to_string()), ..TokenValidation::default() };
+    validate_token_with(token, secret, &validation)
+}
+
+/// Validates and decodes a JWT token against the given leeway and expected audience and issuer
+pub fn validate_token_with(token: &str, secret: &[u8], validation: &TokenValidation) -> Result<Claims, Box<dyn Error>> {
     let token_data = decode::<Claims>(
         token,
         &DecodingKey::from_secret(secret),
-        &Validation::new(Algorithm::HS256),
+        &validation_for(Algorithm::HS256, validation),
     )?;
 
-    Ok(token_data.claims)
+    access_claims(token_data.claims)
 }
 
-/// Extracts token from various formats
-pub fn extract_token(auth_header: &str) -> Option<&str> {
-    if auth_header.starts_with("Bearer ") {
-        Some(&auth_header[7..])
+/// Validates and decodes an RS256 JWT token against an RSA public key (PEM)
+pub fn validate_token_rs256(token: &str, public_key_pem: &[u8]) -> Result<Claims, Box<dyn Error>> {
+    let token_data = decode::<Claims>(
+        token,
+        &DecodingKey::from_rsa_pem(public_key_pem)?,
+        &validation_for(Algorithm::RS256, &TokenValidation::default()),
+    )?;
+
+    access_claims(token_data.claims)
+}
+
+/// What a token must satisfy besides its signature
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenValidation {
+    /// Seconds a token is still accepted past its `exp`, to tolerate clock skew between the
+    /// issuer and the validator. `0` rejects a token the moment it expires.
+    pub leeway_secs: u64,
+    /// Required `aud` claim; tokens without one are rejected too. `None` ignores the claim.
+    pub audience: Option<String>,
+    /// Required `iss` claim; tokens without one are rejected too. `None` ignores the claim.
+    pub issuer: Option<String>,
+}
+
+impl Default for TokenValidation {
+    fn default() -> Self {
+        TokenValidation { leeway_secs: DEFAULT_LEEWAY_SECS, audience: None, issuer: None }
+    }
+}
+
+// Only the given algorithm is accepted, so an HS256 token cannot pass as RS256 or vice versa
+fn validation_for(algorithm: Algorithm, requirements: &TokenValidation) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.leeway = requirements.leeway_secs;
+    let mut required = vec!["exp"];
+    match &requirements.audience {
+        Some(aud) => {
+            // Tokens without an audience are not accepted by an endpoint that expects one
+            validation.set_audience(&[aud]);
+            required.push("aud");
+        }
+        None => validation.validate_aud = false,
+    }
+    if let Some(iss) = &requirements.issuer {
+        validation.set_issuer(&[iss]);
+        required.push("iss");
+    }
+    validation.set_required_spec_claims(&required);
+    validation
+}
+
+/// Reads a token's `sid` claim without checking its signature. For clients, which hold the
+/// token but not the key; never use it to make an authorization decision.
+pub fn unverified_session_id(token: &str) -> Option<String> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation).ok()?.claims.sid
+}
+
+/// Returns true if the error was caused by a missing or mismatched `aud` claim
+pub fn is_audience_error(err: &(dyn Error + 'static)) -> bool {
+    use jsonwebtoken::errors::ErrorKind;
+
+    match err.downcast_ref::<jsonwebtoken::errors::Error>() {
+        Some(e) => match e.kind() {
+            ErrorKind::InvalidAudience => true,
+            ErrorKind::MissingRequiredClaim(claim) => claim == "aud",
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// Returns true if the error was caused by a missing or mismatched `iss` claim
+pub fn is_issuer_error(err: &(dyn Error + 'static)) -> bool {
+    use jsonwebtoken::errors::ErrorKind;
+
+    match err.downcast_ref::<jsonwebtoken::errors::Error>() {
+        Some(e) => match e.kind() {
+            ErrorKind::InvalidIssuer => true,
+            ErrorKind::MissingRequiredClaim(claim) => claim == "iss",
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// Reads a sensitive setting from the file named by the `file_var` environment variable, or else
+/// from the `env_var` variable itself. A trailing newline in the file is dropped. The returned
+/// buffer is zeroized when dropped, so copy out what is needed and let it go.
+pub fn read_secret(file_var: &str, env_var: &str) -> Option<Zeroizing<Vec<u8>>> {
+    if let Ok(path) = env::var(file_var) {
+        match fs::read(&path) {
+            Ok(contents) => {
+                let mut contents = Zeroizing::new(contents);
+                while matches!(contents.last(), Some(b'\n' | b'\r')) {
+                    contents.pop();
+                }
+                return Some(contents);
+            }
+            Err(e) => error!("Could not read {} from {}: {}", file_var, path, e),
+        }
+    }
+    env::var(env_var).ok().map(|value| Zeroizing::new(value.into_bytes()))
+}
+
+/// The configured HMAC signing secret: the contents of `JWT_SECRET_FILE`, else `JWT_SECRET_KEY`
+pub fn configured_jwt_secret() -> Option<Zeroizing<Vec<u8>>> {
+    read_secret(JWT_SECRET_FILE_VAR, JWT_SECRET_KEY_VAR)
+}
+
+/// Why a configured JWT signing key was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JwtKeyError {
+    /// The key is neither hex nor base64
+    NotEncoded,
+    /// The key decoded to this many bytes instead of `JWT_KEY_LEN`
+    WrongLength(usize),
+}
+
+impl fmt::Display for JwtKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtKeyError::NotEncoded => write!(f, "JWT signing key must be {} bytes written as base64 or hex", JWT_KEY_LEN),
+            JwtKeyError::WrongLength(len) => write!(f, "JWT signing key must decode to {} bytes, got {}", JWT_KEY_LEN, len),
+        }
+    }
+}
+
+impl Error for JwtKeyError {}
+
+/// Decodes a signing key written as hex (64 digits) or standard base64. Surrounding whitespace
+/// is ignored; anything that does not decode to exactly `JWT_KEY_LEN` bytes is an error.
+pub fn decode_jwt_key(encoded: &[u8]) -> Result<Zeroizing<[u8; JWT_KEY_LEN]>, JwtKeyError> {
+    let encoded = std::str::from_utf8(encoded).map_err(|_| JwtKeyError::NotEncoded)?.trim();
+    let is_hex = !encoded.is_empty() && encoded.len() % 2 == 0 && encoded.bytes().all(|b| b.is_ascii_hexdigit());
+    let decoded = Zeroizing::new(if is_hex {
+        (0..encoded.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&encoded[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| JwtKeyError::NotEncoded)?
     } else {
-        None
+        STANDARD.decode(encoded).map_err(|_| JwtKeyError::NotEncoded)?
+    });
+    if decoded.len() != JWT_KEY_LEN {
+        return Err(JwtKeyError::WrongLength(decoded.len()));
     }
+    let mut key = Zeroizing::new([0u8; JWT_KEY_LEN]);
+    key.copy_from_slice(&decoded);
+    Ok(key)
+}
+
+/// The configured signing key decoded with `decode_jwt_key`, or `None` when neither
+/// `JWT_SECRET_FILE` nor `JWT_SECRET_KEY` is set
+pub fn configured_jwt_key() -> Result<Option<Zeroizing<[u8; JWT_KEY_LEN]>>, JwtKeyError> {
+    configured_jwt_secret().map(|secret| decode_jwt_key(&secret)).transpose()
+}
+
+/// The signing key shared by the token issuer and the WebSocket endpoint: the configured key,
+/// or the insecure development default when none is configured
+pub fn load_jwt_key() -> Result<Zeroizing<[u8; JWT_KEY_LEN]>, JwtKeyError> {
+    Ok(configured_jwt_key()?.unwrap_or_else(|| Zeroizing::new(*DEFAULT_JWT_KEY)))
+}
+
+/// Extracts token from various formats
+pub fn extract_token(auth_header: &str) -> Option<&str> {
+    auth_header.strip_prefix("Bearer ")
 }
@@ -4,29 +4,144 @@ pub mod enc_utils;
 pub mod enc_api_route;
 pub mod jwt_utils;
 pub mod jwt_api_route;
+pub mod admin_api_route;
+pub mod metrics_api_route;
+pub mod conn_config;
+pub mod metrics;
+pub mod topic_pattern;
+pub mod transfer;
+pub mod transport;
+pub mod session_bus;
+pub mod timestamp;
+pub mod delta;
+pub mod history;
+pub mod resume;
+pub mod audit;
+pub mod direct;
+pub mod subscribers;
+pub mod binary_proto;
+pub mod capabilities;
+pub mod retained;
+pub mod presence;
+pub mod credentials;
+pub mod error_frame;
+pub mod command;
+pub mod compression;
+pub mod rate_limit;
+pub mod session_crypto;
+pub mod request_reply;
+pub mod revocation;
+pub mod send_queue;
+pub mod connections;
+pub mod subprotocol;
+pub mod publisher_filter;
+pub mod interceptor;
+pub mod parked;
+pub mod authorizer;
+pub mod server_builder;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::ws::{close_code, CloseFrame, Message, WebSocketUpgrade},
     extract::{ConnectInfo, Query},
-    response::IntoResponse,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
 };
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{SinkExt, Stream, StreamExt};
 use serde_json::{json, Value};
 use std::{
     collections::HashMap,
+    future::poll_fn,
     net::SocketAddr,
     sync::{Arc, Mutex},
-    env,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use serde::Deserialize;
 use tokio::sync::mpsc::{self, UnboundedSender};
-use crate::jwt_utils::{validate_token, Claims};
+use tokio::sync::{oneshot, watch, Notify};
+use tokio::time::Interval;
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+use zeroize::Zeroizing;
+use crate::jwt_utils::{is_audience_error, is_issuer_error, validate_token_with, Claims, JWT_KEY_LEN};
+use crate::timestamp::now_rfc3339;
+use crate::binary_proto::{BinaryFrame, Opcode};
+use crate::capabilities::Capability;
+use crate::error_frame::{error_frame, ErrorCode};
+use crate::command::{AckOp, ClientCommand, ServerMessage};
+use crate::interceptor::{Decision, Envelope};
+use crate::parked::{ParkedSession, ParkedSessions};
+use crate::presence::PresenceRoster;
+use crate::publisher_filter::PublisherFilter;
+use crate::rate_limit::{Admission, PublishLimiter};
+use crate::send_queue::SendQueue;
+use crate::subprotocol::{EncodedFrame, WireFormat};
+pub use crate::authorizer::TopicAuthorizer;
+pub use crate::conn_config::{ConnectionConfig, TopicPolicy, UnknownCommandPolicy};
+pub use crate::transport::Transport;
+pub use crate::session_bus::SessionBus;
+pub use crate::subscribers::SubscriberRegistry;
+pub use crate::server_builder::WsServerBuilder;
+use crate::subscribers::{ConnectionId, Subscriber, SubscriberMap};
 
 // Type aliases for topic names and subscriber management
 pub type Topic = String;
 pub type SessionId = String;
-// New type: Map of topics to a map of session IDs to subscribers
-pub type Subscribers = Arc<Mutex<HashMap<Topic, HashMap<SessionId, Vec<UnboundedSender<String>>>>>>;
+// Map of topics to a map of session IDs to subscribers, sharded by topic
+pub type Subscribers = Arc<SubscriberRegistry>;
+
+/// Role a token must carry to publish with `"broadcast": true`.
+pub const BROADCAST_ROLE: &str = "admin";
+
+/// Name of the cookie that pins a client to the server instance holding its session state.
+pub const STICKY_COOKIE_NAME: &str = "rws_instance";
+
+/// How long a closing connection waits for the client's side of the close handshake before
+/// the socket is dropped.
+const CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a slow consumer's socket gets to accept the close frame before it is dropped
+const SLOW_CONSUMER_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How a connection's receive loop ended
+enum ReceiveEnd {
+    // The stream ended after a completed close handshake
+    Closed,
+    // A close frame was sent or received; reading on completes the handshake
+    Closing,
+    // The client stopped answering pings; its socket is dropped without waiting
+    Abandoned,
+    // The transport failed before any close handshake
+    Failed,
+}
+
+/// How messages on a subscription are delivered relative to each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeliveryOrder {
+    /// Messages arrive in the order they were published (the default).
+    #[default]
+    Ordered,
+    /// Messages may arrive out of order; for idempotent consumers.
+    Unordered,
+}
+
+impl DeliveryOrder {
+    /// Wire name used in `subscribe:<topic>|<session>|<order>`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryOrder::Ordered => "ordered",
+            DeliveryOrder::Unordered => "unordered",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ordered" => Some(DeliveryOrder::Ordered),
+            "unordered" => Some(DeliveryOrder::Unordered),
+            _ => None,
+        }
+    }
+}
 
 // Query parameters struct for WebSocket connections
 #[derive(Deserialize, Debug)]
@@ -41,94 +156,365 @@ pub async fn handle_socket(
     params: Option<Query<WebSocketParams>>, // Add query parameters to extract token
     subscribers: Subscribers,
 ) -> impl IntoResponse {
-    println!("[handle_socket] WS connection from {}", addr);
+    handle_socket_with_config(ws, ConnectInfo(addr), params, HeaderMap::new(), subscribers, Arc::new(ConnectionConfig::default())).await
+}
+
+/// Handles the WebSocket upgrade using the given endpoint configuration.
+pub async fn handle_socket_with_config(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    params: Option<Query<WebSocketParams>>,
+    headers: HeaderMap,
+    subscribers: Subscribers,
+    config: Arc<ConnectionConfig>,
+) -> Response {
+    // Every event of the connection carries the peer, and the user once the token is validated
+    let span = info_span!("connection", peer = %addr, user = field::Empty);
+    let _entered = span.enter();
+    info!("[handle_socket] WS connection from {}", addr);
+
+    // Pages from origins outside the allow-list must not open a socket with the user's cookies
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        let origin = origin.to_str().unwrap_or_default();
+        if !config.is_origin_allowed(origin) {
+            warn!("[handle_socket] Rejecting {} from disallowed origin {:?}", addr, origin);
+            return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+        }
+    }
+
+    // A reconnect pinned to another instance must go back through the load balancer
+    if let Some(instance_id) = &config.instance_id {
+        if let Some(pinned) = cookie_value(&headers, STICKY_COOKIE_NAME) {
+            if pinned != *instance_id {
+                debug!("[handle_socket] Sticky cookie for instance {} reached instance {}, re-routing", pinned, instance_id);
+                let clear_cookie = format!("{}=; Path=/; Max-Age=0", STICKY_COOKIE_NAME);
+                return (
+                    StatusCode::MISDIRECTED_REQUEST,
+                    [(header::SET_COOKIE, clear_cookie)],
+                    "Session is pinned to another instance",
+                ).into_response();
+            }
+        }
+    }
     
     // Extract token from query parameters if present
     let token = params.as_ref().and_then(|p| p.token.clone());
 
+    // A malformed key must not quietly turn every token away; refuse the upgrade instead
+    let secret = match config.jwt_key() {
+        Ok(secret) => secret,
+        Err(e) => {
+            warn!("[handle_socket] Refusing connection from {}: {}", addr, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "JWT signing key is misconfigured").into_response();
+        }
+    };
+
     // Check if we have a token (for authenticated connections)
     let user_info = if let Some(token_str) = token {
-        // Get JWT secret from environment variable or use default
-        let secret = env::var("JWT_SECRET_KEY")
-            .map(|s| s.into_bytes())
-            .unwrap_or_else(|_| b"rusty_websocket_jwt_secret_key_32b".to_vec());
-        
-        // Try to validate the token
-        match validate_token(&token_str, &secret) {
+        // Try to validate the token against this endpoint's audience
+        match validate_token_with(&token_str, &secret[..], &config.token_validation()) {
+            Ok(claims) if config.is_revoked(&claims) => {
+                // A revoked token must not fall back to an anonymous connection either
+                warn!("[handle_socket] Rejecting revoked JWT of user: {}", claims.sub);
+                return (StatusCode::UNAUTHORIZED, "Token revoked").into_response();
+            },
             Ok(claims) => {
-                println!("[handle_socket] Validated JWT for user: {}", claims.sub);
+                span.record("user", claims.sub.as_str());
+                info!("[handle_socket] Validated JWT for user: {}", claims.sub);
                 Some(claims)
             },
+            Err(e) if is_audience_error(e.as_ref()) => {
+                // A token minted for another service must not fall back to an anonymous connection
+                warn!("[handle_socket] Rejecting JWT minted for another audience: {}", e);
+                return (StatusCode::UNAUTHORIZED, "Token audience mismatch").into_response();
+            },
+            Err(e) if is_issuer_error(e.as_ref()) => {
+                warn!("[handle_socket] Rejecting JWT from another issuer: {}", e);
+                return (StatusCode::UNAUTHORIZED, "Token issuer mismatch").into_response();
+            },
             Err(e) => {
-                println!("[handle_socket] Invalid JWT token: {}", e);
+                warn!("[handle_socket] Invalid JWT token: {}", e);
                 None
             }
         }
     } else {
-        println!("[handle_socket] No JWT token provided");
+        debug!("[handle_socket] No JWT token provided");
         None
     };
 
+    // Shed load once the endpoint is full, telling the client when to come back
+    let Some(slot) = config.metrics.try_open_connection(config.max_connections) else {
+        let retry_after = config.overload_retry_after.as_secs_f64().ceil() as u64;
+        warn!("[handle_socket] At capacity ({} connections), rejecting {} with Retry-After {}s",
+            config.metrics.active_connections(), addr, retry_after);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            "Server is at capacity",
+        ).into_response();
+    };
+
+    let sticky_cookie = config.instance_id.as_ref().map(|instance_id| {
+        format!("{}={}; Path=/; HttpOnly; SameSite=Lax", STICKY_COOKIE_NAME, instance_id)
+    });
+
+    // Pick the first offered subprotocol the client asked for; without one the connection uses the default format
+    let ws = ws.protocols(config.subprotocols.iter().map(WireFormat::protocol));
+
     // Upgrade the connection and run the WebSocket handler
-    ws.on_upgrade(move |socket| {
+    let connection_span = span.clone();
+    let mut response = ws.on_upgrade(move |socket| {
         async move {
-            if let Err(e) = run_connection(socket, subscribers, user_info).await {
-                eprintln!("[handle_socket] Client error: {:?}", e);
+            // Hold the slot for the life of the connection
+            let _slot = slot;
+            let format = socket.protocol()
+                .and_then(|protocol| protocol.to_str().ok())
+                .and_then(WireFormat::from_protocol)
+                .unwrap_or_default();
+            if let Err(e) = run_connection(socket, Some(addr), subscribers, user_info, secret, config, format).await {
+                error!("[handle_socket] Client error: {:?}", e);
             }
         }
-    })
+        .instrument(connection_span)
+    }).into_response();
+
+    // Pin subsequent reconnects to this instance
+    if let Some(cookie) = sticky_cookie.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        response.headers_mut().insert(header::SET_COOKIE, cookie);
+    }
+    response
+}
+
+/// Serves the pub/sub protocol over an already-established transport.
+///
+/// `handle_socket_with_config` does this for upgraded WebSockets; other transports,
+/// such as the in-process one used by tests, can be wired in directly. They have no upgrade to
+/// negotiate a subprotocol in, so they use the default [`WireFormat`].
+pub async fn serve_transport<T: Transport>(
+    transport: T,
+    subscribers: Subscribers,
+    user_info: Option<Claims>,
+    config: Arc<ConnectionConfig>,
+) -> Result<(), String> {
+    let secret = config.jwt_key().map_err(|e| e.to_string())?;
+    run_connection(transport, None, subscribers, user_info, secret, config, WireFormat::default()).await
+}
+
+/// Reads a cookie value from the request's `Cookie` headers.
+fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
 }
 
 /// Manages the WebSocket connection, handling messages, subscriptions, and publishing.
-async fn run_connection(
-    socket: WebSocket, 
+/// `peer` is the remote address, recorded with each of the connection's subscriptions.
+async fn run_connection<T: Transport>(
+    socket: T,
+    peer: Option<SocketAddr>,
     subscribers: Subscribers,
-    user_info: Option<Claims>
+    user_info: Option<Claims>,
+    secret: Arc<Zeroizing<[u8; JWT_KEY_LEN]>>,
+    config: Arc<ConnectionConfig>,
+    format: WireFormat,
 ) -> Result<(), String> {
-    println!("[run_connection] Executing WebSocket connection handler...");
+    debug!("[run_connection] Executing WebSocket connection handler...");
     
     // Extract user ID and associated session ID from token claims
     let (user_id, token_session_id) = if let Some(claims) = &user_info {
-        println!("[run_connection] JWT claims: user_id={}, session_id={:?}", 
+        debug!("[run_connection] JWT claims: user_id={}, session_id={:?}", 
             claims.sub, claims.sid);
         (
             Some(claims.sub.clone()),
             claims.sid.clone()
         )
     } else {
-        println!("[run_connection] No JWT claims available");
+        debug!("[run_connection] No JWT claims available");
         (None, None)
     };
 
     if let Some(id) = &user_id {
-        println!("[run_connection] Authenticated connection for user: {}", id);
+        info!("[run_connection] Authenticated connection for user: {}", id);
     } else {
-        println!("[run_connection] Anonymous connection");
+        info!("[run_connection] Anonymous connection");
     }
+    debug!("[run_connection] Using the {} message format", format.protocol());
 
     // Split the WebSocket into sender and receiver
     let (mut ws_sender, mut ws_receiver) = socket.split();
 
+    // Identifies this connection's entries in the subscriber map
+    let connection_id = ConnectionId::generate();
+    debug!("[run_connection] Connection {} from {}", connection_id, peer.map_or("an in-process transport".to_string(), |peer| peer.to_string()));
+
     // Track topics the client is subscribed to
     let my_subscriptions = Arc::new(Mutex::new(Vec::<(String, String)>::new())); // Now stores (topic, sessionId) pairs
+    // Topic and payload published if the connection ends without the client closing it
+    let my_will = Arc::new(Mutex::new(None::<(String, Value)>));
+    // Set once a key exchange made this connection a holder of its session's key
+    let holds_session_key = Arc::new(Mutex::new(false));
 
     // Create a channel for sending messages to the client
-    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
-    let tx_clone = tx.clone();
+    let (tx, rx) = mpsc::unbounded_channel::<String>();
+    // Shared so subscriptions can apply the queue limit and the receive side can report how
+    // many messages are still waiting to be written
+    let send_queue = SendQueue::new(rx, config.send_queue_limit, config.metrics.clone());
+    let send_queue_inner = send_queue.clone();
+
+    // Tell the client what this server supports before anything else
+    reply(&tx, capabilities::server_hello(&config));
+    // Lets the client take this connection's subscriptions back if it drops
+    let session_token = config.session_grace.map(|grace| {
+        let token = ParkedSessions::issue_token();
+        reply(&tx, json!({"type": "session_token", "token": token, "grace_secs": grace.as_secs()}));
+        token
+    });
+    // Closes the connection when the server shuts down
+    let mut shutting_down = config.connections.register(connection_id, tx.clone());
     let subscribers_inner = subscribers.clone();
     let subscriptions_inner = my_subscriptions.clone();
+    let will_inner = my_will.clone();
+    let holds_session_key_inner = holds_session_key.clone();
+
+    // Binary subscriptions register this sender; their envelopes wait in a queue under the same
+    // limit and are written as binary frames
+    let (binary_tx, binary_rx) = mpsc::unbounded_channel::<String>();
+    let binary_queue = send_queue.alongside(binary_rx);
+    let binary_queue_inner = binary_queue.clone();
+
+    // Senders registered by delta subscriptions, keyed by (topic, sessionId)
+    let delta_sinks = Arc::new(Mutex::new(HashMap::<(String, String), UnboundedSender<String>>::new()));
+    let delta_sinks_inner = delta_sinks.clone();
+
+    // Signals the send task to close the socket once the receive side is done, with the
+    // close frame to send when the server is the one ending the connection
+    let (close_tx, mut close_rx) = oneshot::channel::<CloseFrame<'static>>();
+
+    // Set when the client sent a close frame; the send task stops without flushing its queue
+    let peer_closed = Arc::new(Notify::new());
+    let peer_closed_inner = peer_closed.clone();
+
+    // Ping interval for this connection; the client may renegotiate it
+    let (heartbeat_tx, mut heartbeat_rx) = watch::channel(config.heartbeat_interval);
+
+    // Payload and send time of the most recent ping, matched against incoming pongs
+    let outstanding_ping = Arc::new(Mutex::new(None::<(Vec<u8>, Instant)>));
+    let outstanding_ping_inner = outstanding_ping.clone();
+
+    // Resume checkpoints are issued from the send side, which knows what was actually sent
+    let resume_subscriptions = my_subscriptions.clone();
+    let resume_secret = secret.clone();
+    let (resume_user, resume_session) = (user_id.clone(), token_session_id.clone());
+    let mut resume_tick = config.resume_token_interval.map(tokio::time::interval);
+    let send_metrics = config.metrics.clone();
 
     // Task for sending messages to the client
     let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if ws_sender.send(Message::Text(msg)).await.is_err() {
-                break;
+        let mut ping_tick = heartbeat_timer(*heartbeat_rx.borrow());
+        let mut ping_seq: u64 = 0;
+        // Last sequence number sent per session, and whether it moved since the last checkpoint
+        let mut sent_seqs: HashMap<String, u64> = HashMap::new();
+        let mut checkpoint_due = false;
+        loop {
+            tokio::select! {
+                // Flush queued messages (such as a final notice) before honouring a close
+                biased;
+                // A consumer this slow may never drain its socket, so give up on what is queued
+                _ = send_queue.overflowed() => {
+                    let close = CloseFrame { code: close_code::POLICY, reason: "slow consumer".into() };
+                    let _ = tokio::time::timeout(SLOW_CONSUMER_CLOSE_TIMEOUT, ws_sender.send(Message::Close(Some(close)))).await;
+                    break;
+                }
+                // Nobody is reading any more; the close reply was already queued by the socket
+                _ = peer_closed.notified() => break,
+                msg = poll_fn(|cx| send_queue.poll_recv(cx)) => match msg {
+                    Some(msg) => {
+                        if resume_tick.is_some() {
+                            if let Some((session, seq)) = envelope_seq(&msg) {
+                                sent_seqs.insert(session, seq);
+                                checkpoint_due = true;
+                            }
+                        }
+                        let frame = format.encode(msg);
+                        let bytes = frame.len();
+                        // A write blocked on a stalled socket is abandoned when the consumer is disconnected
+                        let sent = tokio::select! {
+                            sent = ws_sender.send(wire_message(frame)) => sent.is_ok(),
+                            _ = send_queue.overflowed() => false,
+                        };
+                        if !sent {
+                            break;
+                        }
+                        send_metrics.record_bytes_sent(bytes);
+                    }
+                    None => break,
+                },
+                Some(envelope) = poll_fn(|cx| binary_queue.poll_recv(cx)) => {
+                    let Some(frame) = binary_proto::subscriber_frame(&envelope) else {
+                        continue;
+                    };
+                    let bytes = frame.len();
+                    let sent = tokio::select! {
+                        sent = ws_sender.send(Message::Binary(frame)) => sent.is_ok(),
+                        _ = binary_queue.overflowed() => false,
+                    };
+                    if !sent {
+                        break;
+                    }
+                    send_metrics.record_bytes_sent(bytes);
+                }
+                frame = &mut close_rx => {
+                    let _ = ws_sender.send(Message::Close(frame.ok())).await;
+                    break;
+                }
+                Ok(()) = heartbeat_rx.changed() => {
+                    ping_tick = heartbeat_timer(*heartbeat_rx.borrow_and_update());
+                }
+                _ = next_tick(&mut resume_tick), if checkpoint_due => {
+                    checkpoint_due = false;
+                    let claims = resume::ResumeClaims {
+                        sub: resume_user.clone(),
+                        sid: resume_session.clone(),
+                        subs: resume_subscriptions.lock().unwrap().clone(),
+                        seqs: sent_seqs.clone(),
+                        exp: unix_now() + resume::RESUME_TOKEN_TTL.as_secs(),
+                    };
+                    match resume::issue(&claims, &resume_secret[..]) {
+                        Ok(token) => {
+                            let notice = json!({"type": "resume_token", "token": token}).to_string();
+                            if ws_sender.send(wire_message(format.encode(notice))).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => error!("[resume] Failed to issue resume token: {}", e),
+                    }
+                }
+                _ = next_tick(&mut ping_tick) => {
+                    ping_seq += 1;
+                    let payload = ping_seq.to_be_bytes().to_vec();
+                    *outstanding_ping.lock().unwrap() = Some((payload.clone(), Instant::now()));
+                    if ws_sender.send(Message::Ping(payload)).await.is_err() {
+                        break;
+                    }
+                }
             }
         }
-    });
+    }.in_current_span());
+
+    let cleanup_config = config.clone();
+    let park_user_id = user_id.clone();
 
     // Task for receiving messages from the client
     let receive_task = tokio::spawn(async move {
+        // `close_tx` is sent or dropped when this task ends, which tells the send task to close the socket
+        let mut close_frame: Option<CloseFrame<'static>> = None;
+        let mut end = ReceiveEnd::Closed;
+
         // Fix 1: Use clone to avoid moving user_id
         let user_id_for_name = user_id.clone();
         let mut client_name = user_id_for_name.unwrap_or_else(|| "<unknown>".to_string());
@@ -136,185 +522,1516 @@ async fn run_connection(
         // Fix 2: Use clone to avoid moving token_session_id
         let token_session_id_for_session = token_session_id.clone();
         let mut session_id = token_session_id_for_session.unwrap_or_else(|| "default".to_string());
+
+        // Where direct messages to this connection are addressed
+        let direct_address = direct::connection_address(user_id.as_deref());
+
+        // Claims of the current token; replaced when the client reauthenticates
+        let mut user_info = user_info;
+
+        // Liveness: when the client last answered a ping, and how long that round trip took
+        let mut last_pong: Option<Instant> = None;
+        // Any frame proves the client is alive; used to reap connections that stop answering pings
+        let mut last_seen = tokio::time::Instant::now();
+        let mut ping_latency: Option<Duration> = None;
+
+        // Token expiry is re-checked periodically when reauthentication is enabled
+        let mut token_exp = user_info.as_ref().map(|claims| claims.exp);
+        let mut reauth_deadline: Option<Instant> = None;
+        let mut reauth_tick = match (&user_info, config.reauth_check_interval) {
+            (Some(_), Some(every)) => Some(tokio::time::interval(every)),
+            _ => None,
+        };
         
-        while let Some(msg_result) = ws_receiver.next().await {
+        // Connections are cycled after their maximum lifetime, however active they are
+        let lifetime = sleep_until(config.max_connection_lifetime.map(|max| tokio::time::Instant::now() + max));
+        tokio::pin!(lifetime);
+
+        // Per-connection features turned on with `negotiate:`
+        let mut features: Vec<Capability> = Vec::new();
+
+        // Session this connection announced itself in, and the presence events it watches
+        let mut presence_session: Option<(String, String)> = None;
+        let mut presence_subscription: Option<String> = None;
+        if token_session_id.is_some() {
+            presence_session = Some(announce_presence(&subscribers_inner, &config.presence, &session_id, &client_name));
+        }
+
+        // Send queue depth is reported periodically when configured, and on request
+        let mut queue_depth_tick = heartbeat_timer(config.queue_depth_interval);
+        let queue_depth = || json!({"type": "queue_depth", "depth": send_queue_inner.len()});
+
+        // Unknown commands received so far, for `UnknownCommandPolicy::DisconnectAfter`
+        let mut unknown_commands: u32 = 0;
+        // Token bucket for `ConnectionConfig::publish_rate_limit`
+        let mut publish_limiter = config.publish_rate_limit.map(PublishLimiter::new);
+
+        loop {
+            let msg_result = tokio::select! {
+                msg = ws_receiver.next() => match msg {
+                    Some(msg) => {
+                        last_seen = tokio::time::Instant::now();
+                        msg
+                    }
+                    None => break,
+                },
+                _ = sleep_until(config.pong_timeout.filter(|_| heartbeat_tx.borrow().is_some()).map(|timeout| last_seen + timeout)) => {
+                    info!("[heartbeat] No pong from {} within {:?}, closing", client_name, config.pong_timeout.unwrap_or_default());
+                    close_frame = Some(CloseFrame { code: close_code::AWAY, reason: "pong timeout".into() });
+                    end = ReceiveEnd::Abandoned;
+                    break;
+                }
+                _ = send_queue_inner.overflowed() => {
+                    warn!("[send-queue] Send queue of {} is full, disconnecting the slow consumer", client_name);
+                    end = ReceiveEnd::Abandoned;
+                    break;
+                }
+                _ = shutting_down.wait_for(|shutting_down| *shutting_down) => {
+                    info!("[shutdown] Server is shutting down, closing the connection with {}", client_name);
+                    close_frame = Some(CloseFrame { code: close_code::AWAY, reason: "server shutting down".into() });
+                    end = ReceiveEnd::Closing;
+                    break;
+                }
+                _ = &mut lifetime => {
+                    info!("[lifetime] Connection for {} reached its maximum lifetime, closing", client_name);
+                    reply(&tx, json!({"type": "lifetime_exceeded", "reconnect": true}));
+                    close_frame = Some(CloseFrame { code: close_code::AWAY, reason: "maximum connection lifetime reached".into() });
+                    end = ReceiveEnd::Closing;
+                    break;
+                }
+                _ = next_tick(&mut reauth_tick) => {
+                    if let Some(deadline) = reauth_deadline {
+                        if Instant::now() >= deadline {
+                            info!("[reauth] Grace window elapsed for {}, disconnecting", client_name);
+                            close_frame = Some(CloseFrame { code: close_code::POLICY, reason: "reauthentication required".into() });
+                            end = ReceiveEnd::Closing;
+                            break;
+                        }
+                    } else if token_exp.is_some_and(|exp| exp <= unix_now()) {
+                        info!("[reauth] Token expired for {}, requesting reauthentication", client_name);
+                        reply(&tx, json!({"type": "reauth_required"}));
+                        reauth_deadline = Some(Instant::now() + config.reauth_grace);
+                    }
+                    continue;
+                }
+                _ = next_tick(&mut queue_depth_tick) => {
+                    reply(&tx, queue_depth());
+                    continue;
+                }
+            };
+
+            // A token revoked after the connection opened ends it at the next command
+            let is_command = matches!(msg_result, Ok(Message::Text(_) | Message::Binary(_)));
+            if is_command && user_info.as_ref().is_some_and(|claims| config.is_revoked(claims)) {
+                info!("[revocation] Token of {} was revoked, closing", client_name);
+                reply(&tx, json!({"type": "token_revoked"}));
+                close_frame = Some(CloseFrame { code: close_code::POLICY, reason: "token revoked".into() });
+                end = ReceiveEnd::Closing;
+                break;
+            }
+
+            // Oversized frames are refused before anything parses or stores them
+            let size = match &msg_result {
+                Ok(Message::Text(text)) => text.len(),
+                Ok(Message::Binary(bytes)) => bytes.len(),
+                _ => 0,
+            };
+            if let Some(max_message_bytes) = config.max_message_bytes.filter(|max| size > *max) {
+                warn!("[receive] {} sent a {} byte frame, over the {} byte limit", client_name, size, max_message_bytes);
+                reply_error(&tx, ErrorCode::MessageTooLarge, json!({"size": size, "max_message_bytes": max_message_bytes}));
+                if config.close_on_oversized_message {
+                    close_frame = Some(CloseFrame { code: close_code::SIZE, reason: "message too large".into() });
+                    end = ReceiveEnd::Closing;
+                    break;
+                }
+                continue;
+            }
+
+            // MessagePack connections send commands as binary frames; they are handled as their JSON text
+            let msg_result = match msg_result {
+                Ok(Message::Binary(bytes)) => match format.decode(&bytes) {
+                    Some(text) => Ok(Message::Text(text)),
+                    None => Ok(Message::Binary(bytes)),
+                },
+                other => other,
+            };
+
+            // Binary subscribe and unsubscribe frames are handled as their text commands;
+            // binary publishes carry raw bytes and are delivered here
+            let msg_result = match msg_result {
+                Ok(Message::Binary(bytes)) => {
+                    let frame = match BinaryFrame::decode(&bytes) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            warn!("[binary] {} sent an invalid frame: {}", client_name, e);
+                            reply_error(&tx, ErrorCode::InvalidBinaryFrame, json!({"detail": e.to_string()}));
+                            continue;
+                        }
+                    };
+                    if frame.topic.is_empty() {
+                        warn!("[binary] {} sent a {:?} frame without a topic", client_name, frame.opcode);
+                        reply_error(&tx, ErrorCode::MissingTopic, json!({"detail": "frame has no topic"}));
+                        continue;
+                    }
+                    let requested = Some(frame.session_id.as_str()).filter(|s| !s.is_empty());
+                    let frame_session = match bound_session(token_session_id.as_deref(), requested, &session_id) {
+                        Ok(session) => session,
+                        Err(requested) => {
+                            reject_session_mismatch(&tx, &client_name, &frame.topic, &requested, &session_id, None);
+                            continue;
+                        }
+                    };
+                    match frame.opcode {
+                        Opcode::Subscribe => Ok(Message::Text(format!("subscribe:{}|{}|{}", frame.topic, frame_session, binary_proto::BINARY_OPTION))),
+                        Opcode::Unsubscribe => Ok(Message::Text(format!("unsubscribe:{}|{}", frame.topic, frame_session))),
+                        Opcode::Publish => {
+                            let received_at = Instant::now();
+                            let topic = frame.topic;
+                            match publish_limiter.as_mut().map_or(Admission::Allowed, PublishLimiter::admit) {
+                                Admission::Allowed => {}
+                                Admission::Throttled(retry_after) => {
+                                    reply_error(&tx, ErrorCode::RateLimited, json!({"topic": topic, "retry_after_ms": retry_after.as_millis() as u64}));
+                                    continue;
+                                }
+                                Admission::Disconnect => {
+                                    warn!("[rate-limit] {} kept publishing over its limit, closing", client_name);
+                                    close_frame = Some(CloseFrame { code: close_code::POLICY, reason: "publish rate limit exceeded".into() });
+                                    end = ReceiveEnd::Closing;
+                                    break;
+                                }
+                            }
+                            if !config.may_publish(user_info.as_ref(), &topic) || direct::is_direct_topic(&topic) || presence::is_presence_topic(&topic) {
+                                warn!("[binary] {} denied publishing to {}", client_name, topic);
+                                reply_error(&tx, ErrorCode::PublishNotAllowed, json!({"topic": topic}));
+                                continue;
+                            }
+                            if config.requires_encryption(&topic) {
+                                warn!("[binary] {} sent a plaintext publish to encrypted topic {}", client_name, topic);
+                                reply_error(&tx, ErrorCode::EncryptionRequired, json!({"topic": topic}));
+                                continue;
+                            }
+                            config.metrics.record_publish(&topic);
+
+                            let correlation_id = new_correlation_id();
+                            let subs = subscribers_inner.read(&topic);
+                            let patterns = config.can_subscribe(&topic).then(|| subscribers_inner.read_patterns());
+                            let sinks = subscribers::matching_sinks_from(&subs, patterns.as_deref(), &topic, &frame_session, Some(&client_name));
+                            if let Some(max_fan_out) = config.max_fan_out.filter(|max| sinks.len() > *max) {
+                                let fan_out = sinks.len();
+                                drop(sinks);
+                                drop((subs, patterns));
+                                reply(&tx, reject_fan_out(&config, &topic, &frame_session, &client_name, fan_out, max_fan_out));
+                                continue;
+                            }
+                            let build = |seq| {
+                                binary_proto::publish_envelope(&client_name, &topic, &frame_session, &frame.payload, &correlation_id, Some(seq))
+                            };
+                            let mut closed = false;
+                            let mut delivered = 0;
+                            config.history.append(&frame_session, &topic, build, |seq, envelope| {
+                                (delivered, closed) = subscribers::send_to_all(&sinks, envelope, Some(seq));
+                                config.metrics.record_delivery_failures(sinks.len() - delivered);
+                                debug!("[binary] {} published {} bytes to topic={}, session={}, delivered to {}",
+                                    client_name, frame.payload.len(), topic, frame_session, delivered);
+                            });
+                            drop((subs, patterns));
+                            config.metrics.record_publish_latency(received_at.elapsed());
+                            if closed {
+                                prune_closed(&subscribers_inner, &topic, &frame_session, &client_name, &config);
+                            }
+                            if features.contains(&Capability::Acks) {
+                                reply(&tx, ack(AckOp::Publish, &topic, &frame_session, Some(delivered), None).to_value());
+                            }
+                            continue;
+                        }
+                    }
+                }
+                other => other,
+            };
+
             match msg_result {
                 Ok(Message::Text(text)) => {
-                    // Handle client name registration
-                    if let Some(rest) = text.strip_prefix("register-name:") {
-                        // If authenticated, don't allow changing the client name
-                        if user_id.is_none() {
-                            client_name = rest.trim().to_string();
-                            println!("[register-name] => {}", client_name);
-                        } else {
-                            println!("[register-name] Ignoring name registration for authenticated user");
+                    let command = match ClientCommand::parse(&text) {
+                        Some(Ok(command)) => Some(command),
+                        Some(Err(err)) => {
+                            warn!("[command] {} sent a command that failed to parse: {}", client_name, err);
+                            reply_error(&tx, ErrorCode::BadJson, json!({"detail": err.to_string()}));
+                            continue;
                         }
+                        None => None,
+                    };
 
-                    // Handle session ID registration
-                    } else if let Some(rest) = text.strip_prefix("register-session:") {
-                        // If token has session ID, don't allow changing it
-                        if token_session_id.is_none() {
-                            session_id = rest.trim().to_string();
-                            println!("[register-session] {} => {}", client_name, session_id);
-                        } else {
-                            println!("[register-session] Ignoring session registration, using token session");
-                        }
+                    if let Some(command) = command {
+                        match command {
+                            // Handle client name registration
+                            ClientCommand::RegisterName { name } => {
+                                // If authenticated, don't allow changing the client name
+                                if user_id.is_none() {
+                                    client_name = name.trim().to_string();
+                                    info!("[register-name] => {}", client_name);
+                                    // Already present in a session: it sees the old name leave and the new one join
+                                    if let Some((session, previous)) = presence_session.take() {
+                                        depart_presence(&subscribers_inner, &config.presence, &session, &previous);
+                                        presence_session = Some(announce_presence(&subscribers_inner, &config.presence, &session, &client_name));
+                                    }
+                                } else {
+                                    debug!("[register-name] Ignoring name registration for authenticated user");
+                                }
+                            }
 
-                    // Handle topic subscription
-                    } else if let Some(rest) = text.strip_prefix("subscribe:") {
-                        let parts: Vec<&str> = rest.trim().split("|").collect();
-                        let topic = parts[0].to_string();
-                        
-                        // KEY FIX: Use provided session ID, or session ID from token, or default session ID
-                        let sub_session_id = if parts.len() > 1 { 
-                            parts[1].to_string() 
-                        } else if token_session_id.is_some() {
-                            // Use token session ID if available - this is the critical fix
-                            session_id.clone()
-                        } else { 
-                            session_id.clone() 
-                        };
+                            // Handle session ID registration
+                            ClientCommand::RegisterSession { session_id: requested } => {
+                                // If token has session ID, don't allow changing it
+                                if token_session_id.is_none() {
+                                    session_id = requested.trim().to_string();
+                                    info!("[register-session] {} => {}", client_name, session_id);
+                                    if let Some((previous, name)) = presence_session.take() {
+                                        depart_presence(&subscribers_inner, &config.presence, &previous, &name);
+                                    }
+                                    presence_session = Some(announce_presence(&subscribers_inner, &config.presence, &session_id, &client_name));
+                                } else {
+                                    debug!("[register-session] Ignoring session registration, using token session");
+                                }
+                            }
+
+                            // Handle topic subscription
+                            ClientCommand::Subscribe { topic, session_id: requested, options, id } => {
+                                // A client-chosen id asks for an ack, and is echoed on any error
+                                let command_id = id.as_deref().filter(|id| !id.is_empty());
+                                if topic.is_empty() {
+                                    warn!("[subscribe] {} sent a subscribe without a topic", client_name);
+                                    reply_error(&tx, ErrorCode::MissingTopic, json!({
+                                        "detail": "expected subscribe:<topic>[|<session>[|<options>[|<id>]]]", "id": command_id
+                                    }));
+                                    continue;
+                                }
                         
-                        println!("[subscribe] subscriber_name={}, topic={}, session={}", 
-                            client_name, topic, sub_session_id);
-                        println!("[subscribe] Using session ID from token: {}", session_id);
-
-                        let mut subs = subscribers_inner.lock().unwrap();
-                        subs.entry(topic.clone())
-                            .or_insert_with(HashMap::new)
-                            .entry(sub_session_id.clone())
-                            .or_insert_with(Vec::new)
-                            .push(tx.clone());
-
-                        println!("[subscribe] Subscription added for topic={}, session={}", 
-                            topic, sub_session_id);
-                        subscriptions_inner.lock().unwrap().push((topic, sub_session_id));
-
-                    // Handle topic unsubscription
-                    } else if let Some(rest) = text.strip_prefix("unsubscribe:") {
-                        let parts: Vec<&str> = rest.trim().split("|").collect();
-                        let topic = parts[0].to_string();
-                        // Use provided session ID or fallback to the client's session ID
-                        let unsub_session_id = if parts.len() > 1 { parts[1].to_string() } else { session_id.clone() };
+                                // Use the provided session ID, or the connection's; a token's session cannot be overridden
+                                let sub_session_id = match bound_session(token_session_id.as_deref(), requested.as_deref(), &session_id) {
+                                    Ok(session) => session,
+                                    Err(requested) => {
+                                        reject_session_mismatch(&tx, &client_name, &topic, &requested, &session_id, command_id);
+                                        continue;
+                                    }
+                                };
                         
-                        println!("[unsubscribe] {} unsubscribing from {} in session {}", client_name, topic, unsub_session_id);
+                                // Optional comma-separated options: a delivery order, `delta`, `binary`, a group and/or one publisher filter
+                                let mut order = DeliveryOrder::Ordered;
+                                let mut delta = false;
+                                let mut binary = false;
+                                let mut group = None;
+                                let mut filter = None;
+                                let mut invalid_option = None;
+                                for option in options.iter().map(String::as_str) {
+                                    match DeliveryOrder::parse(option) {
+                                        Some(parsed) => order = parsed,
+                                        None if option == delta::DELTA_OPTION => delta = true,
+                                        None if option == binary_proto::BINARY_OPTION => binary = true,
+                                        None if option.starts_with(subscribers::GROUP_OPTION) && group.is_none() => {
+                                            group = subscribers::parse_group(option);
+                                            if group.is_none() {
+                                                invalid_option = Some(option);
+                                            }
+                                        }
+                                        None if PublisherFilter::is_filter(option) && filter.is_none() => {
+                                            filter = PublisherFilter::parse(option);
+                                            if filter.is_none() {
+                                                invalid_option = Some(option);
+                                            }
+                                        }
+                                        None => invalid_option = Some(option),
+                                    }
+                                }
+                                // Patches are JSON; they cannot be delivered as raw bytes
+                                if delta && binary {
+                                    invalid_option = Some(binary_proto::BINARY_OPTION);
+                                }
+                                if let Some(option) = invalid_option {
+                                    warn!("[subscribe] {} sent unknown subscription option '{}'", client_name, option);
+                                    reply_error(&tx, ErrorCode::InvalidSubscriptionOption, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
+                                // Patches only make sense applied in order
+                                if delta {
+                                    order = DeliveryOrder::Ordered;
+                                }
+
+                                if !topic_pattern::is_valid(&topic) {
+                                    warn!("[subscribe] {} sent invalid topic pattern '{}'", client_name, topic);
+                                    reply_error(&tx, ErrorCode::InvalidTopicPattern, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
+                                if !config.may_subscribe(user_info.as_ref(), &topic) || direct::is_direct_topic(&topic) {
+                                    warn!("[subscribe] {} denied subscribing to {}", client_name, topic);
+                                    reply_error(&tx, ErrorCode::SubscribeNotAllowed, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
 
-                        let mut subs = subscribers_inner.lock().unwrap();
-                        if let Some(session_map) = subs.get_mut(&topic) {
-                            if let Some(vec) = session_map.get_mut(&unsub_session_id) {
-                                vec.retain(|s| !same_channel(s, &tx));
-                                if vec.is_empty() {
-                                    session_map.remove(&unsub_session_id);
+                                info!("[subscribe] subscriber_name={}, topic={}, session={}, order={}", 
+                                    client_name, topic, sub_session_id, order.as_str());
+                                debug!("[subscribe] Using session ID from token: {}", session_id);
+
+                                let key = (topic.clone(), sub_session_id.clone());
+                                let replaced_delta = delta_sinks_inner.lock().unwrap().remove(&key);
+                                let sink = if binary {
+                                    binary_tx.clone()
+                                } else if delta {
+                                    // Each delta subscription remembers its own last payload
+                                    let (delta_tx, delta_rx) = mpsc::unbounded_channel::<String>();
+                                    tokio::spawn(delta::forward_deltas(delta_rx, tx.clone()));
+                                    delta_sinks_inner.lock().unwrap().insert(key, delta_tx.clone());
+                                    delta_tx
+                                } else {
+                                    // Unordered subscriptions share the send queue too; the order only
+                                    // stops the server from promising anything about arrival
+                                    tx.clone()
+                                };
+                                // Dropping a replaced delta sender ends its forwarder
+                                drop(replaced_delta);
+                                let mut subs = subscribers_inner.write(&topic);
+                                let sinks = subscriber_entry(&mut subs, &topic, &sub_session_id, &client_name, &config);
+                                // A resubscribe replaces this connection's previous delivery options
+                                sinks.retain(|s| s.connection_id != connection_id);
+                                let mut subscriber = Subscriber::new(sink.clone(), connection_id).with_peer(peer).with_options(options);
+                                if let Some(group) = &group {
+                                    subscriber = subscriber.with_group(group);
+                                }
+                                if let Some(filter) = filter {
+                                    subscriber = subscriber.with_filter(filter);
+                                }
+                                let replay_filter = subscriber.filter.clone();
+                                let admits = |envelope: &str| replay_filter.as_ref().is_none_or(|filter| {
+                                    envelope_publisher(envelope).is_none_or(|publisher| filter.admits(&publisher))
+                                });
+                                // Binary frames are written from their own queue, bounded like the text one
+                                let queue = if binary { &binary_queue_inner } else { &send_queue_inner };
+                                sinks.push(subscriber.with_send_queue(queue.clone()));
+                                // Sent under the write lock so a concurrent retained publish arrives after it, not before
+                                for (retained_topic, envelope) in config.retained.matching(&sub_session_id, &topic) {
+                                    if config.can_subscribe(&retained_topic) && admits(&envelope) {
+                                        let _ = sink.send(envelope);
+                                    }
+                                }
+                                // Then the topic's recent messages, oldest first, when history keeps them
+                                for (recent_topic, envelope) in config.history.recent(&sub_session_id, &topic) {
+                                    if config.can_subscribe(&recent_topic) && admits(&envelope) {
+                                        let _ = sink.send(envelope);
+                                    }
                                 }
+
+                                info!("[subscribe] Subscription added for topic={}, session={}", 
+                                    topic, sub_session_id);
+                                config.metrics.record_subscribe();
+                                reply_ack(&tx, &features, ack(AckOp::Subscribe, &topic, &sub_session_id, None, command_id));
+                                subscriptions_inner.lock().unwrap().push((topic, sub_session_id));
                             }
-                        }
+
+                            // Handle topic unsubscription
+                            ClientCommand::Unsubscribe { topic, session_id: requested, id } => {
+                                let command_id = id.as_deref().filter(|id| !id.is_empty());
+                                if topic.is_empty() {
+                                    warn!("[unsubscribe] {} sent an unsubscribe without a topic", client_name);
+                                    reply_error(&tx, ErrorCode::MissingTopic, json!({
+                                        "detail": "expected unsubscribe:<topic>[|<session>[|<id>]]", "id": command_id
+                                    }));
+                                    continue;
+                                }
+                                // Use provided session ID or fallback to the client's session ID
+                                let unsub_session_id = match bound_session(token_session_id.as_deref(), requested.as_deref(), &session_id) {
+                                    Ok(session) => session,
+                                    Err(requested) => {
+                                        reject_session_mismatch(&tx, &client_name, &topic, &requested, &session_id, command_id);
+                                        continue;
+                                    }
+                                };
                         
-                        subscriptions_inner.lock().unwrap().retain(|t| !(t.0 == topic && t.1 == unsub_session_id));
-                    
-                    // Handle JSON message publishing
-                    } else if let Some(rest) = text.strip_prefix("publish-json:") {
-                        match serde_json::from_str::<Value>(rest) {
-                            Ok(parsed) => {
-                                let topic = parsed["topic"].as_str().unwrap_or("<none>").to_string();
-                                let payload = parsed["payload"].as_str().unwrap_or("").to_string();
-                                let publisher = parsed["publisher_name"].as_str().unwrap_or("<unknown>").to_string();
-                                let timestamp = parsed["timestamp"].as_str().unwrap_or("").to_string();
+                                info!("[unsubscribe] {} unsubscribing from {} in session {}", client_name, topic, unsub_session_id);
+
+                                delta_sinks_inner.lock().unwrap().remove(&(topic.clone(), unsub_session_id.clone()));
+                                let mut subs = subscribers_inner.write(&topic);
+                                remove_subscriber(&mut subs, &topic, &unsub_session_id, &client_name, &config, connection_id);
+                        
+                                subscriptions_inner.lock().unwrap().retain(|t| !(t.0 == topic && t.1 == unsub_session_id));
+                                config.metrics.record_unsubscribe();
+                                reply_ack(&tx, &features, ack(AckOp::Unsubscribe, &topic, &unsub_session_id, None, command_id));
+                            }
+
+                            // Handle JSON message publishing
+                            ClientCommand::Publish(publish) => {
+                                let received_at = Instant::now();
+                                let command_id = publish.id.as_deref().filter(|id| !id.is_empty());
+                                let topic = publish.topic;
+                                // Checked first so a flood is refused before it touches the subscriber locks
+                                match publish_limiter.as_mut().map_or(Admission::Allowed, PublishLimiter::admit) {
+                                    Admission::Allowed => {}
+                                    Admission::Throttled(retry_after) => {
+                                        reply_error(&tx, ErrorCode::RateLimited, json!({
+                                            "topic": topic, "retry_after_ms": retry_after.as_millis() as u64, "id": command_id
+                                        }));
+                                        continue;
+                                    }
+                                    Admission::Disconnect => {
+                                        warn!("[rate-limit] {} kept publishing over its limit, closing", client_name);
+                                        close_frame = Some(CloseFrame { code: close_code::POLICY, reason: "publish rate limit exceeded".into() });
+                                        end = ReceiveEnd::Closing;
+                                        break;
+                                    }
+                                }
+                                if topic.is_empty() {
+                                    warn!("[publish-json] {} sent a publish without a topic", client_name);
+                                    reply_error(&tx, ErrorCode::MissingTopic, json!({
+                                        "detail": "publish body needs a \"topic\" string", "id": command_id
+                                    }));
+                                    continue;
+                                }
+                                let payload = publish.payload;
+                                // A compressed payload is checked here so subscribers never receive one they cannot inflate,
+                                // and may not inflate past the size of frame the server accepts; an encrypted one can only
+                                // be checked for shape, as the server never decrypts it
+                                let encoding = publish.encoding;
+                                let inflated_limit = config.max_message_bytes
+                                    .map_or(compression::MAX_INFLATED_BYTES, |max| (max as u64).min(compression::MAX_INFLATED_BYTES));
+                                let decodes = match encoding.as_deref() {
+                                    None => true,
+                                    Some(compression::GZIP_ENCODING) => compression::inflate_payload_within(&payload, inflated_limit).is_some(),
+                                    Some(session_crypto::AES_GCM_ENCODING) => session_crypto::is_ciphertext(&payload),
+                                    Some(_) => false,
+                                };
+                                if !decodes {
+                                    warn!("[publish-json] {} sent a payload that does not decode as {:?}", client_name, encoding);
+                                    reply_error(&tx, ErrorCode::InvalidEncoding, json!({"topic": topic, "encoding": encoding, "id": command_id}));
+                                    continue;
+                                }
+                                if config.requires_encryption(&topic) && encoding.as_deref() != Some(session_crypto::AES_GCM_ENCODING) {
+                                    warn!("[publish-json] {} sent a plaintext publish to encrypted topic {}", client_name, topic);
+                                    reply_error(&tx, ErrorCode::EncryptionRequired, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
+                                let publisher = publish.publisher_name.unwrap_or_else(|| "<unknown>".to_string());
+                                let timestamp = publish.timestamp.unwrap_or_default();
                                 // Extract session ID from JSON or use default
-                                let pub_session_id = parsed["session_id"].as_str().unwrap_or(&session_id).to_string();
+                                let pub_session_id = match bound_session(token_session_id.as_deref(), publish.session_id.as_deref(), &session_id) {
+                                    Ok(session) => session,
+                                    Err(requested) => {
+                                        reject_session_mismatch(&tx, &publisher, &topic, &requested, &session_id, command_id);
+                                        continue;
+                                    }
+                                };
+                                // Keep the publisher's correlation id, or assign one so every delivery is traceable
+                                let correlation_id = publish.correlation_id.unwrap_or_else(new_correlation_id);
+                                // Passed through untouched so a responder knows where to answer
+                                let reply_to = publish.reply_to;
+                                let retain = publish.retain;
 
-                                println!(
+                                debug!(
                                     "[publish-json] publisher_name={}, topic={}, payload={}, timestamp={}, session={}",
                                     publisher, topic, payload, timestamp, pub_session_id
                                 );
+                                if !config.may_publish(user_info.as_ref(), &topic) || direct::is_direct_topic(&topic) || presence::is_presence_topic(&topic) {
+                                    warn!("[publish-json] {} denied publishing to {}", publisher, topic);
+                                    reply_error(&tx, ErrorCode::PublishNotAllowed, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
+                                if publish.broadcast && !user_info.as_ref().is_some_and(|claims| claims.has_role(BROADCAST_ROLE)) {
+                                    warn!("[publish-json] {} denied broadcasting to {}", publisher, topic);
+                                    reply_error(&tx, ErrorCode::BroadcastNotAllowed, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
+                                config.metrics.record_publish(&topic);
 
-                                let json_payload = json!({
-                                    "publisher_name": publisher,
-                                    "topic": topic,
-                                    "payload": payload,
-                                    "timestamp": timestamp,
-                                    "session_id": pub_session_id
-                                }).to_string();
-
-                                let subs = subscribers_inner.lock().unwrap();
-                                if let Some(session_map) = subs.get(&topic) {
-                                    // Only send to subscribers of the same session
-                                    println!("[publish-json] Session map has {} entries", session_map.len());
-                                    for (sess_id, _) in session_map.iter() {
-                                        println!("[publish-json] Available session: {}", sess_id);
-                                    }
-                                    
-                                    if let Some(sinks) = session_map.get(&pub_session_id) {
-                                        println!("[publish-json] Found {} subscribers for session {}", sinks.len(), pub_session_id);
-                                        for s in sinks {
-                                            if s.send(json_payload.clone()).is_err() {
-                                                eprintln!("[publish-json] Failed to send to subscriber.");
-                                            } else {
-                                                println!("[publish-json] Sent to topic '{}' in session '{}'", topic, pub_session_id);
-                                            }
+                                // Interceptors may rewrite the publish, or drop it before it is retained or delivered
+                                let mut envelope = Envelope::new(publisher, &topic, &pub_session_id, payload, timestamp, correlation_id);
+                                if interceptor::run(&config.interceptors, &mut envelope) == Decision::Drop {
+                                    debug!("[publish-json] Interceptor dropped {}'s publish to topic '{}' in session '{}'",
+                                        envelope.publisher_name, topic, pub_session_id);
+                                    reply_ack(&tx, &features, ack(AckOp::Publish, &topic, &pub_session_id, Some(0), command_id));
+                                    continue;
+                                }
+                                let Envelope { publisher_name: publisher, payload, timestamp, correlation_id, .. } = envelope;
+
+                                // A broadcast reaches every session's subscribers, each with its own session in the envelope
+                                if publish.broadcast {
+                                    let wildcards = config.can_subscribe(&topic);
+                                    let sessions = subscribers_inner.sessions(&topic, wildcards);
+                                    let mut delivered = 0;
+                                    for target_session in &sessions {
+                                        let mut envelope = message_envelope(&publisher, &topic, &payload, &timestamp, target_session, &correlation_id, None);
+                                        if let Some(encoding) = &encoding {
+                                            envelope = compression::with_encoding(&envelope, encoding);
+                                        }
+                                        if let Some(reply_to) = &reply_to {
+                                            envelope = request_reply::with_reply_to(&envelope, reply_to);
                                         }
+                                        delivered += deliver(&subscribers_inner, &topic, target_session, &envelope, wildcards, Some(&publisher));
+                                    }
+                                    debug!("[publish-json] {} broadcast to topic '{}' in {} sessions, delivered to {}",
+                                        publisher, topic, sessions.len(), delivered);
+                                    config.metrics.record_publish_latency(received_at.elapsed());
+                                    reply_ack(&tx, &features, ack(AckOp::Publish, &topic, &pub_session_id, Some(delivered), command_id));
+                                    continue;
+                                }
+
+                                // Sequence under the topic's read lock so a concurrent resume sees
+                                // each message either in history or live, never both
+                                let subs = subscribers_inner.read(&topic);
+                                // Wildcard subscribers only see topics they could have subscribed to directly
+                                let patterns = config.can_subscribe(&topic).then(|| subscribers_inner.read_patterns());
+
+                                // An empty retained payload clears the retained message instead of publishing
+                                if retain && (payload.is_null() || payload == "") {
+                                    let wildcards = patterns.is_some();
+                                    drop((subs, patterns));
+                                    if config.retained.clear(&pub_session_id, &topic) {
+                                        let tombstone = retained::tombstone(&topic, &pub_session_id);
+                                        let delivered = deliver(&subscribers_inner, &topic, &pub_session_id, &tombstone, wildcards, None);
+                                        info!("[publish-json] {} cleared retained message on topic '{}' in session '{}', tombstone sent to {}",
+                                            publisher, topic, pub_session_id, delivered);
+                                    }
+                                    continue;
+                                }
+
+                                // Refuse before sequencing so a rejected publish never reaches history
+                                // Subscriptions filtering out this publisher do not count towards the fan-out either
+                                let sinks = subscribers::matching_sinks_from(&subs, patterns.as_deref(), &topic, &pub_session_id, Some(&publisher));
+                                if let Some(max_fan_out) = config.max_fan_out.filter(|max| sinks.len() > *max) {
+                                    let fan_out = sinks.len();
+                                    drop(sinks);
+                                    drop((subs, patterns));
+                                    let mut error = reject_fan_out(&config, &topic, &pub_session_id, &client_name, fan_out, max_fan_out);
+                                    if let Some(id) = command_id {
+                                        error["id"] = json!(id);
+                                    }
+                                    reply(&tx, error);
+                                    continue;
+                                }
+                                if let Some(required) = publish.min_subscribers.filter(|min| sinks.len() < *min) {
+                                    let present = sinks.len();
+                                    drop(sinks);
+                                    drop((subs, patterns));
+                                    warn!("[publish-json] {} needs {} subscribers on topic '{}' in session '{}', found {}",
+                                        publisher, required, topic, pub_session_id, present);
+                                    reply_error(&tx, ErrorCode::InsufficientSubscribers, json!({
+                                        "topic": topic, "present": present, "required": required, "id": command_id
+                                    }));
+                                    continue;
+                                }
+
+                                let build = |seq| {
+                                    let mut envelope = message_envelope(&publisher, &topic, &payload, &timestamp, &pub_session_id, &correlation_id, Some(seq));
+                                    if let Some(encoding) = &encoding {
+                                        envelope = compression::with_encoding(&envelope, encoding);
+                                    }
+                                    if let Some(reply_to) = &reply_to {
+                                        envelope = request_reply::with_reply_to(&envelope, reply_to);
+                                    }
+                                    if config.include_server_latency {
+                                        with_server_latency(&envelope, received_at.elapsed())
                                     } else {
-                                        println!("[publish-json] No subscribers found for session '{}'", pub_session_id);
+                                        envelope
+                                    }
+                                };
+                                let mut closed = false;
+                                let mut delivered = 0;
+                                config.history.append(&pub_session_id, &topic, build, |seq, json_payload| {
+                                    if retain {
+                                        config.retained.set(&pub_session_id, &topic, json_payload);
+                                    }
+                                    // Only send to subscribers of the same session, exact topic first
+                                    if sinks.is_empty() {
+                                        debug!("[publish-json] No subscribers found for topic '{}' in session '{}'", topic, pub_session_id);
+                                        return;
+                                    }
+                                    (delivered, closed) = subscribers::send_to_all(&sinks, json_payload, Some(seq));
+                                    config.metrics.record_delivery_failures(sinks.len() - delivered);
+                                    debug!("[publish-json] Sent to {} of {} subscribers of topic '{}' in session '{}'",
+                                        delivered, sinks.len(), topic, pub_session_id);
+                                });
+                                drop((subs, patterns));
+                                config.metrics.record_publish_latency(received_at.elapsed());
+                                if closed {
+                                    prune_closed(&subscribers_inner, &topic, &pub_session_id, &publisher, &config);
+                                }
+                                reply_ack(&tx, &features, ack(AckOp::Publish, &topic, &pub_session_id, Some(delivered), command_id));
+                            }
+
+                            // Hand the session key to this connection, encrypted for the public key it sent
+                            ClientCommand::KeyExchange { public_key, id } => {
+                                let command_id = id.as_deref().filter(|id| !id.is_empty());
+                                let Some(enc_state) = &config.encryption else {
+                                    warn!("[key-exchange] {} asked for a session key, but encryption is not configured", client_name);
+                                    reply_error(&tx, ErrorCode::EncryptionUnavailable, json!({"detail": "no server keypair", "id": command_id}));
+                                    continue;
+                                };
+                                // Anyone can register into a session by name, so only its token proves membership
+                                if token_session_id.as_deref() != Some(session_id.as_str()) {
+                                    warn!("[key-exchange] {} asked for the key of session {} without a token for it", client_name, session_id);
+                                    reply_error(&tx, ErrorCode::KeyExchangeNotAllowed, json!({"session_id": session_id, "id": command_id}));
+                                    continue;
+                                }
+                                let key = {
+                                    let mut holds = holds_session_key_inner.lock().unwrap();
+                                    let key = if *holds { config.session_keys.key(&session_id) } else { config.session_keys.hold(&session_id) };
+                                    *holds |= key.is_ok();
+                                    key
+                                };
+                                let wrapped = key
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|key| {
+                                        let server = enc_state.keypair.read().unwrap();
+                                        session_crypto::wrap_session_key(&server, &public_key, &key).map_err(|e| e.to_string())
+                                    });
+                                match wrapped {
+                                    Ok(wrapped_key) => {
+                                        info!("[key-exchange] {} received the key for session {}", client_name, session_id);
+                                        reply(&tx, ServerMessage::SessionKey {
+                                            session_id: session_id.clone(),
+                                            wrapped_key,
+                                            id: command_id.map(str::to_string),
+                                        }.to_value());
+                                    }
+                                    Err(e) => {
+                                        warn!("[key-exchange] {} sent an unusable public key: {}", client_name, e);
+                                        reply_error(&tx, ErrorCode::EncryptionUnavailable, json!({"detail": e, "id": command_id}));
+                                    }
+                                }
+                            }
+
+                            ClientCommand::ListPresence { session_id: requested } => {
+                                let listed = match bound_session(token_session_id.as_deref(), requested.as_deref(), &session_id) {
+                                    Ok(session) => session,
+                                    Err(requested) => {
+                                        reject_session_mismatch(&tx, &client_name, presence::PRESENCE_TOPIC, &requested, &session_id, None);
+                                        continue;
+                                    }
+                                };
+                                let members = config.presence.members(&listed);
+                                debug!("[list-presence] {} listed {} members of session {}", client_name, members.len(), listed);
+                                reply(&tx, ServerMessage::PresenceList { session: listed, members }.to_value());
+                            }
+
+                            // Count the live connections a publish would reach, so publishers can skip empty topics
+                            ClientCommand::HasSubscribers { topic, session_id: requested, id } => {
+                                let command_id = id.as_deref().filter(|id| !id.is_empty());
+                                if topic.is_empty() {
+                                    reply_error(&tx, ErrorCode::MissingTopic, json!({"detail": "has-subscribers without a topic", "id": command_id}));
+                                    continue;
+                                }
+                                let counted = match bound_session(token_session_id.as_deref(), requested.as_deref(), &session_id) {
+                                    Ok(session) => session,
+                                    Err(requested) => {
+                                        reject_session_mismatch(&tx, &client_name, &topic, &requested, &session_id, command_id);
+                                        continue;
                                     }
+                                };
+                                let count = {
+                                    let subs = subscribers_inner.read(&topic);
+                                    let patterns = config.can_subscribe(&topic).then(|| subscribers_inner.read_patterns());
+                                    subscribers::matching_sinks(&subs, patterns.as_deref(), &topic, &counted)
+                                        .iter()
+                                        .filter(|sink| !sink.sender.is_closed())
+                                        .count()
+                                };
+                                debug!("[has-subscribers] {} asked about topic={}, session={}: {}", client_name, topic, counted, count);
+                                reply(&tx, ServerMessage::SubscriberCount {
+                                    topic,
+                                    session_id: counted,
+                                    count,
+                                    id: command_id.map(str::to_string),
+                                }.to_value());
+                            }
+
+                            ClientCommand::SetWill { topic, payload, id } => {
+                                let command_id = id.as_deref().filter(|id| !id.is_empty());
+                                if topic.is_empty() {
+                                    reply_error(&tx, ErrorCode::MissingTopic, json!({"detail": "expected set-will:<topic>|<payload>", "id": command_id}));
+                                    continue;
+                                }
+                                // Checked now, as nobody is left to answer once the will is published
+                                if !config.may_publish(user_info.as_ref(), &topic) || direct::is_direct_topic(&topic) || presence::is_presence_topic(&topic) {
+                                    warn!("[set-will] {} denied a will on {}", client_name, topic);
+                                    reply_error(&tx, ErrorCode::PublishNotAllowed, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
+                                if config.requires_encryption(&topic) {
+                                    warn!("[set-will] {} sent a plaintext will for encrypted topic {}", client_name, topic);
+                                    reply_error(&tx, ErrorCode::EncryptionRequired, json!({"topic": topic, "id": command_id}));
+                                    continue;
+                                }
+                                info!("[set-will] {} registered a will on topic={}, session={}", client_name, topic, session_id);
+                                reply_ack(&tx, &features, ack(AckOp::SetWill, &topic, &session_id, None, command_id));
+                                *will_inner.lock().unwrap() = Some((topic, payload));
+                            }
+
+                            ClientCommand::Ping => {
+                                debug!("[ping] Received ping message");
+                                // Send a pong response
+                                if tx.send("pong".to_string()).is_err() {
+                                    warn!("[ping] Failed to send pong response");
                                 } else {
-                                    println!("[publish-json] No session map found for topic '{}'", topic);
+                                    debug!("[ping] Sent pong response");
+                                }
+                            }
+                        }
+                    // Handle heartbeat negotiation, clamped to the server's bounds
+                    } else if let Some(rest) = text.strip_prefix("register-heartbeat:") {
+                        match rest.trim().parse::<u64>() {
+                            Ok(requested_ms) => {
+                                let interval = Duration::from_millis(requested_ms)
+                                    .clamp(config.heartbeat_min_interval, config.heartbeat_max_interval);
+                                info!("[register-heartbeat] {} requested {}ms, using {}ms",
+                                    client_name, requested_ms, interval.as_millis());
+                                let _ = heartbeat_tx.send(Some(interval));
+                                reply(&tx, ServerMessage::Heartbeat { interval_ms: interval.as_millis() as u64 }.to_value());
+                            }
+                            Err(e) => warn!("[register-heartbeat] Invalid interval '{}': {}", rest, e),
+                        }
+
+                    // Issue a one-time token a standby connection can use to adopt these subscriptions
+                    } else if text == "transfer-token" {
+                        let token = config.transfers.issue(user_id.clone(), session_id.clone(), subscriptions_inner.clone());
+                        info!("[transfer-token] Issued transfer token for {} in session {}", client_name, session_id);
+                        reply(&tx, json!({"type": "transfer_token", "token": token}));
+
+                    // Adopt another connection's subscriptions; it keeps them until it disconnects
+                    } else if let Some(rest) = text.strip_prefix("transfer-subscription:") {
+                        match config.transfers.redeem(rest.trim(), user_id.as_deref(), &session_id) {
+                            Some(adopted) => {
+                                let mut mine = subscriptions_inner.lock().unwrap();
+                                let mut topics = Vec::new();
+                                for (topic, sub_session_id) in adopted {
+                                    if mine.contains(&(topic.clone(), sub_session_id.clone())) {
+                                        continue;
+                                    }
+                                    subscriber_entry(&mut subscribers_inner.write(&topic), &topic, &sub_session_id, &client_name, &config)
+                                        .push(Subscriber::new(tx.clone(), connection_id).with_peer(peer).with_send_queue(send_queue_inner.clone()));
+                                    topics.push(topic.clone());
+                                    mine.push((topic, sub_session_id));
+                                }
+                                info!("[transfer-subscription] {} adopted topics {:?}", client_name, topics);
+                                reply(&tx, json!({"type": "transfer_complete", "topics": topics}));
+                            }
+                            None => {
+                                warn!("[transfer-subscription] Rejected transfer token from {}", client_name);
+                                reply_error(&tx, ErrorCode::InvalidTransferToken, json!({}));
+                            }
+                        }
+
+                    // Handle token renewal on an authenticated connection
+                    } else if let Some(rest) = text.strip_prefix("authenticate:") {
+                        if user_id.is_none() {
+                            warn!("[authenticate] Ignoring token on anonymous connection");
+                            continue;
+                        }
+
+                        // Only accept a fresh token for the same user and session
+                        let renewed = validate_token_with(rest.trim(), &secret[..], &config.token_validation())
+                            .ok()
+                            .filter(|claims| Some(&claims.sub) == user_id.as_ref() && claims.sid == token_session_id)
+                            .filter(|claims| !config.is_revoked(claims));
+
+                        let response = match renewed {
+                            Some(claims) => {
+                                info!("[authenticate] Token renewed for {}, expires at {}", client_name, claims.exp);
+                                token_exp = Some(claims.exp);
+                                reauth_deadline = None;
+                                let response = json!({"type": "reauth_ok", "exp": claims.exp});
+                                // Custom claims follow the renewed token
+                                user_info = Some(claims);
+                                response
+                            }
+                            None => {
+                                warn!("[authenticate] Rejected token renewal for {}", client_name);
+                                json!({"type": "reauth_failed"})
+                            }
+                        };
+                        reply(&tx, response);
+
+                    // Restore a previous connection's subscriptions and replay what it missed
+                    } else if let Some(rest) = text.strip_prefix("resume:") {
+                        // A token only resumes for the user and token session it was issued to
+                        let checkpoint = match resume::redeem(rest.trim(), &secret[..]) {
+                            Ok(checkpoint) if checkpoint.sub == user_id && checkpoint.sid == token_session_id => checkpoint,
+                            Ok(_) => {
+                                warn!("[resume] {} presented a resume token issued to another user or session", client_name);
+                                reply_error(&tx, ErrorCode::InvalidResumeToken, json!({}));
+                                continue;
+                            }
+                            Err(e) => {
+                                warn!("[resume] {} presented an invalid resume token: {}", client_name, e);
+                                reply_error(&tx, ErrorCode::InvalidResumeToken, json!({}));
+                                continue;
+                            }
+                        };
+
+                        // Every shard stays locked until the replay is queued, so nothing is sent twice
+                        let mut mine = subscriptions_inner.lock().unwrap();
+                        let mut shards = subscribers_inner.write_all();
+                        let mut topics_by_session: HashMap<String, Vec<String>> = HashMap::new();
+                        for (topic, sub_session_id) in checkpoint.subs {
+                            // Another connection's private topic is never restored here
+                            let foreign_direct = direct::is_direct_topic(&topic)
+                                && topic != direct::direct_topic(&direct_address);
+                            if !config.may_subscribe(user_info.as_ref(), &topic) || foreign_direct {
+                                continue;
+                            }
+                            // Subscriptions outside the connection's token session are dropped
+                            let Ok(sub_session_id) = bound_session(token_session_id.as_deref(), Some(&sub_session_id), &session_id) else {
+                                warn!("[resume] {} skipped {} in session {}, outside its token session", client_name, topic, sub_session_id);
+                                continue;
+                            };
+                            if !mine.contains(&(topic.clone(), sub_session_id.clone())) {
+                                subscriber_entry(shards.shard(&topic), &topic, &sub_session_id, &client_name, &config)
+                                    .push(Subscriber::new(tx.clone(), connection_id).with_peer(peer).with_send_queue(send_queue_inner.clone()));
+                                mine.push((topic.clone(), sub_session_id.clone()));
+                            }
+                            topics_by_session.entry(sub_session_id).or_default().push(topic);
+                        }
+
+                        let mut replayed = 0;
+                        let mut truncated = false;
+                        for (sub_session_id, topics) in &topics_by_session {
+                            let after = checkpoint.seqs.get(sub_session_id).copied().unwrap_or(0);
+                            let replay = config.history.since(sub_session_id, after, topics);
+                            truncated |= replay.truncated;
+                            replayed += replay.messages.len();
+                            for envelope in replay.messages {
+                                let _ = tx.send(envelope);
+                            }
+                        }
+                        info!("[resume] {} resumed {} subscriptions, replayed {} messages", client_name, mine.len(), replayed);
+                        reply(&tx, json!({"type": "resume_complete", "replayed": replayed, "truncated": truncated}));
+
+                    // Take over a dropped connection's parked subscriptions and what was buffered for them
+                    } else if let Some(rest) = text.strip_prefix("reattach:") {
+                        let Some(parked) = config.parked.claim(rest.trim(), user_id.as_deref()) else {
+                            warn!("[reattach] {} presented an unknown or expired session token", client_name);
+                            reply_error(&tx, ErrorCode::InvalidResumeToken, json!({}));
+                            continue;
+                        };
+
+                        // Every shard stays locked until the buffer is queued, so it goes out before newer messages
+                        let mut mine = subscriptions_inner.lock().unwrap();
+                        let mut shards = subscribers_inner.write_all();
+                        for (topic, sub_session_id) in &parked.subscriptions {
+                            let subscription = (topic.clone(), sub_session_id.clone());
+                            let already_mine = mine.contains(&subscription);
+                            let shard = shards.shard(topic);
+                            if let Some(sinks) = shard.get_mut(topic).and_then(|sessions| sessions.get_mut(sub_session_id)) {
+                                if already_mine {
+                                    // Subscribed again before reattaching; the parked copy would deliver twice
+                                    sinks.retain(|sink| sink.connection_id != parked.connection_id);
+                                }
+                                for sink in sinks.iter_mut().filter(|sink| sink.connection_id == parked.connection_id) {
+                                    sink.sender = tx.clone();
+                                    sink.queue = Some(send_queue_inner.clone());
+                                    sink.connection_id = connection_id;
+                                    sink.peer = peer;
                                 }
                             }
-                            Err(err) => {
-                                eprintln!("[publish-json] Failed to parse JSON: {}", err);
-                                println!("[publish-json] Raw JSON: {}", rest);
+                            if !already_mine {
+                                mine.push(subscription);
+                            }
+                        }
+                        // The parked hold on a session key passes to this connection, or is given up
+                        if let Some(key_session) = &parked.session_key {
+                            let mut holds = holds_session_key_inner.lock().unwrap();
+                            if *holds || *key_session != session_id {
+                                config.session_keys.release(key_session);
+                            } else {
+                                *holds = true;
+                            }
+                        }
+                        let buffered = parked.buffer.drain();
+                        let flushed = buffered.len();
+                        for message in buffered {
+                            let _ = tx.send(message);
+                        }
+                        drop(shards);
+                        info!("[reattach] {} reattached {} subscriptions, flushed {} buffered messages", client_name, parked.subscriptions.len(), flushed);
+                        reply(&tx, json!({"type": "reattached", "subscriptions": parked.subscriptions.len(), "flushed": flushed}));
+
+                    // Report who the server thinks this connection is, including custom token claims
+                    } else if text == "whoami" {
+                        let claims = user_info.as_ref().map(|claims| claims.extra.clone()).unwrap_or_default();
+                        reply(&tx, json!({
+                            "type": "identity",
+                            "user_id": user_id,
+                            "client_name": client_name,
+                            "session_id": session_id,
+                            "claims": claims,
+                            "last_pong_ms_ago": last_pong.map(|at| at.elapsed().as_millis() as u64),
+                            "ping_latency_ms": ping_latency.map(|latency| latency.as_millis() as u64),
+                            "protocol": format.protocol()
+                        }));
+
+                    // Subscribe to this connection's private topic so others can message it directly
+                    } else if text == "subscribe-self" {
+                        let topic = direct::direct_topic(&direct_address);
+                        let key = (topic.clone(), direct::DIRECT_SESSION.to_string());
+                        let mut mine = subscriptions_inner.lock().unwrap();
+                        if !mine.contains(&key) {
+                            let mut subs = subscribers_inner.write(&topic);
+                            subscriber_entry(&mut subs, &topic, direct::DIRECT_SESSION, &client_name, &config)
+                                .push(Subscriber::new(tx.clone(), connection_id).with_peer(peer).with_send_queue(send_queue_inner.clone()));
+                            mine.push(key);
+                        }
+                        info!("[subscribe-self] {} receives direct messages at {}", client_name, direct_address);
+                        reply(&tx, json!({"type": "self_subscribed", "address": direct_address, "topic": topic}));
+
+                    // Send a direct message to the connections subscribed to an address
+                    } else if let Some(rest) = text.strip_prefix("publish-to:") {
+                        let received_at = Instant::now();
+                        let (address, payload) = rest.split_once('|').unwrap_or((rest, ""));
+                        let topic = direct::direct_topic(address.trim());
+                        // Direct messages draw on the same bucket as publishes, so they cannot be used to flood
+                        match publish_limiter.as_mut().map_or(Admission::Allowed, PublishLimiter::admit) {
+                            Admission::Allowed => {}
+                            Admission::Throttled(retry_after) => {
+                                reply_error(&tx, ErrorCode::RateLimited, json!({
+                                    "topic": topic, "retry_after_ms": retry_after.as_millis() as u64
+                                }));
+                                continue;
+                            }
+                            Admission::Disconnect => {
+                                warn!("[rate-limit] {} kept sending direct messages over its limit, closing", client_name);
+                                close_frame = Some(CloseFrame { code: close_code::POLICY, reason: "publish rate limit exceeded".into() });
+                                end = ReceiveEnd::Closing;
+                                break;
                             }
                         }
-                    } else if text == "ping" {
-                        println!("[ping] Received ping message");
-                        // Send a pong response
-                        if tx.send("pong".to_string()).is_err() {
-                            eprintln!("[ping] Failed to send pong response");
-                        } else {
-                            println!("[ping] Sent pong response");
+                        if !config.may_publish(user_info.as_ref(), &topic) {
+                            warn!("[publish-to] {} denied a direct message to {}", client_name, address);
+                            reply_error(&tx, ErrorCode::PublishNotAllowed, json!({"topic": topic}));
+                            continue;
+                        }
+                        // A direct message is never encrypted, so it cannot go where encryption is required
+                        if config.requires_encryption(&topic) {
+                            warn!("[publish-to] {} sent a plaintext direct message to encrypted topic {}", client_name, topic);
+                            reply_error(&tx, ErrorCode::EncryptionRequired, json!({"topic": topic}));
+                            continue;
+                        }
+                        // One bucket for every address, so each recipient does not become its own series
+                        config.metrics.record_publish(&direct::direct_topic("*"));
+
+                        // A payload that parses as JSON is delivered as JSON, anything else as text
+                        let payload = serde_json::from_str(payload).unwrap_or_else(|_| Value::from(payload));
+                        let mut envelope = Envelope::new(client_name.clone(), &topic, direct::DIRECT_SESSION, payload, now_rfc3339(), new_correlation_id());
+                        if interceptor::run(&config.interceptors, &mut envelope) == Decision::Drop {
+                            debug!("[publish-to] Interceptor dropped {}'s direct message to {}", client_name, address);
+                            continue;
+                        }
+                        let Envelope { publisher_name: publisher, payload, timestamp, correlation_id, .. } = envelope;
+                        let envelope = message_envelope(&publisher, &topic, &payload, &timestamp, direct::DIRECT_SESSION, &correlation_id, None);
+
+                        let subs = subscribers_inner.read(&topic);
+                        let sinks = subscribers::matching_sinks_from(&subs, None, &topic, direct::DIRECT_SESSION, Some(&publisher));
+                        if let Some(max_fan_out) = config.max_fan_out.filter(|max| sinks.len() > *max) {
+                            let fan_out = sinks.len();
+                            drop(sinks);
+                            drop(subs);
+                            reply(&tx, reject_fan_out(&config, &topic, direct::DIRECT_SESSION, &client_name, fan_out, max_fan_out));
+                            continue;
+                        }
+                        let (delivered, closed) = subscribers::send_to_all(&sinks, &envelope, None);
+                        config.metrics.record_delivery_failures(sinks.len() - delivered);
+                        drop(sinks);
+                        drop(subs);
+                        config.metrics.record_publish_latency(received_at.elapsed());
+                        if closed {
+                            prune_closed(&subscribers_inner, &topic, direct::DIRECT_SESSION, &publisher, &config);
+                        }
+                        debug!("[publish-to] {} sent a direct message to {}, delivered to {}", client_name, address, delivered);
+                        if delivered == 0 {
+                            reply_error(&tx, ErrorCode::RecipientUnavailable, json!({"topic": topic}));
+                        }
+
+                    // Turn on per-connection features; the new set replaces any earlier negotiation
+                    } else if let Some(rest) = text.strip_prefix("negotiate:") {
+                        let (enabled, unsupported) = capabilities::negotiate(&config, rest);
+                        info!("[negotiate] {} enabled {:?}, unsupported {:?}", client_name, enabled, unsupported);
+                        let watch_presence = enabled.contains(&Capability::Presence).then(|| session_id.clone());
+                        if presence_subscription != watch_presence {
+                            let key = |session: &String| (presence::PRESENCE_TOPIC.to_string(), session.clone());
+                            if let Some(previous) = presence_subscription.take() {
+                                remove_subscriber(&mut subscribers_inner.write(presence::PRESENCE_TOPIC), presence::PRESENCE_TOPIC,
+                                    &previous, &client_name, &config, connection_id);
+                                subscriptions_inner.lock().unwrap().retain(|t| *t != key(&previous));
+                            }
+                            if let Some(session) = &watch_presence {
+                                let mut subs = subscribers_inner.write(presence::PRESENCE_TOPIC);
+                                subscriber_entry(&mut subs, presence::PRESENCE_TOPIC, session, &client_name, &config)
+                                    .push(Subscriber::new(tx.clone(), connection_id).with_peer(peer).with_send_queue(send_queue_inner.clone()));
+                                subscriptions_inner.lock().unwrap().push(key(session));
+                            }
+                            presence_subscription = watch_presence;
                         }
+                        features = enabled;
+                        reply(&tx, json!({"type": "negotiated", "features": features, "unsupported": unsupported}));
+
+                    // Report how many messages are queued ahead of this reply
+                    } else if text == "queue-depth" {
+                        reply(&tx, queue_depth());
+
+                    // Report this connection's subscriptions, for debugging session routing
+                    } else if text == "list-subscriptions" {
+                        let subscriptions: Vec<Value> = subscriptions_inner.lock().unwrap()
+                            .iter()
+                            .map(|(topic, sub_session_id)| json!({"topic": topic, "session_id": sub_session_id}))
+                            .collect();
+                        debug!("[list-subscriptions] {} has {} subscriptions", client_name, subscriptions.len());
+                        reply(&tx, json!({"type": "subscriptions", "subscriptions": subscriptions}));
+
                     } else {
-                        println!("[unknown] Received unknown message: {}", text);
+                        warn!("[unknown] Received unknown message: {}", text);
+                        // Name the command only; the rest of the frame may be a large payload
+                        let command = text.split(':').next().unwrap_or_default();
+                        let error = error_frame(ErrorCode::UnknownCommand, json!({"command": command}));
+                        match config.unknown_command_policy {
+                            UnknownCommandPolicy::Ignore => {}
+                            UnknownCommandPolicy::ErrorReply => reply(&tx, error),
+                            UnknownCommandPolicy::DisconnectAfter(limit) => {
+                                reply(&tx, error);
+                                unknown_commands += 1;
+                                if unknown_commands >= limit {
+                                    warn!("[unknown] {} sent {} unknown commands, closing", client_name, unknown_commands);
+                                    close_frame = Some(CloseFrame { code: close_code::POLICY, reason: "too many unknown commands".into() });
+                                    end = ReceiveEnd::Closing;
+                                    break;
+                                }
+                            }
+                        }
                     }
                 }
-                Ok(_) => eprintln!("[run_connection] Received non-text message"),
+                Ok(Message::Pong(payload)) => {
+                    last_pong = Some(Instant::now());
+                    // Only a pong echoing the latest ping yields a latency sample
+                    let sent = outstanding_ping_inner.lock().unwrap()
+                        .take_if(|(expected, _)| *expected == payload)
+                        .map(|(_, sent)| sent);
+                    if let Some(sent) = sent {
+                        let latency = sent.elapsed();
+                        ping_latency = Some(latency);
+                        config.metrics.record_ping_latency(latency);
+                    }
+                }
+                Ok(Message::Close(frame)) => {
+                    match frame {
+                        Some(frame) => info!("[run_connection] {} closed the connection: code={}, reason={:?}",
+                            client_name, frame.code, frame.reason),
+                        None => info!("[run_connection] {} closed the connection without a close code", client_name),
+                    }
+                    peer_closed_inner.notify_one();
+                    // Leaving on purpose discards the will
+                    if will_inner.lock().unwrap().take().is_some() {
+                        debug!("[set-will] {} closed the connection, discarding its will", client_name);
+                    }
+                    end = ReceiveEnd::Closing;
+                    break;
+                }
+                Ok(_) => warn!("[run_connection] Received non-text message"),
                 Err(e) => {
-                    eprintln!("[run_connection] Error receiving: {:?}", e);
+                    error!("[run_connection] Error receiving: {:?}", e);
+                    end = ReceiveEnd::Failed;
                     break;
                 }
             }
         }
-    });
+        if let Some(frame) = close_frame {
+            let _ = close_tx.send(frame);
+        }
+        if let Some((session, name)) = presence_session {
+            depart_presence(&subscribers_inner, &config.presence, &session, &name);
+        }
+
+        // Cleanup is attributed to the name the client ended up with; the receiver is kept to
+        // finish the close handshake once cleanup is done
+        (client_name, session_id, ws_receiver, end)
+    }.in_current_span());
 
     // Wait for both tasks to complete
-    match tokio::try_join!(send_task, receive_task) {
-        Ok(_) => println!("[run_connection] Connection closed cleanly."),
+    let (client_name, session_id, mut ws_receiver, end) = match tokio::try_join!(send_task, receive_task) {
+        Ok((_, ended)) => {
+            debug!("[run_connection] Connection tasks finished.");
+            ended
+        }
         Err(e) => {
-            eprintln!("[run_connection] Task error: {:?}", e);
+            error!("[run_connection] Task error: {:?}", e);
+            cleanup_config.connections.unregister(connection_id);
             return Err("WebSocket task crashed".into());
         }
+    };
+
+    // Cleanup subscriptions on client disconnect; a dropped connection may park them instead
+    let dropped = matches!(end, ReceiveEnd::Abandoned | ReceiveEnd::Failed);
+    match (session_token, cleanup_config.session_grace) {
+        (Some(token), Some(grace)) if dropped => {
+            delta_sinks.lock().unwrap().clear();
+            let subscriptions = my_subscriptions.lock().unwrap().clone();
+            let session_key = (*holds_session_key.lock().unwrap()).then(|| session_id.clone());
+            let parked = ParkedSession::new(park_user_id, connection_id, subscriptions, session_key, Instant::now() + grace, cleanup_config.metrics.clone());
+            park_subscriptions(&subscribers, &cleanup_config, token, parked, &client_name);
+        }
+        _ => {
+            let mut delta_sinks = delta_sinks.lock().unwrap();
+            for (topic, session_id) in my_subscriptions.lock().unwrap().iter() {
+                delta_sinks.remove(&(topic.clone(), session_id.clone()));
+                remove_subscriber(&mut subscribers.write(topic), topic, session_id, &client_name, &cleanup_config, connection_id);
+            }
+            if *holds_session_key.lock().unwrap() {
+                cleanup_config.session_keys.release(&session_id);
+            }
+        }
+    }
+    // A connection that ended without the client closing it leaves its will behind
+    let will = my_will.lock().unwrap().take();
+    if let Some((topic, payload)) = will {
+        publish_will(&subscribers, &cleanup_config, &client_name, &session_id, &topic, &payload);
+    }
+    debug!("[run_connection] Cleanup complete.");
+
+    // A close sent by both sides at once is still a completed handshake, not a transport error
+    let clean = match end {
+        ReceiveEnd::Closed => true,
+        ReceiveEnd::Closing => finish_close_handshake(&mut ws_receiver, &client_name).await,
+        ReceiveEnd::Abandoned | ReceiveEnd::Failed => false,
+    };
+    cleanup_config.metrics.record_close(clean);
+    cleanup_config.connections.unregister(connection_id);
+    if clean {
+        info!("[run_connection] Connection with {} closed cleanly.", client_name);
+    } else {
+        info!("[run_connection] Connection with {} ended without a close handshake.", client_name);
+    }
+    Ok(())
+}
+
+/// A frame encoded in the connection's format, as the message written to the socket.
+fn wire_message(frame: EncodedFrame) -> Message {
+    match frame {
+        EncodedFrame::Text(text) => Message::Text(text),
+        EncodedFrame::Binary(bytes) => Message::Binary(bytes),
     }
+}
 
-    // Cleanup subscriptions on client disconnect
-    let mut subs = subscribers.lock().unwrap();
-    for (topic, session_id) in my_subscriptions.lock().unwrap().iter() {
-        if let Some(session_map) = subs.get_mut(topic) {
-            if let Some(vec) = session_map.get_mut(session_id) {
-                vec.retain(|s| !same_channel(s, &tx_clone));
-                if vec.is_empty() {
-                    session_map.remove(session_id);
+/// Reads what is left of the stream after a close frame was sent or received. This flushes the
+/// server's reply to the client's close and waits for the client's reply to the server's, which
+/// may already be in flight if both sides closed at once. False if the transport failed or the
+/// client did not answer within `CLOSE_HANDSHAKE_TIMEOUT`.
+async fn finish_close_handshake<S>(receiver: &mut S, client_name: &str) -> bool
+where
+    S: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    let finished = tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, async {
+        loop {
+            match receiver.next().await {
+                None => return true,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    warn!("[run_connection] Error finishing the close handshake with {}: {:?}", client_name, e);
+                    return false;
                 }
             }
-            if session_map.is_empty() {
-                subs.remove(topic);
+        }
+    }).await;
+    finished.unwrap_or_else(|_| {
+        warn!("[run_connection] {} did not answer the close frame within {:?}", client_name, CLOSE_HANDSHAKE_TIMEOUT);
+        false
+    })
+}
+
+/// Returns the sinks registered for a topic in a session, creating (and auditing) the key if needed.
+fn subscriber_entry<'a>(
+    subs: &'a mut SubscriberMap,
+    topic: &str,
+    session_id: &str,
+    actor: &str,
+    config: &ConnectionConfig,
+) -> &'a mut Vec<Subscriber> {
+    let sessions = subs.entry(topic.to_string()).or_default();
+    if !sessions.contains_key(session_id) {
+        audit::emit(config.audit_sink.as_deref(), audit::TopicAuditKind::Created, topic, session_id, actor);
+    }
+    sessions.entry(session_id.to_string()).or_default()
+}
+
+/// Drops a topic's closed subscribers in a session after a publish found some, auditing the
+/// keys that were left empty.
+fn prune_closed(subscribers: &Subscribers, topic: &str, session_id: &str, actor: &str, config: &ConnectionConfig) {
+    for key in subscribers.prune_closed(topic, session_id) {
+        audit::emit(config.audit_sink.as_deref(), audit::TopicAuditKind::Removed, &key, session_id, actor);
+    }
+}
+
+/// Removes the connection's subscription to a topic in a session, dropping (and auditing) the key once empty.
+fn remove_subscriber(
+    subs: &mut SubscriberMap,
+    topic: &str,
+    session_id: &str,
+    actor: &str,
+    config: &ConnectionConfig,
+    connection_id: ConnectionId,
+) {
+    let Some(sessions) = subs.get_mut(topic) else {
+        return;
+    };
+    if let Some(sinks) = sessions.get_mut(session_id) {
+        sinks.retain(|s| s.connection_id != connection_id);
+        if sinks.is_empty() {
+            sessions.remove(session_id);
+            audit::emit(config.audit_sink.as_deref(), audit::TopicAuditKind::Removed, topic, session_id, actor);
+        }
+    }
+    if sessions.is_empty() {
+        subs.remove(topic);
+    }
+}
+
+/// The session a subscribe, unsubscribe or publish applies to: the one the client named, or the
+/// connection's own when it named none. A token's session is binding, so naming another one is
+/// an error carrying the requested session.
+fn bound_session(token_session: Option<&str>, requested: Option<&str>, connection_session: &str) -> Result<String, String> {
+    let requested = requested.filter(|session| !session.is_empty());
+    match (token_session, requested) {
+        (Some(bound), Some(requested)) if requested != bound => Err(requested.to_string()),
+        (_, Some(requested)) => Ok(requested.to_string()),
+        (_, None) => Ok(connection_session.to_string()),
+    }
+}
+
+/// Tells a token-bound client it named a session other than its own.
+fn reject_session_mismatch(tx: &UnboundedSender<String>, actor: &str, topic: &str, requested: &str, bound: &str, id: Option<&str>) {
+    warn!("[session] {} denied access to session {} on topic {}, token is bound to {}", actor, requested, topic, bound);
+    reply_error(tx, ErrorCode::SessionMismatch, json!({"topic": topic, "session_id": requested, "id": id}));
+}
+
+/// Refuses a publish whose fan-out exceeds `max_fan_out`: audits it and returns the error frame
+/// for the publisher.
+fn reject_fan_out(
+    config: &ConnectionConfig,
+    topic: &str,
+    session_id: &str,
+    actor: &str,
+    fan_out: usize,
+    max_fan_out: usize,
+) -> Value {
+    warn!("[publish] {} refused: topic={} in session {} has {} subscribers, limit is {}",
+        actor, topic, session_id, fan_out, max_fan_out);
+    audit::emit(config.audit_sink.as_deref(), audit::TopicAuditKind::FanOutRejected, topic, session_id, actor);
+    error_frame(ErrorCode::FanOutTooLarge, json!({"topic": topic, "fan_out": fan_out, "max_fan_out": max_fan_out}))
+}
+
+/// Acknowledges a subscribe, unsubscribe or publish when the command carried an id or the
+/// connection negotiated `Acks`; the id, if any, is echoed so the client can match the reply.
+fn reply_ack(tx: &UnboundedSender<String>, features: &[Capability], ack: ServerMessage) {
+    let requested = matches!(ack, ServerMessage::Ack { id: Some(_), .. });
+    if !requested && !features.contains(&Capability::Acks) {
+        return;
+    }
+    reply(tx, ack.to_value());
+}
+
+fn ack(op: AckOp, topic: &str, session_id: &str, delivered: Option<usize>, id: Option<&str>) -> ServerMessage {
+    ServerMessage::Ack {
+        op,
+        topic: topic.to_string(),
+        session_id: session_id.to_string(),
+        delivered,
+        id: id.map(str::to_string),
+    }
+}
+
+/// Tells this connection's client why its frame was refused.
+fn reply_error(tx: &UnboundedSender<String>, code: ErrorCode, extra: Value) {
+    reply(tx, error_frame(code, extra));
+}
+
+/// Sends a JSON control frame to this connection's client.
+fn reply(tx: &UnboundedSender<String>, message: Value) {
+    if tx.send(message.to_string()).is_err() {
+        warn!("[reply] Failed to send {} to client", message["type"]);
+    }
+}
+
+/// Waits for the next tick of an optional interval; never completes when the interval is disabled.
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending::<()>().await,
+    }
+}
+
+/// Sleeps until the deadline; never completes when there is none.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending::<()>().await,
+    }
+}
+
+/// Builds the ping timer for a heartbeat interval; the first ping goes out after one full interval.
+fn heartbeat_timer(every: Option<Duration>) -> Option<Interval> {
+    every.map(|every| tokio::time::interval_at(tokio::time::Instant::now() + every, every))
+}
+
+/// Publishes a payload to a topic's subscribers in a session from server code, without a
+/// WebSocket connection. The envelope is the one `publish-json:` produces, with publisher
+/// `"server"` and the payload value as given.
+/// Wildcard subscribers are included. Returns the number of subscribers the message was
+/// handed to; senders whose connection has gone are pruned.
+pub fn publish_to_topic(subscribers: &Subscribers, session_id: &str, topic: &str, payload: Value) -> usize {
+    let envelope = message_envelope(
+        session_bus::SERVER_PUBLISHER_NAME,
+        topic,
+        &payload,
+        &now_rfc3339(),
+        session_id,
+        &new_correlation_id(),
+        None,
+    );
+    deliver(subscribers, topic, session_id, &envelope, true, Some(session_bus::SERVER_PUBLISHER_NAME))
+}
+
+/// Sends a frame to the topic's subscribers in a session, and to matching wildcard subscribers
+/// when `wildcards` is set, then prunes closed senders. Returns the number delivered to.
+/// A frame with a `publisher` skips subscriptions whose filter refuses it.
+/// Keys emptied by the pruning are only logged, as there is no audit sink at hand.
+pub(crate) fn deliver(subscribers: &Subscribers, topic: &str, session_id: &str, frame: &str, wildcards: bool, publisher: Option<&str>) -> usize {
+    let (delivered, closed) = {
+        let subs = subscribers.read(topic);
+        let patterns = wildcards.then(|| subscribers.read_patterns());
+        subscribers::send_to_all(&subscribers::matching_sinks_from(&subs, patterns.as_deref(), topic, session_id, publisher), frame, None)
+    };
+    if closed {
+        for key in subscribers.prune_closed(topic, session_id) {
+            audit::emit(None, audit::TopicAuditKind::Removed, &key, session_id, session_bus::SERVER_PUBLISHER_NAME);
+        }
+    }
+    delivered
+}
+
+/// Points a dropped connection's subscriptions at a bounded buffer and parks them under its
+/// session token. A timer removes them once the grace runs out, unless a reconnect claimed them.
+fn park_subscriptions(subscribers: &Subscribers, config: &Arc<ConnectionConfig>, token: String, parked: ParkedSession, client_name: &str) {
+    let expires_at = parked.expires_at;
+    for (topic, session_id) in &parked.subscriptions {
+        if let Some(sinks) = subscribers.write(topic).get_mut(topic).and_then(|sessions| sessions.get_mut(session_id)) {
+            for sink in sinks.iter_mut().filter(|sink| sink.connection_id == parked.connection_id) {
+                sink.sender = parked.sender.clone();
+                sink.queue = Some(parked.buffer.clone());
             }
         }
     }
+    info!("[park] Holding {} subscriptions of {} for {:?}", parked.subscriptions.len(), client_name,
+        expires_at.saturating_duration_since(Instant::now()));
+    config.parked.park(token.clone(), parked);
 
-    println!("[run_connection] Cleanup complete.");
-    Ok(())
+    // The reaper: whatever is still parked when the grace runs out is discarded
+    let subscribers = subscribers.clone();
+    let config = config.clone();
+    let client_name = client_name.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(tokio::time::Instant::from_std(expires_at)).await;
+        let Some(expired) = config.parked.expire(&token) else {
+            return;
+        };
+        for (topic, session_id) in &expired.subscriptions {
+            remove_subscriber(&mut subscribers.write(topic), topic, session_id, &client_name, &config, expired.connection_id);
+        }
+        if let Some(key_session) = &expired.session_key {
+            config.session_keys.release(key_session);
+        }
+        info!("[park] {}'s parked session expired, discarding {} buffered messages", client_name, expired.buffer.len());
+    }.in_current_span());
+}
+
+/// Publishes a departed connection's will to the topic in its session, marked `"will": true`.
+/// Like a broadcast, it is not sequenced, retained or kept in history.
+fn publish_will(subscribers: &Subscribers, config: &ConnectionConfig, client_name: &str, session_id: &str, topic: &str, payload: &Value) {
+    let mut envelope: Value = serde_json::from_str(&message_envelope(
+        client_name,
+        topic,
+        payload,
+        &now_rfc3339(),
+        session_id,
+        &new_correlation_id(),
+        None,
+    )).unwrap_or_default();
+    envelope["will"] = Value::Bool(true);
+    config.metrics.record_publish(topic);
+    let delivered = deliver(subscribers, topic, session_id, &envelope.to_string(), config.can_subscribe(topic), Some(client_name));
+    info!("[set-will] Published {}'s will to topic={}, session={}, delivered to {}", client_name, topic, session_id, delivered);
+}
+
+/// Adds a client to the session's roster and tells its presence watchers. Returns the session
+/// and the name it joined under, which its later leave must match.
+fn announce_presence(subscribers: &Subscribers, roster: &PresenceRoster, session_id: &str, client_name: &str) -> (String, String) {
+    roster.join(session_id, client_name);
+    deliver(subscribers, presence::PRESENCE_TOPIC, session_id, &presence::joined(session_id, client_name), false, None);
+    (session_id.to_string(), client_name.to_string())
+}
+
+/// Removes a client from the session's roster and tells its presence watchers.
+fn depart_presence(subscribers: &Subscribers, roster: &PresenceRoster, session_id: &str, client_name: &str) {
+    roster.leave(session_id, client_name);
+    deliver(subscribers, presence::PRESENCE_TOPIC, session_id, &presence::left(session_id, client_name), false, None);
+}
+
+/// Builds the JSON envelope delivered to subscribers for a published message. The payload is
+/// carried as the JSON value it was published as.
+/// `seq` is the message's sequence number within its session, when it was sequenced.
+pub(crate) fn message_envelope(
+    publisher: &str,
+    topic: &str,
+    payload: &Value,
+    timestamp: &str,
+    session_id: &str,
+    correlation_id: &str,
+    seq: Option<u64>,
+) -> String {
+    let mut envelope = json!({
+        "publisher_name": publisher,
+        "topic": topic,
+        "payload": payload,
+        "timestamp": timestamp,
+        "session_id": session_id,
+        "correlation_id": correlation_id
+    });
+    if let Some(seq) = seq {
+        envelope["seq"] = json!(seq);
+    }
+    envelope.to_string()
+}
+
+/// Session and sequence number of an outgoing message envelope, if it carries one.
+fn envelope_seq(text: &str) -> Option<(String, u64)> {
+    let parsed: Value = serde_json::from_str(text).ok()?;
+    Some((parsed["session_id"].as_str()?.to_string(), parsed["seq"].as_u64()?))
+}
+
+/// Publisher of an outgoing message envelope, if it names one.
+fn envelope_publisher(text: &str) -> Option<String> {
+    let parsed: Value = serde_json::from_str(text).ok()?;
+    Some(parsed["publisher_name"].as_str()?.to_string())
+}
+
+/// Adds the time a publish has spent in the server to its envelope as `server_latency_ms`.
+fn with_server_latency(envelope: &str, latency: Duration) -> String {
+    let mut envelope: Value = serde_json::from_str(envelope).unwrap_or_default();
+    envelope["server_latency_ms"] = json!(latency.as_secs_f64() * 1000.0);
+    envelope.to_string()
+}
+
+/// Generates a correlation id for a message whose publisher did not supply one.
+pub(crate) fn new_correlation_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
 }
 
-/// Compares two channels to check if they are the same.
-fn same_channel(a: &UnboundedSender<String>, b: &UnboundedSender<String>) -> bool {
-    std::ptr::eq(a, b)
+/// Current time as seconds since the Unix epoch, matching JWT `exp`.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
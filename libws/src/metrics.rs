@@ -0,0 +1,237 @@
+// src/metrics.rs
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::topic_pattern;
+
+/// Upper bounds of the publish latency histogram buckets. A final bucket counts slower publishes.
+pub const PUBLISH_LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_micros(2500),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(250),
+];
+
+/// Server counters shared by every connection on an endpoint.
+///
+/// Per-topic counters are keyed by bucket: a topic matching one of the configured
+/// aggregation patterns is counted under that pattern, so `sensor/123/temp` and
+/// `sensor/456/temp` both land in `sensor/*/temp`. Routing is unaffected.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    topic_patterns: Vec<String>,
+    publishes_by_topic: Mutex<HashMap<String, u64>>,
+    active_connections: AtomicUsize,
+    subscribes: AtomicU64,
+    unsubscribes: AtomicU64,
+    bytes_sent: AtomicU64,
+    delivery_failures: AtomicU64,
+    send_queue_overflows: AtomicU64,
+    clean_closes: AtomicU64,
+    abnormal_closes: AtomicU64,
+    ping_latency_samples: AtomicU64,
+    ping_latency_total_us: AtomicU64,
+    publish_latency: LatencyHistogram,
+}
+
+// Sample counts per bucket of PUBLISH_LATENCY_BUCKETS, plus one for slower samples
+#[derive(Debug, Default)]
+struct LatencyHistogram {
+    counts: [AtomicU64; PUBLISH_LATENCY_BUCKETS.len() + 1],
+    total_us: AtomicU64,
+}
+
+/// Point-in-time copy of the publish latency histogram.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// Upper bound and sample count of each bucket, in order. Counts are per bucket, not
+    /// cumulative; the last bucket has no upper bound.
+    pub buckets: Vec<(Option<Duration>, u64)>,
+    /// Number of samples recorded.
+    pub count: u64,
+    /// Sum of all samples.
+    pub sum: Duration,
+}
+
+impl LatencySnapshot {
+    /// Mean latency, or `None` before any sample was recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.sum / self.count as u32)
+    }
+}
+
+/// A reserved connection slot, released when dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.metrics.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Metrics {
+    /// Creates metrics that group topics by the given patterns.
+    /// Segments are separated by `/` and `*` matches exactly one segment.
+    pub fn with_topic_patterns(patterns: Vec<String>) -> Self {
+        Metrics {
+            topic_patterns: patterns,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the bucket a topic is counted under: the first matching pattern, or the topic itself.
+    pub fn topic_bucket(&self, topic: &str) -> String {
+        self.topic_patterns
+            .iter()
+            .find(|pattern| topic_pattern::matches(pattern, topic))
+            .cloned()
+            .unwrap_or_else(|| topic.to_string())
+    }
+
+    /// Records one publish on the given topic.
+    pub fn record_publish(&self, topic: &str) {
+        let bucket = self.topic_bucket(topic);
+        *self.publishes_by_topic.lock().unwrap().entry(bucket).or_insert(0) += 1;
+    }
+
+    /// Number of publishes recorded for a bucket.
+    pub fn publish_count(&self, bucket: &str) -> u64 {
+        self.publishes_by_topic.lock().unwrap().get(bucket).copied().unwrap_or(0)
+    }
+
+    /// Snapshot of publish counts for every bucket.
+    pub fn publishes_by_topic(&self) -> HashMap<String, u64> {
+        self.publishes_by_topic.lock().unwrap().clone()
+    }
+
+    /// Reserves a connection slot unless `limit` connections are already active.
+    pub fn try_open_connection(self: &Arc<Self>, limit: Option<usize>) -> Option<ConnectionSlot> {
+        self.active_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                limit.is_none_or(|limit| active < limit).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot { metrics: self.clone() })
+    }
+
+    /// Number of connections currently holding a slot.
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Records one accepted subscribe.
+    pub fn record_subscribe(&self) {
+        self.subscribes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of subscribes accepted.
+    pub fn subscribes(&self) -> u64 {
+        self.subscribes.load(Ordering::SeqCst)
+    }
+
+    /// Records one unsubscribe.
+    pub fn record_unsubscribe(&self) {
+        self.unsubscribes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of unsubscribes handled.
+    pub fn unsubscribes(&self) -> u64 {
+        self.unsubscribes.load(Ordering::SeqCst)
+    }
+
+    /// Records a frame written to a client socket.
+    pub fn record_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    /// Total payload bytes of the text and binary frames written to client sockets.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::SeqCst)
+    }
+
+    /// Records messages a fan-out could not hand to subscribers whose connection had gone or
+    /// whose send queue refused them.
+    pub fn record_delivery_failures(&self, failures: usize) {
+        self.delivery_failures.fetch_add(failures as u64, Ordering::SeqCst);
+    }
+
+    /// Number of deliveries refused by closed subscribers or full send queues.
+    pub fn delivery_failures(&self) -> u64 {
+        self.delivery_failures.load(Ordering::SeqCst)
+    }
+
+    /// Records a publish that found its subscriber's send queue full.
+    pub fn record_send_queue_overflow(&self) {
+        self.send_queue_overflows.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of publishes that found a send queue full, whatever its overflow policy did with them.
+    pub fn send_queue_overflows(&self) -> u64 {
+        self.send_queue_overflows.load(Ordering::SeqCst)
+    }
+
+    /// Records how a connection ended: `clean` when the close handshake completed, including
+    /// when both sides sent a close frame at once.
+    pub fn record_close(&self, clean: bool) {
+        let counter = if clean { &self.clean_closes } else { &self.abnormal_closes };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of connections that ended with a completed close handshake.
+    pub fn clean_closes(&self) -> u64 {
+        self.clean_closes.load(Ordering::SeqCst)
+    }
+
+    /// Number of connections that ended on a transport error, a missing close reply or a pong timeout.
+    pub fn abnormal_closes(&self) -> u64 {
+        self.abnormal_closes.load(Ordering::SeqCst)
+    }
+
+    /// Records the round trip of a server ping answered by a matching pong.
+    pub fn record_ping_latency(&self, latency: Duration) {
+        self.ping_latency_samples.fetch_add(1, Ordering::SeqCst);
+        self.ping_latency_total_us.fetch_add(latency.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Number of ping round trips recorded.
+    pub fn ping_latency_samples(&self) -> u64 {
+        self.ping_latency_samples.load(Ordering::SeqCst)
+    }
+
+    /// Records the time a publish spent in the server, from receipt until fan-out completed.
+    pub fn record_publish_latency(&self, latency: Duration) {
+        let bucket = PUBLISH_LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(PUBLISH_LATENCY_BUCKETS.len());
+        self.publish_latency.counts[bucket].fetch_add(1, Ordering::SeqCst);
+        self.publish_latency.total_us.fetch_add(latency.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Snapshot of the publish latency histogram.
+    pub fn publish_latency(&self) -> LatencySnapshot {
+        let counts: Vec<u64> = self.publish_latency.counts.iter().map(|count| count.load(Ordering::SeqCst)).collect();
+        let bounds = PUBLISH_LATENCY_BUCKETS.iter().copied().map(Some).chain(std::iter::once(None));
+        LatencySnapshot {
+            count: counts.iter().sum(),
+            buckets: bounds.zip(counts).collect(),
+            sum: Duration::from_micros(self.publish_latency.total_us.load(Ordering::SeqCst)),
+        }
+    }
+
+    /// Mean ping round trip, or `None` before any pong has been matched.
+    pub fn mean_ping_latency(&self) -> Option<Duration> {
+        let samples = self.ping_latency_samples();
+        (samples > 0).then(|| Duration::from_micros(self.ping_latency_total_us.load(Ordering::SeqCst) / samples))
+    }
+}
@@ -0,0 +1,76 @@
+// src/metrics_api_route.rs
+use axum::{
+    Router,
+    routing::get,
+    extract::State,
+    http::header,
+    response::IntoResponse,
+};
+use std::fmt::Write;
+use std::sync::Arc;
+use crate::metrics::Metrics;
+
+/// Content type of the Prometheus text exposition format.
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Creates the router serving `GET /metrics` in the Prometheus text format.
+/// Pass the same [`Metrics`] the WebSocket endpoint's `ConnectionConfig` records into.
+pub fn metrics_api_router<S>(metrics: Arc<Metrics>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(metrics)
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)], render_prometheus(&metrics))
+}
+
+/// Renders every counter, gauge and histogram in the Prometheus text exposition format.
+pub fn render_prometheus(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    let mut sample = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    };
+    sample("rusty_ws_active_connections", "gauge", "WebSocket connections currently open.",
+        metrics.active_connections() as u64);
+    sample("rusty_ws_subscribes_total", "counter", "Subscribes accepted.", metrics.subscribes());
+    sample("rusty_ws_unsubscribes_total", "counter", "Unsubscribes handled.", metrics.unsubscribes());
+    sample("rusty_ws_bytes_sent_total", "counter", "Payload bytes written to client sockets.", metrics.bytes_sent());
+    sample("rusty_ws_delivery_failures_total", "counter", "Deliveries refused by closed subscribers or full send queues.",
+        metrics.delivery_failures());
+    sample("rusty_ws_send_queue_overflows_total", "counter", "Publishes that found a connection's send queue full.",
+        metrics.send_queue_overflows());
+    sample("rusty_ws_clean_closes_total", "counter", "Connections ended with a completed close handshake.",
+        metrics.clean_closes());
+    sample("rusty_ws_abnormal_closes_total", "counter", "Connections ended without a completed close handshake.",
+        metrics.abnormal_closes());
+
+    let _ = writeln!(out, "# HELP rusty_ws_publishes_total Publishes accepted, by topic bucket.");
+    let _ = writeln!(out, "# TYPE rusty_ws_publishes_total counter");
+    let mut publishes: Vec<(String, u64)> = metrics.publishes_by_topic().into_iter().collect();
+    publishes.sort();
+    for (topic, count) in publishes {
+        let _ = writeln!(out, "rusty_ws_publishes_total{{topic=\"{}\"}} {}", escape_label(&topic), count);
+    }
+
+    let latency = metrics.publish_latency();
+    let _ = writeln!(out, "# HELP rusty_ws_publish_latency_seconds Time a publish spent in the server.");
+    let _ = writeln!(out, "# TYPE rusty_ws_publish_latency_seconds histogram");
+    let mut cumulative = 0;
+    for (bound, count) in &latency.buckets {
+        cumulative += count;
+        let le = bound.map_or_else(|| "+Inf".to_string(), |bound| bound.as_secs_f64().to_string());
+        let _ = writeln!(out, "rusty_ws_publish_latency_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+    }
+    let _ = writeln!(out, "rusty_ws_publish_latency_seconds_sum {}", latency.sum.as_secs_f64());
+    let _ = writeln!(out, "rusty_ws_publish_latency_seconds_count {}", latency.count);
+    out
+}
+
+// Label values escape backslashes, double quotes and newlines
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
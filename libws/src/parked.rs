@@ -0,0 +1,103 @@
+// src/parked.rs
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use crate::metrics::Metrics;
+use crate::send_queue::{OverflowPolicy, SendQueue, SendQueueLimit};
+use crate::subscribers::ConnectionId;
+
+/// Messages held for a parked connection; once full, the oldest are dropped.
+pub const PARKED_BUFFER_LIMIT: usize = 256;
+
+/// Connections that dropped without closing, each keeping its subscriptions and a buffer of what
+/// was published to them until `ConnectionConfig::session_grace` runs out. A reconnecting client
+/// takes them over by presenting the session token it was sent when it first connected.
+#[derive(Debug, Default)]
+pub struct ParkedSessions {
+    parked: Mutex<HashMap<String, ParkedSession>>,
+}
+
+/// A dropped connection's state, held under its session token.
+#[derive(Debug)]
+pub(crate) struct ParkedSession {
+    pub(crate) user_id: Option<String>,
+    /// The dropped connection, whose entries in the subscriber map now feed the buffer.
+    pub(crate) connection_id: ConnectionId,
+    pub(crate) subscriptions: Vec<(String, String)>,
+    /// Where the parked subscriptions send, and the bounded buffer it fills.
+    pub(crate) sender: UnboundedSender<String>,
+    pub(crate) buffer: SendQueue,
+    /// Session whose key the dropped connection held, released unless the session is reattached.
+    pub(crate) session_key: Option<String>,
+    pub(crate) expires_at: Instant,
+}
+
+impl ParkedSession {
+    pub(crate) fn new(
+        user_id: Option<String>,
+        connection_id: ConnectionId,
+        subscriptions: Vec<(String, String)>,
+        session_key: Option<String>,
+        expires_at: Instant,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let limit = SendQueueLimit::new(PARKED_BUFFER_LIMIT, OverflowPolicy::DropOldest);
+        ParkedSession {
+            user_id,
+            connection_id,
+            subscriptions,
+            sender,
+            buffer: SendQueue::new(receiver, Some(limit), metrics),
+            session_key,
+            expires_at,
+        }
+    }
+}
+
+impl ParkedSessions {
+    /// A new session token, sent to a connection when it opens.
+    pub(crate) fn issue_token() -> String {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect()
+    }
+
+    /// Holds a dropped connection's state under its token.
+    pub(crate) fn park(&self, token: String, session: ParkedSession) {
+        self.parked.lock().unwrap().insert(token, session);
+    }
+
+    /// Takes a parked session over, provided it has not expired and the reconnecting client is
+    /// the same user. A token presented by another user stays parked.
+    pub(crate) fn claim(&self, token: &str, user_id: Option<&str>) -> Option<ParkedSession> {
+        let mut parked = self.parked.lock().unwrap();
+        let session = parked.get(token)?;
+        if session.expires_at <= Instant::now() || session.user_id.as_deref() != user_id {
+            return None;
+        }
+        parked.remove(token)
+    }
+
+    /// Removes a session whose grace has run out, if it was not claimed first.
+    pub(crate) fn expire(&self, token: &str) -> Option<ParkedSession> {
+        let mut parked = self.parked.lock().unwrap();
+        if parked.get(token)?.expires_at > Instant::now() {
+            return None;
+        }
+        parked.remove(token)
+    }
+
+    /// Number of sessions waiting to be reattached.
+    pub fn len(&self) -> usize {
+        self.parked.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,66 @@
+// src/presence.rs
+use std::collections::HashMap;
+use std::sync::Mutex;
+use serde_json::json;
+use crate::SessionId;
+
+/// Reserved topic carrying join and leave events for the connections in a session.
+/// Clients cannot publish to it.
+pub const PRESENCE_TOPIC: &str = "__presence__";
+
+/// Whether the topic is the reserved presence topic.
+pub fn is_presence_topic(topic: &str) -> bool {
+    topic == PRESENCE_TOPIC
+}
+
+/// Event announcing that a client entered the session.
+pub(crate) fn joined(session_id: &str, client_name: &str) -> String {
+    json!({"type": "presence", "session": session_id, "joined": client_name}).to_string()
+}
+
+/// Event announcing that a client left the session.
+pub(crate) fn left(session_id: &str, client_name: &str) -> String {
+    json!({"type": "presence", "session": session_id, "left": client_name}).to_string()
+}
+
+/// Who is connected in each session, by client name (the JWT `sub` for authenticated
+/// connections). A name connected more than once stays listed until its last connection leaves.
+#[derive(Debug, Default)]
+pub struct PresenceRoster {
+    sessions: Mutex<HashMap<SessionId, HashMap<String, usize>>>,
+}
+
+impl PresenceRoster {
+    /// Records a connection entering the session under a name.
+    pub fn join(&self, session_id: &str, client_name: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        *sessions.entry(session_id.to_string()).or_default().entry(client_name.to_string()).or_default() += 1;
+    }
+
+    /// Records a connection leaving the session. Sessions nobody is left in are forgotten.
+    pub fn leave(&self, session_id: &str, client_name: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(members) = sessions.get_mut(session_id) else {
+            return;
+        };
+        if let Some(count) = members.get_mut(client_name) {
+            *count -= 1;
+            if *count == 0 {
+                members.remove(client_name);
+            }
+        }
+        if members.is_empty() {
+            sessions.remove(session_id);
+        }
+    }
+
+    /// Names connected in the session, sorted.
+    pub fn members(&self, session_id: &str) -> Vec<String> {
+        let mut members: Vec<String> = self.sessions.lock().unwrap()
+            .get(session_id)
+            .map(|members| members.keys().cloned().collect())
+            .unwrap_or_default();
+        members.sort();
+        members
+    }
+}
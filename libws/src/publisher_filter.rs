@@ -0,0 +1,93 @@
+// src/publisher_filter.rs
+use std::fmt;
+
+/// Subscription option that delivers only messages from the listed publishers, e.g. `from=Client1,Client2`.
+pub const FROM_OPTION: &str = "from=";
+
+/// Subscription option that delivers messages from everyone but the listed publishers, e.g. `not-from=Noisy`.
+pub const NOT_FROM_OPTION: &str = "not-from=";
+
+/// Which publishers' messages a subscription receives, matched against the envelope's
+/// `publisher_name`. Frames with no publisher, such as presence notices and retained-message
+/// tombstones, always pass.
+///
+/// A filter is the last subscription option and runs to the end of the options, so its names
+/// are comma-separated like the options before it:
+///
+/// ```text
+/// subscribe:<topic>|<session>|[<option>,...]from=<name>[,<name>...]
+/// subscribe:<topic>|<session>|[<option>,...]not-from=<name>[,<name>...]
+/// ```
+///
+/// In the JSON command the filter is a single entry of `options`, e.g. `"from=Client1,Client2"`.
+/// Names are matched exactly and may not contain `,` or `|`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PublisherFilter {
+    /// Only these publishers.
+    Allow(Vec<String>),
+    /// Everyone except these publishers.
+    Deny(Vec<String>),
+}
+
+impl PublisherFilter {
+    /// Whether a subscription option is a publisher filter, well-formed or not.
+    pub fn is_filter(option: &str) -> bool {
+        option.starts_with(FROM_OPTION) || option.starts_with(NOT_FROM_OPTION)
+    }
+
+    /// Parses a filter option. `None` when the option is not a filter or names no publisher.
+    pub fn parse(option: &str) -> Option<Self> {
+        let (names, allow) = if let Some(names) = option.strip_prefix(FROM_OPTION) {
+            (names, true)
+        } else {
+            (option.strip_prefix(NOT_FROM_OPTION)?, false)
+        };
+        let names: Vec<String> = names.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        if names.is_empty() {
+            return None;
+        }
+        Some(if allow { PublisherFilter::Allow(names) } else { PublisherFilter::Deny(names) })
+    }
+
+    /// Whether a message from this publisher is delivered.
+    pub fn admits(&self, publisher: &str) -> bool {
+        match self {
+            PublisherFilter::Allow(names) => names.iter().any(|name| name == publisher),
+            PublisherFilter::Deny(names) => !names.iter().any(|name| name == publisher),
+        }
+    }
+}
+
+impl fmt::Display for PublisherFilter {
+    /// The filter as a subscription option.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (prefix, names) = match self {
+            PublisherFilter::Allow(names) => (FROM_OPTION, names),
+            PublisherFilter::Deny(names) => (NOT_FROM_OPTION, names),
+        };
+        write!(f, "{}{}", prefix, names.join(","))
+    }
+}
+
+/// Splits the comma-separated options of a legacy `subscribe:` command. A publisher filter
+/// keeps the rest of the options, commas included, as its one entry.
+pub fn split_options(options: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut rest = options;
+    while !rest.is_empty() {
+        if PublisherFilter::is_filter(rest) {
+            split.push(rest.to_string());
+            break;
+        }
+        let (option, tail) = rest.split_once(',').unwrap_or((rest, ""));
+        if !option.is_empty() {
+            split.push(option.to_string());
+        }
+        rest = tail;
+    }
+    split
+}
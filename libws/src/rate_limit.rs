@@ -0,0 +1,76 @@
+// src/rate_limit.rs
+use std::time::{Duration, Instant};
+
+/// Token-bucket limit on how fast one connection may publish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Publishes per second refilled into the bucket.
+    pub per_second: f64,
+    /// Publishes a connection may send at once after being idle.
+    pub burst: u32,
+    /// Close the connection (code 1008) once it has exceeded the limit this many times.
+    /// `None` keeps refusing publishes without disconnecting.
+    pub disconnect_after: Option<u32>,
+}
+
+impl RateLimit {
+    /// Allows `per_second` publishes per second with bursts of up to `burst`.
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        RateLimit { per_second, burst, disconnect_after: None }
+    }
+
+    /// Closes connections after this many refused publishes.
+    pub fn disconnect_after(mut self, violations: u32) -> Self {
+        self.disconnect_after = Some(violations);
+        self
+    }
+}
+
+/// Outcome of offering one publish to a [`PublishLimiter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    /// Refused; a token is available again after this long.
+    Throttled(Duration),
+    /// Refused, and the connection has used up its allowed violations.
+    Disconnect,
+}
+
+/// Per-connection limiter state, owned by the receive task.
+#[derive(Debug)]
+pub struct PublishLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    violations: u32,
+}
+
+impl PublishLimiter {
+    /// Starts with a full bucket.
+    pub fn new(limit: RateLimit) -> Self {
+        PublishLimiter { limit, tokens: f64::from(limit.burst), refilled_at: Instant::now(), violations: 0 }
+    }
+
+    /// Takes a token for one publish if the bucket has one.
+    pub fn admit(&mut self) -> Admission {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * self.limit.per_second;
+        self.tokens = (self.tokens + refill).min(f64::from(self.limit.burst));
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Admission::Allowed;
+        }
+        self.violations += 1;
+        if self.limit.disconnect_after.is_some_and(|limit| self.violations >= limit) {
+            return Admission::Disconnect;
+        }
+        let wait = if self.limit.per_second > 0.0 { (1.0 - self.tokens) / self.limit.per_second } else { f64::MAX };
+        Admission::Throttled(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+    }
+
+    /// Number of publishes refused so far.
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+}
@@ -0,0 +1,23 @@
+// src/request_reply.rs
+use serde_json::Value;
+
+/// Prefix of the topics replies to `WsClient::request` arrive on, one per request.
+pub const REPLY_TOPIC_PREFIX: &str = "_reply/";
+
+/// Topic the reply to the request with this correlation id is expected on. A responder
+/// publishes its reply there, keeping the request's correlation id.
+pub fn reply_topic(correlation_id: &str) -> String {
+    format!("{}{}", REPLY_TOPIC_PREFIX, correlation_id)
+}
+
+/// Whether a topic carries replies to requests.
+pub fn is_reply_topic(topic: &str) -> bool {
+    topic.starts_with(REPLY_TOPIC_PREFIX)
+}
+
+/// Adds the publisher's `reply_to` topic to a built envelope.
+pub(crate) fn with_reply_to(envelope: &str, reply_to: &str) -> String {
+    let mut envelope: Value = serde_json::from_str(envelope).unwrap_or_default();
+    envelope["reply_to"] = Value::from(reply_to);
+    envelope.to_string()
+}
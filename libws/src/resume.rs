@@ -0,0 +1,38 @@
+// src/resume.rs
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How long a resume token can be presented after it was issued.
+pub const RESUME_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Checkpoint carried by a resume token: what the connection was subscribed to and the
+/// last sequence number it was sent in each session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeClaims {
+    /// User the token was issued to; `None` for an anonymous connection
+    #[serde(default)]
+    pub sub: Option<String>,
+    /// Session the issuing connection's access token was bound to, if any
+    #[serde(default)]
+    pub sid: Option<String>,
+    /// (topic, sessionId) subscriptions to restore
+    pub subs: Vec<(String, String)>,
+    /// Last delivered sequence number per session
+    pub seqs: HashMap<String, u64>,
+    /// Expiration time
+    pub exp: u64,
+}
+
+/// Signs a checkpoint so it can be handed to the client.
+pub(crate) fn issue(claims: &ResumeClaims, secret: &[u8]) -> jsonwebtoken::errors::Result<String> {
+    encode(&Header::default(), claims, &EncodingKey::from_secret(secret))
+}
+
+/// Verifies a resume token and returns its checkpoint.
+pub(crate) fn redeem(token: &str, secret: &[u8]) -> jsonwebtoken::errors::Result<ResumeClaims> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_aud = false;
+    Ok(decode::<ResumeClaims>(token, &DecodingKey::from_secret(secret), &validation)?.claims)
+}
@@ -0,0 +1,60 @@
+// src/retained.rs
+use std::collections::HashMap;
+use std::sync::Mutex;
+use serde_json::{json, Value};
+use crate::{topic_pattern, SessionId, Topic};
+
+/// Frame `type` sent to subscribers when a topic's retained message is cleared.
+pub const TOMBSTONE_TYPE: &str = "tombstone";
+
+/// The last message published with `"retain": true` on each topic, per session.
+///
+/// A new subscriber is sent the retained message of every topic its subscription covers, so
+/// it does not have to wait for the next publish to learn the current value. Publishing an
+/// empty retained payload clears the value and sends current subscribers a tombstone.
+#[derive(Debug, Default)]
+pub struct RetainedMessages {
+    messages: Mutex<HashMap<(SessionId, Topic), String>>,
+}
+
+impl RetainedMessages {
+    /// Keeps the envelope as the topic's retained message, marked `"retained": true`.
+    pub fn set(&self, session_id: &str, topic: &str, envelope: &str) {
+        let mut envelope: Value = serde_json::from_str(envelope).unwrap_or_default();
+        envelope["retained"] = Value::Bool(true);
+        self.messages.lock().unwrap().insert((session_id.to_string(), topic.to_string()), envelope.to_string());
+    }
+
+    /// Drops the topic's retained message. Returns whether there was one.
+    pub fn clear(&self, session_id: &str, topic: &str) -> bool {
+        self.messages.lock().unwrap().remove(&(session_id.to_string(), topic.to_string())).is_some()
+    }
+
+    /// The topic's retained message, if any.
+    pub fn get(&self, session_id: &str, topic: &str) -> Option<String> {
+        self.messages.lock().unwrap().get(&(session_id.to_string(), topic.to_string())).cloned()
+    }
+
+    /// Retained messages in the session on topics covered by a subscription (an exact topic or a
+    /// wildcard pattern), sorted by topic.
+    pub fn matching(&self, session_id: &str, subscription: &str) -> Vec<(Topic, String)> {
+        let mut matching: Vec<(Topic, String)> = self.messages.lock().unwrap()
+            .iter()
+            .filter(|((session, topic), _)| session == session_id && topic_pattern::covers(subscription, topic))
+            .map(|((_, topic), envelope)| (topic.clone(), envelope.clone()))
+            .collect();
+        matching.sort();
+        matching
+    }
+}
+
+/// Frame telling subscribers that the topic's retained message is gone and cached copies
+/// should be purged.
+pub fn tombstone(topic: &str, session_id: &str) -> String {
+    json!({"type": TOMBSTONE_TYPE, "topic": topic, "session_id": session_id}).to_string()
+}
+
+/// Whether a delivered frame is a tombstone rather than a message envelope.
+pub(crate) fn is_tombstone(frame: &str) -> bool {
+    serde_json::from_str::<Value>(frame).is_ok_and(|frame| frame["type"] == TOMBSTONE_TYPE)
+}
@@ -0,0 +1,66 @@
+// src/revocation.rs
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One entry of the blocklist: a token id and the time its token expires
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub exp: u64,
+}
+
+/// Blocklist of token ids (`jti`) revoked before they expire, shared by every clone.
+///
+/// Each id is kept only until its token would have expired, since an expired token fails
+/// validation anyway; [`purge_expired`](Self::purge_expired) drops the rest.
+#[derive(Clone, Debug, Default)]
+pub struct TokenRevocation {
+    revoked: Arc<RwLock<HashMap<String, u64>>>,
+}
+
+impl TokenRevocation {
+    /// Revokes the token with this id, which expires at `exp`. Returns false if it already was.
+    pub fn revoke(&self, jti: &str, exp: u64) -> bool {
+        self.purge_expired(unix_now());
+        self.revoked.write().unwrap().insert(jti.to_string(), exp).is_none()
+    }
+
+    /// Whether the token with this id has been revoked.
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.revoked.read().unwrap().contains_key(jti)
+    }
+
+    /// Drops the ids of tokens that expired before `now` (seconds since the epoch), returning how many.
+    pub fn purge_expired(&self, now: u64) -> usize {
+        let mut revoked = self.revoked.write().unwrap();
+        let before = revoked.len();
+        revoked.retain(|_, exp| *exp >= now);
+        before - revoked.len()
+    }
+
+    /// Every revoked id that has not expired yet, sorted by id.
+    pub fn entries(&self) -> Vec<RevokedToken> {
+        self.purge_expired(unix_now());
+        let mut entries: Vec<RevokedToken> = self.revoked.read().unwrap()
+            .iter()
+            .map(|(jti, exp)| RevokedToken { jti: jti.clone(), exp: *exp })
+            .collect();
+        entries.sort_by(|a, b| a.jti.cmp(&b.jti));
+        entries
+    }
+
+    /// Number of ids on the blocklist, including expired ones not yet purged.
+    pub fn len(&self) -> usize {
+        self.revoked.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
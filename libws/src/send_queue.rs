@@ -0,0 +1,126 @@
+// src/send_queue.rs
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
+use crate::metrics::Metrics;
+
+/// What happens to a publish delivered to a connection whose send queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room.
+    DropOldest,
+    /// Discard the new message.
+    DropNewest,
+    /// Close the connection (code 1008) without writing what is still queued.
+    Disconnect,
+}
+
+/// Bound on the number of messages waiting to be written to one connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendQueueLimit {
+    /// Messages a connection may have queued before the policy applies.
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl SendQueueLimit {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        SendQueueLimit { capacity, policy }
+    }
+}
+
+impl Default for SendQueueLimit {
+    /// 1024 messages, dropping the oldest.
+    fn default() -> Self {
+        SendQueueLimit::new(1024, OverflowPolicy::DropOldest)
+    }
+}
+
+/// Receiving end of one connection's send queue, shared by the send task, which drains it, and
+/// the connection's subscriptions, which check it before queueing a publish.
+///
+/// The check only reads the queue length, so a publish to a slow consumer never waits for it
+/// while holding the subscriber lock.
+#[derive(Clone, Debug)]
+pub struct SendQueue {
+    receiver: Arc<Mutex<UnboundedReceiver<String>>>,
+    limit: Option<SendQueueLimit>,
+    metrics: Arc<Metrics>,
+    // Set once a full queue disconnects the connection under `OverflowPolicy::Disconnect`
+    slow_consumer: Arc<watch::Sender<bool>>,
+}
+
+impl SendQueue {
+    /// Wraps the receiver of a connection's send channel. `None` leaves the queue unbounded.
+    pub fn new(receiver: UnboundedReceiver<String>, limit: Option<SendQueueLimit>, metrics: Arc<Metrics>) -> Self {
+        SendQueue {
+            receiver: Arc::new(Mutex::new(receiver)),
+            limit,
+            metrics,
+            slow_consumer: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// A second queue for the same connection, such as the one its binary frames wait in. It
+    /// has the same limit, and an overflow that disconnects either one disconnects both.
+    pub(crate) fn alongside(&self, receiver: UnboundedReceiver<String>) -> Self {
+        SendQueue {
+            receiver: Arc::new(Mutex::new(receiver)),
+            limit: self.limit,
+            metrics: self.metrics.clone(),
+            slow_consumer: self.slow_consumer.clone(),
+        }
+    }
+
+    /// Number of messages waiting to be written.
+    pub fn len(&self) -> usize {
+        self.receiver.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<String>> {
+        self.receiver.lock().unwrap().poll_recv(cx)
+    }
+
+    /// Whether one more publish may be queued, applying the overflow policy when the queue is full.
+    pub(crate) fn admit(&self) -> bool {
+        if *self.slow_consumer.borrow() {
+            return false;
+        }
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        let mut receiver = self.receiver.lock().unwrap();
+        if receiver.len() < limit.capacity {
+            return true;
+        }
+        self.metrics.record_send_queue_overflow();
+        match limit.policy {
+            OverflowPolicy::DropOldest => {
+                let _ = receiver.try_recv();
+                true
+            }
+            OverflowPolicy::DropNewest => false,
+            OverflowPolicy::Disconnect => {
+                self.slow_consumer.send_replace(true);
+                false
+            }
+        }
+    }
+
+    /// Takes every message waiting in the queue, oldest first.
+    pub(crate) fn drain(&self) -> Vec<String> {
+        let mut receiver = self.receiver.lock().unwrap();
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    /// Resolves once the connection has been marked a slow consumer to disconnect.
+    pub(crate) async fn overflowed(&self) {
+        let mut slow_consumer = self.slow_consumer.subscribe();
+        let _ = slow_consumer.wait_for(|slow| *slow).await;
+    }
+}
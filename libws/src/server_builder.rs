@@ -0,0 +1,151 @@
+// src/server_builder.rs
+use axum::{
+    extract::{ws::WebSocketUpgrade, ConnectInfo, Query, State},
+    http::HeaderMap,
+    routing::get,
+    Router,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tower_http::cors::CorsLayer;
+use crate::enc_api_route::{enc_api_router, EncApiState};
+use crate::jwt_api_route::{jwt_api_router, JwtState};
+use crate::{handle_socket_with_config, ConnectionConfig, Subscribers, WebSocketParams};
+
+/// Path the WebSocket endpoint is served at unless [`WsServerBuilder::with_path`] sets another.
+pub const DEFAULT_WS_PATH: &str = "/ws";
+
+/// Assembles the router of a pub/sub server: the WebSocket endpoint, and optionally the JWT and
+/// encryption APIs and a CORS policy.
+///
+/// ```no_run
+/// # async fn serve() -> std::io::Result<()> {
+/// use libws::WsServerBuilder;
+/// use libws::jwt_api_route::create_default_jwt_state;
+///
+/// let app = WsServerBuilder::new()
+///     .with_jwt(create_default_jwt_state())
+///     .build();
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:8081").await?;
+/// axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await
+/// # }
+/// ```
+///
+/// The router reads each client's address, so it must be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`. Servers that need more control can
+/// route [`handle_socket_with_config`](crate::handle_socket_with_config) themselves.
+pub struct WsServerBuilder {
+    path: String,
+    subscribers: Subscribers,
+    jwt: Option<JwtState>,
+    encryption: Option<EncApiState>,
+    cors: Option<CorsLayer>,
+    config: ConnectionConfig,
+    routes: Router<Subscribers>,
+}
+
+impl Default for WsServerBuilder {
+    fn default() -> Self {
+        WsServerBuilder {
+            path: DEFAULT_WS_PATH.to_string(),
+            subscribers: Subscribers::default(),
+            jwt: None,
+            encryption: None,
+            cors: None,
+            config: ConnectionConfig::default(),
+            routes: Router::new(),
+        }
+    }
+}
+
+impl WsServerBuilder {
+    /// A server at `/ws` with a fresh subscriber map, the default connection configuration,
+    /// no JWT or encryption API and no CORS policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves the WebSocket endpoint at another path.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Shares a subscriber map the caller keeps a handle to, e.g. for `publish_to_topic` or the
+    /// admin API.
+    pub fn with_subscribers(mut self, subscribers: Subscribers) -> Self {
+        self.subscribers = subscribers;
+        self
+    }
+
+    /// Serves the JWT API and validates tokens on the endpoint with its key, unless the
+    /// connection configuration names another issuer.
+    pub fn with_jwt(mut self, jwt: JwtState) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    /// Serves the encryption API and uses its keypair for key exchanges, unless the connection
+    /// configuration names another.
+    pub fn with_encryption(mut self, encryption: EncApiState) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Applies a CORS policy to every route.
+    pub fn with_cors(mut self, cors: CorsLayer) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Configuration handed to every connection.
+    pub fn with_config(mut self, config: ConnectionConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds routes served alongside the endpoint, such as the admin or metrics API, under the
+    /// same CORS policy.
+    pub fn with_routes(mut self, routes: Router<Subscribers>) -> Self {
+        self.routes = self.routes.merge(routes);
+        self
+    }
+
+    /// Builds the router.
+    pub fn build(self) -> Router {
+        let mut config = self.config;
+        if config.jwt.is_none() {
+            config.jwt = self.jwt.clone();
+        }
+        if config.encryption.is_none() {
+            config.encryption = self.encryption.clone();
+        }
+        let config = Arc::new(config);
+
+        let mut router = Router::new()
+            .route(
+                &self.path,
+                get(move |ws: WebSocketUpgrade,
+                          ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                          State(subscribers): State<Subscribers>,
+                          query_params: Option<Query<WebSocketParams>>,
+                          headers: HeaderMap| {
+                    let config = config.clone();
+                    async move {
+                        handle_socket_with_config(ws, ConnectInfo(addr), query_params, headers, subscribers, config).await
+                    }
+                }),
+            )
+            .merge(self.routes);
+        if let Some(encryption) = self.encryption {
+            router = router.merge(enc_api_router::<Subscribers>(encryption));
+        }
+        if let Some(jwt) = self.jwt {
+            router = router.merge(jwt_api_router::<Subscribers>(jwt));
+        }
+        if let Some(cors) = self.cors {
+            router = router.layer(cors);
+        }
+        router.with_state(self.subscribers)
+    }
+}
@@ -0,0 +1,45 @@
+// src/session_bus.rs
+use std::collections::BTreeSet;
+use serde_json::Value;
+use crate::{publish_to_topic, Subscribers};
+use tracing::debug;
+
+/// Publisher name stamped on messages injected by server code.
+pub const SERVER_PUBLISHER_NAME: &str = "server";
+
+/// Server-side handle for publishing into the pub/sub system without a WebSocket connection,
+/// for example from a background job. Messages use the same envelope as client publishes.
+#[derive(Clone)]
+pub struct SessionBus {
+    subscribers: Subscribers,
+}
+
+impl SessionBus {
+    /// Wraps the subscriber map shared with the WebSocket handlers.
+    pub fn new(subscribers: Subscribers) -> Self {
+        SessionBus { subscribers }
+    }
+
+    /// Publishes a payload to the topic's subscribers in a session.
+    /// Returns the number of subscribers the message was handed to. See [`publish_to_topic`].
+    pub fn publish(&self, topic: &str, session_id: &str, payload: &str) -> usize {
+        let delivered = publish_to_topic(&self.subscribers, session_id, topic, Value::from(payload));
+        debug!("[session_bus] Published to topic '{}' in session '{}', delivered to {}", topic, session_id, delivered);
+        delivered
+    }
+
+    /// Number of subscriptions to the topic in a session.
+    pub fn subscriber_count(&self, topic: &str, session_id: &str) -> usize {
+        self.subscribers.subscriber_count(topic, session_id)
+    }
+
+    /// Topics that currently have at least one subscriber, in sorted order.
+    pub fn topics(&self) -> Vec<String> {
+        self.subscribers
+            .topics()
+            .into_iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+}
@@ -0,0 +1,187 @@
+// src/session_crypto.rs
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rand::{rngs::OsRng, RngCore};
+use serde_json::Value;
+use zeroize::Zeroizing;
+use crate::enc_utils::{decrypt, encrypt, EncryptionError, KeyPair, KeyType};
+
+/// `encoding` value marking a payload sent as the base64 of its JSON text encrypted with
+/// AES-256-GCM under the session key.
+pub const AES_GCM_ENCODING: &str = "aes-256-gcm";
+
+// AES-GCM nonce and tag lengths; a ciphertext is at least this long
+const MIN_CIPHERTEXT_BYTES: usize = 12 + 16;
+
+/// The content key of each session, held by the server and generated on first use.
+///
+/// A client obtains its session's key with a key exchange: it sends a public key, and the
+/// server answers with the session key encrypted under the ECDH secret between that key and
+/// the server keypair served at `/enc/public-key`. The ECDH secret is derived per connection
+/// and dropped straight after; only the session keys are kept, and they are scrubbed from
+/// memory when removed.
+///
+/// Each key counts the connections holding it, parked ones included, and is removed when the
+/// last of them releases it.
+#[derive(Default)]
+pub struct SessionKeys {
+    keys: Mutex<HashMap<String, HeldKey>>,
+}
+
+// A session key and the number of connections holding it
+struct HeldKey {
+    key: Zeroizing<Vec<u8>>,
+    holders: usize,
+}
+
+impl SessionKeys {
+    /// The session's key, generated when the session has none. Does not count as holding it.
+    pub fn key(&self, session_id: &str) -> Result<Zeroizing<Vec<u8>>, EncryptionError> {
+        let mut keys = self.keys.lock().unwrap();
+        Ok(Self::entry(&mut keys, session_id)?.key.clone())
+    }
+
+    /// The session's key, generated when the session has none, counting the caller as one more
+    /// holder until it calls [`SessionKeys::release`].
+    pub fn hold(&self, session_id: &str) -> Result<Zeroizing<Vec<u8>>, EncryptionError> {
+        let mut keys = self.keys.lock().unwrap();
+        let held = Self::entry(&mut keys, session_id)?;
+        held.holders += 1;
+        Ok(held.key.clone())
+    }
+
+    /// Gives up one hold on the session's key, removing the key when nobody holds it any more.
+    pub fn release(&self, session_id: &str) {
+        let released = {
+            let mut keys = self.keys.lock().unwrap();
+            let Some(held) = keys.get_mut(session_id) else {
+                return;
+            };
+            held.holders = held.holders.saturating_sub(1);
+            held.holders == 0
+        };
+        if released {
+            self.remove(session_id);
+        }
+    }
+
+    fn entry<'a>(keys: &'a mut HashMap<String, HeldKey>, session_id: &str) -> Result<&'a mut HeldKey, EncryptionError> {
+        match keys.entry(session_id.to_string()) {
+            Entry::Occupied(held) => Ok(held.into_mut()),
+            Entry::Vacant(vacant) => {
+                let mut key = Zeroizing::new(vec![0u8; 32]);
+                OsRng.try_fill_bytes(&mut key).map_err(EncryptionError::Rng)?;
+                Ok(vacant.insert(HeldKey { key, holders: 0 }))
+            }
+        }
+    }
+
+    /// Number of sessions with a key.
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets a session's key, so the next key exchange in the session issues a new one.
+    /// Payloads encrypted under the old key can no longer be read by clients that join later.
+    pub fn remove(&self, session_id: &str) {
+        self.keys.lock().unwrap().remove(session_id);
+    }
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKeys").field("sessions", &self.keys.lock().unwrap().len()).finish()
+    }
+}
+
+// The ECDH secret between our keypair and the peer's public key, on whichever curve ours uses
+fn shared_secret(keypair: &KeyPair, other_public_key: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    match keypair.key_type {
+        KeyType::P256 => keypair.compute_shared_secret_p256(other_public_key),
+        KeyType::X25519 => keypair.compute_shared_secret(other_public_key),
+    }
+}
+
+/// Generates a client keypair on the curve of the server's public key: X25519 keys are 32 bytes,
+/// P-256 keys are SEC1 points.
+pub fn client_keypair_for(server_public_key: &str) -> Result<KeyPair, Box<dyn Error>> {
+    let key_type = match STANDARD.decode(server_public_key)?.len() {
+        32 => KeyType::X25519,
+        33 | 65 => KeyType::P256,
+        len => return Err(format!("unrecognised server public key of {} bytes", len).into()),
+    };
+    Ok(match key_type {
+        KeyType::X25519 => KeyPair::generate()?,
+        KeyType::P256 => KeyPair::generate_p256()?,
+    })
+}
+
+/// Server side of the key exchange: the session key encrypted for the client's public key, base64-encoded.
+pub fn wrap_session_key(server: &KeyPair, client_public_key: &str, session_key: &[u8]) -> Result<String, Box<dyn Error>> {
+    let secret = shared_secret(server, client_public_key)?;
+    Ok(STANDARD.encode(encrypt(session_key, &secret, &[])?))
+}
+
+/// Client side of the key exchange: recovers the session key with the client's keypair and the server's public key.
+pub fn unwrap_session_key(client: &KeyPair, server_public_key: &str, wrapped: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let secret = shared_secret(client, server_public_key)?;
+    Ok(Zeroizing::new(decrypt(&STANDARD.decode(wrapped)?, &secret, &[])?))
+}
+
+/// Associated data binding a payload's ciphertext to the topic and session it was published in:
+/// the JSON text of `[topic, session_id]`. A ciphertext replayed on another topic or in another
+/// session fails to decrypt.
+pub fn payload_aad(topic: &str, session_id: &str) -> Vec<u8> {
+    serde_json::to_vec(&[topic, session_id]).unwrap_or_default()
+}
+
+/// Encrypts a payload's JSON text under the session key, bound to its topic and session, and
+/// base64-encodes the result so it fits in a text frame.
+pub fn encrypt_payload(payload: &Value, session_key: &[u8], topic: &str, session_id: &str) -> Result<Value, Box<dyn Error>> {
+    let ciphertext = encrypt(payload.to_string().as_bytes(), session_key, &payload_aad(topic, session_id))?;
+    Ok(Value::from(STANDARD.encode(ciphertext)))
+}
+
+/// The value an encrypted payload carries, or `None` when it does not decrypt under the key or
+/// was encrypted for another topic or session.
+pub fn decrypt_payload(payload: &Value, session_key: &[u8], topic: &str, session_id: &str) -> Option<Value> {
+    let ciphertext = STANDARD.decode(payload.as_str()?).ok()?;
+    let plaintext = Zeroizing::new(decrypt(&ciphertext, session_key, &payload_aad(topic, session_id)).ok()?);
+    serde_json::from_slice(&plaintext).ok()
+}
+
+/// Whether a payload is shaped like an encrypted one. The server cannot check more than this
+/// without the key, and forwards the ciphertext untouched.
+pub fn is_ciphertext(payload: &Value) -> bool {
+    payload.as_str()
+        .and_then(|payload| STANDARD.decode(payload).ok())
+        .is_some_and(|ciphertext| ciphertext.len() > MIN_CIPHERTEXT_BYTES)
+}
+
+/// Replaces the payload of an envelope marked `"encoding":"aes-256-gcm"` with the value it
+/// carries and drops the marker. Other envelopes are left alone. Returns false when there is no
+/// key or the payload does not decrypt under it for the envelope's topic and session.
+pub fn decrypt_envelope(envelope: &mut Value, session_key: Option<&[u8]>) -> bool {
+    if envelope["encoding"] != AES_GCM_ENCODING {
+        return true;
+    }
+    let (Some(topic), Some(session_id)) = (envelope["topic"].as_str(), envelope["session_id"].as_str()) else {
+        return false;
+    };
+    let Some(payload) = session_key.and_then(|key| decrypt_payload(&envelope["payload"], key, topic, session_id)) else {
+        return false;
+    };
+    envelope["payload"] = payload;
+    if let Some(fields) = envelope.as_object_mut() {
+        fields.remove("encoding");
+    }
+    true
+}
@@ -0,0 +1,92 @@
+// src/subprotocol.rs
+use serde_json::Value;
+
+/// `Sec-WebSocket-Protocol` name of the JSON text format.
+pub const JSON_V1: &str = "json.v1";
+
+/// `Sec-WebSocket-Protocol` name of the MessagePack format.
+pub const MSGPACK_V1: &str = "msgpack.v1";
+
+/// Message format of a connection, chosen with the `Sec-WebSocket-Protocol` header during the
+/// upgrade. A client that asks for no subprotocol, or only for ones the endpoint does not offer,
+/// gets the default.
+///
+/// The format only changes the codec: commands, envelopes and control frames carry the same
+/// JSON values either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Commands and messages as JSON text frames (the default).
+    #[default]
+    Json,
+    /// Commands and messages as MessagePack binary frames. Frames of the binary protocol in
+    /// [`binary_proto`](crate::binary_proto) still work alongside them.
+    MessagePack,
+}
+
+/// A frame encoded for the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EncodedFrame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl EncodedFrame {
+    /// Length of the frame's payload in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            EncodedFrame::Text(text) => text.len(),
+            EncodedFrame::Binary(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl WireFormat {
+    /// Subprotocol name the format is negotiated under.
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON_V1,
+            WireFormat::MessagePack => MSGPACK_V1,
+        }
+    }
+
+    /// The format negotiated under a subprotocol name, if this build supports it.
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol.trim() {
+            JSON_V1 => Some(WireFormat::Json),
+            MSGPACK_V1 => Some(WireFormat::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Encodes the JSON text of a command, envelope or control frame. Under MessagePack the value
+    /// becomes a binary frame; text that is not JSON, such as `pong`, stays a text frame.
+    pub fn encode(&self, text: String) -> EncodedFrame {
+        match self {
+            WireFormat::Json => EncodedFrame::Text(text),
+            WireFormat::MessagePack => match serde_json::from_str::<Value>(&text) {
+                Ok(value) => match rmp_serde::to_vec_named(&value) {
+                    Ok(bytes) => EncodedFrame::Binary(bytes),
+                    Err(_) => EncodedFrame::Text(text),
+                },
+                Err(_) => EncodedFrame::Text(text),
+            },
+        }
+    }
+
+    /// The JSON text of a binary frame holding a MessagePack map, as sent under MessagePack.
+    /// `None` for any other binary frame, such as one of the binary protocol, whose first byte
+    /// is an opcode rather than a map marker.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        match self {
+            WireFormat::Json => None,
+            WireFormat::MessagePack => match rmp_serde::from_slice::<Value>(bytes) {
+                Ok(value @ Value::Object(_)) => Some(value.to_string()),
+                _ => None,
+            },
+        }
+    }
+}
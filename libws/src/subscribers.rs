@@ -0,0 +1,473 @@
+// src/subscribers.rs
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use serde::{Serialize, Serializer};
+use tokio::sync::mpsc::UnboundedSender;
+use crate::publisher_filter::PublisherFilter;
+use crate::send_queue::SendQueue;
+use crate::{direct, topic_pattern, SessionId, Topic};
+use tracing::debug;
+
+/// Subscriptions held by one shard: topic, then session, then each subscribed connection.
+pub type SubscriberMap = HashMap<Topic, HashMap<SessionId, Vec<Subscriber>>>;
+
+/// Subscribe option placing the subscription in a group, as in `group=workers`.
+pub const GROUP_OPTION: &str = "group=";
+
+/// Group named by a `group=` option. `None` when the option is not one or names no group.
+pub fn parse_group(option: &str) -> Option<String> {
+    option.strip_prefix(GROUP_OPTION).filter(|group| !group.is_empty()).map(str::to_string)
+}
+
+/// Identifies a connection: a random (version 4) UUID, so ids from before a restart are not
+/// handed out again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u128);
+
+impl ConnectionId {
+    /// A new random id.
+    pub fn generate() -> Self {
+        let bits: u128 = rand::random();
+        // Version 4 in bits 76-79, the RFC 4122 variant in bits 62-63
+        ConnectionId(bits & !(0xf << 76) & !(0x3 << 62) | (0x4 << 76) | (0x2 << 62))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(f, "{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+}
+
+impl Serialize for ConnectionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// One connection's subscription to a topic in a session.
+#[derive(Clone, Debug)]
+pub struct Subscriber {
+    /// Where messages are sent: the connection's send queue, or the delta or binary forwarder
+    /// in front of it.
+    pub sender: UnboundedSender<String>,
+    /// Connection that owns the subscription.
+    pub connection_id: ConnectionId,
+    /// Remote address of that connection; `None` for transports without one, such as the
+    /// in-process transport.
+    pub peer: Option<SocketAddr>,
+    /// Options given when subscribing, such as `unordered` or `delta`; empty for the defaults.
+    pub options: Vec<String>,
+    /// Group the subscription was made in, if any.
+    pub group: Option<String>,
+    /// Bounded queue the sender leads to, checked before each message is handed over.
+    pub queue: Option<SendQueue>,
+    /// Publishers whose messages the subscription receives; `None` receives everyone's.
+    pub filter: Option<Arc<PublisherFilter>>,
+    // Shared by clones so a delivery through any copy is recorded
+    last_seq: Arc<AtomicU64>,
+}
+
+impl Subscriber {
+    /// A subscription with default options and no group.
+    pub fn new(sender: UnboundedSender<String>, connection_id: ConnectionId) -> Self {
+        Subscriber {
+            sender,
+            connection_id,
+            peer: None,
+            options: Vec::new(),
+            group: None,
+            queue: None,
+            filter: None,
+            last_seq: Arc::default(),
+        }
+    }
+
+    /// Records the remote address of the owning connection, if its transport has one.
+    pub fn with_peer(mut self, peer: Option<SocketAddr>) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    /// Records the options the subscription was made with.
+    pub fn with_options(mut self, options: Vec<String>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Places the subscription in a group.
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+
+    /// Applies the connection's send queue limit to messages handed to this subscription.
+    pub fn with_send_queue(mut self, queue: SendQueue) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Delivers only messages whose publisher the filter admits.
+    pub fn with_filter(mut self, filter: PublisherFilter) -> Self {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Whether a message from this publisher is delivered to the subscription.
+    pub fn accepts_publisher(&self, publisher: &str) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.admits(publisher))
+    }
+
+    /// Sequence number of the last sequenced message handed to this subscriber; 0 before any.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Relaxed)
+    }
+
+    /// Hands a frame to the subscriber, recording its sequence number. False once the connection
+    /// has gone, or when its full send queue refuses the frame.
+    pub(crate) fn send(&self, frame: &str, seq: Option<u64>) -> bool {
+        if self.queue.as_ref().is_some_and(|queue| !queue.admit()) {
+            return false;
+        }
+        if self.sender.send(frame.to_string()).is_err() {
+            return false;
+        }
+        if let Some(seq) = seq {
+            self.last_seq.fetch_max(seq, Ordering::Relaxed);
+        }
+        true
+    }
+}
+
+/// Number of shards used by [`SubscriberRegistry::default`].
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Subscription counts for one topic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicCounts {
+    pub topic: Topic,
+    /// Sessions with at least one subscriber on the topic.
+    pub sessions: usize,
+    /// Subscriptions across all of those sessions.
+    pub subscribers: usize,
+}
+
+/// One live subscription to a topic, as reported to operators.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscriberInfo {
+    pub session_id: SessionId,
+    pub connection_id: ConnectionId,
+    pub peer: Option<SocketAddr>,
+    pub options: Vec<String>,
+}
+
+/// Subscriber map shared by every connection on an endpoint.
+///
+/// Topics are spread across shards by hash, each behind its own `RwLock`. Publishing takes a
+/// read lock on one shard, so publishes run concurrently; subscribe and unsubscribe take a
+/// write lock on the topic's shard only, so they do not block traffic on other topics.
+///
+/// Wildcard subscriptions (see [`topic_pattern`]) are keyed by their pattern in a separate map,
+/// which every publish consults after the exact-topic lookup.
+#[derive(Debug)]
+pub struct SubscriberRegistry {
+    shards: Vec<RwLock<SubscriberMap>>,
+    patterns: RwLock<SubscriberMap>,
+    hasher: RandomState,
+}
+
+impl Default for SubscriberRegistry {
+    fn default() -> Self {
+        SubscriberRegistry::with_shards(DEFAULT_SHARDS)
+    }
+}
+
+impl SubscriberRegistry {
+    /// Creates a registry with the given number of shards (at least one).
+    pub fn with_shards(shards: usize) -> Self {
+        SubscriberRegistry {
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+            patterns: RwLock::default(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Read access to the shard holding the topic, or to the wildcard map for a pattern.
+    pub fn read(&self, topic: &str) -> RwLockReadGuard<'_, SubscriberMap> {
+        self.lock_for(topic).read().unwrap()
+    }
+
+    /// Write access to the shard holding the topic, or to the wildcard map for a pattern.
+    pub fn write(&self, topic: &str) -> RwLockWriteGuard<'_, SubscriberMap> {
+        self.lock_for(topic).write().unwrap()
+    }
+
+    /// Read access to the wildcard subscriptions, keyed by pattern.
+    pub fn read_patterns(&self) -> RwLockReadGuard<'_, SubscriberMap> {
+        self.patterns.read().unwrap()
+    }
+
+    /// Whether any session is subscribed to the topic (or wildcard pattern).
+    pub fn contains_topic(&self, topic: &str) -> bool {
+        self.read(topic).contains_key(topic)
+    }
+
+    /// Number of subscribers to the topic in a session.
+    pub fn subscriber_count(&self, topic: &str, session_id: &str) -> usize {
+        self.read(topic)
+            .get(topic)
+            .and_then(|sessions| sessions.get(session_id))
+            .map_or(0, |sinks| sinks.len())
+    }
+
+    /// Topics and wildcard patterns that currently have at least one subscriber, in no particular order.
+    pub fn topics(&self) -> Vec<Topic> {
+        self.maps()
+            .flat_map(|shard| {
+                shard.read().unwrap()
+                    .iter()
+                    .filter(|(_, sessions)| sessions.values().any(|sinks| !sinks.is_empty()))
+                    .map(|(topic, _)| topic.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Counts for every topic and wildcard pattern, sorted by topic. Each shard is read-locked only while its
+    /// topics are counted; subscriber lists are never copied. Subscribers whose connection has gone are
+    /// not counted, and topics left with none are omitted.
+    pub fn topic_counts(&self) -> Vec<TopicCounts> {
+        let mut counts: Vec<TopicCounts> = self.maps()
+            .flat_map(|shard| {
+                shard.read().unwrap()
+                    .iter()
+                    .map(|(topic, sessions)| {
+                        let live: Vec<usize> = sessions.values().map(|sinks| live_count(sinks)).filter(|n| *n > 0).collect();
+                        TopicCounts { topic: topic.clone(), sessions: live.len(), subscribers: live.iter().sum() }
+                    })
+                    .filter(|counts| counts.subscribers > 0)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        counts.sort_by(|a, b| a.topic.cmp(&b.topic));
+        counts
+    }
+
+    /// Sessions with a subscriber to the topic, sorted, including those subscribed through a
+    /// matching wildcard pattern when `wildcards` is set.
+    pub fn sessions(&self, topic: &str, wildcards: bool) -> Vec<SessionId> {
+        let mut sessions: Vec<SessionId> = self.read(topic)
+            .get(topic)
+            .map(|sessions| sessions.keys().cloned().collect())
+            .unwrap_or_default();
+        if wildcards && !direct::is_direct_topic(topic) {
+            for (pattern, pattern_sessions) in self.read_patterns().iter() {
+                if topic_pattern::matches(pattern, topic) {
+                    sessions.extend(pattern_sessions.keys().cloned());
+                }
+            }
+        }
+        sessions.sort();
+        sessions.dedup();
+        sessions
+    }
+
+    /// Number of live subscribers per session on the topic, sorted by session id. Sessions whose
+    /// subscribers have all disconnected are omitted.
+    pub fn session_counts(&self, topic: &str) -> Vec<(SessionId, usize)> {
+        let mut counts: Vec<(SessionId, usize)> = self.read(topic)
+            .get(topic)
+            .map(|sessions| {
+                sessions.iter()
+                    .map(|(session, sinks)| (session.clone(), live_count(sinks)))
+                    .filter(|(_, count)| *count > 0)
+                    .collect()
+            })
+            .unwrap_or_default();
+        counts.sort();
+        counts
+    }
+
+    /// The live subscribers to the topic (or wildcard pattern), sorted by session and then by
+    /// connection id. Subscribers whose connection has gone are omitted.
+    pub fn subscriber_infos(&self, topic: &str) -> Vec<SubscriberInfo> {
+        let mut infos: Vec<SubscriberInfo> = self.read(topic)
+            .get(topic)
+            .map(|sessions| {
+                sessions.iter()
+                    .flat_map(|(session, sinks)| {
+                        sinks.iter()
+                            .filter(|sink| !sink.sender.is_closed())
+                            .map(move |sink| SubscriberInfo {
+                                session_id: session.clone(),
+                                connection_id: sink.connection_id,
+                                peer: sink.peer,
+                                options: sink.options.clone(),
+                            })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        infos.sort_by(|a, b| (&a.session_id, a.connection_id).cmp(&(&b.session_id, b.connection_id)));
+        infos
+    }
+
+    /// Drops sinks whose connection has gone from the topic, and from the wildcard patterns that
+    /// match it, in a session. A session left without sinks is removed, and so is a topic or
+    /// pattern left without sessions. Returns the topics and patterns the session was removed
+    /// from, so the caller can audit their removal; the owning connection's cleanup will no
+    /// longer find them.
+    pub fn prune_closed(&self, topic: &str, session_id: &str) -> Vec<Topic> {
+        let mut emptied = Vec::new();
+        let mut pruned = prune_entry(&mut self.write(topic), topic, session_id, &mut emptied);
+        if !direct::is_direct_topic(topic) {
+            let mut patterns = self.patterns.write().unwrap();
+            let matching: Vec<Topic> = patterns.keys()
+                .filter(|pattern| topic_pattern::matches(pattern, topic))
+                .cloned()
+                .collect();
+            for pattern in matching {
+                pruned += prune_entry(&mut patterns, &pattern, session_id, &mut emptied);
+            }
+        }
+        if pruned > 0 {
+            debug!("[subscribers] Pruned {} closed subscribers of topic '{}' in session '{}'", pruned, topic, session_id);
+        }
+        emptied
+    }
+
+    /// Write access to every shard, taken in a fixed order so concurrent callers cannot deadlock.
+    /// Used where an operation must exclude publishes on several topics at once.
+    pub(crate) fn write_all(&self) -> ShardsWriteGuard<'_> {
+        ShardsWriteGuard {
+            registry: self,
+            guards: self.maps().map(|map| map.write().unwrap()).collect(),
+        }
+    }
+
+    // Every shard followed by the wildcard map; locks are always taken in this order
+    fn maps(&self) -> impl Iterator<Item = &RwLock<SubscriberMap>> {
+        self.shards.iter().chain(std::iter::once(&self.patterns))
+    }
+
+    fn lock_for(&self, topic: &str) -> &RwLock<SubscriberMap> {
+        if topic_pattern::is_wildcard(topic) {
+            &self.patterns
+        } else {
+            &self.shards[self.shard_index(topic)]
+        }
+    }
+
+    // Position of the topic's lock in `maps()`
+    fn map_index(&self, topic: &str) -> usize {
+        if topic_pattern::is_wildcard(topic) {
+            self.shards.len()
+        } else {
+            self.shard_index(topic)
+        }
+    }
+
+    fn shard_index(&self, topic: &str) -> usize {
+        (self.hasher.hash_one(topic) % self.shards.len() as u64) as usize
+    }
+}
+
+// Drops the closed sinks under one topic or pattern in a session, collapsing emptied entries.
+// Returns how many sinks were dropped and records the key in `emptied` if the session went.
+fn prune_entry(map: &mut SubscriberMap, key: &str, session_id: &str, emptied: &mut Vec<Topic>) -> usize {
+    let Some(sessions) = map.get_mut(key) else {
+        return 0;
+    };
+    let Some(sinks) = sessions.get_mut(session_id) else {
+        return 0;
+    };
+    let before = sinks.len();
+    sinks.retain(|sink| !sink.sender.is_closed());
+    let pruned = before - sinks.len();
+    if sinks.is_empty() {
+        sessions.remove(session_id);
+        emptied.push(key.to_string());
+    }
+    if sessions.is_empty() {
+        map.remove(key);
+    }
+    pruned
+}
+
+// Subscribers whose connection is still open
+fn live_count(sinks: &[Subscriber]) -> usize {
+    sinks.iter().filter(|sink| !sink.sender.is_closed()).count()
+}
+
+/// Subscribers a message published on `topic` in a session goes to: the exact subscribers, then
+/// the wildcard subscribers whose pattern matches. A sender matched by several subscriptions is
+/// returned once. Pass `patterns` as `None` to skip wildcard matching; it is always skipped
+/// for direct-message topics.
+pub(crate) fn matching_sinks<'a>(
+    exact: &'a SubscriberMap,
+    patterns: Option<&'a SubscriberMap>,
+    topic: &str,
+    session_id: &str,
+) -> Vec<&'a Subscriber> {
+    matching_sinks_from(exact, patterns, topic, session_id, None)
+}
+
+/// Like [`matching_sinks`], leaving out subscriptions whose filter refuses the publisher.
+pub(crate) fn matching_sinks_from<'a>(
+    exact: &'a SubscriberMap,
+    patterns: Option<&'a SubscriberMap>,
+    topic: &str,
+    session_id: &str,
+    publisher: Option<&str>,
+) -> Vec<&'a Subscriber> {
+    let accepts = |sink: &&Subscriber| publisher.is_none_or(|publisher| sink.accepts_publisher(publisher));
+    let mut sinks: Vec<&Subscriber> = exact
+        .get(topic)
+        .and_then(|sessions| sessions.get(session_id))
+        .map(|sinks| sinks.iter().filter(accepts).collect())
+        .unwrap_or_default();
+    let Some(patterns) = patterns.filter(|_| !direct::is_direct_topic(topic)) else {
+        return sinks;
+    };
+    for (pattern, sessions) in patterns.iter() {
+        if !topic_pattern::matches(pattern, topic) {
+            continue;
+        }
+        for sink in sessions.get(session_id).into_iter().flatten().filter(accepts) {
+            if !sinks.iter().any(|seen| seen.sender.same_channel(&sink.sender)) {
+                sinks.push(sink);
+            }
+        }
+    }
+    sinks
+}
+
+/// Hands a frame, with its sequence number if it has one, to each subscriber. Returns how many
+/// accepted it, and whether any had closed and should be [`SubscriberRegistry::prune_closed`]
+/// once the caller's locks are released.
+///
+/// A subscriber that refused the frame because its send queue was full is not counted as closed.
+pub(crate) fn send_to_all(sinks: &[&Subscriber], frame: &str, seq: Option<u64>) -> (usize, bool) {
+    let delivered = sinks.iter().filter(|sink| sink.send(frame, seq)).count();
+    (delivered, delivered < sinks.len() && sinks.iter().any(|sink| sink.sender.is_closed()))
+}
+
+/// Every shard of a registry, write-locked.
+pub(crate) struct ShardsWriteGuard<'a> {
+    registry: &'a SubscriberRegistry,
+    guards: Vec<RwLockWriteGuard<'a, SubscriberMap>>,
+}
+
+impl ShardsWriteGuard<'_> {
+    /// The locked shard holding the topic.
+    pub(crate) fn shard(&mut self, topic: &str) -> &mut SubscriberMap {
+        let index = self.registry.map_index(topic);
+        &mut self.guards[index]
+    }
+}
@@ -0,0 +1,21 @@
+// src/timestamp.rs
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::OffsetDateTime;
+
+/// Current time in the canonical message timestamp format.
+pub fn now_rfc3339() -> String {
+    format_rfc3339(SystemTime::now())
+}
+
+/// Formats an instant as canonical RFC 3339: UTC, millisecond precision and a `Z` suffix,
+/// e.g. `2024-01-24T10:25:37.123Z`. This matches JavaScript's `Date.toISOString()`.
+pub fn format_rfc3339(at: SystemTime) -> String {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let dt = OffsetDateTime::from_unix_timestamp(since_epoch.as_secs() as i64)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        dt.year(), dt.month() as u8, dt.day(),
+        dt.hour(), dt.minute(), dt.second(), since_epoch.subsec_millis()
+    )
+}
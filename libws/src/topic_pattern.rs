@@ -0,0 +1,44 @@
+// src/topic_pattern.rs
+
+/// Checks a topic against a pattern segment by segment.
+/// Segments are separated by `/` or `.`. `*` matches exactly one segment, and `#`, which may only
+/// be the last segment, matches all remaining segments, including none (`sensor.#` matches `sensor`).
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern_segments = segments(pattern);
+    let mut topic_segments = segments(topic);
+
+    loop {
+        match (pattern_segments.next(), topic_segments.next()) {
+            (Some("#"), _) => return true,
+            (None, None) => return true,
+            (Some("*"), Some(_)) => continue,
+            (Some(p), Some(t)) if p == t => continue,
+            _ => return false,
+        }
+    }
+}
+
+/// Whether the pattern contains a `*` or `#` segment, as opposed to naming a single topic.
+pub fn is_wildcard(pattern: &str) -> bool {
+    segments(pattern).any(|segment| segment == "*" || segment == "#")
+}
+
+/// Whether the pattern is well formed: `#` may only appear as the last segment.
+pub fn is_valid(pattern: &str) -> bool {
+    let mut segments = segments(pattern).peekable();
+    while let Some(segment) = segments.next() {
+        if segment == "#" && segments.peek().is_some() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Whether a subscription, exact or wildcard, receives messages published on the topic.
+pub fn covers(subscription: &str, topic: &str) -> bool {
+    subscription == topic || (is_wildcard(subscription) && matches(subscription, topic))
+}
+
+fn segments(value: &str) -> impl Iterator<Item = &str> {
+    value.split(['/', '.'])
+}
@@ -0,0 +1,61 @@
+// src/transfer.rs
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a transfer token can be redeemed after it is issued.
+const TRANSFER_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+// (topic, session) pairs a connection is subscribed to
+type SubscriptionList = Arc<Mutex<Vec<(String, String)>>>;
+
+/// One-time tokens that let a standby connection adopt another connection's subscriptions.
+#[derive(Debug, Default)]
+pub struct SubscriptionTransfers {
+    pending: Mutex<HashMap<String, PendingTransfer>>,
+}
+
+#[derive(Debug)]
+struct PendingTransfer {
+    user_id: Option<String>,
+    session_id: String,
+    subscriptions: SubscriptionList,
+    expires_at: Instant,
+}
+
+impl SubscriptionTransfers {
+    /// Issues a token for the given connection's subscriptions.
+    /// The list is read when the token is redeemed, so later subscribes are included.
+    pub(crate) fn issue(&self, user_id: Option<String>, session_id: String, subscriptions: SubscriptionList) -> String {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, transfer| transfer.expires_at > Instant::now());
+        pending.insert(token.clone(), PendingTransfer {
+            user_id,
+            session_id,
+            subscriptions,
+            expires_at: Instant::now() + TRANSFER_TOKEN_TTL,
+        });
+        token
+    }
+
+    /// Consumes a token and returns the subscriptions to adopt, provided it has not
+    /// expired and the redeeming connection has the same user and session.
+    pub(crate) fn redeem(&self, token: &str, user_id: Option<&str>, session_id: &str) -> Option<Vec<(String, String)>> {
+        let transfer = self.pending.lock().unwrap().remove(token)?;
+        if transfer.expires_at <= Instant::now()
+            || transfer.user_id.as_deref() != user_id
+            || transfer.session_id != session_id
+        {
+            return None;
+        }
+        let subscriptions = transfer.subscriptions.lock().unwrap().clone();
+        Some(subscriptions)
+    }
+}
@@ -0,0 +1,79 @@
+// src/transport.rs
+use axum::extract::ws::Message;
+use futures_util::{Sink, Stream};
+
+/// A bidirectional stream of WebSocket frames that a connection can be served over.
+///
+/// Implemented by axum's `WebSocket` (the tungstenite transport used in production)
+/// and, with the `memory-transport` feature, by [`MemoryTransport`].
+pub trait Transport:
+    Stream<Item = Result<Message, axum::Error>> + Sink<Message, Error = axum::Error> + Send + Unpin + 'static
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<Message, axum::Error>> + Sink<Message, Error = axum::Error> + Send + Unpin + 'static
+{
+}
+
+#[cfg(feature = "memory-transport")]
+pub use memory::{memory_pair, MemoryTransport};
+
+#[cfg(feature = "memory-transport")]
+mod memory {
+    use super::Message;
+    use futures_util::{Sink, Stream};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+    /// One end of an in-process duplex channel carrying WebSocket frames.
+    /// Frames sent on one end are received, in order, on the other.
+    pub struct MemoryTransport {
+        outgoing: Option<UnboundedSender<Message>>,
+        incoming: UnboundedReceiver<Message>,
+    }
+
+    /// Creates two connected in-process transports, one per peer.
+    pub fn memory_pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        (
+            MemoryTransport { outgoing: Some(a_tx), incoming: b_rx },
+            MemoryTransport { outgoing: Some(b_tx), incoming: a_rx },
+        )
+    }
+
+    impl Stream for MemoryTransport {
+        type Item = Result<Message, axum::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.incoming.poll_recv(cx).map(|msg| msg.map(Ok))
+        }
+    }
+
+    impl Sink<Message> for MemoryTransport {
+        type Error = axum::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            let closed = || axum::Error::new("memory transport closed");
+            let outgoing = self.outgoing.as_ref().ok_or_else(closed)?;
+            outgoing.send(item).map_err(|_| closed())?;
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        // Dropping the sender ends the peer's stream, like a closed TCP connection
+        fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.outgoing = None;
+            Poll::Ready(Ok(()))
+        }
+    }
+}
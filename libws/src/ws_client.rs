@@ -1,47 +1,469 @@
 // src/ws_client.rs
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use futures_util::{FutureExt, SinkExt, StreamExt};
 use tokio::task::JoinHandle;
+use tokio::sync::{oneshot, watch};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use futures_util::stream::{SplitSink, SplitStream};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
-use serde_json::json;
+use serde_json::Value;
 use std::time::{Duration, Instant};
 use std::error::Error;
+use crate::delta::{apply_merge_patch, DELTA_OPTION};
+use crate::DeliveryOrder;
+use crate::binary_proto::{BinaryFrame, Opcode};
+use crate::capabilities::{self, Capability, CapabilityError};
+use crate::error_frame::ServerError;
+use crate::command::{ClientCommand, PublishCommand};
+use crate::compression;
+use crate::request_reply;
+use crate::timestamp::now_rfc3339;
+use crate::session_crypto;
+use crate::publisher_filter::{self, PublisherFilter};
+use crate::subprotocol::{EncodedFrame, WireFormat};
+use zeroize::Zeroizing;
+use crate::jwt_utils::unverified_session_id;
 
 // Add JWT-related imports
-use reqwest;
 use serde::Deserialize;
 use url::Url;
+use tracing::{debug, info, warn};
 
-type Callback = Box<dyn Fn(String) + Send + Sync>;
+// The sending and receiving halves of a connection
+type WsSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type WsSource = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// The current connection's sink, shared with reconnects and subscription guards.
+pub type SharedSink = Arc<tokio::sync::Mutex<WsSink>>;
+
+// Handlers receive the message with its envelope fields
+type Callback = Box<dyn Fn(IncomingMessage) + Send + Sync>;
+
+// Binary handlers receive the raw payload of a publish frame
+type BinaryCallback = Box<dyn Fn(Vec<u8>) + Send + Sync>;
+
+// Close handlers receive the server's close code, if it sent one, and the reason
+type CloseCallback = Box<dyn Fn(Option<u16>, String) + Send + Sync>;
+
+// Error handlers receive each error frame the server sends
+type ErrorCallback = Box<dyn Fn(ServerError) + Send + Sync>;
+
+// Close code and reason, recorded once the connection has ended
+type CloseInfo = Arc<Mutex<Option<(Option<u16>, String)>>>;
+
+// How the connection ended: the server's close code, or the transport error that ended it
+type CloseOutcome = Result<Option<u16>, String>;
+
+// Commands waiting for their ack, keyed by the id they were sent with; an error frame
+// carrying the id resolves the wait with that error
+type AckWaiters = Arc<Mutex<HashMap<String, oneshot::Sender<Result<(), ServerError>>>>>;
+
+// Messages that arrived before a handler was registered for their topic
+type PendingMessages = Arc<Mutex<HashMap<String, VecDeque<(Instant, IncomingMessage)>>>>;
+
+// The session's encryption key, once a key exchange has succeeded
+type SessionKey = Arc<Mutex<Option<Zeroizing<Vec<u8>>>>>;
+
+// Answers to `has-subscribers`, keyed by the id of the command they answer
+type SubscriberCounts = Arc<Mutex<HashMap<String, usize>>>;
+
+// Subscribed topics and how each was made, replayed after a reconnect
+type Subscriptions = Arc<Mutex<HashMap<String, Resubscribe>>>;
+
+// How a subscription is made again on a new connection
+#[derive(Clone, Debug)]
+enum Resubscribe {
+    // A `subscribe` command with these options, such as `delta` or a publisher filter
+    Command(Vec<String>),
+    // A binary subscribe frame
+    Binary,
+}
+
+/// How long a message for a topic without a handler is kept for a late `on_message` call.
+const UNHANDLED_MESSAGE_GRACE: Duration = Duration::from_secs(5);
+/// Maximum number of buffered messages kept per topic without a handler.
+const UNHANDLED_MESSAGE_LIMIT: usize = 64;
+
+/// How long `require_capabilities` waits for the server's `server_hello`.
+const SERVER_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `subscribe` and `on` wait for the server to acknowledge a subscribe.
+const SUBSCRIBE_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `exchange_session_key` waits for the server's wrapped session key.
+const KEY_EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `subscriber_count` waits for the server's count.
+const SUBSCRIBER_COUNT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `close` waits for the server to answer its close frame.
+const CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Wait used between overload retries when the server does not send `Retry-After`.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Message of [`WsError::Reconnecting`], returned while a client made with
+/// `connect_with_reconnect` is reconnecting.
+pub const RECONNECTING_ERROR: &str = "WebSocket is reconnecting";
+
+/// Why a `WsClient` call failed.
+#[derive(Debug, thiserror::Error)]
+pub enum WsError {
+    /// The WebSocket handshake failed, for example because the server is down or refused the upgrade.
+    #[error("connect failed: {0}")]
+    Connect(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+    /// A URL given to connect could not be parsed.
+    #[error("invalid URL: {0}")]
+    InvalidUrl(#[from] url::ParseError),
+    /// The auth endpoint did not issue a token.
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    /// The refresh token could not be exchanged for a new access token.
+    #[error("token refresh failed: {0}")]
+    TokenRefresh(String),
+    /// Writing a frame failed; the client is marked disconnected.
+    #[error("send failed: {0}")]
+    Send(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+    /// The connection has ended, or ended before the server answered.
+    #[error("WebSocket is not connected")]
+    NotConnected,
+    /// A client made with `connect_with_reconnect` is replacing its dropped connection.
+    #[error("{}", RECONNECTING_ERROR)]
+    Reconnecting,
+    /// The server refused the command with an error frame.
+    #[error("refused by server: {0}")]
+    Refused(#[source] ServerError),
+    /// The server did not answer in time; says what was waited for.
+    #[error("timed out waiting for {0}")]
+    Timeout(String),
+    /// The connection failed while the client waited on it, as during `close`.
+    #[error("connection failed: {0}")]
+    Transport(String),
+    /// A payload or frame could not be encoded.
+    #[error("cannot encode payload: {0}")]
+    Serialization(String),
+    /// The session key could not be obtained, or a payload could not be encrypted with it.
+    #[error("encryption failed: {0}")]
+    Encryption(String),
+    /// The server lacks capabilities the client requires.
+    #[error(transparent)]
+    Capabilities(#[from] CapabilityError),
+}
+
+impl WsError {
+    /// The server's error frame, when the server refused the command.
+    pub fn server_error(&self) -> Option<&ServerError> {
+        match self {
+            WsError::Refused(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// How a client made with `WsClient::connect_with_reconnect` retries after its connection drops.
+/// The first attempt waits `initial_backoff`, and each later one twice as long as the one
+/// before, up to `max_backoff`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts made before the client gives up and reports the close.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before the given attempt, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16);
+        self.initial_backoff.saturating_mul(1 << doublings).min(self.max_backoff)
+    }
+}
+
+/// How `WsClient::connect_with_options` opens its connection. The default registers the
+/// client's name and session straight after the handshake, waits as long as the handshake
+/// takes and does not reconnect, as `connect_with_session` does.
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Send `register-name:` once connected.
+    pub register_name: bool,
+    /// Send `register-session:` once connected.
+    pub register_session: bool,
+    /// Longest the handshake may take, on the first connect and on every reconnect attempt.
+    pub connect_timeout: Option<Duration>,
+    /// Reconnect with this policy when the connection drops.
+    pub reconnect: Option<RetryPolicy>,
+    /// Message format asked for with `Sec-WebSocket-Protocol`. Anything but JSON fails the
+    /// connect when the server does not accept it.
+    pub codec: WireFormat,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            register_name: true,
+            register_session: true,
+            connect_timeout: None,
+            reconnect: None,
+            codec: WireFormat::default(),
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Whether to send `register-name:`. A server that took the name from a token ignores it.
+    pub fn with_register_name(mut self, register: bool) -> Self {
+        self.register_name = register;
+        self
+    }
+
+    /// Whether to send `register-session:`. A server that took the session from a token ignores it.
+    pub fn with_register_session(mut self, register: bool) -> Self {
+        self.register_session = register;
+        self
+    }
+
+    /// Fails the connect with `WsError::Timeout` when the handshake takes longer than this.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Keeps the connection up as `WsClient::connect_with_reconnect` does.
+    pub fn with_reconnect(mut self, policy: RetryPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Sends commands and receives messages in this format, such as MessagePack.
+    pub fn with_codec(mut self, codec: WireFormat) -> Self {
+        self.codec = codec;
+        self
+    }
+}
+
+// What the receive task needs to replace a dropped connection
+struct Reconnector {
+    client_name: String,
+    session_id: String,
+    ws_url: String,
+    options: ConnectOptions,
+    policy: RetryPolicy,
+    subscriptions: Subscriptions,
+    is_connected: Arc<Mutex<bool>>,
+    reconnecting: Arc<Mutex<bool>>,
+    closing: Arc<Mutex<bool>>,
+    sink: SharedSink,
+}
+
+impl Reconnector {
+    // Reconnects with backoff, replays the subscriptions and hands the new sink to the client.
+    // Returns the new connection's stream, or `None` once the attempts run out or the client closes.
+    async fn reconnect(&self) -> Option<WsSource> {
+        if *self.closing.lock().unwrap() {
+            *self.is_connected.lock().unwrap() = false;
+            return None;
+        }
+        // Set before the connection is marked down so a publish never sees neither flag
+        *self.reconnecting.lock().unwrap() = true;
+        *self.is_connected.lock().unwrap() = false;
+        let mut reconnected = None;
+        let mut wait = self.policy.backoff(1);
+        for attempt in 1..=self.policy.max_attempts {
+            info!("[reconnect] {} reconnecting in {:?} (attempt {}/{})",
+                self.client_name, wait, attempt, self.policy.max_attempts);
+            tokio::time::sleep(wait).await;
+            if *self.closing.lock().unwrap() {
+                break;
+            }
+            match self.open_and_replay().await {
+                Ok((sink, stream)) => {
+                    *self.sink.lock().await = sink;
+                    *self.is_connected.lock().unwrap() = true;
+                    reconnected = Some(stream);
+                    break;
+                }
+                Err(e) => {
+                    warn!("[reconnect] {} attempt {} failed: {}", self.client_name, attempt, e);
+                    // An overloaded server says when to come back, as in `connect_with_retry`
+                    wait = overload_retry_after(&e).unwrap_or_else(|| self.policy.backoff(attempt + 1));
+                }
+            }
+        }
+        *self.reconnecting.lock().unwrap() = false;
+        reconnected
+    }
+
+    async fn open_and_replay(&self) -> Result<(WsSink, WsSource), WsError> {
+        let (mut sink, stream) = WsClient::open(&self.client_name, &self.session_id, &self.ws_url, &self.options).await?;
+        let subscriptions: Vec<(String, Resubscribe)> = self.subscriptions.lock().unwrap()
+            .iter()
+            .map(|(topic, how)| (topic.clone(), how.clone()))
+            .collect();
+        for (topic, how) in &subscriptions {
+            let message = match how {
+                Resubscribe::Command(options) => {
+                    let subscribe = ClientCommand::Subscribe {
+                        topic: topic.clone(),
+                        session_id: Some(self.session_id.clone()),
+                        options: options.clone(),
+                        id: None,
+                    };
+                    encode_frame(self.options.codec, subscribe.to_frame())
+                }
+                Resubscribe::Binary => {
+                    let frame = BinaryFrame::subscribe(topic, &self.session_id);
+                    Message::Binary(frame.encode().map_err(|e| WsError::Serialization(e.to_string()))?)
+                }
+            };
+            sink.send(message).await.map_err(|e| WsError::Send(Box::new(e)))?;
+        }
+        let topics: Vec<&String> = subscriptions.iter().map(|(topic, _)| topic).collect();
+        info!("[reconnect] {} reconnected, resubscribed to {:?}", self.client_name, topics);
+        Ok((sink, stream))
+    }
+}
 
 /// JWT Auth Response from the server
 #[derive(Debug, Deserialize)]
 struct JwtAuthResponse {
     token: String,
     expires_in: u64,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+/// A subscription made with `WsClient::on`, already acknowledged by the server. Pass it to
+/// `WsClient::off` to unsubscribe and remove the handler together.
+#[derive(Debug)]
+pub struct SubscriptionHandle {
+    topic: String,
+}
+
+impl SubscriptionHandle {
+    /// The subscribed topic.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+/// Subscriptions made with `WsClient::subscribe_many` or `WsClient::subscribe_guarded`. Dropping
+/// the guard sends `unsubscribe` for each of its topics and stops replaying them after a reconnect;
+/// call [`SubscriptionGuard::detach`] to keep them instead.
+#[must_use = "dropping the guard unsubscribes its topics"]
+pub struct SubscriptionGuard {
+    topics: Vec<String>,
+    session_id: String,
+    codec: WireFormat,
+    sink: SharedSink,
+    subscriptions: Subscriptions,
+}
+
+impl SubscriptionGuard {
+    /// The subscribed topics.
+    pub fn topics(&self) -> &[String] {
+        &self.topics
+    }
+
+    /// Keeps the subscriptions for the life of the client, as plain `subscribe` does.
+    pub fn detach(mut self) {
+        self.topics.clear();
+    }
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        if self.topics.is_empty() {
+            return;
+        }
+        debug!("[unsubscribe] guard dropped, topics={:?}, session={}", self.topics, self.session_id);
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let frames: Vec<String> = self.topics.drain(..)
+            .inspect(|topic| { subscriptions.remove(topic); })
+            .map(|topic| ClientCommand::Unsubscribe { topic, session_id: Some(self.session_id.clone()), id: None }.to_frame())
+            .collect();
+        // Drop cannot await, so the frames go out from a task; without a runtime there is no socket to write to
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let sink = self.sink.clone();
+        let codec = self.codec;
+        runtime.spawn(async move {
+            let mut sink = sink.lock().await;
+            for frame in frames {
+                if sink.feed(encode_frame(codec, frame)).await.is_err() {
+                    return;
+                }
+            }
+            let _ = sink.flush().await;
+        });
+    }
+}
+
+/// A message delivered to a topic handler registered with [`WsClient::on_message_full`].
+/// Envelope fields the server did not send are empty.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IncomingMessage {
+    pub topic: String,
+    /// Text payloads as-is, structured ones as their JSON text, delta subscriptions as the rebuilt object
+    pub payload: String,
+    pub publisher_name: String,
+    pub timestamp: String,
+    pub session_id: String,
+    /// Assigned by the server when the publisher did not set one, so it is normally present
+    pub correlation_id: Option<String>,
 }
 
 /// Represents a WebSocket client with per-topic message handlers.
 pub struct WsClient {
     pub name: String, // The name of the client
     pub session_id: String, // The session ID for this client
-    pub ws_channel: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>, // WebSocket channel for sending messages
-    on_message_handlers: Arc<Mutex<HashMap<String, Callback>>>, // Handlers for incoming messages by topic
-    _async_task_handler: JoinHandle<()>, // Background task for receiving messages
+    pub ws_channel: SharedSink, // WebSocket channel for sending messages, replaced by reconnects
+    on_message_handlers: Arc<Mutex<HashMap<String, Vec<Callback>>>>, // Handlers for incoming messages by topic, in registration order
+    on_binary_handlers: Arc<Mutex<HashMap<String, BinaryCallback>>>, // Handlers for binary publish frames by topic
+    pending_messages: PendingMessages, // Messages waiting for a handler to be registered
+    receive_task: JoinHandle<()>, // Background task for receiving messages
     is_connected: Arc<Mutex<bool>>, // Tracks the connection state
+    reconnecting: Arc<Mutex<bool>>, // Set while a dropped connection is being replaced
+    closing: Arc<Mutex<bool>>, // Set by `close` so the connection is not replaced
+    subscriptions: Subscriptions, // Subscribed topics and their options, replayed after a reconnect
+    on_close_handler: Arc<Mutex<Option<CloseCallback>>>, // Called when the server ends the connection
+    on_error_handler: Arc<Mutex<Option<ErrorCallback>>>, // Called when the server refuses a frame
+    on_any_handler: Arc<Mutex<Option<Callback>>>, // Called for every message, after the topic's handlers
+    close_info: CloseInfo, // Close code and reason, once the connection has ended
+    close_outcome: watch::Receiver<Option<CloseOutcome>>, // Set when the receive task ends
+    server_capabilities: watch::Receiver<Option<Vec<Capability>>>, // Set once the server_hello arrives
+    ack_waiters: AckWaiters, // Subscribes waiting for the server's ack
+    session_key: SessionKey, // Decrypts `aes-256-gcm` payloads and encrypts `publish_encrypted`
+    wrapped_session_key: Arc<Mutex<Option<String>>>, // Latest key exchange answer, not yet unwrapped
+    subscriber_counts: SubscriberCounts, // Counts received for `subscriber_count`, not yet collected
+    codec: WireFormat, // Format commands are sent in
+    next_command_id: u64, // Id given to the next acknowledged command
     // New fields for JWT authentication
     auth_token: Arc<Mutex<Option<String>>>, // JWT token if authenticated
     token_expiry: Arc<Mutex<Option<Instant>>>, // When the token expires
+    refresh_token: Arc<Mutex<Option<String>>>, // Single-use token exchanged for a new access token
     auth_url: Option<String>, // URL for token refresh
 }
 
 impl WsClient {
     /// Connects to a WebSocket server and registers the client name.
-    pub async fn connect(client_name: &str, ws_url: &str) -> tokio_tungstenite::tungstenite::Result<Self> {
+    pub async fn connect(client_name: &str, ws_url: &str) -> Result<Self, WsError> {
         // Use a default session ID derived from client name
         let session_id = format!("session-{}", client_name);
         Self::connect_with_session(client_name, session_id.as_str(), ws_url).await
@@ -52,72 +474,400 @@ impl WsClient {
         client_name: &str, 
         session_id: &str, 
         ws_url: &str
-    ) -> tokio_tungstenite::tungstenite::Result<Self> {
-        println!("[connect] client_name={}, session_id={}, ws_url={} -- executing", 
-            client_name, session_id, ws_url);
+    ) -> Result<Self, WsError> {
+        Self::connect_with_options(client_name, session_id, ws_url, ConnectOptions::default()).await
+    }
 
-        // Establish the WebSocket connection
-        let (stream, _) = connect_async(ws_url).await?;
-        let (mut ws_channel, mut ws_receiver): (SplitSink<_, _>, SplitStream<_>) = stream.split();
+    /// Connects with a specific session ID and keeps the connection up: when it drops, the
+    /// client reconnects with exponential backoff, registers its name and session again and
+    /// resubscribes to every topic it was subscribed to. While reconnecting, `publish` fails
+    /// with [`RECONNECTING_ERROR`]. `on_close` runs only once the policy's attempts run out;
+    /// calling `close` stops reconnecting.
+    pub async fn connect_with_reconnect(
+        client_name: &str,
+        session_id: &str,
+        ws_url: &str,
+        policy: RetryPolicy,
+    ) -> Result<Self, WsError> {
+        Self::connect_with_options(client_name, session_id, ws_url, ConnectOptions::default().with_reconnect(policy)).await
+    }
+
+    /// Connects with a specific session ID, choosing which registrations are sent, how long the
+    /// handshake may take and whether the connection is kept up. See [`ConnectOptions`].
+    pub async fn connect_with_options(
+        client_name: &str,
+        session_id: &str,
+        ws_url: &str,
+        options: ConnectOptions,
+    ) -> Result<Self, WsError> {
+        Url::parse(ws_url)?;
+        Self::start(client_name, session_id, ws_url, options).await
+    }
+
+    // Opens the socket and registers the client's name and session on it, as the options ask
+    async fn open(client_name: &str, session_id: &str, ws_url: &str, options: &ConnectOptions) -> Result<(WsSink, WsSource), WsError> {
+        // Establish the WebSocket connection, asking for the codec unless it is the default
+        let mut request = ws_url.into_client_request().map_err(|e| WsError::Connect(Box::new(e)))?;
+        if options.codec != WireFormat::default() {
+            request.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(options.codec.protocol()));
+        }
+        let handshake = connect_async(request);
+        let connected = match options.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake).await
+                .map_err(|_| WsError::Timeout(format!("the WebSocket handshake within {:?}", timeout)))?,
+            None => handshake.await,
+        };
+        let (stream, response) = connected.map_err(|e| WsError::Connect(Box::new(e)))?;
+        if options.codec != WireFormat::default() {
+            let accepted = response.headers().get(header::SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|protocol| protocol.to_str().ok())
+                .and_then(WireFormat::from_protocol);
+            if accepted != Some(options.codec) {
+                return Err(WsError::Transport(format!("the server did not accept the {} subprotocol", options.codec.protocol())));
+            }
+        }
+        let (mut ws_channel, ws_receiver): (SplitSink<_, _>, SplitStream<_>) = stream.split();
 
         // Register the client name with the server
-        let register_msg = format!("register-name:{}", client_name);
-        ws_channel.send(Message::Text(register_msg)).await?;
-        
+        if options.register_name {
+            let register_msg = ClientCommand::RegisterName { name: client_name.to_string() };
+            ws_channel.send(encode_frame(options.codec, register_msg.to_frame())).await.map_err(|e| WsError::Send(Box::new(e)))?;
+        }
+
         // Register the session ID with the server
-        let register_session = format!("register-session:{}", session_id);
-        ws_channel.send(Message::Text(register_session)).await?;
+        if options.register_session {
+            let register_session = ClientCommand::RegisterSession { session_id: session_id.to_string() };
+            ws_channel.send(encode_frame(options.codec, register_session.to_frame())).await.map_err(|e| WsError::Send(Box::new(e)))?;
+        }
+        Ok((ws_channel, ws_receiver))
+    }
+
+    async fn start(
+        client_name: &str,
+        session_id: &str,
+        ws_url: &str,
+        options: ConnectOptions,
+    ) -> Result<Self, WsError> {
+        info!("[connect] client_name={}, session_id={}, ws_url={} -- executing", 
+            client_name, session_id, ws_url);
+
+        let (ws_channel, mut ws_receiver) = Self::open(client_name, session_id, ws_url, &options).await?;
 
         let name_clone = client_name.to_string();
-        let handlers = Arc::new(Mutex::new(HashMap::<String, Callback>::new()));
+        let handlers = Arc::new(Mutex::new(HashMap::<String, Vec<Callback>>::new()));
         let handlers_clone = handlers.clone();
+        let binary_handlers = Arc::new(Mutex::new(HashMap::<String, BinaryCallback>::new()));
+        let binary_handlers_clone = binary_handlers.clone();
+        let pending: PendingMessages = Arc::new(Mutex::new(HashMap::new()));
+        let pending_clone = pending.clone();
+        let is_connected = Arc::new(Mutex::new(true));
+        let is_connected_clone = is_connected.clone();
+        let (capabilities_tx, capabilities_rx) = watch::channel(None);
+        let close_handler = Arc::new(Mutex::new(None::<CloseCallback>));
+        let close_handler_clone = close_handler.clone();
+        let error_handler = Arc::new(Mutex::new(None::<ErrorCallback>));
+        let error_handler_clone = error_handler.clone();
+        let any_handler = Arc::new(Mutex::new(None::<Callback>));
+        let any_handler_clone = any_handler.clone();
+        let close_info: CloseInfo = Arc::new(Mutex::new(None));
+        let close_info_clone = close_info.clone();
+        let (close_outcome_tx, close_outcome_rx) = watch::channel(None::<CloseOutcome>);
+        let ack_waiters: AckWaiters = Arc::new(Mutex::new(HashMap::new()));
+        let ack_waiters_clone = ack_waiters.clone();
+        let session_key: SessionKey = Arc::new(Mutex::new(None));
+        let session_key_clone = session_key.clone();
+        let wrapped_session_key = Arc::new(Mutex::new(None::<String>));
+        let wrapped_session_key_clone = wrapped_session_key.clone();
+        let subscriber_counts: SubscriberCounts = Arc::new(Mutex::new(HashMap::new()));
+        let subscriber_counts_clone = subscriber_counts.clone();
+        let reconnecting = Arc::new(Mutex::new(false));
+        let closing = Arc::new(Mutex::new(false));
+        let subscriptions: Subscriptions = Arc::new(Mutex::new(HashMap::new()));
+        let ws_channel: SharedSink = Arc::new(tokio::sync::Mutex::new(ws_channel));
+        let codec = options.codec;
+        let reconnector = options.reconnect.clone().map(|policy| Reconnector {
+            client_name: client_name.to_string(),
+            session_id: session_id.to_string(),
+            ws_url: ws_url.to_string(),
+            options,
+            policy,
+            subscriptions: subscriptions.clone(),
+            is_connected: is_connected.clone(),
+            reconnecting: reconnecting.clone(),
+            closing: closing.clone(),
+            sink: ws_channel.clone(),
+        });
 
         // Spawn a task to handle incoming messages
         let task = tokio::spawn(async move {
-            while let Some(Ok(msg)) = ws_receiver.next().await {
-                if let Message::Text(txt) = msg {
-                    match serde_json::from_str::<serde_json::Value>(&txt) {
-                        Ok(parsed) => {
-                            let topic = parsed.get("topic").and_then(|t| t.as_str()).unwrap_or("<unknown>");
-                            let payload = parsed.get("payload").and_then(|m| m.as_str()).unwrap_or("<no message>");
-                            let publisher = parsed.get("publisher_name").and_then(|p| p.as_str()).unwrap_or("<unknown>");
-                            let timestamp = parsed.get("timestamp").and_then(|t| t.as_str()).unwrap_or("???");
-                            let msg_session = parsed.get("session_id").and_then(|s| s.as_str()).unwrap_or("<unknown>");
-
-                            println!(
-                                "[on_message] {} <- topic={}, payload={}, publisher={}, timestamp={}, session={}",
-                                name_clone, topic, payload, publisher, timestamp, msg_session
-                            );
-
-                            // Invoke the callback for the topic if it exists
-                            if let Some(callback) = handlers_clone.lock().unwrap().get(topic) {
-                                callback(payload.to_string());
+            // Last full object per delta-mode topic, used to rebuild patched payloads
+            let mut delta_state: HashMap<String, serde_json::Map<String, serde_json::Value>> = HashMap::new();
+            let (close, failure) = loop {
+                let mut close = (None, String::new());
+                let mut failure = None;
+                while let Some(msg) = ws_receiver.next().await {
+                    // The stream ends without an error once the close handshake completes, whichever side started it
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            failure = Some(e.to_string());
+                            break;
+                        }
+                    };
+                    if let Message::Close(Some(frame)) = &msg {
+                        close = (Some(u16::from(frame.code)), frame.reason.to_string());
+                    }
+                    // Under MessagePack the server's messages arrive as binary frames; they are handled as their JSON text
+                    let msg = match msg {
+                        Message::Binary(bytes) => match codec.decode(&bytes) {
+                            Some(text) => Message::Text(text),
+                            None => Message::Binary(bytes),
+                        },
+                        msg => msg,
+                    };
+                    if let Message::Binary(bytes) = &msg {
+                        match BinaryFrame::decode(bytes) {
+                            Ok(frame) if frame.opcode == Opcode::Publish => {
+                                debug!("[on_binary] {} <- topic={}, {} bytes, session={}",
+                                    name_clone, frame.topic, frame.payload.len(), frame.session_id);
+                                if let Some(callback) = binary_handlers_clone.lock().unwrap().get(&frame.topic) {
+                                    callback(frame.payload);
+                                }
                             }
+                            Ok(frame) => debug!("[on_binary] {} ignoring {:?} frame", name_clone, frame.opcode),
+                            Err(e) => warn!("[on_binary] {} received a malformed frame: {}", name_clone, e),
                         }
-                        Err(_) => {
-                            println!("[on_message] {} received malformed text: {}", name_clone, txt);
+                    }
+                    if let Message::Text(txt) = msg {
+                        match serde_json::from_str::<serde_json::Value>(&txt) {
+                            Ok(parsed) if parsed["type"] == "server_hello" => {
+                                debug!("[server_hello] {} <- {}", name_clone, parsed["capabilities"]);
+                                let _ = capabilities_tx.send(capabilities::from_server_hello(&parsed));
+                            }
+                            Ok(parsed) if parsed["type"] == "ack" => {
+                                debug!("[ack] {} <- op={}, topic={}, id={}", name_clone, parsed["op"], parsed["topic"], parsed["id"]);
+                                let waiter = parsed["id"].as_str().and_then(|id| ack_waiters_clone.lock().unwrap().remove(id));
+                                if let Some(waiter) = waiter {
+                                    let _ = waiter.send(Ok(()));
+                                }
+                            }
+                            Ok(parsed) if parsed["type"] == "session_key" => {
+                                debug!("[key-exchange] {} <- session={}, id={}", name_clone, parsed["session_id"], parsed["id"]);
+                                *wrapped_session_key_clone.lock().unwrap() = parsed["wrapped_key"].as_str().map(str::to_string);
+                                let waiter = parsed["id"].as_str().and_then(|id| ack_waiters_clone.lock().unwrap().remove(id));
+                                if let Some(waiter) = waiter {
+                                    let _ = waiter.send(Ok(()));
+                                }
+                            }
+                            Ok(parsed) if parsed["type"] == "subscriber_count" => {
+                                debug!("[has-subscribers] {} <- topic={}, count={}, id={}", name_clone, parsed["topic"], parsed["count"], parsed["id"]);
+                                if let (Some(id), Some(count)) = (parsed["id"].as_str(), parsed["count"].as_u64()) {
+                                    let waiter = ack_waiters_clone.lock().unwrap().remove(id);
+                                    if let Some(waiter) = waiter {
+                                        subscriber_counts_clone.lock().unwrap().insert(id.to_string(), count as usize);
+                                        let _ = waiter.send(Ok(()));
+                                    }
+                                }
+                            }
+                            Ok(mut parsed) if parsed["type"] == "error" => {
+                                if let Some(fields) = parsed.as_object_mut() {
+                                    fields.remove("type");
+                                }
+                                match serde_json::from_value::<ServerError>(parsed) {
+                                    Ok(error) => {
+                                        warn!("[on_error] {} <- {}", name_clone, error);
+                                        let waiter = error.id.as_deref().and_then(|id| ack_waiters_clone.lock().unwrap().remove(id));
+                                        if let Some(waiter) = waiter {
+                                            let _ = waiter.send(Err(error.clone()));
+                                        }
+                                        if let Some(callback) = error_handler_clone.lock().unwrap().as_ref() {
+                                            callback(error);
+                                        }
+                                    }
+                                    Err(e) => warn!("[on_error] {} received a malformed error frame: {}", name_clone, e),
+                                }
+                            }
+                            Ok(mut parsed) => {
+                                // A compressed payload is inflated before anything else looks at it
+                                if !compression::inflate_envelope(&mut parsed) {
+                                    warn!("[on_message] {} received a payload that could not be inflated on topic {}", name_clone, parsed["topic"]);
+                                    continue;
+                                }
+                                // An encrypted payload is readable only with the key from `exchange_session_key`
+                                let decrypted = session_crypto::decrypt_envelope(&mut parsed, session_key_clone.lock().unwrap().as_deref().map(Vec::as_slice));
+                                if !decrypted {
+                                    warn!("[on_message] {} skipped an encrypted payload it has no key for on topic {}", name_clone, parsed["topic"]);
+                                    continue;
+                                }
+                                let topic = parsed.get("topic").and_then(|t| t.as_str()).unwrap_or("<unknown>");
+                                // Text payloads reach handlers as-is, structured ones as their JSON text
+                                let payload = match parsed.get("payload") {
+                                    Some(Value::String(payload)) => payload.clone(),
+                                    Some(payload) => payload.to_string(),
+                                    None => "<no message>".to_string(),
+                                };
+                                let correlation_id = parsed.get("correlation_id").and_then(|c| c.as_str()).map(|c| c.to_string());
+
+                                // Delta subscriptions deliver patches; hand the handler the rebuilt object
+                                let rebuilt = match parsed.get("delta").and_then(|d| d.as_bool()) {
+                                    Some(is_patch) => {
+                                        let object = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&payload).unwrap_or_default();
+                                        if is_patch {
+                                            let state = delta_state.entry(topic.to_string()).or_default();
+                                            apply_merge_patch(state, &object);
+                                            Some(serde_json::Value::Object(state.clone()).to_string())
+                                        } else {
+                                            delta_state.insert(topic.to_string(), object);
+                                            None
+                                        }
+                                    }
+                                    None => None,
+                                };
+                                let message = IncomingMessage {
+                                    topic: topic.to_string(),
+                                    payload: rebuilt.unwrap_or(payload),
+                                    publisher_name: parsed.get("publisher_name").and_then(|p| p.as_str()).unwrap_or_default().to_string(),
+                                    timestamp: parsed.get("timestamp").and_then(|t| t.as_str()).unwrap_or_default().to_string(),
+                                    session_id: parsed.get("session_id").and_then(|s| s.as_str()).unwrap_or_default().to_string(),
+                                    correlation_id,
+                                };
+
+                                debug!(
+                                    "[on_message] {} <- topic={}, payload={}, publisher={}, timestamp={}, session={}, correlation_id={:?}",
+                                    name_clone, message.topic, message.payload, message.publisher_name, message.timestamp, message.session_id, message.correlation_id
+                                );
+
+                                // The catch-all sees the message after the topic's handlers, or after it was buffered
+                                let for_any = any_handler_clone.lock().unwrap().is_some().then(|| message.clone());
+
+                                // Lock order (pending, then handlers) matches on_message so buffered
+                                // messages are always delivered before newer ones
+                                let mut pending = pending_clone.lock().unwrap();
+                                if let Some(callbacks) = handlers_clone.lock().unwrap().get(topic).filter(|callbacks| !callbacks.is_empty()) {
+                                    // Invoke every callback for the topic, in registration order
+                                    for callback in callbacks {
+                                        callback(message.clone());
+                                    }
+                                } else if request_reply::is_reply_topic(topic) {
+                                    // The request it answers has already timed out or been answered
+                                    debug!("[request] {} dropped a late reply on {}", name_clone, topic);
+                                } else {
+                                    // Keep it briefly in case the handler is registered right after subscribing
+                                    let queue = pending.entry(topic.to_string()).or_default();
+                                    queue.retain(|(received, _)| received.elapsed() <= UNHANDLED_MESSAGE_GRACE);
+                                    if queue.len() >= UNHANDLED_MESSAGE_LIMIT {
+                                        queue.pop_front();
+                                    }
+                                    queue.push_back((Instant::now(), message));
+                                }
+                                drop(pending);
+                                if let Some(message) = for_any {
+                                    if let Some(callback) = any_handler_clone.lock().unwrap().as_ref() {
+                                        callback(message);
+                                    }
+                                }
+                            }
+                            Err(_) => {
+                                warn!("[on_message] {} received malformed text: {}", name_clone, txt);
+                            }
                         }
                     }
                 }
+
+                match &failure {
+                    None => info!("[on_message] {} connection closed: code={:?}, reason={:?}", name_clone, close.0, close.1),
+                    Some(e) => warn!("[on_message] {} connection failed: {}", name_clone, e),
+                }
+                // No ack can arrive now; dropping the waiters fails their commands at once
+                ack_waiters_clone.lock().unwrap().clear();
+
+                // A reconnecting client carries on with a replacement connection
+                let Some(reconnector) = reconnector.as_ref() else {
+                    *is_connected_clone.lock().unwrap() = false;
+                    break (close, failure);
+                };
+                match reconnector.reconnect().await {
+                    Some(stream) => {
+                        ws_receiver = stream;
+                        // The resubscribes start delta topics over from a full object
+                        delta_state.clear();
+                    }
+                    None => break (close, failure),
+                }
+            };
+
+            // The server closed the socket or the stream failed, and the client is not reconnecting
+            // Lock order (close info, then handler) matches on_close so the callback runs exactly once
+            *close_info_clone.lock().unwrap() = Some(close.clone());
+            if let Some(callback) = close_handler_clone.lock().unwrap().as_ref() {
+                callback(close.0, close.1);
             }
+            let _ = close_outcome_tx.send(Some(failure.map_or(Ok(close.0), Err)));
         });
 
-        println!("[connect] client_name={}, session_id={} -- complete", client_name, session_id);
+        info!("[connect] client_name={}, session_id={} -- complete", client_name, session_id);
 
         Ok(Self {
             name: client_name.to_string(),
             session_id: session_id.to_string(),
             ws_channel,
             on_message_handlers: handlers,
-            _async_task_handler: task,
-            is_connected: Arc::new(Mutex::new(true)),
+            on_binary_handlers: binary_handlers,
+            pending_messages: pending,
+            receive_task: task,
+            is_connected,
+            reconnecting,
+            closing,
+            subscriptions,
+            on_close_handler: close_handler,
+            on_error_handler: error_handler,
+            on_any_handler: any_handler,
+            close_info,
+            close_outcome: close_outcome_rx,
+            server_capabilities: capabilities_rx,
+            ack_waiters,
+            session_key,
+            wrapped_session_key,
+            subscriber_counts,
+            codec,
+            next_command_id: 1,
             auth_token: Arc::new(Mutex::new(None)),
             token_expiry: Arc::new(Mutex::new(None)),
+            refresh_token: Arc::new(Mutex::new(None)),
             auth_url: None,
         })
     }
 
-    /// Connects to a WebSocket server with JWT authentication
+    /// Connects with a specific session ID, retrying while the server sheds load.
+    ///
+    /// A 429 or 503 rejection is retried after the server's `Retry-After` delay, up to
+    /// `max_attempts` attempts in total. Any other error is returned immediately.
+    pub async fn connect_with_retry(
+        client_name: &str,
+        session_id: &str,
+        ws_url: &str,
+        max_attempts: u32,
+    ) -> Result<Self, WsError> {
+        let mut attempt = 1;
+        loop {
+            match Self::connect_with_session(client_name, session_id, ws_url).await {
+                Err(e) if attempt < max_attempts => match overload_retry_after(&e) {
+                    Some(wait) => {
+                        info!("[connect] {} rejected by overloaded server, retrying in {:?} (attempt {}/{})",
+                            client_name, wait, attempt, max_attempts);
+                        tokio::time::sleep(wait).await;
+                        attempt += 1;
+                    }
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Connects to a WebSocket server with JWT authentication. The server takes the client's
+    /// name from the token, so no `register-name:` is sent, and `register-session:` only when
+    /// the token carries no session.
     pub async fn connect_with_auth(
         client_name: &str,
         ws_url: &str,
@@ -125,24 +875,34 @@ impl WsClient {
         username: &str,
         password: &str,
         session_id: Option<&str>,
-    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        println!("[connect_with_auth] Getting JWT token for {}...", username);
+    ) -> Result<Self, WsError> {
+        info!("[connect_with_auth] Getting JWT token for {}...", username);
         
         // Get JWT token from auth endpoint
-        let token_result = Self::get_auth_token(auth_url, username, password, session_id).await?;
+        let token_result = Self::get_auth_token(auth_url, username, password, session_id).await
+            .map_err(|e| WsError::Auth(e.to_string()))?;
         let token = token_result.token;
         
         // Calculate token expiry time
         let expires_at = Instant::now() + Duration::from_secs(token_result.expires_in);
         
-        println!("[connect_with_auth] JWT token obtained, expires in {} seconds", token_result.expires_in);
+        info!("[connect_with_auth] JWT token obtained, expires in {} seconds", token_result.expires_in);
         
         // Modify WebSocket URL to include token as a query parameter
         let mut ws_url_with_token = Url::parse(ws_url)?;
         ws_url_with_token.query_pairs_mut().append_pair("token", &token);
         
+        // The server binds the connection to the token's session, so subscribe and publish in it
+        let token_session = unverified_session_id(&token);
+        let options = ConnectOptions::default()
+            .with_register_name(false)
+            .with_register_session(token_session.is_none());
+        let session = token_session
+            .or(session_id.map(str::to_string))
+            .unwrap_or_else(|| format!("session-{}", client_name));
+
         // Connect to WebSocket with the token
-        let client = Self::connect(client_name, ws_url_with_token.as_str()).await?;
+        let client = Self::connect_with_options(client_name, &session, ws_url_with_token.as_str(), options).await?;
         
         // Update authentication fields
         {
@@ -151,13 +911,15 @@ impl WsClient {
             
             let mut token_expiry = client.token_expiry.lock().unwrap();
             *token_expiry = Some(expires_at);
+
+            *client.refresh_token.lock().unwrap() = token_result.refresh_token;
         }
         
         // Store auth URL for potential token refresh
         let mut client = client;
         client.auth_url = Some(auth_url.to_string());
         
-        println!("[connect_with_auth] Authenticated connection established for {}", username);
+        info!("[connect_with_auth] Authenticated connection established for {}", username);
         Ok(client)
     }
 
@@ -197,8 +959,28 @@ impl WsClient {
         Ok(token_response)
     }
 
+    /// Exchanges the refresh token at the auth server's `/auth/refresh` endpoint
+    async fn refresh_auth_token(
+        auth_url: &str,
+        refresh_token: &str,
+    ) -> Result<JwtAuthResponse, Box<dyn Error + Send + Sync>> {
+        // The refresh endpoint sits next to the token endpoint, e.g. /auth/token -> /auth/refresh
+        let refresh_url = Url::parse(auth_url)?.join("refresh")?;
+        let response = reqwest::Client::new()
+            .post(refresh_url)
+            .json(&serde_json::json!({ "refresh_token": refresh_token }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Token refresh failed: HTTP {}", response.status()).into());
+        }
+
+        Ok(response.json::<JwtAuthResponse>().await?)
+    }
+
     /// Refreshes the JWT token if needed
-    pub async fn refresh_token_if_needed(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    pub async fn refresh_token_if_needed(&mut self) -> Result<bool, WsError> {
         let needs_refresh = {
             let expiry = self.token_expiry.lock().unwrap();
             match *expiry {
@@ -206,7 +988,7 @@ impl WsClient {
                     // Refresh if token will expire in the next 5 minutes
                     let five_min = Duration::from_secs(300);
                     expires_at.checked_duration_since(Instant::now())
-                        .map_or(true, |remaining| remaining < five_min)
+                        .is_none_or(|remaining| remaining < five_min)
                 },
                 None => false, // No token, so no need to refresh
             }
@@ -215,30 +997,25 @@ impl WsClient {
         // If token needs refreshing and we have an auth URL
         if needs_refresh {
             if let Some(auth_url) = &self.auth_url {
-                // We need to re-authenticate - this would typically use a refresh token
-                // but for this example we'll assume we have the username/password stored
-                // In a real app, you'd use a more secure token refresh mechanism
-                println!("[refresh_token] Token expiring soon, refreshing...");
-                
-                // This is placeholder code - in a real app you'd implement a proper token refresh
-                // This just demonstrates the concept of refreshing a token
-                let token_result = Self::get_auth_token(
-                    auth_url, 
-                    &self.name, 
-                    "placeholder_password", 
-                    Some(&self.session_id)
-                ).await?;
+                info!("[refresh_token] Token expiring soon, refreshing...");
+
+                let refresh_token = self.refresh_token.lock().unwrap().clone()
+                    .ok_or_else(|| WsError::TokenRefresh("no refresh token; reconnect with credentials".to_string()))?;
+                let token_result = Self::refresh_auth_token(auth_url, &refresh_token).await
+                    .map_err(|e| WsError::TokenRefresh(e.to_string()))?;
                 
-                // Update token and expiry
+                // Update tokens and expiry; the old refresh token is spent
                 {
                     let mut auth_token = self.auth_token.lock().unwrap();
                     *auth_token = Some(token_result.token);
                     
                     let mut token_expiry = self.token_expiry.lock().unwrap();
                     *token_expiry = Some(Instant::now() + Duration::from_secs(token_result.expires_in));
+
+                    *self.refresh_token.lock().unwrap() = token_result.refresh_token;
                 }
                 
-                println!("[refresh_token] Token refreshed successfully");
+                info!("[refresh_token] Token refreshed successfully");
                 return Ok(true);
             }
         }
@@ -251,82 +1028,678 @@ impl WsClient {
         self.auth_token.lock().unwrap().clone()
     }
 
-    /// Subscribes the client to a specific topic within its session.
-    pub async fn subscribe(&mut self, subscriber_name: &str, topic: &str, payload: &str) {
-        println!("[subscribe] subscriber_name={}, topic={}, payload={}, session={}", 
-            subscriber_name, topic, payload, self.session_id);
-        
-        let cmd = format!("subscribe:{}|{}", topic, self.session_id);
-        if let Err(e) = self.ws_channel.send(Message::Text(cmd)).await {
-            println!("[subscribe] Error: {:?}", e);
+    /// Subscribes the client to a specific topic within its session, returning once the server
+    /// has registered the subscription. Returns an error if the client is not connected, if the
+    /// subscribe frame could not be sent, if the server refused it ([`WsError::Refused`]),
+    /// or if no ack arrived within five seconds.
+    pub async fn subscribe(&mut self, subscriber_name: &str, topic: &str, payload: &str) -> Result<(), WsError> {
+        self.subscribe_with_order(subscriber_name, topic, payload, DeliveryOrder::Ordered).await
+    }
+
+    /// Subscribes with an explicit delivery order. `DeliveryOrder::Unordered` lets the server
+    /// deliver concurrently for throughput; use it only when the handler is idempotent and order-insensitive.
+    pub async fn subscribe_with_order(
+        &mut self,
+        subscriber_name: &str,
+        topic: &str,
+        payload: &str,
+        order: DeliveryOrder,
+    ) -> Result<(), WsError> {
+        self.send_subscribe(subscriber_name, topic, payload, order.as_str()).await
+    }
+
+    /// Subscribes in delta mode: after the first message the server sends only the top-level
+    /// fields that changed, and the client rebuilds the full object before calling the handler.
+    /// Payloads on the topic are expected to be JSON objects.
+    pub async fn subscribe_delta(&mut self, subscriber_name: &str, topic: &str, payload: &str) -> Result<(), WsError> {
+        let options = format!("{},{}", DeliveryOrder::Ordered.as_str(), DELTA_OPTION);
+        self.send_subscribe(subscriber_name, topic, payload, &options).await
+    }
+
+    /// Subscribes to receive only the messages whose publisher the filter admits, e.g.
+    /// `PublisherFilter::Allow(vec!["Client1".into()])`.
+    pub async fn subscribe_filtered(
+        &mut self,
+        subscriber_name: &str,
+        topic: &str,
+        payload: &str,
+        filter: &PublisherFilter,
+    ) -> Result<(), WsError> {
+        let options = format!("{},{}", DeliveryOrder::Ordered.as_str(), filter);
+        self.send_subscribe(subscriber_name, topic, payload, &options).await
+    }
+
+    /// Subscribes to several topics with a single socket write, returning once the server has
+    /// acknowledged all of them. The subscriptions last as long as the returned guard. On error,
+    /// topics that were already acknowledged are unsubscribed again.
+    pub async fn subscribe_many(&mut self, topics: &[&str]) -> Result<SubscriptionGuard, WsError> {
+        let name = self.name.clone();
+        let options = DeliveryOrder::Ordered.as_str();
+        match self.send_subscribes(&name, topics, "", options).await {
+            Ok(topics) => Ok(self.guard(topics)),
+            Err((acked, e)) => {
+                // Dropping a guard over the acknowledged topics undoes the partial subscribe
+                drop(self.guard(acked));
+                Err(e)
+            }
         }
     }
 
-    /// Unsubscribes the client from a specific topic within its session.
-    pub async fn unsubscribe(&mut self, topic: &str) {
-        println!("[unsubscribe] topic={}, session={}", topic, self.session_id);
-        let cmd = format!("unsubscribe:{}|{}", topic, self.session_id);
-        if let Err(e) = self.ws_channel.send(Message::Text(cmd)).await {
-            println!("[unsubscribe] Error: {:?}", e);
+    /// Like `subscribe`, but the subscription lasts only as long as the returned guard.
+    pub async fn subscribe_guarded(&mut self, topic: &str) -> Result<SubscriptionGuard, WsError> {
+        self.subscribe_many(&[topic]).await
+    }
+
+    fn guard(&self, topics: Vec<String>) -> SubscriptionGuard {
+        SubscriptionGuard {
+            topics,
+            session_id: self.session_id.clone(),
+            codec: self.codec,
+            sink: self.ws_channel.clone(),
+            subscriptions: self.subscriptions.clone(),
         }
     }
 
-    /// Publishes a message to a specific topic within the client's session.
-    pub async fn publish(&mut self, publisher_name: &str, topic: &str, payload: &str, timestamp: &str) -> Result<(), String> {
-        // Check if token needs refreshing before publishing
-        if self.auth_token.lock().unwrap().is_some() {
-            if let Err(e) = self.refresh_token_if_needed().await {
-                println!("[publish] Error refreshing token: {}", e);
-                // Continue anyway with the old token
+    async fn send_subscribe(
+        &mut self,
+        subscriber_name: &str,
+        topic: &str,
+        payload: &str,
+        options: &str,
+    ) -> Result<(), WsError> {
+        self.send_subscribes(subscriber_name, &[topic], payload, options).await
+            .map(|_| ())
+            .map_err(|(_, e)| e)
+    }
+
+    // Sends one subscribe per topic, flushed together, then waits for each ack in turn.
+    // Returns the acknowledged topics, also alongside the error when one fails.
+    async fn send_subscribes(
+        &mut self,
+        subscriber_name: &str,
+        topics: &[&str],
+        payload: &str,
+        options: &str,
+    ) -> Result<Vec<String>, (Vec<String>, WsError)> {
+        // Check connection state first
+        if let Err(e) = self.ensure_connected() {
+            return Err((Vec::new(), e));
+        }
+
+        debug!("[subscribe] subscriber_name={}, topics={:?}, payload={}, session={}, options={}", 
+            subscriber_name, topics, payload, self.session_id, options);
+
+        let options = publisher_filter::split_options(options);
+        let mut waiting = Vec::with_capacity(topics.len());
+        for topic in topics {
+            let id = self.next_command_id.to_string();
+            self.next_command_id += 1;
+            let (ack_tx, ack_rx) = oneshot::channel();
+            self.ack_waiters.lock().unwrap().insert(id.clone(), ack_tx);
+            let cmd = ClientCommand::Subscribe {
+                topic: topic.to_string(),
+                session_id: Some(self.session_id.clone()),
+                options: options.clone(),
+                id: Some(id.clone()),
+            };
+            waiting.push((topic.to_string(), id, ack_rx, cmd.to_frame()));
+        }
+        let sent = {
+            let mut sink = self.channel().await;
+            let mut sent = Ok(());
+            for (_, _, _, frame) in &waiting {
+                sent = sink.feed(encode_frame(self.codec, frame.clone())).await;
+                if sent.is_err() {
+                    break;
+                }
             }
+            match sent {
+                Ok(()) => sink.flush().await,
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = sent {
+            warn!("[subscribe] Error: {:?}", e);
+            let mut ack_waiters = self.ack_waiters.lock().unwrap();
+            for (_, id, _, _) in &waiting {
+                ack_waiters.remove(id);
+            }
+            // Mark as disconnected on error
+            *self.is_connected.lock().unwrap() = false;
+            return Err((Vec::new(), WsError::Send(Box::new(e))));
         }
 
-        // Check connection state first
+        let mut acked = Vec::with_capacity(waiting.len());
+        let mut waiting = waiting.into_iter();
+        let deadline = tokio::time::Instant::now() + SUBSCRIBE_ACK_TIMEOUT;
+        let failure = loop {
+            let Some((topic, id, ack_rx, _)) = waiting.next() else {
+                break None;
+            };
+            match tokio::time::timeout_at(deadline, ack_rx).await {
+                Ok(Ok(Ok(()))) => {
+                    self.subscriptions.lock().unwrap().insert(topic.clone(), Resubscribe::Command(options.clone()));
+                    acked.push(topic);
+                }
+                Ok(Ok(Err(refused))) => break Some(WsError::Refused(refused)),
+                Ok(Err(_)) => break Some(WsError::NotConnected),
+                Err(_) => {
+                    self.ack_waiters.lock().unwrap().remove(&id);
+                    break Some(WsError::Timeout(format!("subscribe ack for {}", topic)));
+                }
+            }
+        };
+        match failure {
+            None => Ok(acked),
+            Some(e) => {
+                let mut ack_waiters = self.ack_waiters.lock().unwrap();
+                for (_, id, _, _) in waiting {
+                    ack_waiters.remove(&id);
+                }
+                Err((acked, e))
+            }
+        }
+    }
+
+    /// Registers a handler for a topic and subscribes to it in one call, so a subscription
+    /// never exists without its handler. Like `subscribe`, this waits for the server to confirm
+    /// the subscribe. `on_message` and `subscribe` remain for finer control.
+    pub async fn on<F>(&mut self, topic: &str, callback: F) -> Result<SubscriptionHandle, WsError>
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        // The handler goes in first so nothing published right after the subscribe is missed
+        self.on_message(topic, callback);
+
+        let name = self.name.clone();
+        if let Err(e) = self.subscribe(&name, topic, "").await {
+            // Only the handler added above goes; others on the topic stay
+            if let Some(callbacks) = self.on_message_handlers.lock().unwrap().get_mut(topic) {
+                callbacks.pop();
+            }
+            return Err(e);
+        }
+        Ok(SubscriptionHandle { topic: topic.to_string(),  })
+    }
+
+    /// Unsubscribes a subscription made with `on` and removes the topic's handlers. The handlers
+    /// are removed even when the unsubscribe cannot be sent.
+    pub async fn off(&mut self, handle: SubscriptionHandle) -> Result<(), WsError> {
+        self.clear_handlers(&handle.topic);
+        self.unsubscribe(&handle.topic).await
+    }
+
+    /// Unsubscribes the client from a specific topic within its session. The topic is no longer
+    /// replayed after a reconnect, even when the unsubscribe frame cannot be sent.
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), WsError> {
+        debug!("[unsubscribe] topic={}, session={}", topic, self.session_id);
+        self.subscriptions.lock().unwrap().remove(topic);
+        let cmd = ClientCommand::Unsubscribe {
+            topic: topic.to_string(),
+            session_id: Some(self.session_id.clone()),
+            id: None,
+        };
+        self.send_text(cmd.to_frame()).await.inspect_err(|e| warn!("[unsubscribe] Error: {}", e))
+    }
+
+    /// Subscribes to this connection's private direct-message topic. The server answers with a
+    /// `self_subscribed` frame carrying the address; for an authenticated client it is the token's `sub`,
+    /// so messages can be handled with `on_message(&direct::direct_topic(sub), ...)`.
+    pub async fn subscribe_self(&mut self) -> Result<(), WsError> {
+        debug!("[subscribe-self] session={}", self.session_id);
+        self.send_text("subscribe-self".to_string()).await
+    }
+
+    /// Sends a direct message to the connections that called `subscribe_self` under `address`.
+    pub async fn publish_to(&mut self, address: &str, payload: &str) -> Result<(), WsError> {
+        debug!("[publish-to] address={}, payload={}", address, payload);
+        self.send_text(format!("publish-to:{}|{}", address, payload)).await
+    }
+
+    /// Turns on per-connection features such as `Acks` and `Presence`, replacing any earlier
+    /// negotiation. The server answers with a `negotiated` frame listing what it enabled.
+    pub async fn negotiate(&mut self, features: &[Capability]) -> Result<(), WsError> {
+        let names: Vec<&str> = features.iter().map(Capability::as_str).collect();
+        debug!("[negotiate] features={:?}", names);
+        self.send_text(format!("negotiate:{}", names.join(","))).await
+    }
+
+    /// Subscribes to a topic with binary delivery: each message arrives as raw bytes at the
+    /// handler registered with `on_binary`. Register the handler first; frames for topics
+    /// without a handler are dropped.
+    pub async fn subscribe_binary(&mut self, topic: &str) -> Result<(), WsError> {
+        debug!("[subscribe_binary] topic={}, session={}", topic, self.session_id);
+        self.send_frame(BinaryFrame::subscribe(topic, &self.session_id)).await?;
+        self.subscriptions.lock().unwrap().insert(topic.to_string(), Resubscribe::Binary);
+        Ok(())
+    }
+
+    /// Publishes raw bytes to a topic within the client's session. Binary subscribers receive
+    /// the bytes as-is; text subscribers receive them base64-encoded.
+    pub async fn publish_binary(&mut self, topic: &str, payload: &[u8]) -> Result<(), WsError> {
+        debug!("[publish_binary] topic={}, {} bytes, session={}", topic, payload.len(), self.session_id);
+        self.send_frame(BinaryFrame::publish(topic, &self.session_id, payload.to_vec())).await
+    }
+
+    async fn send_frame(&mut self, frame: BinaryFrame) -> Result<(), WsError> {
+        self.ensure_connected()?;
+        let bytes = frame.encode().map_err(|e| WsError::Serialization(e.to_string()))?;
+        self.send_message(Message::Binary(bytes)).await
+    }
+
+    // Fails without touching the socket when the connection is known to be down
+    fn ensure_connected(&self) -> Result<(), WsError> {
+        if *self.reconnecting.lock().unwrap() {
+            return Err(WsError::Reconnecting);
+        }
         if !*self.is_connected.lock().unwrap() {
-            return Err("WebSocket is not connected".to_string());
+            return Err(WsError::NotConnected);
         }
+        Ok(())
+    }
+
+    async fn send_text(&mut self, frame: String) -> Result<(), WsError> {
+        self.ensure_connected()?;
+        self.send_message(encode_frame(self.codec, frame)).await
+    }
 
-        println!("[publish] publisher_name={}, topic={}, payload={}, timestamp={}, session={}", 
+    // Writes one frame, marking the client disconnected when the write fails
+    async fn send_message(&mut self, message: Message) -> Result<(), WsError> {
+        if let Err(e) = self.channel().await.send(message).await {
+            *self.is_connected.lock().unwrap() = false;
+            return Err(WsError::Send(Box::new(e)));
+        }
+        Ok(())
+    }
+
+    /// Publishes a message to a specific topic within the client's session.
+    pub async fn publish(&mut self, publisher_name: &str, topic: &str, payload: &str, timestamp: &str) -> Result<(), WsError> {
+        self.publish_value(publisher_name, topic, Value::from(payload), timestamp).await
+    }
+
+    /// Publishes any JSON value, such as an object or array, to a topic within the client's
+    /// session. Subscribers receive it as that value rather than as an encoded string.
+    pub async fn publish_value(&mut self, publisher_name: &str, topic: &str, payload: Value, timestamp: &str) -> Result<(), WsError> {
+        debug!("[publish] publisher_name={}, topic={}, payload={}, timestamp={}, session={}", 
             publisher_name, topic, payload, timestamp, self.session_id);
-        
-        let msg = json!({
-            "publisher_name": publisher_name,
-            "topic": topic,
-            "payload": payload,
-            "timestamp": timestamp,
-            "session_id": self.session_id
-        });
-        let cmd = format!("publish-json:{}", msg.to_string());
+        let publish = self.publish_command(publisher_name, topic, payload, timestamp);
+        self.send_publish(publish).await
+    }
+
+    /// Publishes a JSON value gzipped, for large payloads. Subscribers using `WsClient` receive
+    /// it inflated; others see `"encoding":"gzip"` and a base64 payload. The base64 step costs a
+    /// third on top of the compressed size, so small payloads are better sent with `publish_value`.
+    pub async fn publish_compressed(&mut self, publisher_name: &str, topic: &str, payload: &Value, timestamp: &str) -> Result<(), WsError> {
+        let compressed = compression::compress_payload(payload);
+        debug!("[publish_compressed] publisher_name={}, topic={}, {} bytes compressed to {}, session={}",
+            publisher_name, topic, payload.to_string().len(), compressed.as_str().map_or(0, str::len), self.session_id);
+        let mut publish = self.publish_command(publisher_name, topic, compressed, timestamp);
+        publish.encoding = Some(compression::GZIP_ENCODING.to_string());
+        self.send_publish(publish).await
+    }
 
-        match self.ws_channel.send(Message::Text(cmd)).await {
-            Ok(_) => Ok(()),
+    /// Publishes a JSON value encrypted with AES-256-GCM under the session key, which must first be
+    /// obtained with `exchange_session_key`. The server forwards the ciphertext as it is; only
+    /// subscribers holding the session key can read it.
+    pub async fn publish_encrypted(&mut self, publisher_name: &str, topic: &str, payload: &Value, timestamp: &str) -> Result<(), WsError> {
+        let encrypted = {
+            let session_key = self.session_key.lock().unwrap();
+            let session_key = session_key.as_ref()
+                .ok_or_else(|| WsError::Encryption("no session key; call exchange_session_key first".to_string()))?;
+            session_crypto::encrypt_payload(payload, session_key, topic, &self.session_id)
+                .map_err(|e| WsError::Encryption(e.to_string()))?
+        };
+        debug!("[publish_encrypted] publisher_name={}, topic={}, session={}", publisher_name, topic, self.session_id);
+        let mut publish = self.publish_command(publisher_name, topic, encrypted, timestamp);
+        publish.encoding = Some(session_crypto::AES_GCM_ENCODING.to_string());
+        self.send_publish(publish).await
+    }
+
+    /// Publishes a request and waits for exactly one reply, returning its payload. The request
+    /// carries a fresh correlation id and a `reply_to` topic derived from it, which the client
+    /// subscribes to for the duration of the call. A responder publishes its answer to `reply_to`
+    /// in the same session. Fails if no reply arrives within `timeout`; replies arriving after
+    /// that are dropped.
+    pub async fn request(&mut self, topic: &str, payload: &str, timeout: Duration) -> Result<String, WsError> {
+        let correlation_id = crate::new_correlation_id();
+        let reply_topic = request_reply::reply_topic(&correlation_id);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let reply_tx = Mutex::new(Some(reply_tx));
+        // Only the first reply resolves the request
+        self.on_message(&reply_topic, move |reply| {
+            if let Some(reply_tx) = reply_tx.lock().unwrap().take() {
+                let _ = reply_tx.send(reply);
+            }
+        });
+        let subscription = match self.subscribe_guarded(&reply_topic).await {
+            Ok(subscription) => subscription,
             Err(e) => {
-                // Mark as disconnected on error
-                *self.is_connected.lock().unwrap() = false;
-                Err(format!("Failed to send message: {}", e))
+                self.clear_handlers(&reply_topic);
+                return Err(e);
             }
+        };
+
+        debug!("[request] topic={}, reply_to={}, session={}", topic, reply_topic, self.session_id);
+        let mut publish = self.publish_command(&self.name, topic, Value::from(payload), &now_rfc3339());
+        publish.correlation_id = Some(correlation_id);
+        publish.reply_to = Some(reply_topic.clone());
+        let outcome = match self.send_publish(publish).await {
+            Ok(()) => match tokio::time::timeout(timeout, reply_rx).await {
+                Ok(Ok(reply)) => Ok(reply),
+                Ok(Err(_)) => Err(WsError::NotConnected),
+                Err(_) => Err(WsError::Timeout(format!("a reply on {} within {:?}", reply_topic, timeout))),
+            },
+            Err(e) => Err(e),
+        };
+
+        // Removing the handler before unsubscribing means a late reply is dropped, not buffered
+        self.clear_handlers(&reply_topic);
+        self.pending_messages.lock().unwrap().remove(&reply_topic);
+        drop(subscription);
+        outcome
+    }
+
+    /// Asks the server how many live connections a publish to `topic` in the client's session would
+    /// reach, wildcard subscribers included, so a publisher can skip building messages nobody receives.
+    pub async fn subscriber_count(&mut self, topic: &str) -> Result<usize, WsError> {
+        debug!("[has-subscribers] topic={}, session={}", topic, self.session_id);
+        let id = self.next_command_id.to_string();
+        self.next_command_id += 1;
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.ack_waiters.lock().unwrap().insert(id.clone(), ack_tx);
+        let cmd = ClientCommand::HasSubscribers {
+            topic: topic.to_string(),
+            session_id: Some(self.session_id.clone()),
+            id: Some(id.clone()),
+        };
+        if let Err(e) = self.send_text(cmd.to_frame()).await {
+            self.ack_waiters.lock().unwrap().remove(&id);
+            return Err(e);
         }
+        match tokio::time::timeout(SUBSCRIBER_COUNT_TIMEOUT, ack_rx).await {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(refused))) => return Err(WsError::Refused(refused)),
+            Ok(Err(_)) => return Err(WsError::NotConnected),
+            Err(_) => {
+                self.ack_waiters.lock().unwrap().remove(&id);
+                return Err(WsError::Timeout(format!("a subscriber count for {}", topic)));
+            }
+        }
+        self.subscriber_counts.lock().unwrap().remove(&id)
+            .ok_or_else(|| WsError::Transport("subscriber count answer was lost".to_string()))
     }
 
-    /// Registers a callback to handle messages for a specific topic.
+    /// Obtains the session's encryption key. Generates a keypair on the curve of
+    /// `server_public_key`, the key served at `/enc/public-key`, sends its public half, and
+    /// decrypts the session key the server answers with. Until this succeeds, encrypted messages
+    /// are skipped and `publish_encrypted` fails.
+    pub async fn exchange_session_key(&mut self, server_public_key: &str) -> Result<(), WsError> {
+        let keypair = session_crypto::client_keypair_for(server_public_key).map_err(|e| WsError::Encryption(e.to_string()))?;
+        debug!("[key-exchange] session={}", self.session_id);
+
+        let id = self.next_command_id.to_string();
+        self.next_command_id += 1;
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.ack_waiters.lock().unwrap().insert(id.clone(), ack_tx);
+        let cmd = ClientCommand::KeyExchange { public_key: keypair.public_key.clone(), id: Some(id.clone()) };
+        if let Err(e) = self.send_text(cmd.to_frame()).await {
+            self.ack_waiters.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        match tokio::time::timeout(KEY_EXCHANGE_TIMEOUT, ack_rx).await {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(refused))) => return Err(WsError::Refused(refused)),
+            Ok(Err(_)) => return Err(WsError::NotConnected),
+            Err(_) => {
+                self.ack_waiters.lock().unwrap().remove(&id);
+                return Err(WsError::Timeout("an answer to the key exchange".to_string()));
+            }
+        }
+
+        let wrapped = self.wrapped_session_key.lock().unwrap().take()
+            .ok_or_else(|| WsError::Encryption("key exchange answer had no key".to_string()))?;
+        let key = session_crypto::unwrap_session_key(&keypair, server_public_key, &wrapped)
+            .map_err(|e| WsError::Encryption(e.to_string()))?;
+        *self.session_key.lock().unwrap() = Some(key);
+        Ok(())
+    }
+
+    fn publish_command(&self, publisher_name: &str, topic: &str, payload: Value, timestamp: &str) -> PublishCommand {
+        PublishCommand {
+            topic: topic.to_string(),
+            payload,
+            publisher_name: Some(publisher_name.to_string()),
+            timestamp: Some(timestamp.to_string()),
+            session_id: Some(self.session_id.clone()),
+            ..PublishCommand::default()
+        }
+    }
+
+    async fn send_publish(&mut self, publish: PublishCommand) -> Result<(), WsError> {
+        // Check if token needs refreshing before publishing
+        if self.auth_token.lock().unwrap().is_some() {
+            if let Err(e) = self.refresh_token_if_needed().await {
+                warn!("[publish] Error refreshing token: {}", e);
+                // Continue anyway with the old token
+            }
+        }
+
+        let cmd = ClientCommand::Publish(publish);
+        self.send_text(cmd.to_frame()).await
+    }
+
+    /// Registers a callback to handle messages for a specific topic. A topic may have several
+    /// callbacks; each message reaches all of them in the order they were registered.
+    /// Messages for the topic received shortly before the first registration are delivered first.
     pub fn on_message<F>(&mut self, topic: &str, callback: F)
     where
         F: Fn(String) + Send + Sync + 'static,
     {
-        println!("[on_message] registering handler for topic: {}", topic);
+        self.on_message_with_correlation(topic, move |payload, _| callback(payload));
+    }
+
+    /// Registers a callback that also receives the message's correlation id.
+    /// The server assigns one when the publisher did not, so it is normally present.
+    pub fn on_message_with_correlation<F>(&mut self, topic: &str, callback: F)
+    where
+        F: Fn(String, Option<String>) + Send + Sync + 'static,
+    {
+        self.on_message_full(topic, move |message| callback(message.payload, message.correlation_id));
+    }
+
+    /// Registers a callback that receives the whole message: topic, payload, publisher,
+    /// timestamp, session and correlation id. Handlers added with `on_message` share the topic.
+    pub fn on_message_full<F>(&mut self, topic: &str, callback: F)
+    where
+        F: Fn(IncomingMessage) + Send + Sync + 'static,
+    {
+        debug!("[on_message] registering handler for topic: {}", topic);
+        let mut pending = self.pending_messages.lock().unwrap();
+        if let Some(queue) = pending.remove(topic) {
+            for (received, message) in queue {
+                if received.elapsed() <= UNHANDLED_MESSAGE_GRACE {
+                    callback(message);
+                }
+            }
+        }
         self.on_message_handlers
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default()
+            .push(Box::new(callback));
+    }
+
+    /// Removes every callback registered for a topic. The subscription stays; messages that
+    /// arrive without a callback are buffered briefly, as before the first registration.
+    pub fn clear_handlers(&mut self, topic: &str) {
+        debug!("[on_message] clearing handlers for topic: {}", topic);
+        self.on_message_handlers.lock().unwrap().remove(topic);
+    }
+
+    /// Registers a callback for binary publish frames on a topic.
+    pub fn on_binary<F>(&mut self, topic: &str, callback: F)
+    where
+        F: Fn(Vec<u8>) + Send + Sync + 'static,
+    {
+        debug!("[on_binary] registering handler for topic: {}", topic);
+        self.on_binary_handlers
             .lock()
             .unwrap()
             .insert(topic.to_string(), Box::new(callback));
     }
 
+    /// Registers a callback for when the server ends the connection. It receives the close code
+    /// and reason from the server's close frame; the code is `None` when the socket ended without
+    /// one. Runs immediately if the connection has already ended. After [`WsClient::close`] it
+    /// receives the code the server answered with; it is not called when the client is dropped.
+    pub fn on_close<F>(&mut self, callback: F)
+    where
+        F: Fn(Option<u16>, String) + Send + Sync + 'static,
+    {
+        let close_info = self.close_info.lock().unwrap();
+        if let Some((code, reason)) = close_info.clone() {
+            callback(code, reason);
+            return;
+        }
+        *self.on_close_handler.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// Registers a callback for error frames, sent when the server refuses a command this client
+    /// sent: bad JSON, a missing topic, a denied topic and so on. Replaces any earlier callback.
+    pub fn on_error<F>(&mut self, callback: F)
+    where
+        F: Fn(ServerError) + Send + Sync + 'static,
+    {
+        *self.on_error_handler.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// Registers a catch-all callback for every message the client receives, whatever its topic.
+    /// It runs after the topic's own handlers, and also for topics that have none, in which case
+    /// the message is still buffered for a handler registered later. Replaces any earlier callback.
+    pub fn on_any<F>(&mut self, callback: F)
+    where
+        F: Fn(IncomingMessage) + Send + Sync + 'static,
+    {
+        *self.on_any_handler.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// Capabilities the server advertised, or `None` before its `server_hello` has arrived.
+    pub fn server_capabilities(&self) -> Option<Vec<Capability>> {
+        self.server_capabilities.borrow().clone()
+    }
+
+    /// Fails unless the server advertises every required capability, so a client that depends
+    /// on a feature does not silently proceed without it. Chain it onto a connect call:
+    ///
+    /// ```ignore
+    /// let client = WsClient::connect("Client1", url).await?
+    ///     .require_capabilities(&[Capability::Resume]).await?;
+    /// ```
+    ///
+    /// Waits briefly for the `server_hello` if it has not arrived yet. The connection is closed on failure.
+    pub async fn require_capabilities(self, required: &[Capability]) -> Result<Self, WsError> {
+        let mut hello = self.server_capabilities.clone();
+        let advertised = tokio::time::timeout(SERVER_HELLO_TIMEOUT, hello.wait_for(|capabilities| capabilities.is_some())).await;
+        let advertised = match advertised {
+            Ok(Ok(capabilities)) => capabilities.clone().unwrap_or_default(),
+            _ => return Err(CapabilityError::NotAdvertised.into()),
+        };
+        let missing: Vec<Capability> = required.iter().filter(|c| !advertised.contains(c)).copied().collect();
+        if !missing.is_empty() {
+            warn!("[require_capabilities] {} missing {:?}, closing", self.name, missing);
+            let _ = self.channel().await.close().await;
+            return Err(CapabilityError::Missing(missing).into());
+        }
+        Ok(self)
+    }
+
+    /// Closes the connection with a normal close frame and waits for the server's answer.
+    /// Returns the code of the server's close frame. If the server was closing the connection
+    /// at the same moment, its close frame serves as the answer and the close is still clean;
+    /// an error means the transport failed before the handshake completed.
+    pub async fn close(&mut self) -> Result<Option<u16>, WsError> {
+        *self.closing.lock().unwrap() = true;
+        let frame = CloseFrame { code: CloseCode::Normal, reason: "".into() };
+        // Refused once the server's close frame has already been answered, which ends the handshake too
+        if let Err(e) = self.channel().await.send(Message::Close(Some(frame))).await {
+            warn!("[close] {} did not send a close frame: {}", self.name, e);
+        }
+        let mut outcome = self.close_outcome.clone();
+        let ended = tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, outcome.wait_for(|outcome| outcome.is_some())).await;
+        let outcome = match ended {
+            Ok(Ok(outcome)) => outcome.clone().unwrap_or(Ok(None)),
+            Ok(Err(_)) => Err("receive task stopped".to_string()),
+            Err(_) => return Err(WsError::Timeout("the server to answer the close frame".to_string())),
+        };
+        match outcome {
+            Ok(code) => {
+                info!("[close] {} closed cleanly: code={:?}", self.name, code);
+                Ok(code)
+            }
+            Err(e) => Err(WsError::Transport(e)),
+        }
+    }
+
     /// Checks if the WebSocket connection is active.
     pub fn is_connected(&self) -> bool {
         *self.is_connected.lock().unwrap()
     }
 
+    /// Whether a client made with `connect_with_reconnect` is replacing a dropped connection.
+    pub fn is_reconnecting(&self) -> bool {
+        *self.reconnecting.lock().unwrap()
+    }
+
+    // The current connection's sink, which a reconnect may have replaced
+    async fn channel(&self) -> tokio::sync::MutexGuard<'_, WsSink> {
+        self.ws_channel.lock().await
+    }
+
     /// Checks if the client is authenticated with a JWT token
     pub fn is_authenticated(&self) -> bool {
         self.auth_token.lock().unwrap().is_some()
     }
 }
+
+impl Drop for WsClient {
+    /// Stops the background receive task and sends a best-effort close frame so the
+    /// socket is released as soon as the client is dropped.
+    fn drop(&mut self) {
+        debug!("[drop] {} closing connection", self.name);
+        self.receive_task.abort();
+        // Drop cannot await; a close frame that does not go out immediately is skipped
+        if let Ok(mut sink) = self.ws_channel.try_lock() {
+            let _ = sink.send(Message::Close(None)).now_or_never();
+        }
+        *self.is_connected.lock().unwrap() = false;
+    }
+}
+
+/// How long to wait before retrying a connection the server refused because it was overloaded.
+/// Returns `None` for errors that are not overload rejections.
+fn overload_retry_after(error: &WsError) -> Option<Duration> {
+    let WsError::Connect(error) = error else {
+        return None;
+    };
+    let tokio_tungstenite::tungstenite::Error::Http(response) = error.as_ref() else {
+        return None;
+    };
+    if response.status() != StatusCode::TOO_MANY_REQUESTS && response.status() != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+    let retry_after = response.headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    Some(retry_after.unwrap_or(DEFAULT_RETRY_AFTER))
+}
+
+/// A command's JSON text as the frame sent in the connection's codec.
+fn encode_frame(codec: WireFormat, text: String) -> Message {
+    match codec.encode(text) {
+        EncodedFrame::Text(text) => Message::Text(text),
+        EncodedFrame::Binary(bytes) => Message::Binary(bytes),
+    }
+}
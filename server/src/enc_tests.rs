@@ -5,15 +5,32 @@ use p256::{
     EncodedPoint, PublicKey,
 };
 use rand::rngs::OsRng;
-use reqwest;
+use rand::{CryptoRng, RngCore};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use aes_gcm::{
     Aes256Gcm, KeyInit, aead::{Aead, AeadCore},
 };
 use std::error::Error;
-use std::time::SystemTime;
 use serde::{Serialize, Deserialize};
 use generic_array::GenericArray;
+use axum::Router;
+use libws::enc_api_route::{enc_api_router, create_web_compatible_state, EncApiState};
+use libws::enc_utils::{decrypt as lib_decrypt, encrypt as lib_encrypt, encrypt_with_rng, EncryptionError, KeyPair};
+use libws::jwt_api_route::create_default_jwt_state;
+use libws::jwt_utils::{configured_jwt_secret, create_token, load_jwt_key};
+use libws::timestamp::now_rfc3339;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+use reqwest::{header, StatusCode};
+use tokio::net::TcpListener;
+use futures_util::SinkExt;
+use libws::session_crypto::{decrypt_envelope, decrypt_payload, encrypt_payload, payload_aad, SessionKeys, AES_GCM_ENCODING};
+use libws::ws_client::{WsClient, WsError};
+use libws::ConnectionConfig;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use crate::test_server::{connect_raw, recv_topic, recv_type, spawn_ws_server, sync_raw};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct TestMessage {
@@ -92,20 +109,6 @@ fn decrypt(encrypted_data: &[u8], shared_secret: &[u8]) -> Result<Vec<u8>, Box<d
     Ok(plaintext)
 }
 
-// Get current timestamp in ISO format
-fn get_timestamp() -> String {
-    let now = SystemTime::now();
-    let since_epoch = now.duration_since(SystemTime::UNIX_EPOCH).unwrap();
-    let secs = since_epoch.as_secs();
-    let millis = since_epoch.subsec_millis();
-    
-    // Very simple ISO timestamp format
-    let dt = time::OffsetDateTime::from_unix_timestamp(secs as i64).unwrap();
-    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", 
-        dt.year(), dt.month() as u8, dt.day(), 
-        dt.hour(), dt.minute(), dt.second(), millis)
-}
-
 // This function will run the encryption tests that match the JavaScript tests
 pub async fn run_encryption_tests() -> Result<(), Box<dyn Error>> {
     println!("Running encryption tests from Rust client...");
@@ -133,7 +136,7 @@ pub async fn run_encryption_tests() -> Result<(), Box<dyn Error>> {
     // Create test message (matching JavaScript test)
     let test_message = TestMessage {
         text: "Hello, secure world!".to_string(),
-        timestamp: get_timestamp(),
+        timestamp: now_rfc3339(),
     };
     println!("Test message: {:?}", test_message);
     
@@ -156,3 +159,363 @@ pub async fn run_encryption_tests() -> Result<(), Box<dyn Error>> {
     
     Ok(())
 }
+
+// Fetches the public key, revalidates it with If-None-Match, then rotates and revalidates again
+pub async fn run_public_key_caching_test() -> Result<(), Box<dyn Error>> {
+    println!("Running public key caching test...");
+
+    let state = create_web_compatible_state()?;
+    let app = Router::new().merge(enc_api_router::<()>(state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/enc/public-key", listener.local_addr()?);
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let first = client.get(&url).send().await?;
+    let etag = first.headers().get(header::ETAG).ok_or("missing ETag")?.to_str()?.to_string();
+    if first.headers().get(header::CACHE_CONTROL).is_none() {
+        return Err("missing Cache-Control".into());
+    }
+    println!("Fetched public key with ETag {}", etag);
+
+    let revalidated = client.get(&url).header(header::IF_NONE_MATCH, &etag).send().await?;
+    if revalidated.status() != StatusCode::NOT_MODIFIED {
+        return Err(format!("expected 304 for unchanged key, got {}", revalidated.status()).into());
+    }
+
+    state.rotate(KeyPair::generate_p256()?);
+    let rotated = client.get(&url).header(header::IF_NONE_MATCH, &etag).send().await?;
+    if rotated.status() != StatusCode::OK {
+        return Err(format!("expected 200 after rotation, got {}", rotated.status()).into());
+    }
+    let new_etag = rotated.headers().get(header::ETAG).ok_or("missing ETag")?.to_str()?.to_string();
+    if new_etag == etag {
+        return Err("ETag did not change after rotation".into());
+    }
+    println!("Rotation served a new key with ETag {}", new_etag);
+
+    server_handle.abort();
+    Ok(())
+}
+
+// A /ws URL carrying a token bound to session-secure, as key exchange requires
+fn secure_session_url(ws_url: &str, user: &str) -> Result<String, Box<dyn Error>> {
+    let secret = load_jwt_key()?;
+    let token = create_token(user, Some("session-secure"), &secret[..], Duration::from_secs(60))?;
+    Ok(format!("{}?token={}", ws_url, token))
+}
+
+// Publishes on an encrypted topic travel as ciphertext under a per-session key that clients
+// obtain by key exchange against the key served at /enc/public-key. Only connections whose token
+// carries the session may exchange, and the key is dropped once none of them is left
+pub async fn run_encrypted_topic_test() -> Result<(), Box<dyn Error>> {
+    println!("Running encrypted topic test...");
+
+    let state = EncApiState::new(KeyPair::generate()?);
+    let app = Router::new().merge(enc_api_router::<()>(state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let key_url = format!("http://{}/enc/public-key", listener.local_addr()?);
+    let api_handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let server_public_key = reqwest::get(&key_url).await?.text().await?;
+    let session_keys = Arc::new(SessionKeys::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        encryption: Some(state),
+        encrypted_topics: vec!["secure/*".to_string()],
+        session_keys: session_keys.clone(),
+        ..Default::default()
+    }).await?;
+
+    // Key exchange by hand: the wrapped key decrypts under the X25519 secret shared with the server
+    let mut observer = connect_raw(&secure_session_url(&server.ws_url, "observer")?).await?;
+    let observer_keys = KeyPair::generate()?;
+    observer.send(Message::Text(json!({"op": "key_exchange", "public_key": observer_keys.public_key}).to_string())).await?;
+    let answer = recv_type(&mut observer, "session_key", Duration::from_secs(2)).await
+        .ok_or("key exchange was not answered")?;
+    let shared_secret = observer_keys.compute_shared_secret(&server_public_key)?;
+    let session_key = lib_decrypt(&BASE64.decode(answer["wrapped_key"].as_str().ok_or("no wrapped key")?)?, &shared_secret, &[])?;
+    observer.send(Message::Text("subscribe:secure/chat".to_string())).await?;
+    sync_raw(&mut observer).await?;
+
+    let mut publisher = WsClient::connect_with_session("SecurePublisher", "session-secure", &secure_session_url(&server.ws_url, "publisher")?).await?;
+    publisher.exchange_session_key(&server_public_key).await?;
+    let mut reader = WsClient::connect_with_session("SecureReader", "session-secure", &secure_session_url(&server.ws_url, "reader")?).await?;
+    reader.exchange_session_key(&server_public_key).await?;
+
+    // Registering into the session by name does not entitle a connection to its key
+    let mut eavesdropper = WsClient::connect_with_session("Eavesdropper", "session-secure", &server.ws_url).await?;
+    match eavesdropper.exchange_session_key(&server_public_key).await {
+        Err(WsError::Refused(refused)) if refused.code == "key_exchange_not_allowed" => {
+            println!("Key exchange without a session token refused");
+        }
+        other => return Err(format!("expected the key exchange to be refused, got {:?}", other).into()),
+    }
+    let read = Arc::new(Mutex::new(Vec::new()));
+    let overheard = Arc::new(Mutex::new(Vec::new()));
+    for (client, received) in [(&mut reader, &read), (&mut eavesdropper, &overheard)] {
+        let received = received.clone();
+        client.on("secure/chat", move |msg| received.lock().unwrap().push(msg)).await?;
+    }
+
+    let message = json!({"text": "top secret"});
+    publisher.publish_encrypted("SecurePublisher", "secure/chat", &message, &now_rfc3339()).await?;
+
+    // The server forwards ciphertext; the session key recovered by hand decrypts it
+    let envelope = recv_topic(&mut observer, "secure/chat", Duration::from_secs(2)).await
+        .ok_or("encrypted publish was not delivered")?;
+    if envelope["encoding"] != AES_GCM_ENCODING || envelope.to_string().contains("top secret") {
+        return Err(format!("delivery was not ciphertext: {}", envelope).into());
+    }
+    let plaintext = lib_decrypt(&BASE64.decode(envelope["payload"].as_str().ok_or("payload is not a string")?)?, &session_key, &payload_aad("secure/chat", "session-secure"))?;
+    if serde_json::from_slice::<Value>(&plaintext)? != message {
+        return Err("ciphertext did not decrypt to the published message".into());
+    }
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let read = read.lock().unwrap().clone();
+    if read.len() != 1 || serde_json::from_str::<Value>(&read[0])? != message {
+        return Err(format!("key holder did not read the message: {:?}", read).into());
+    }
+    if !overheard.lock().unwrap().is_empty() {
+        return Err("a client without the session key received the message".into());
+    }
+    println!("Encrypted publish read by the key holder only");
+
+    // Plaintext is refused on encrypted topics
+    observer.send(Message::Text(json!({"op": "publish", "topic": "secure/chat", "payload": "in the clear"}).to_string())).await?;
+    let error = recv_type(&mut observer, "error", Duration::from_secs(2)).await
+        .ok_or("plaintext publish was not refused")?;
+    if error["code"] != "encryption_required" {
+        return Err(format!("unexpected error frame: {}", error).into());
+    }
+
+    // The key goes once every connection that holds it has closed
+    if session_keys.len() != 1 {
+        return Err(format!("expected one session key while its holders are connected, got {:?}", session_keys).into());
+    }
+    observer.close(None).await?;
+    publisher.close().await?;
+    reader.close().await?;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+    while !session_keys.is_empty() {
+        if tokio::time::Instant::now() > deadline {
+            return Err(format!("session key outlived its holders: {:?}", session_keys).into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    println!("Session key removed after its last holder left");
+
+    server.stop();
+    api_handle.abort();
+    Ok(())
+}
+
+// An RNG that always fails, standing in for an exhausted or unavailable entropy source
+struct FailingRng;
+
+impl RngCore for FailingRng {
+    fn next_u32(&mut self) -> u32 {
+        panic!("FailingRng must only be used through try_fill_bytes")
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        panic!("FailingRng must only be used through try_fill_bytes")
+    }
+
+    fn fill_bytes(&mut self, _dest: &mut [u8]) {
+        panic!("FailingRng must only be used through try_fill_bytes")
+    }
+
+    fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> {
+        Err(rand::Error::new("entropy source unavailable"))
+    }
+}
+
+impl CryptoRng for FailingRng {}
+
+// Key and nonce generation report a failing RNG as an error instead of panicking
+pub fn run_rng_failure_test() -> Result<(), Box<dyn Error>> {
+    println!("Running RNG failure test...");
+
+    match KeyPair::generate_with_rng(&mut FailingRng) {
+        Err(EncryptionError::Rng(e)) => println!("X25519 generation failed cleanly: {}", e),
+        Ok(_) => return Err("X25519 generation succeeded with a failing RNG".into()),
+    }
+    match KeyPair::generate_p256_with_rng(&mut FailingRng) {
+        Err(EncryptionError::Rng(e)) => println!("P-256 generation failed cleanly: {}", e),
+        Ok(_) => return Err("P-256 generation succeeded with a failing RNG".into()),
+    }
+    match encrypt_with_rng(b"secret", &[7u8; 32], &[], &mut FailingRng) {
+        Err(e) if e.downcast_ref::<EncryptionError>().is_some() => println!("Encryption failed cleanly: {}", e),
+        Err(e) => return Err(format!("unexpected encryption error: {}", e).into()),
+        Ok(_) => return Err("encryption succeeded without a nonce".into()),
+    }
+
+    // The default RNG still works
+    KeyPair::generate()?;
+    KeyPair::generate_p256()?;
+    Ok(())
+}
+
+// Compiles only while the argument scrubs itself on drop
+fn assert_zeroized_on_drop<T: ZeroizeOnDrop>(_: &T) {}
+
+// Private keys, derived secrets and the JWT signing key are all wrapped so they are scrubbed on drop
+pub fn run_zeroization_test() -> Result<(), Box<dyn Error>> {
+    println!("Running key material zeroization test...");
+
+    let server = KeyPair::generate()?;
+    let client = KeyPair::generate()?;
+    let shared = server.compute_shared_secret(&client.public_key)?;
+    assert_zeroized_on_drop(&server.private_key);
+    assert_zeroized_on_drop(&shared);
+
+    let p256_server = KeyPair::generate_p256()?;
+    let p256_client = KeyPair::generate_p256()?;
+    let p256_shared = p256_server.compute_shared_secret_p256(&p256_client.public_key)?;
+    assert_zeroized_on_drop(&p256_server.private_key);
+    assert_zeroized_on_drop(&p256_shared);
+
+    let jwt_state = create_default_jwt_state();
+    assert_zeroized_on_drop(&*jwt_state.secret_key);
+    assert_zeroized_on_drop(&configured_jwt_secret().unwrap_or_default());
+
+    // The wrappers are transparent: the derived secret still works as a key
+    if *shared != *client.compute_shared_secret(&server.public_key)? {
+        return Err("zeroizing wrapper changed the derived X25519 secret".into());
+    }
+    let ciphertext = lib_encrypt(b"scrubbed", &p256_shared, &[])?;
+    if lib_decrypt(&ciphertext, &p256_client.compute_shared_secret_p256(&p256_server.public_key)?, &[])? != b"scrubbed" {
+        return Err("zeroizing wrapper broke encryption with the P-256 secret".into());
+    }
+
+    // Scrubbing a copy leaves only zeroes behind
+    let mut scrubbed = shared.clone();
+    scrubbed.zeroize();
+    if scrubbed.iter().any(|byte| *byte != 0) {
+        return Err("zeroize left key bytes behind".into());
+    }
+
+    println!("Key material is wrapped for zeroization");
+    Ok(())
+}
+
+// Server and client P-256 keypairs derive the same secret in both directions, and it works as an AES key
+pub fn run_p256_symmetry_test() -> Result<(), Box<dyn Error>> {
+    println!("Running P-256 shared secret symmetry test...");
+
+    let server = KeyPair::generate_p256()?;
+    let client = KeyPair::generate_p256()?;
+
+    let server_side = server.compute_shared_secret_p256(&client.public_key)?;
+    let client_side = client.compute_shared_secret_p256(&server.public_key)?;
+    if server_side != client_side {
+        return Err("server and client derived different P-256 shared secrets".into());
+    }
+    // Repeated derivations must be stable, not fresh per call
+    if server.compute_shared_secret_p256(&client.public_key)? != server_side {
+        return Err("P-256 shared secret changed between computations".into());
+    }
+
+    // A client using the raw p256 API agrees with the stored keypair too
+    let (browser_secret, browser_public) = generate_keypair();
+    let browser_side = derive_shared_secret(&browser_secret, &import_public_key(&server.public_key)?);
+    if *server.compute_shared_secret_p256(&export_public_key(&browser_public))? != browser_side {
+        return Err("stored keypair disagrees with an ephemeral P-256 client".into());
+    }
+
+    let plaintext = b"Hello, symmetric world!";
+    let from_server = lib_encrypt(plaintext, &server_side, &[])?;
+    if lib_decrypt(&from_server, &client_side, &[])? != plaintext {
+        return Err("client could not decrypt a message from the server".into());
+    }
+    let from_client = lib_encrypt(plaintext, &client_side, &[])?;
+    if lib_decrypt(&from_client, &server_side, &[])? != plaintext {
+        return Err("server could not decrypt a message from the client".into());
+    }
+    println!("Both sides derived the same key and round-tripped a message");
+    Ok(())
+}
+
+// Both X25519 parties derive the same secret from their stored keys, every time
+pub fn run_x25519_symmetry_test() -> Result<(), Box<dyn Error>> {
+    println!("Running X25519 shared secret symmetry test...");
+
+    let server = KeyPair::generate()?;
+    let client = KeyPair::generate()?;
+
+    let server_side = server.compute_shared_secret(&client.public_key)?;
+    let client_side = client.compute_shared_secret(&server.public_key)?;
+    if server_side != client_side {
+        return Err("server and client derived different X25519 shared secrets".into());
+    }
+    if server.compute_shared_secret(&client.public_key)? != server_side {
+        return Err("X25519 shared secret changed between computations".into());
+    }
+    // A third party derives something else with either side
+    let outsider = KeyPair::generate()?;
+    if outsider.compute_shared_secret(&server.public_key)? == server_side {
+        return Err("an unrelated keypair derived the same X25519 secret".into());
+    }
+
+    let plaintext = b"Hello, symmetric world!";
+    if lib_decrypt(&lib_encrypt(plaintext, &server_side, &[])?, &client_side, &[])? != plaintext {
+        return Err("client could not decrypt a message from the server".into());
+    }
+    println!("Both X25519 sides derived the same key and round-tripped a message");
+    Ok(())
+}
+
+// Ciphertexts only decrypt with the associated data they were sealed with, and tampering is detected
+pub fn run_aad_binding_test() -> Result<(), Box<dyn Error>> {
+    println!("Running associated data binding test...");
+
+    let key = [9u8; 32];
+    let aad = payload_aad("secure/chat", "session-a");
+    let ciphertext = lib_encrypt(b"bound", &key, &aad)?;
+    if lib_decrypt(&ciphertext, &key, &aad)? != b"bound" {
+        return Err("ciphertext did not decrypt with its own associated data".into());
+    }
+    for (other, what) in [(payload_aad("secure/other", "session-a"), "another topic"),
+                          (payload_aad("secure/chat", "session-b"), "another session"),
+                          (Vec::new(), "no associated data")] {
+        if lib_decrypt(&ciphertext, &key, &other).is_ok() {
+            return Err(format!("ciphertext decrypted with {}", what).into());
+        }
+    }
+    // Flipping any byte of the nonce, ciphertext or tag breaks authentication
+    for index in [0, 12, ciphertext.len() - 1] {
+        let mut tampered = ciphertext.clone();
+        tampered[index] ^= 0x01;
+        if lib_decrypt(&tampered, &key, &aad).is_ok() {
+            return Err(format!("tampered byte {} went undetected", index).into());
+        }
+    }
+    println!("Mismatched associated data and tampered bytes were rejected");
+
+    // Payloads are bound to the topic and session they were published in
+    let message = json!({"text": "replay me"});
+    let payload = encrypt_payload(&message, &key, "secure/chat", "session-a")?;
+    if decrypt_payload(&payload, &key, "secure/chat", "session-a") != Some(message.clone()) {
+        return Err("payload did not decrypt in its own topic and session".into());
+    }
+    if decrypt_payload(&payload, &key, "secure/other", "session-a").is_some()
+        || decrypt_payload(&payload, &key, "secure/chat", "session-b").is_some() {
+        return Err("payload decrypted outside its topic and session".into());
+    }
+
+    // An envelope replayed onto another topic is refused and left encrypted
+    let mut replayed = json!({"topic": "secure/other", "session_id": "session-a", "payload": payload, "encoding": AES_GCM_ENCODING});
+    if decrypt_envelope(&mut replayed, Some(&key)) || replayed["payload"] != payload {
+        return Err("a replayed envelope was decrypted".into());
+    }
+    let mut original = json!({"topic": "secure/chat", "session_id": "session-a", "payload": payload, "encoding": AES_GCM_ENCODING});
+    if !decrypt_envelope(&mut original, Some(&key)) || original["payload"] != message {
+        return Err("the original envelope did not decrypt".into());
+    }
+    println!("Replayed ciphertext was refused outside its topic and session");
+    Ok(())
+}
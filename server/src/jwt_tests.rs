@@ -0,0 +1,1111 @@
+// src/jwt_tests.rs
+use futures_util::{SinkExt, StreamExt};
+use axum::Router;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use libws::binary_proto::BinaryFrame;
+use libws::command::ClientCommand;
+use libws::jwt_api_route::{create_default_jwt_state, jwt_api_router, try_create_default_jwt_state};
+use libws::credentials::{HashMapVerifier, UserContext};
+use libws::jwt_utils::{
+    configured_jwt_secret, create_token, create_token_rs256, create_token_with_audience, create_token_with_claims,
+    create_token_with_issuer, decode_jwt_key, load_jwt_key, validate_refresh_token, validate_token, validate_token_rs256,
+    validate_token_with, Claims, JwtKeyError, TokenValidation, JWT_SECRET_FILE_VAR, JWT_SECRET_KEY_VAR, REFRESH_TOKEN_TYPE,
+};
+use libws::resume::ResumeClaims;
+use libws::revocation::TokenRevocation;
+use libws::ws_client::WsClient;
+use libws::authorizer::{Authorizer, TopicAuthorizer, TopicHooks};
+use libws::direct;
+use libws::ConnectionConfig;
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+use crate::test_server::{
+    connect_raw, handshake_commands, recv_topic, recv_type, spawn_recording_server, spawn_ws_server, sync_raw, RawSocket,
+};
+
+// Secret used by handle_socket to validate tokens
+fn socket_secret() -> Vec<u8> {
+    load_jwt_key().expect("JWT signing key is misconfigured").to_vec()
+}
+
+/// Runs the JWT tests against dedicated test servers.
+pub async fn run_jwt_tests() -> Result<(), Box<dyn Error>> {
+    test_audience_mismatch_rejected().await?;
+    test_reauth_preserves_subscriptions().await?;
+    test_custom_claims_reach_connection().await?;
+    test_topic_authorizers().await?;
+    test_authorizer_trait().await?;
+    test_admin_broadcast().await?;
+    test_token_issuance_limit().await?;
+    test_credential_verifier().await?;
+    test_requested_token_ttl().await?;
+    test_issued_token_accepted_on_ws().await?;
+    test_rs256_tokens()?;
+    test_token_leeway()?;
+    test_token_issuer().await?;
+    test_refresh_tokens().await?;
+    test_auth_connect_registrations().await?;
+    test_token_revocation().await?;
+    test_revocation_cleanup()?;
+    test_session_binding().await?;
+    test_resume_token_binding().await?;
+    test_secret_file().await?;
+    test_signing_key_lengths().await?;
+    Ok(())
+}
+
+// A token minted for serviceA must not be accepted by an endpoint expecting serviceB
+async fn test_audience_mismatch_rejected() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Audience mismatch test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        expected_audience: Some("serviceB".to_string()),
+        ..Default::default()
+    }).await?;
+
+    let secret = socket_secret();
+    let token_a = create_token_with_audience("alice", Some("session-aud"), Some("serviceA"), &secret, Duration::from_secs(60))?;
+    let token_b = create_token_with_audience("alice", Some("session-aud"), Some("serviceB"), &secret, Duration::from_secs(60))?;
+
+    let rejected = WsClient::connect("AudClientA", &format!("{}?token={}", server.ws_url, token_a)).await;
+    if rejected.is_ok() {
+        return Err("token with aud=serviceA was accepted by serviceB endpoint".into());
+    }
+    println!("[jwt_tests] aud=serviceA rejected: {}", rejected.err().unwrap());
+
+    WsClient::connect("AudClientB", &format!("{}?token={}", server.ws_url, token_b)).await
+        .map_err(|e| format!("token with aud=serviceB was rejected: {}", e))?;
+    println!("[jwt_tests] aud=serviceB accepted");
+
+    server.stop();
+    Ok(())
+}
+
+// An expired connection is asked to reauthenticate and keeps its subscriptions once it does
+async fn test_reauth_preserves_subscriptions() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Reauthentication test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        reauth_check_interval: Some(Duration::from_millis(200)),
+        reauth_grace: Duration::from_secs(1),
+        ..Default::default()
+    }).await?;
+
+    let secret = socket_secret();
+    let short_token = create_token("bob", Some("session-reauth"), &secret, Duration::from_secs(1))?;
+    let mut socket = connect_raw(&format!("{}?token={}", server.ws_url, short_token)).await?;
+    socket.send(Message::Text("subscribe:ReauthTopic".to_string())).await?;
+
+    recv_type(&mut socket, "reauth_required", Duration::from_secs(5)).await
+        .ok_or("server did not request reauthentication")?;
+    println!("[jwt_tests] Server requested reauthentication");
+
+    let fresh_token = create_token("bob", Some("session-reauth"), &secret, Duration::from_secs(60))?;
+    socket.send(Message::Text(format!("authenticate:{}", fresh_token))).await?;
+    recv_type(&mut socket, "reauth_ok", Duration::from_secs(2)).await
+        .ok_or("server did not accept the fresh token")?;
+
+    // Outlast the grace window, then check the subscription still delivers
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let publish = json!({
+        "publisher_name": "bob",
+        "topic": "ReauthTopic",
+        "payload": "still here",
+        "timestamp": "",
+        "session_id": "session-reauth"
+    });
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    recv_topic(&mut socket, "ReauthTopic", Duration::from_secs(2)).await
+        .ok_or("subscription was lost after reauthentication")?;
+    println!("[jwt_tests] Connection and subscription persisted after reauthentication");
+
+    server.stop();
+    Ok(())
+}
+
+// Custom claims minted into a token survive validation and are visible to the connection
+async fn test_custom_claims_reach_connection() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Custom claims test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+
+    let mut extra = serde_json::Map::new();
+    extra.insert("tenant".to_string(), json!("acme"));
+    extra.insert("plan".to_string(), json!("enterprise"));
+    let token = create_token_with_claims("carol", Some("session-claims"), None, extra, &socket_secret(), Duration::from_secs(60))?;
+
+    let mut socket = connect_raw(&format!("{}?token={}", server.ws_url, token)).await?;
+    socket.send(Message::Text("whoami".to_string())).await?;
+    let identity = recv_type(&mut socket, "identity", Duration::from_secs(2)).await
+        .ok_or("server did not report the connection identity")?;
+    if identity["user_id"] != "carol" || identity["claims"]["tenant"] != "acme" || identity["claims"]["plan"] != "enterprise" {
+        return Err(format!("custom claims were not available to the connection: {}", identity).into());
+    }
+    println!("[jwt_tests] Connection sees claims {}", identity["claims"]);
+
+    server.stop();
+    Ok(())
+}
+
+fn has_role(claims: Option<&Claims>, role: &str) -> bool {
+    claims.is_some_and(|claims| claims.has_role(role))
+}
+
+// Subscribe and publish rights come from separate hooks, so the same claims can hold one without the other
+async fn test_topic_authorizers() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Topic authorizers test...");
+
+    // Anyone may read announcements but only admins post them; anyone may report metrics but only admins read them
+    let server = spawn_ws_server(ConnectionConfig {
+        authorizer: Arc::new(TopicHooks::new(
+            TopicAuthorizer::new(|claims, topic| topic != "metrics" || has_role(claims, "admin")),
+            TopicAuthorizer::new(|claims, topic| topic != "announcements" || has_role(claims, "admin")),
+        )),
+        ..Default::default()
+    }).await?;
+
+    let connect_as = |user: &'static str, role: &'static str| {
+        let url = server.ws_url.clone();
+        async move {
+            let mut extra = serde_json::Map::new();
+            extra.insert("role".to_string(), json!(role));
+            let token = create_token_with_claims(user, Some("session-authz"), None, extra, &socket_secret(), Duration::from_secs(60))?;
+            connect_raw(&format!("{}?token={}", url, token)).await
+        }
+    };
+    let mut viewer = connect_as("viewer", "viewer").await?;
+    let mut admin = connect_as("admin", "admin").await?;
+    let publish = |topic: &str, payload: &str| {
+        let body = json!({"publisher_name": "Authz", "topic": topic, "payload": payload, "timestamp": ""});
+        Message::Text(format!("publish-json:{}", body))
+    };
+
+    // The viewer may subscribe to announcements but not publish there
+    viewer.send(Message::Text("subscribe:announcements".to_string())).await?;
+    sync_raw(&mut viewer).await?;
+    viewer.send(publish("announcements", "from viewer")).await?;
+    let error = recv_type(&mut viewer, "error", Duration::from_secs(2)).await
+        .ok_or("viewer publish to announcements was not rejected")?;
+    if error["code"] != "publish_not_allowed" || error["topic"] != "announcements" {
+        return Err(format!("unexpected publish error: {}", error).into());
+    }
+    admin.send(publish("announcements", "from admin")).await?;
+    let delivered = recv_topic(&mut viewer, "announcements", Duration::from_secs(2)).await
+        .ok_or("viewer did not receive the admin announcement")?;
+    if delivered["payload"] != "from admin" {
+        return Err(format!("unexpected announcement: {}", delivered).into());
+    }
+    println!("[jwt_tests] Viewer subscribed to announcements, publish denied: {}", error);
+
+    // The other way round: the viewer may publish metrics but not subscribe to them
+    viewer.send(Message::Text("subscribe:metrics".to_string())).await?;
+    let error = recv_type(&mut viewer, "error", Duration::from_secs(2)).await
+        .ok_or("viewer subscribe to metrics was not rejected")?;
+    if error["code"] != "subscribe_not_allowed" || error["topic"] != "metrics" {
+        return Err(format!("unexpected subscribe error: {}", error).into());
+    }
+    admin.send(Message::Text("subscribe:metrics".to_string())).await?;
+    sync_raw(&mut admin).await?;
+    viewer.send(publish("metrics", "cpu=3")).await?;
+    let delivered = recv_topic(&mut admin, "metrics", Duration::from_secs(2)).await
+        .ok_or("admin did not receive the viewer's metrics")?;
+    if delivered["payload"] != "cpu=3" {
+        return Err(format!("unexpected metrics message: {}", delivered).into());
+    }
+    println!("[jwt_tests] Viewer published metrics, subscribe denied: {}", error);
+
+    server.stop();
+    Ok(())
+}
+
+// Members-only topics and direct messages need a login, and ledger entries may only be posted by their own user
+struct LedgerAuthorizer;
+
+impl Authorizer for LedgerAuthorizer {
+    fn can_subscribe(&self, claims: Option<&Claims>, topic: &str) -> bool {
+        !topic.starts_with("members.") || claims.is_some()
+    }
+
+    fn can_publish(&self, claims: Option<&Claims>, topic: &str) -> bool {
+        if direct::is_direct_topic(topic) {
+            return claims.is_some();
+        }
+        match topic.strip_prefix("ledger.") {
+            Some(owner) => claims.is_some_and(|claims| claims.sub == owner),
+            None => true,
+        }
+    }
+}
+
+// An Authorizer on the config refuses subscribes and publishes per topic and claims, anonymous ones included
+async fn test_authorizer_trait() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Authorizer trait test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        authorizer: Arc::new(LedgerAuthorizer),
+        ..Default::default()
+    }).await?;
+    let token = create_token("grace", Some("session-ledger"), &socket_secret(), Duration::from_secs(60))?;
+    let mut grace = connect_raw(&format!("{}?token={}", server.ws_url, token)).await?;
+    let mut anonymous = connect_raw(&server.ws_url).await?;
+    anonymous.send(Message::Text("register-session:session-ledger".to_string())).await?;
+    let publish = |topic: &str| {
+        let body = json!({"publisher_name": "Ledger", "topic": topic, "payload": "entry", "timestamp": ""});
+        Message::Text(format!("publish-json:{}", body))
+    };
+
+    // Members-only topics refuse anonymous subscribers but take logged-in ones
+    anonymous.send(Message::Text("subscribe:members.news".to_string())).await?;
+    let error = recv_type(&mut anonymous, "error", Duration::from_secs(2)).await
+        .ok_or("anonymous subscribe to a members-only topic was not rejected")?;
+    if error["code"] != "subscribe_not_allowed" || error["topic"] != "members.news" {
+        return Err(format!("unexpected subscribe error: {}", error).into());
+    }
+    grace.send(Message::Text("subscribe:members.news".to_string())).await?;
+    grace.send(Message::Text("subscribe:ledger.grace".to_string())).await?;
+    sync_raw(&mut grace).await?;
+    if server.subscribers.subscriber_count("members.news", "session-ledger") != 1 {
+        return Err("the logged-in subscription to members.news was not added".into());
+    }
+
+    // Only grace may post to ledger.grace
+    anonymous.send(publish("ledger.grace")).await?;
+    let error = recv_type(&mut anonymous, "error", Duration::from_secs(2)).await
+        .ok_or("anonymous publish to a ledger was not rejected")?;
+    if error["code"] != "publish_not_allowed" || error["topic"] != "ledger.grace" {
+        return Err(format!("unexpected publish error: {}", error).into());
+    }
+    grace.send(publish("ledger.heidi")).await?;
+    let error = recv_type(&mut grace, "error", Duration::from_secs(2)).await
+        .ok_or("publish to another user's ledger was not rejected")?;
+    if error["code"] != "publish_not_allowed" || error["topic"] != "ledger.heidi" {
+        return Err(format!("unexpected publish error: {}", error).into());
+    }
+    grace.send(publish("ledger.grace")).await?;
+    recv_topic(&mut grace, "ledger.grace", Duration::from_secs(2)).await
+        .ok_or("grace's own ledger entry was not delivered")?;
+
+    // Direct messages are publishes too, so the authorizer refuses them the same way
+    grace.send(Message::Text("subscribe-self".to_string())).await?;
+    recv_type(&mut grace, "self_subscribed", Duration::from_secs(2)).await.ok_or("subscribe-self was not answered")?;
+    anonymous.send(Message::Text("publish-to:grace|psst".to_string())).await?;
+    let error = recv_type(&mut anonymous, "error", Duration::from_secs(2)).await
+        .ok_or("anonymous direct message was not rejected")?;
+    if error["code"] != "publish_not_allowed" || error["topic"] != direct::direct_topic("grace") {
+        return Err(format!("unexpected direct message error: {}", error).into());
+    }
+    if let Some(leaked) = recv_topic(&mut grace, &direct::direct_topic("grace"), Duration::from_millis(300)).await {
+        return Err(format!("a refused direct message was delivered: {}", leaked).into());
+    }
+    println!("[jwt_tests] Authorizer refused anonymous and foreign access, allowed grace's own");
+
+    server.stop();
+    Ok(())
+}
+
+// A broadcast from an admin reaches every session; anyone else is refused, and plain publishes stay scoped
+async fn test_admin_broadcast() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Admin broadcast test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut in_a = connect_raw(&server.ws_url).await?;
+    in_a.send(Message::Text("subscribe:system.notice|session-a".to_string())).await?;
+    sync_raw(&mut in_a).await?;
+    let mut in_b = connect_raw(&server.ws_url).await?;
+    in_b.send(Message::Text("subscribe:system.*|session-b".to_string())).await?;
+    sync_raw(&mut in_b).await?;
+
+    let connect_with = |user: &'static str, claim: &'static str, value: serde_json::Value| {
+        let url = server.ws_url.clone();
+        async move {
+            let mut extra = serde_json::Map::new();
+            extra.insert(claim.to_string(), value);
+            let token = create_token_with_claims(user, Some("session-ops"), None, extra, &socket_secret(), Duration::from_secs(60))?;
+            connect_raw(&format!("{}?token={}", url, token)).await
+        }
+    };
+    let mut admin = connect_with("root", "roles", json!(["ops", "admin"])).await?;
+    let mut viewer = connect_with("viewer", "role", json!("viewer")).await?;
+    let mut anonymous = connect_raw(&server.ws_url).await?;
+    let broadcast = |payload: &str| Message::Text(json!({
+        "op": "publish", "topic": "system.notice", "payload": payload, "broadcast": true, "id": "bc"
+    }).to_string());
+
+    for (socket, who) in [(&mut anonymous, "anonymous"), (&mut viewer, "viewer")] {
+        socket.send(broadcast("not allowed")).await?;
+        let error = recv_type(socket, "error", Duration::from_secs(2)).await
+            .ok_or_else(|| format!("{} broadcast was not refused", who))?;
+        if error["code"] != "broadcast_not_allowed" || error["id"] != "bc" {
+            return Err(format!("unexpected error for {}: {}", who, error).into());
+        }
+    }
+
+    admin.send(broadcast("maintenance at noon")).await?;
+    let ack = recv_type(&mut admin, "ack", Duration::from_secs(2)).await.ok_or("admin broadcast was not acknowledged")?;
+    if ack["delivered"] != 2 {
+        return Err(format!("expected the broadcast to reach 2 subscribers: {}", ack).into());
+    }
+    for (socket, session) in [(&mut in_a, "session-a"), (&mut in_b, "session-b")] {
+        let delivered = recv_topic(socket, "system.notice", Duration::from_secs(2)).await
+            .ok_or_else(|| format!("{} missed the broadcast", session))?;
+        if delivered["payload"] != "maintenance at noon" || delivered["session_id"] != session {
+            return Err(format!("unexpected broadcast in {}: {}", session, delivered).into());
+        }
+    }
+    println!("[jwt_tests] Broadcast acknowledged: {}", ack);
+
+    // An ordinary publish still reaches only its own session
+    anonymous.send(Message::Text(json!({
+        "op": "publish", "topic": "system.notice", "payload": "only a", "session_id": "session-a"
+    }).to_string())).await?;
+    recv_topic(&mut in_a, "system.notice", Duration::from_secs(2)).await.ok_or("session-a missed its own publish")?;
+    if let Some(leaked) = recv_topic(&mut in_b, "system.notice", Duration::from_millis(300)).await {
+        return Err(format!("scoped publish leaked into session-b: {}", leaked).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Token requests beyond the concurrency limit are shed with 503 and Retry-After
+async fn test_token_issuance_limit() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Token issuance limit test...");
+
+    let state = create_default_jwt_state().with_issuance_limit(2, Duration::from_secs(3));
+    let permits = state.issuance_permits.clone().ok_or("issuance limit was not configured")?;
+    let app = Router::new().merge(jwt_api_router::<()>(state));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/auth/token", listener.local_addr()?);
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let body = json!({"username": "dave", "password": "password"});
+
+    // Occupy both permits as if two slow credential checks were in flight
+    let in_flight = permits.clone().acquire_many_owned(2).await?;
+    let requests = (0..5).map(|_| client.post(&url).json(&body).send());
+    let responses = futures_util::future::join_all(requests).await;
+    for response in responses {
+        let response = response?;
+        if response.status() != reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            return Err(format!("expected 503 while saturated, got {}", response.status()).into());
+        }
+        let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or("shed token request did not include Retry-After")?;
+        if retry_after != "3" {
+            return Err(format!("expected Retry-After 3, got {}", retry_after).into());
+        }
+    }
+    println!("[jwt_tests] 5 token requests shed while saturated");
+
+    drop(in_flight);
+    let response = client.post(&url).json(&body).send().await?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(format!("token request failed after permits freed: {}", response.status()).into());
+    }
+    if permits.available_permits() != 2 {
+        return Err("token request did not release its permit".into());
+    }
+    println!("[jwt_tests] Token issued once permits were free");
+
+    server_handle.abort();
+    Ok(())
+}
+
+// A token request may ask for its own TTL up to the configured maximum; the default is clamped to it
+async fn test_requested_token_ttl() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Requested token TTL test...");
+
+    let mut state = create_default_jwt_state().with_max_token_expiration(Duration::from_secs(600));
+    state.token_expiration = Duration::from_secs(3600);
+    let secret = state.secret_key.clone();
+    let app = Router::new().merge(jwt_api_router::<()>(state));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let token_url = format!("http://{}/auth/token", listener.local_addr()?);
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let request_token = |ttl: Option<u64>| {
+        client.post(&token_url).json(&json!({"username": "svc", "password": "pw", "ttl_seconds": ttl})).send()
+    };
+    // Granted TTLs show in expires_in and in the token's own lifetime
+    for (requested, granted) in [(Some(30), 30), (Some(600), 600), (None, 600)] {
+        let response = request_token(requested).await?;
+        if response.status() != reqwest::StatusCode::OK {
+            return Err(format!("TTL {:?} was refused with {}", requested, response.status()).into());
+        }
+        let issued = response.json::<serde_json::Value>().await?;
+        let claims = validate_token(issued["token"].as_str().ok_or("no token issued")?, &secret[..])?;
+        if issued["expires_in"] != granted || claims.exp - claims.iat != granted {
+            return Err(format!("TTL {:?} granted expires_in={}, exp-iat={}, expected {}",
+                requested, issued["expires_in"], claims.exp - claims.iat, granted).into());
+        }
+    }
+    println!("[jwt_tests] Requested TTLs granted, default clamped to the maximum");
+
+    for requested in [601, 0] {
+        let response = request_token(Some(requested)).await?;
+        if response.status() != reqwest::StatusCode::BAD_REQUEST {
+            return Err(format!("TTL {} answered {} instead of 400", requested, response.status()).into());
+        }
+        let body = response.json::<serde_json::Value>().await?;
+        println!("[jwt_tests] TTL {} refused: {}", requested, body["error"]);
+    }
+
+    server_handle.abort();
+    Ok(())
+}
+
+// Only users known to the verifier get tokens, carrying the session and claims it supplies
+async fn test_credential_verifier() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Credential verifier test...");
+
+    let verifier = HashMapVerifier::default()
+        .with_user("bob", "hunter2")
+        .with_user_context("alice", "s3cret", UserContext::new("alice")
+            .with_session("session-alice")
+            .with_claim("roles", json!(["admin"])));
+    let state = create_default_jwt_state().with_verifier(Arc::new(verifier));
+    let secret = state.secret_key.clone();
+    let app = Router::new().merge(jwt_api_router::<()>(state));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/auth/token", listener.local_addr()?);
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    for (username, password) in [("alice", "wrong"), ("mallory", "s3cret"), ("alice", "")] {
+        let response = client.post(&url).json(&json!({"username": username, "password": password})).send().await?;
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Err(format!("{}/{} got {}, expected 401", username, password, response.status()).into());
+        }
+    }
+
+    // The verifier's session overrides the requested one
+    let response = client.post(&url)
+        .json(&json!({"username": "alice", "password": "s3cret", "session_id": "session-other"}))
+        .send().await?;
+    let token = response.json::<serde_json::Value>().await?["token"].as_str().ok_or("no token issued")?.to_string();
+    let claims = validate_token(&token, &secret[..])?;
+    if claims.sub != "alice" || claims.sid.as_deref() != Some("session-alice") || claims.extra.get("roles") != Some(&json!(["admin"])) {
+        return Err(format!("unexpected claims: {:?}", claims).into());
+    }
+    println!("[jwt_tests] alice issued sid={:?}, roles={:?}", claims.sid, claims.extra.get("roles"));
+
+    // Without a session from the verifier the requested one is used
+    let response = client.post(&url)
+        .json(&json!({"username": "bob", "password": "hunter2", "session_id": "session-bob"}))
+        .send().await?;
+    let token = response.json::<serde_json::Value>().await?["token"].as_str().ok_or("no token issued")?.to_string();
+    let claims = validate_token(&token, &secret[..])?;
+    if claims.sub != "bob" || claims.sid.as_deref() != Some("session-bob") || !claims.extra.is_empty() {
+        return Err(format!("unexpected claims: {:?}", claims).into());
+    }
+
+    server_handle.abort();
+    Ok(())
+}
+
+// The endpoint validates with its issuer's key, whatever JWT_SECRET_KEY says
+async fn test_issued_token_accepted_on_ws() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Issued token on /ws test...");
+
+    let mut state = create_default_jwt_state();
+    state.secret_key = Arc::new(Zeroizing::new([0x42; 32]));
+    let app = Router::new().merge(jwt_api_router::<()>(state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/auth/token", listener.local_addr()?);
+    let token_server = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let server = spawn_ws_server(ConnectionConfig { jwt: Some(state), ..Default::default() }).await?;
+
+    let response = reqwest::Client::new().post(&url)
+        .json(&json!({"username": "erin", "password": "pw", "session_id": "session-issued"}))
+        .send().await?;
+    let token = response.json::<serde_json::Value>().await?["token"].as_str().ok_or("no token issued")?.to_string();
+    // An accepted token names the connection after its subject
+    let mut socket = connect_raw(&format!("{}?token={}", server.ws_url, token)).await?;
+    socket.send(Message::Text("list-presence:".to_string())).await?;
+    let roster = recv_type(&mut socket, "presence_list", Duration::from_secs(2)).await.ok_or("presence query was not answered")?;
+    if roster["session"] != "session-issued" || roster["members"] != json!(["erin"]) {
+        return Err(format!("token from /auth/token was not accepted on /ws: {}", roster).into());
+    }
+    println!("[jwt_tests] Token from /auth/token accepted on /ws: {}", roster);
+
+    // A token signed with the environment's key is not
+    let foreign = create_token("mallory", Some("session-foreign"), &socket_secret(), Duration::from_secs(60))?;
+    let mut socket = connect_raw(&format!("{}?token={}", server.ws_url, foreign)).await?;
+    socket.send(Message::Text("list-presence:session-foreign".to_string())).await?;
+    let roster = recv_type(&mut socket, "presence_list", Duration::from_secs(2)).await.ok_or("presence query was not answered")?;
+    if roster["members"] != json!([]) {
+        return Err(format!("a token signed with another key was accepted: {}", roster).into());
+    }
+
+    server.stop();
+    token_server.abort();
+    Ok(())
+}
+
+// An RS256 token verifies with the signer's public key only, and never as an HS256 token
+fn test_rs256_tokens() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] RS256 tokens...");
+    let private_key = include_bytes!("../testdata/jwt_rs256_a.key");
+    let public_key = include_bytes!("../testdata/jwt_rs256_a.pub");
+    let other_public_key = include_bytes!("../testdata/jwt_rs256_b.pub");
+
+    let token = create_token_rs256("user123", Some("session-rs256"), private_key, Duration::from_secs(60))?;
+    let claims = validate_token_rs256(&token, public_key)?;
+    if claims.sub != "user123" || claims.sid.as_deref() != Some("session-rs256") {
+        return Err(format!("unexpected RS256 claims: {:?}", claims).into());
+    }
+    println!("[jwt_tests] RS256 token verified with the matching public key");
+
+    if validate_token_rs256(&token, other_public_key).is_ok() {
+        return Err("RS256 token verified against the wrong public key".into());
+    }
+    if validate_token(&token, &socket_secret()).is_ok() {
+        return Err("RS256 token accepted by HS256 validation".into());
+    }
+    let hs256 = create_token("user123", None, &socket_secret(), Duration::from_secs(60))?;
+    if validate_token_rs256(&hs256, public_key).is_ok() {
+        return Err("HS256 token accepted by RS256 validation".into());
+    }
+    println!("[jwt_tests] Wrong key and wrong algorithm rejected");
+    Ok(())
+}
+
+// A token just past its expiry is accepted within the leeway and rejected beyond it
+fn test_token_leeway() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Expiry leeway...");
+    let secret = socket_secret();
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    let expired_at = |exp: u64| jsonwebtoken::encode(&jsonwebtoken::Header::default(), &Claims {
+        sub: "frank".to_string(),
+        sid: None,
+        aud: None,
+        iss: None,
+        iat: exp - 60,
+        exp,
+        typ: None,
+        jti: None,
+        extra: Default::default(),
+    }, &jsonwebtoken::EncodingKey::from_secret(&secret));
+
+    let just_expired = expired_at(now - 5)?;
+    let lenient = TokenValidation { leeway_secs: 30, ..Default::default() };
+    validate_token_with(&just_expired, &secret, &lenient)
+        .map_err(|e| format!("token 5s past expiry rejected with 30s leeway: {}", e))?;
+    let strict = TokenValidation { leeway_secs: 0, ..Default::default() };
+    if validate_token_with(&just_expired, &secret, &strict).is_ok() {
+        return Err("token 5s past expiry accepted with no leeway".into());
+    }
+    println!("[jwt_tests] Token 5s past expiry accepted with 30s leeway, rejected with none");
+
+    let long_expired = expired_at(now - 120)?;
+    if validate_token(&long_expired, &secret).is_ok() {
+        return Err("token 120s past expiry accepted with the default leeway".into());
+    }
+    if validate_token_with(&long_expired, &secret, &lenient).is_ok() {
+        return Err("token 120s past expiry accepted with 30s leeway".into());
+    }
+    println!("[jwt_tests] Token beyond the leeway rejected");
+    Ok(())
+}
+
+// Tokens minted by a state with an issuer and audience carry both, and validation requires them
+async fn test_token_issuer() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Issuer and audience from JwtState...");
+    let validation = TokenValidation {
+        audience: Some("chat".to_string()),
+        issuer: Some("auth.example".to_string()),
+        ..Default::default()
+    };
+    let mut state = create_default_jwt_state().with_token_validation(validation.clone());
+    state.secret_key = Arc::new(Zeroizing::new([0x17; 32]));
+    let secret = state.secret_key.to_vec();
+
+    let app = Router::new().merge(jwt_api_router::<()>(state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/auth/token", listener.local_addr()?);
+    let token_server = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let response = reqwest::Client::new().post(&url)
+        .json(&json!({"username": "grace", "password": "pw"}))
+        .send().await?;
+    token_server.abort();
+    let token = response.json::<serde_json::Value>().await?["token"].as_str().ok_or("no token issued")?.to_string();
+    let claims = validate_token_with(&token, &secret, &validation)
+        .map_err(|e| format!("token from a state with an issuer did not validate: {}", e))?;
+    if claims.iss.as_deref() != Some("auth.example") || claims.aud.as_deref() != Some("chat") {
+        return Err(format!("issued token lacks iss or aud: {:?}", claims).into());
+    }
+    println!("[jwt_tests] Issued token carries iss={:?} aud={:?}", claims.iss, claims.aud);
+
+    let other_issuer = create_token_with_issuer("grace", None, Some("chat"), Some("elsewhere"), Default::default(), &secret, Duration::from_secs(60))?;
+    if validate_token_with(&other_issuer, &secret, &validation).is_ok() {
+        return Err("token from another issuer was accepted".into());
+    }
+    let no_issuer = create_token_with_audience("grace", None, Some("chat"), &secret, Duration::from_secs(60))?;
+    if validate_token_with(&no_issuer, &secret, &validation).is_ok() {
+        return Err("token without an issuer was accepted".into());
+    }
+    println!("[jwt_tests] Tokens from another issuer or without one rejected");
+
+    // The WebSocket endpoint of that issuer refuses a foreign issuer outright
+    let server = spawn_ws_server(ConnectionConfig { jwt: Some(state), ..Default::default() }).await?;
+    if WsClient::connect("IssClient", &format!("{}?token={}", server.ws_url, other_issuer)).await.is_ok() {
+        return Err("WebSocket endpoint accepted a token from another issuer".into());
+    }
+    WsClient::connect("IssClient", &format!("{}?token={}", server.ws_url, token)).await
+        .map_err(|e| format!("WebSocket endpoint rejected its issuer's token: {}", e))?;
+    server.stop();
+    Ok(())
+}
+
+// Refresh tokens are exchanged once for a new access token; spent, revoked or expired ones are refused
+async fn test_refresh_tokens() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Refresh token test...");
+
+    let mut state = create_default_jwt_state().with_verifier(Arc::new(HashMapVerifier::default()
+        .with_user_context("carol", "pw", UserContext::new("carol").with_claim("tenant", json!("acme")))));
+    // Short enough that the client considers it due for refresh
+    state.token_expiration = Duration::from_secs(60);
+    let secret = state.secret_key.clone();
+    let app = Router::new().merge(jwt_api_router::<()>(state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}/auth", listener.local_addr()?);
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let refresh = |refresh_token: String| {
+        client.post(format!("{}/refresh", base)).json(&json!({"refresh_token": refresh_token})).send()
+    };
+    let issued = client.post(format!("{}/token", base))
+        .json(&json!({"username": "carol", "password": "pw", "session_id": "session-carol"}))
+        .send().await?
+        .json::<serde_json::Value>().await?;
+    let access_token = issued["token"].as_str().ok_or("no token issued")?.to_string();
+    let refresh_token = issued["refresh_token"].as_str().ok_or("no refresh token issued")?.to_string();
+
+    // The two token kinds are not interchangeable
+    if validate_token(&refresh_token, &secret[..]).is_ok() || validate_refresh_token(&access_token, &secret[..]).is_ok() {
+        return Err("access and refresh tokens were interchangeable".into());
+    }
+    if refresh(access_token).await?.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Err("/auth/refresh accepted an access token".into());
+    }
+
+    let refreshed = refresh(refresh_token.clone()).await?;
+    if refreshed.status() != reqwest::StatusCode::OK {
+        return Err(format!("refresh failed with {}", refreshed.status()).into());
+    }
+    let refreshed = refreshed.json::<serde_json::Value>().await?;
+    let claims = validate_token(refreshed["token"].as_str().ok_or("no refreshed token")?, &secret[..])?;
+    if claims.sub != "carol" || claims.sid.as_deref() != Some("session-carol") || claims.extra.get("tenant") != Some(&json!("acme")) {
+        return Err(format!("refreshed token lost claims: {:?}", claims).into());
+    }
+    println!("[jwt_tests] Refreshed access token for {} sid={:?}", claims.sub, claims.sid);
+
+    // Each refresh token is single use
+    if refresh(refresh_token).await?.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Err("a spent refresh token was accepted again".into());
+    }
+    let rotated = refreshed["refresh_token"].as_str().ok_or("no rotated refresh token")?.to_string();
+    if !state.revoke_refresh_token(&rotated) {
+        return Err("revoking a valid refresh token failed".into());
+    }
+    if refresh(rotated).await?.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Err("a revoked refresh token was accepted".into());
+    }
+
+    // Expired refresh tokens are refused, beyond the validation leeway
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    let expired = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &Claims {
+        sub: "carol".to_string(),
+        sid: None,
+        aud: None,
+        iss: None,
+        iat: now - 7200,
+        exp: now - 3600,
+        typ: Some(REFRESH_TOKEN_TYPE.to_string()),
+        jti: Some("expired-refresh".to_string()),
+        extra: Default::default(),
+    }, &jsonwebtoken::EncodingKey::from_secret(&secret[..]))?;
+    if refresh(expired).await?.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Err("an expired refresh token was accepted".into());
+    }
+    println!("[jwt_tests] Spent, revoked and expired refresh tokens rejected");
+
+    // The client refreshes with its stored refresh token, which rotates on every use
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut ws_client = WsClient::connect_with_auth("Carol", &server.ws_url, &format!("{}/token", base), "carol", "pw", None)
+        .await.map_err(|e| e.to_string())?;
+    for _ in 0..2 {
+        if !ws_client.refresh_token_if_needed().await.map_err(|e| e.to_string())? {
+            return Err("client did not refresh a token expiring within a minute".into());
+        }
+    }
+    if ws_client.get_token().is_none() {
+        return Err("client lost its token after refresh".into());
+    }
+    println!("[jwt_tests] Client refreshed twice without its password");
+
+    server.stop();
+    server_handle.abort();
+    Ok(())
+}
+
+// connect_with_auth leaves identity to the token: no name registration, and a session registration
+// only when the token has no session
+async fn test_auth_connect_registrations() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Authenticated connect registrations...");
+
+    let state = create_default_jwt_state().with_verifier(Arc::new(HashMapVerifier::default().with_user("iris", "pw")));
+    let app = Router::new().merge(jwt_api_router::<()>(state));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let token_url = format!("http://{}/auth/token", listener.local_addr()?);
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let (server, frames) = spawn_recording_server().await?;
+
+    let mut client = WsClient::connect_with_auth("Iris", &server.ws_url, &token_url, "iris", "pw", Some("session-iris")).await?;
+    let sent = handshake_commands(&mut client, &frames).await?;
+    if !sent.is_empty() {
+        return Err(format!("connect with a session-bound token sent {:?}", sent).into());
+    }
+    drop(client);
+
+    frames.lock().unwrap().clear();
+    let mut client = WsClient::connect_with_auth("Iris", &server.ws_url, &token_url, "iris", "pw", None).await?;
+    let sent = handshake_commands(&mut client, &frames).await?;
+    if sent != [ClientCommand::RegisterSession { session_id: "session-Iris".to_string() }] {
+        return Err(format!("connect with a sessionless token sent {:?}", sent).into());
+    }
+    println!("[jwt_tests] Only the registrations the token leaves open were sent");
+
+    server.stop();
+    server_handle.abort();
+    Ok(())
+}
+
+// Expects a session_mismatch error naming the session the client asked for
+// A revoked access token is refused on connect and ends a connection that is already open
+async fn test_token_revocation() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Token revocation test...");
+
+    let verifier = HashMapVerifier::default()
+        .with_user("henry", "pw")
+        .with_user_context("root", "pw", UserContext::new("root").with_claim("roles", json!(["admin"])));
+    let mut state = create_default_jwt_state().with_verifier(Arc::new(verifier));
+    state.secret_key = Arc::new(Zeroizing::new([0x29; 32]));
+    let app = Router::new().merge(jwt_api_router::<()>(state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}/auth", listener.local_addr()?);
+    let token_server = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let server = spawn_ws_server(ConnectionConfig { jwt: Some(state), ..Default::default() }).await?;
+
+    let client = reqwest::Client::new();
+    let issue = |username: &'static str| {
+        let request = client.post(format!("{}/token", base))
+            .json(&json!({"username": username, "password": "pw", "session_id": "session-revoke"}));
+        async move {
+            let response = request.send().await?.json::<serde_json::Value>().await?;
+            Ok::<_, Box<dyn Error>>(response["token"].as_str().ok_or("no token issued")?.to_string())
+        }
+    };
+    let token = issue("henry").await?;
+    let admin_token = issue("root").await?;
+    let mut socket = connect_raw(&format!("{}?token={}", server.ws_url, token)).await?;
+    sync_raw(&mut socket).await?;
+
+    // The holder of a token may revoke it
+    let revoked = client.post(format!("{}/revoke", base)).json(&json!({"token": token})).send().await?;
+    if revoked.status() != reqwest::StatusCode::OK {
+        return Err(format!("revoking a valid token got {}", revoked.status()).into());
+    }
+    let jti = revoked.json::<serde_json::Value>().await?["revoked"].as_str().ok_or("no revoked jti")?.to_string();
+    println!("[jwt_tests] Revoked token {}", jti);
+
+    if connect_raw(&format!("{}?token={}", server.ws_url, token)).await.is_ok() {
+        return Err("a revoked token was accepted on connect".into());
+    }
+    println!("[jwt_tests] Revoked token refused on connect");
+
+    // The connection opened before the revocation is closed at its next command
+    socket.send(Message::Text("list-presence:".to_string())).await?;
+    if recv_type(&mut socket, "token_revoked", Duration::from_secs(2)).await.is_none() {
+        return Err("open connection was not told its token was revoked".into());
+    }
+    let closed = tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = socket.next().await {
+            if let Message::Close(frame) = msg {
+                return frame.map(|frame| u16::from(frame.code));
+            }
+        }
+        None
+    }).await?;
+    if closed != Some(1008) {
+        return Err(format!("connection with a revoked token closed with {:?}, expected 1008", closed).into());
+    }
+    println!("[jwt_tests] Open connection closed with 1008 after revocation");
+
+    // Revoking by id and listing the blocklist take an admin token
+    let by_id = client.post(format!("{}/revoke", base)).json(&json!({"jti": "stolen"})).send().await?;
+    if by_id.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Err(format!("revoking by id without an admin token got {}", by_id.status()).into());
+    }
+    let user_list = client.get(format!("{}/revoked", base)).bearer_auth(issue("henry").await?).send().await?;
+    if user_list.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Err(format!("listing revocations without an admin token got {}", user_list.status()).into());
+    }
+    client.post(format!("{}/revoke", base)).bearer_auth(&admin_token).json(&json!({"jti": "stolen"})).send().await?
+        .error_for_status()?;
+    let list = client.get(format!("{}/revoked", base)).bearer_auth(&admin_token).send().await?
+        .error_for_status()?.json::<serde_json::Value>().await?;
+    let listed: Vec<&str> = list["revoked"].as_array().ok_or("no revoked list")?
+        .iter().filter_map(|entry| entry["jti"].as_str()).collect();
+    if !listed.contains(&jti.as_str()) || !listed.contains(&"stolen") {
+        return Err(format!("blocklist is missing entries: {}", list).into());
+    }
+    println!("[jwt_tests] Admin revoked by id and listed {} entries", listed.len());
+
+    server.stop();
+    token_server.abort();
+    Ok(())
+}
+
+// Blocklist entries are dropped once their tokens would have expired anyway
+fn test_revocation_cleanup() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Revocation cleanup...");
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    let revocations = TokenRevocation::default();
+    revocations.revoke("expired", now - 10);
+    if !revocations.is_revoked("expired") || revocations.purge_expired(now) != 1 || revocations.is_revoked("expired") {
+        return Err("expired blocklist entry was not purged".into());
+    }
+
+    // Revoking purges too, and entries for live tokens stay
+    revocations.revoke("expired-again", now - 10);
+    revocations.revoke("live", now + 60);
+    let entries: Vec<String> = revocations.entries().into_iter().map(|entry| entry.jti).collect();
+    if entries != ["live"] {
+        return Err(format!("unexpected blocklist after cleanup: {:?}", entries).into());
+    }
+    println!("[jwt_tests] Expired entries purged, live ones kept");
+    Ok(())
+}
+
+async fn expect_session_mismatch(socket: &mut RawSocket, what: &str) -> Result<(), Box<dyn Error>> {
+    let error = recv_type(socket, "error", Duration::from_secs(2)).await
+        .ok_or(format!("{} in a foreign session was not rejected", what))?;
+    if error["code"] != "session_mismatch" || error["session_id"] != "session-victim" {
+        return Err(format!("unexpected error frame for {}: {}", what, error).into());
+    }
+    Ok(())
+}
+
+// A token bound to one session cannot subscribe, unsubscribe or publish in another
+async fn test_session_binding() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Session binding test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut victim = connect_raw(&server.ws_url).await?;
+    victim.send(Message::Text("subscribe:Bound|session-victim".to_string())).await?;
+    sync_raw(&mut victim).await?;
+
+    let token = create_token("dave", Some("session-dave"), &socket_secret(), Duration::from_secs(60))?;
+    let mut socket = connect_raw(&format!("{}?token={}", server.ws_url, token)).await?;
+
+    socket.send(Message::Text("subscribe:Bound|session-victim".to_string())).await?;
+    expect_session_mismatch(&mut socket, "subscribe").await?;
+    socket.send(Message::Text("unsubscribe:Bound|session-victim".to_string())).await?;
+    expect_session_mismatch(&mut socket, "unsubscribe").await?;
+    let publish = json!({"publisher_name": "dave", "topic": "Bound", "payload": "intrusion", "timestamp": "", "session_id": "session-victim"});
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    expect_session_mismatch(&mut socket, "publish").await?;
+    socket.send(Message::Binary(BinaryFrame::publish("Bound", "session-victim", b"intrusion".to_vec()).encode()?)).await?;
+    expect_session_mismatch(&mut socket, "binary publish").await?;
+    if server.subscribers.subscriber_count("Bound", "session-victim") != 1 {
+        return Err("foreign session's subscriptions changed".into());
+    }
+    if recv_topic(&mut victim, "Bound", Duration::from_millis(300)).await.is_some() {
+        return Err("a publish crossed into a foreign session".into());
+    }
+    println!("[jwt_tests] Every foreign-session command was rejected");
+
+    // Naming its own session, or none, works as before
+    socket.send(Message::Text("subscribe:Bound|session-dave".to_string())).await?;
+    socket.send(Message::Text("subscribe:Other".to_string())).await?;
+    sync_raw(&mut socket).await?;
+    if server.subscribers.subscriber_count("Bound", "session-dave") != 1 || server.subscribers.subscriber_count("Other", "session-dave") != 1 {
+        return Err("subscriptions in the token's session were not registered".into());
+    }
+    let publish = json!({"publisher_name": "dave", "topic": "Bound", "payload": "own session", "timestamp": ""});
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    let delivered = recv_topic(&mut socket, "Bound", Duration::from_secs(2)).await
+        .ok_or("publish in the token's session was not delivered")?;
+    if delivered["session_id"] != "session-dave" {
+        return Err(format!("publish landed in the wrong session: {}", delivered).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Signs a resume checkpoint the way the server does
+fn resume_token(sub: Option<&str>, sid: Option<&str>, subs: &[(&str, &str)]) -> Result<String, Box<dyn Error>> {
+    let claims = ResumeClaims {
+        sub: sub.map(str::to_string),
+        sid: sid.map(str::to_string),
+        subs: subs.iter().map(|(topic, session)| (topic.to_string(), session.to_string())).collect(),
+        seqs: HashMap::new(),
+        exp: jsonwebtoken::get_current_timestamp() + 60,
+    };
+    Ok(jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(&socket_secret()))?)
+}
+
+// A resume token only resumes for the user and token session it was issued to, and never
+// restores a subscription outside that session
+async fn test_resume_token_binding() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Resume token binding test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut victim = connect_raw(&server.ws_url).await?;
+    victim.send(Message::Text("subscribe:Resumed|session-victim".to_string())).await?;
+    sync_raw(&mut victim).await?;
+
+    let token = create_token("dave", Some("session-dave"), &socket_secret(), Duration::from_secs(60))?;
+    let mut socket = connect_raw(&format!("{}?token={}", server.ws_url, token)).await?;
+
+    // The victim's anonymous checkpoint is refused outright
+    let stolen = resume_token(None, None, &[("Resumed", "session-victim")])?;
+    socket.send(Message::Text(format!("resume:{}", stolen))).await?;
+    let error = recv_type(&mut socket, "error", Duration::from_secs(2)).await
+        .ok_or("another connection's resume token was accepted")?;
+    if error["code"] != "invalid_resume_token" {
+        return Err(format!("unexpected error frame for a foreign resume token: {}", error).into());
+    }
+
+    // dave's own checkpoint restores only the subscription in dave's session
+    let own = resume_token(Some("dave"), Some("session-dave"), &[("Resumed", "session-victim"), ("Resumed", "session-dave")])?;
+    socket.send(Message::Text(format!("resume:{}", own))).await?;
+    recv_type(&mut socket, "resume_complete", Duration::from_secs(2)).await
+        .ok_or("dave's own resume token was not accepted")?;
+    if server.subscribers.subscriber_count("Resumed", "session-victim") != 1
+        || server.subscribers.subscriber_count("Resumed", "session-dave") != 1 {
+        return Err("resume restored a subscription outside the token session".into());
+    }
+    println!("[jwt_tests] Foreign resume token refused, foreign-session subscription skipped");
+
+    server.stop();
+    Ok(())
+}
+
+// A secret in JWT_SECRET_FILE takes precedence over JWT_SECRET_KEY, for issuing and for validating
+async fn test_secret_file() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Secret file test...");
+
+    let file_secret = BASE64.encode(b"file-secret-0123456789abcdefghij");
+    let path = env::temp_dir().join(format!("rws_jwt_secret_{}", std::process::id()));
+    std::fs::write(&path, format!("{}\n", file_secret))?;
+    env::set_var(JWT_SECRET_FILE_VAR, &path);
+    let result = check_secret_file(&file_secret).await;
+    env::remove_var(JWT_SECRET_FILE_VAR);
+    std::fs::remove_file(&path)?;
+    result
+}
+
+async fn check_secret_file(file_secret: &str) -> Result<(), Box<dyn Error>> {
+    let resolved = configured_jwt_secret().ok_or("JWT_SECRET_FILE was not read")?;
+    if resolved.as_slice() != file_secret.as_bytes() {
+        return Err(format!("resolved secret {:?} does not match the file", String::from_utf8_lossy(&resolved)).into());
+    }
+
+    let state = create_default_jwt_state();
+    if &state.secret_key[..] != b"file-secret-0123456789abcdefghij" {
+        return Err("token issuer is not using the secret file".into());
+    }
+    let token = create_token("filed", Some("session-file"), &state.secret_key[..], Duration::from_secs(60))?;
+    validate_token(&token, b"file-secret-0123456789abcdefghij")?;
+
+    // The WebSocket endpoint validates with the same secret
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let client = WsClient::connect("FileSecretClient", &format!("{}?token={}", server.ws_url, token)).await?;
+    println!("[jwt_tests] Token signed with the file secret accepted for {}", client.name);
+    drop(client);
+
+    server.stop();
+    Ok(())
+}
+
+// Signing keys are exactly 32 bytes of hex or base64; anything else is refused by both the issuer and the endpoint
+async fn test_signing_key_lengths() -> Result<(), Box<dyn Error>> {
+    println!("[jwt_tests] Signing key length test...");
+
+    let key = [0x5au8; 32];
+    let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+    for encoded in [hex.clone(), hex.to_uppercase(), BASE64.encode(key), format!("  {}\n", BASE64.encode(key))] {
+        if *decode_jwt_key(encoded.as_bytes())? != key {
+            return Err(format!("{:?} did not decode to the key", encoded).into());
+        }
+    }
+    let refused = [
+        (BASE64.encode([1u8; 31]), JwtKeyError::WrongLength(31)),
+        (BASE64.encode([1u8; 48]), JwtKeyError::WrongLength(48)),
+        ("ab".repeat(33), JwtKeyError::WrongLength(33)),
+        ("a".repeat(63), JwtKeyError::NotEncoded),
+        ("rusty_websocket_jwt_secret_key_32b".to_string(), JwtKeyError::NotEncoded),
+        (String::new(), JwtKeyError::WrongLength(0)),
+    ];
+    for (encoded, expected) in refused {
+        match decode_jwt_key(encoded.as_bytes()) {
+            Err(e) if e == expected => println!("[jwt_tests] Refused {:?}: {}", encoded, e),
+            other => return Err(format!("{:?} gave {:?}, expected {:?}", encoded, other.map(|_| "a key"), expected).into()),
+        }
+    }
+
+    // A short configured key stops token issuance and WebSocket upgrades alike
+    let previous = env::var(JWT_SECRET_KEY_VAR).ok();
+    env::set_var(JWT_SECRET_KEY_VAR, BASE64.encode([7u8; 16]));
+    let result = check_short_key_refused().await;
+    match previous {
+        Some(previous) => env::set_var(JWT_SECRET_KEY_VAR, previous),
+        None => env::remove_var(JWT_SECRET_KEY_VAR),
+    }
+    result
+}
+
+async fn check_short_key_refused() -> Result<(), Box<dyn Error>> {
+    if try_create_default_jwt_state().is_ok() {
+        return Err("token issuer accepted a 16-byte key".into());
+    }
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    match tokio_tungstenite::connect_async(server.ws_url.as_str()).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) if response.status().is_server_error() => {
+            println!("[jwt_tests] Upgrade refused with {} while the key is malformed", response.status());
+        }
+        other => return Err(format!("upgrade with a malformed key was not refused: {:?}", other.map(|_| "connected")).into()),
+    }
+    server.stop();
+    Ok(())
+}
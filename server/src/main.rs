@@ -11,20 +11,34 @@ use axum::{
     response::IntoResponse,
 };
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use libws::{Subscribers, WebSocketParams};
+use std::time::Duration;
+use libws::{ConnectionConfig, Subscribers, WebSocketParams, WsServerBuilder};
 mod ws_tests; // Updated from client_tests
 mod enc_tests;
+mod jwt_tests;
+mod metrics_tests;
+mod server_tests;
+mod test_server;
+mod tls;
 
 use std::{
-    collections::HashMap,
     env,
 };
 use tokio::net::TcpListener;
+use tokio::sync::watch;
 use tower_http::services::ServeDir;
 use tower_http::cors::{Any, CorsLayer};
 use libws::enc_api_route::{enc_api_router, create_web_compatible_state};
 use libws::jwt_api_route::{jwt_api_router, create_default_jwt_state}; // Add the JWT API module
+use libws::jwt_utils::load_jwt_key;
+use libws::admin_api_route::admin_api_router;
+use libws::metrics_api_route::metrics_api_router;
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// How long shutdown waits for connections to finish their close handshake before the process exits.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
 
 /// Adapter function to bridge between server and library
 async fn handle_socket_adapter(
@@ -39,28 +53,48 @@ async fn handle_socket_adapter(
 
 #[tokio::main]
 async fn main() {
+    // Levels come from RUST_LOG, e.g. RUST_LOG=libws=debug for per-message events; info by default
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
     // Set a custom panic hook to log panic information
     std::panic::set_hook(Box::new(|panic_info| {
-        eprintln!("[server] PANIC: {:?}", panic_info);
+        error!("[server] PANIC: {:?}", panic_info);
     }));
 
     // Log environment variable configuration for JWT
-    if env::var("JWT_SECRET_KEY").is_ok() {
-        println!("Using JWT_SECRET_KEY from environment");
+    if let Ok(path) = env::var("JWT_SECRET_FILE") {
+        info!("Using JWT secret from file {}", path);
+    } else if env::var("JWT_SECRET_KEY").is_ok() {
+        info!("Using JWT_SECRET_KEY from environment");
     } else {
-        println!("JWT_SECRET_KEY not set - using default (insecure for production)");
+        warn!("JWT_SECRET_KEY not set - using default (insecure for production)");
+    }
+    // Issuing and validating share this key, so a malformed one stops the server here
+    if let Err(e) = load_jwt_key() {
+        error!("[server] {}", e);
+        std::process::exit(1);
     }
 
     if let Ok(expiration) = env::var("JWT_EXPIRATION_SECONDS") {
-        println!("Using JWT_EXPIRATION_SECONDS: {} seconds", expiration);
+        info!("Using JWT_EXPIRATION_SECONDS: {} seconds", expiration);
     } else {
-        println!("JWT_EXPIRATION_SECONDS not set - using default (3600 seconds)");
+        info!("JWT_EXPIRATION_SECONDS not set - using default (3600 seconds)");
     }
 
     // Parse command-line arguments to determine the mode of operation
     let args: Vec<String> = env::args().collect();
     if args.len() > 1 && args[1] == "--web" {
-        run_web_test().await; // Run the web test mode
+        // `--web --tls <cert.pem> <key.pem>` serves the WebSocket endpoint over wss://
+        match (args.get(2).map(String::as_str), args.get(3), args.get(4)) {
+            (Some("--tls"), Some(cert_path), Some(key_path)) => run_web_test_tls(cert_path, key_path).await,
+            (Some("--tls"), _, _) => {
+                eprintln!("Usage: server --web --tls <cert.pem> <key.pem>");
+                std::process::exit(2);
+            }
+            _ => run_web_test().await, // Run the web test mode
+        }
     } else {
         run_local_test().await; // Run the local test mode
     }
@@ -68,11 +102,33 @@ async fn main() {
 
 /// Runs the server in web test mode, serving both WebSocket and static web content.
 async fn run_web_test() {
+    run_web_app(None).await;
+}
+
+/// Runs web test mode with the WebSocket endpoint and APIs served over TLS on port 8081.
+async fn run_web_test_tls(cert_path: &str, key_path: &str) {
+    let tls_config = match tls::load_rustls_config(cert_path.as_ref(), key_path.as_ref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to load TLS certificate: {}", e);
+            std::process::exit(1);
+        }
+    };
+    run_web_app(Some(tls_config)).await;
+}
+
+async fn run_web_app(tls_config: Option<RustlsConfig>) {
     // Initialize the subscribers map with session support
-    let subscribers: Subscribers = Arc::new(Mutex::new(HashMap::new()));
+    let subscribers: Subscribers = Subscribers::default();
 
     // Generate a web-compatible keypair for encryption tests
-    let enc_state = create_web_compatible_state();
+    let enc_state = match create_web_compatible_state() {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Failed to generate the server encryption key: {}", e);
+            std::process::exit(1);
+        }
+    };
     
     // Create JWT state for authentication
     let jwt_state = create_default_jwt_state();
@@ -83,33 +139,61 @@ async fn run_web_test() {
         .allow_methods(Any)
         .allow_headers(Any);
 
-    // Create encryption router with the same state type as the main router
-    let encryption_router = enc_api_router::<Subscribers>(enc_state);
-    
-    // Create JWT authentication router
-    let jwt_router = jwt_api_router::<Subscribers>(jwt_state);
+    // Create the admin router reporting subscription counts
+    let admin_router = admin_api_router::<Subscribers>(subscribers.clone());
 
-    // Configure the WebSocket app on port 8081
-    let ws_app = Router::new()
-        .route(
-            "/ws",
-            get(handle_socket_adapter),
-        )
-        // Now merge both routers
-        .merge(encryption_router)
-        .merge(jwt_router) // Add the JWT router
-        .layer(cors)
-        .with_state(subscribers.clone());
+    // One configuration for every connection, so /metrics sees all of them
+    // Key exchanges use the keypair served at /enc/public-key, and tokens validate with the issuer's key
+    let ws_config = ConnectionConfig::default();
+    let metrics_router = metrics_api_router::<Subscribers>(ws_config.metrics.clone());
+    let connections = ws_config.connections.clone();
 
-    // Spawn a task to handle WebSocket connections
+    // Configure the WebSocket app on port 8081, with the encryption and JWT APIs beside it
+    let ws_app = WsServerBuilder::new()
+        .with_subscribers(subscribers.clone())
+        .with_encryption(enc_state)
+        .with_jwt(jwt_state)
+        .with_cors(cors)
+        .with_config(ws_config)
+        .with_routes(admin_router.merge(metrics_router))
+        .build();
+
+    // On Ctrl-C or SIGTERM every connection is sent `server_shutdown` and a close frame,
+    // then both listeners stop accepting
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let notified_connections = connections.clone();
     tokio::spawn(async move {
-        let listener = TcpListener::bind("127.0.0.1:8081").await.unwrap();
-        println!("Listening at ws://127.0.0.1:8081/ws");
-        println!("Encryption API available at http://127.0.0.1:8081/enc/public-key");
-        println!("JWT API available at http://127.0.0.1:8081/jwt"); // Add JWT API info
-        axum::serve(listener, ws_app.into_make_service_with_connect_info::<SocketAddr>())
-            .await
-            .unwrap();
+        shutdown_signal().await;
+        let notified = notified_connections.shutdown();
+        info!("[server] Shutting down, closing {} connections", notified);
+        let _ = shutdown_tx.send(true);
+    });
+    let shutdown_requested = |mut shutdown_rx: watch::Receiver<bool>| async move {
+        let _ = shutdown_rx.wait_for(|requested| *requested).await;
+    };
+    let ws_shutdown = shutdown_requested(shutdown_rx.clone());
+
+    // Spawn a task to handle WebSocket connections
+    let ws_server = tokio::spawn(async move {
+        let (ws_scheme, http_scheme) = if tls_config.is_some() { ("wss", "https") } else { ("ws", "http") };
+        info!("Listening at {}://127.0.0.1:8081/ws", ws_scheme);
+        info!("Encryption API available at {}://127.0.0.1:8081/enc/public-key", http_scheme);
+        info!("JWT API available at {}://127.0.0.1:8081/jwt", http_scheme); // Add JWT API info
+        info!("Admin API available at {}://127.0.0.1:8081/admin/topics", http_scheme);
+        info!("Prometheus metrics available at {}://127.0.0.1:8081/metrics", http_scheme);
+        match tls_config {
+            Some(tls_config) => {
+                let listener = std::net::TcpListener::bind("127.0.0.1:8081").unwrap();
+                tls::serve_tls(listener, tls_config, ws_app, ws_shutdown, SHUTDOWN_GRACE).await.unwrap();
+            }
+            None => {
+                let listener = TcpListener::bind("127.0.0.1:8081").await.unwrap();
+                axum::serve(listener, ws_app.into_make_service_with_connect_info::<SocketAddr>())
+                    .with_graceful_shutdown(ws_shutdown)
+                    .await
+                    .unwrap();
+            }
+        }
     });
 
     // Configure the static web app on port 8080
@@ -118,11 +202,47 @@ async fn run_web_test() {
 
     // Serve the static web content
     let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
-    println!("Serving web UI at http://127.0.0.1:8080");
+    info!("Serving web UI at http://127.0.0.1:8080");
 
     axum::serve(listener, web_app.into_make_service())
+        .with_graceful_shutdown(shutdown_requested(shutdown_rx))
         .await
         .unwrap();
+
+    // Upgraded WebSocket connections outlive the listener, so wait for them to close
+    let _ = ws_server.await;
+    if tokio::time::timeout(SHUTDOWN_GRACE, connections.wait_until_empty()).await.is_err() {
+        warn!("[server] {} connections were still open after {:?}", connections.len(), SHUTDOWN_GRACE);
+    }
+    info!("[server] Shutdown complete");
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("[server] Cannot listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("[server] Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
 /// Runs the server in local test mode, first running encryption tests followed by WebSocket tests.
@@ -135,6 +255,15 @@ async fn run_local_test() {
     // Then run the WebSocket tests
     run_local_ws_tests().await;
     
+    // Then run the JWT tests, which start their own servers
+    run_local_jwt_tests().await;
+    
+    // Then run the metrics tests
+    run_local_metrics_tests().await;
+    
+    // Then run the server behaviour tests
+    run_local_server_tests().await;
+    
     println!("All local tests completed.");
 }
 
@@ -143,7 +272,13 @@ async fn run_local_enc_tests() {
     println!("\n=== Starting Encryption Tests ===");
     
     // Generate a web-compatible keypair for encryption tests
-    let enc_state = create_web_compatible_state();
+    let enc_state = match create_web_compatible_state() {
+        Ok(state) => state,
+        Err(e) => {
+            println!("✗ Encryption tests failed: could not generate the server key: {}", e);
+            return;
+        }
+    };
     
     // Create JWT state for authentication
     let jwt_state = create_default_jwt_state();
@@ -168,8 +303,8 @@ async fn run_local_enc_tests() {
     
     // Start the encryption API server
     let listener = TcpListener::bind("127.0.0.1:8082").await.unwrap();
-    println!("Encryption API available at http://127.0.0.1:8082/enc/public-key");
-    println!("JWT API available at http://127.0.0.1:8082/jwt"); // Add JWT API info
+    info!("Encryption API available at http://127.0.0.1:8082/enc/public-key");
+    info!("JWT API available at http://127.0.0.1:8082/jwt"); // Add JWT API info
     
     // Start the server in a background task
     let server_handle = tokio::spawn(async move {
@@ -187,6 +322,41 @@ async fn run_local_enc_tests() {
         Err(e) => println!("✗ Encryption tests failed: {}", e),
     };
     
+    match enc_tests::run_public_key_caching_test().await {
+        Ok(_) => println!("✓ Public key caching test passed successfully"),
+        Err(e) => println!("✗ Public key caching test failed: {}", e),
+    };
+
+    match enc_tests::run_encrypted_topic_test().await {
+        Ok(_) => println!("✓ Encrypted topic test passed successfully"),
+        Err(e) => println!("✗ Encrypted topic test failed: {}", e),
+    };
+
+    match enc_tests::run_rng_failure_test() {
+        Ok(_) => println!("✓ RNG failure test passed successfully"),
+        Err(e) => println!("✗ RNG failure test failed: {}", e),
+    };
+
+    match enc_tests::run_p256_symmetry_test() {
+        Ok(_) => println!("✓ P-256 symmetry test passed successfully"),
+        Err(e) => println!("✗ P-256 symmetry test failed: {}", e),
+    };
+
+    match enc_tests::run_x25519_symmetry_test() {
+        Ok(_) => println!("✓ X25519 symmetry test passed successfully"),
+        Err(e) => println!("✗ X25519 symmetry test failed: {}", e),
+    };
+
+    match enc_tests::run_aad_binding_test() {
+        Ok(_) => println!("✓ AAD binding test passed successfully"),
+        Err(e) => println!("✗ AAD binding test failed: {}", e),
+    };
+
+    match enc_tests::run_zeroization_test() {
+        Ok(_) => println!("✓ Zeroization test passed successfully"),
+        Err(e) => println!("✗ Zeroization test failed: {}", e),
+    };
+    
     // Terminate the server after tests
     server_handle.abort();
     println!("=== Encryption Tests Completed ===\n");
@@ -197,7 +367,7 @@ async fn run_local_ws_tests() {
     println!("=== Starting WebSocket Tests ===");
     
     // Initialize the subscribers map with session support
-    let subscribers: Subscribers = Arc::new(Mutex::new(HashMap::new()));
+    let subscribers: Subscribers = Subscribers::default();
 
     // Configure the WebSocket app on port 8081
     let app = Router::new().route(
@@ -207,7 +377,7 @@ async fn run_local_ws_tests() {
 
     // Start the WebSocket server
     let listener = TcpListener::bind("127.0.0.1:8081").await.unwrap();
-    println!("Listening at ws://127.0.0.1:8081/ws");
+    info!("Listening at ws://127.0.0.1:8081/ws");
 
     let server_handle = tokio::spawn(async move {
         axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
@@ -219,7 +389,60 @@ async fn run_local_ws_tests() {
     tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
     ws_tests::run_client_tests().await; // Updated from client_tests to ws_tests
     
+    match ws_tests::run_client_error_tests().await {
+        Ok(_) => println!("✓ Client error tests passed successfully"),
+        Err(e) => println!("✗ Client error tests failed: {}", e),
+    };
+    
+    match ws_tests::run_client_delivery_tests().await {
+        Ok(_) => println!("✓ Client delivery tests passed successfully"),
+        Err(e) => println!("✗ Client delivery tests failed: {}", e),
+    };
+
+    // The blocking client refuses to run on an async thread
+    match tokio::task::spawn_blocking(|| ws_tests::run_blocking_client_tests().map_err(|e| e.to_string())).await {
+        Ok(Ok(_)) => println!("✓ Blocking client tests passed successfully"),
+        Ok(Err(e)) => println!("✗ Blocking client tests failed: {}", e),
+        Err(e) => println!("✗ Blocking client tests panicked: {}", e),
+    };
+    
     // Terminate the server after tests
     server_handle.abort();
     println!("=== WebSocket Tests Completed ===");
 }
+
+/// Runs local JWT tests against dedicated test servers
+async fn run_local_jwt_tests() {
+    println!("\n=== Starting JWT Tests ===");
+
+    match jwt_tests::run_jwt_tests().await {
+        Ok(_) => println!("✓ JWT tests passed successfully"),
+        Err(e) => println!("✗ JWT tests failed: {}", e),
+    };
+
+    println!("=== JWT Tests Completed ===");
+}
+
+/// Runs local metrics tests against dedicated test servers
+async fn run_local_metrics_tests() {
+    println!("\n=== Starting Metrics Tests ===");
+
+    match metrics_tests::run_metrics_tests().await {
+        Ok(_) => println!("✓ Metrics tests passed successfully"),
+        Err(e) => println!("✗ Metrics tests failed: {}", e),
+    };
+
+    println!("=== Metrics Tests Completed ===");
+}
+
+/// Runs local server behaviour tests against dedicated test servers
+async fn run_local_server_tests() {
+    println!("\n=== Starting Server Tests ===");
+
+    match server_tests::run_server_tests().await {
+        Ok(_) => println!("✓ Server tests passed successfully"),
+        Err(e) => println!("✗ Server tests failed: {}", e),
+    };
+
+    println!("=== Server Tests Completed ===");
+}
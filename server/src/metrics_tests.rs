@@ -0,0 +1,212 @@
+// src/metrics_tests.rs
+use futures_util::SinkExt;
+use libws::metrics::Metrics;
+use libws::metrics_api_route::metrics_api_router;
+use libws::ConnectionConfig;
+use serde_json::json;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+use crate::test_server::{connect_raw, recv_topic, recv_type, spawn_ws_server, sync_raw};
+
+/// Runs the metrics tests against dedicated test servers.
+pub async fn run_metrics_tests() -> Result<(), Box<dyn Error>> {
+    test_topic_aggregation().await?;
+    test_pong_liveness_and_latency().await?;
+    test_publish_latency().await?;
+    test_prometheus_scrape().await?;
+    Ok(())
+}
+
+// Concrete topics matching an aggregation pattern are counted in a single bucket
+async fn test_topic_aggregation() -> Result<(), Box<dyn Error>> {
+    println!("[metrics_tests] Topic aggregation test...");
+
+    let metrics = Arc::new(Metrics::with_topic_patterns(vec!["sensor/*/temp".to_string()]));
+    let server = spawn_ws_server(ConnectionConfig {
+        metrics: metrics.clone(),
+        ..Default::default()
+    }).await?;
+
+    let mut socket = connect_raw(&server.ws_url).await?;
+    for topic in ["sensor/123/temp", "sensor/456/temp", "sensor/789/temp", "sensor/123/humidity"] {
+        let publish = json!({
+            "publisher_name": "MetricsClient",
+            "topic": topic,
+            "payload": "21.5",
+            "timestamp": "",
+        });
+        socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    }
+    sync_raw(&mut socket).await?;
+
+    let aggregated = metrics.publish_count("sensor/*/temp");
+    let unmatched = metrics.publish_count("sensor/123/humidity");
+    println!("[metrics_tests] sensor/*/temp={}, sensor/123/humidity={}", aggregated, unmatched);
+    if aggregated != 3 || unmatched != 1 {
+        return Err(format!("unexpected publish counts: {:?}", metrics.publishes_by_topic()).into());
+    }
+    if metrics.publishes_by_topic().contains_key("sensor/123/temp") {
+        return Err("concrete topic leaked into metrics despite matching a pattern".into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Pongs answering server pings refresh the connection's liveness and record a latency sample
+async fn test_pong_liveness_and_latency() -> Result<(), Box<dyn Error>> {
+    println!("[metrics_tests] Pong liveness test...");
+
+    let metrics = Arc::new(Metrics::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        heartbeat_interval: Some(Duration::from_millis(100)),
+        metrics: metrics.clone(),
+        ..Default::default()
+    }).await?;
+
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("whoami".to_string())).await?;
+    let before = recv_type(&mut socket, "identity", Duration::from_secs(2)).await
+        .ok_or("server did not report the connection identity")?;
+    if !before["last_pong_ms_ago"].is_null() {
+        return Err(format!("liveness recorded before any pong: {}", before).into());
+    }
+
+    // Keep reading so tungstenite answers the server's pings
+    recv_type(&mut socket, "none", Duration::from_millis(350)).await;
+
+    socket.send(Message::Text("whoami".to_string())).await?;
+    let after = recv_type(&mut socket, "identity", Duration::from_secs(2)).await
+        .ok_or("server did not report the connection identity")?;
+    println!("[metrics_tests] Liveness after pings: last_pong_ms_ago={}, ping_latency_ms={}",
+        after["last_pong_ms_ago"], after["ping_latency_ms"]);
+    if after["last_pong_ms_ago"].is_null() || after["ping_latency_ms"].is_null() {
+        return Err(format!("pong did not update liveness: {}", after).into());
+    }
+    if metrics.ping_latency_samples() == 0 || metrics.mean_ping_latency().is_none() {
+        return Err("no ping latency sample was recorded".into());
+    }
+    println!("[metrics_tests] {} latency samples, mean {:?}", metrics.ping_latency_samples(), metrics.mean_ping_latency());
+
+    server.stop();
+    Ok(())
+}
+
+// Each publish records its in-server latency, and the envelope carries it when enabled
+async fn test_publish_latency() -> Result<(), Box<dyn Error>> {
+    println!("[metrics_tests] Publish latency test...");
+
+    for include_server_latency in [false, true] {
+        let metrics = Arc::new(Metrics::default());
+        let server = spawn_ws_server(ConnectionConfig {
+            metrics: metrics.clone(),
+            include_server_latency,
+            ..Default::default()
+        }).await?;
+
+        let mut socket = connect_raw(&server.ws_url).await?;
+        socket.send(Message::Text("subscribe:LatencyTopic".to_string())).await?;
+        let publish = json!({"publisher_name": "LatencyClient", "topic": "LatencyTopic", "payload": "tick", "timestamp": ""});
+        socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+        let envelope = recv_topic(&mut socket, "LatencyTopic", Duration::from_secs(2)).await
+            .ok_or("publish was not delivered")?;
+        sync_raw(&mut socket).await?;
+
+        let latency = metrics.publish_latency();
+        if latency.count != 1 || latency.buckets.iter().map(|(_, count)| count).sum::<u64>() != 1 {
+            return Err(format!("expected one latency sample, got {:?}", latency).into());
+        }
+        match (include_server_latency, envelope["server_latency_ms"].as_f64()) {
+            (true, Some(ms)) => println!("[metrics_tests] server_latency_ms={}, mean {:?}", ms, latency.mean()),
+            (false, None) => {}
+            _ => return Err(format!("server_latency_ms with include_server_latency={}: {}", include_server_latency, envelope).into()),
+        }
+
+        server.stop();
+    }
+    Ok(())
+}
+
+// Reads one sample from a Prometheus text scrape
+fn scraped_value(body: &str, series: &str) -> Option<f64> {
+    body.lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .and_then(|value| value.parse().ok())
+}
+
+// The /metrics endpoint reports the counters moved by real traffic, and the connection gauge
+// falls back once a socket is dropped without a close handshake
+async fn test_prometheus_scrape() -> Result<(), Box<dyn Error>> {
+    println!("[metrics_tests] Prometheus scrape test...");
+
+    let metrics = Arc::new(Metrics::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        metrics: metrics.clone(),
+        ..Default::default()
+    }).await?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let metrics_url = format!("http://{}/metrics", listener.local_addr()?);
+    let metrics_handle = tokio::spawn(async move {
+        let _ = axum::serve(listener, metrics_api_router::<()>(metrics.clone()).into_make_service()).await;
+    });
+    let scrape = || async {
+        let response = reqwest::get(&metrics_url).await?;
+        let content_type = response.headers().get("content-type").and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+        if !content_type.starts_with("text/plain") {
+            return Err(format!("unexpected content type {:?}", content_type).into());
+        }
+        Ok::<String, Box<dyn Error>>(response.text().await?)
+    };
+
+    let mut subscriber = connect_raw(&server.ws_url).await?;
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    subscriber.send(Message::Text(json!({"op": "subscribe", "topic": "metrics/scrape"}).to_string())).await?;
+    sync_raw(&mut subscriber).await?;
+    for n in 0..2 {
+        let publish = json!({"op": "publish", "topic": "metrics/scrape", "payload": n, "publisher_name": "Scraper"});
+        publisher.send(Message::Text(publish.to_string())).await?;
+        recv_topic(&mut subscriber, "metrics/scrape", Duration::from_secs(2)).await
+            .ok_or("publish was not delivered")?;
+    }
+    subscriber.send(Message::Text(json!({"op": "unsubscribe", "topic": "metrics/scrape"}).to_string())).await?;
+    sync_raw(&mut subscriber).await?;
+
+    let body = scrape().await?;
+    let expected = [
+        ("rusty_ws_active_connections", 2.0),
+        ("rusty_ws_subscribes_total", 1.0),
+        ("rusty_ws_unsubscribes_total", 1.0),
+        ("rusty_ws_publishes_total{topic=\"metrics/scrape\"}", 2.0),
+        ("rusty_ws_publish_latency_seconds_count", 2.0),
+    ];
+    for (series, value) in expected {
+        if scraped_value(&body, series) != Some(value) {
+            return Err(format!("expected {} {} in scrape:\n{}", series, value, body).into());
+        }
+    }
+    if !scraped_value(&body, "rusty_ws_bytes_sent_total").is_some_and(|bytes| bytes > 0.0) {
+        return Err(format!("bytes sent did not move:\n{}", body).into());
+    }
+
+    // Dropping the sockets without a close frame must still release their slots
+    drop(subscriber);
+    drop(publisher);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    loop {
+        let active = scraped_value(&scrape().await?, "rusty_ws_active_connections");
+        if active == Some(0.0) {
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(format!("active connections stuck at {:?} after disconnect", active).into());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    metrics_handle.abort();
+    server.stop();
+    Ok(())
+}
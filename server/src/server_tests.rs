@@ -0,0 +1,2772 @@
+// src/server_tests.rs
+use axum::extract::ws::Message as FrameMessage;
+use futures_util::task::AtomicWaker;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use libws::binary_proto::BinaryFrame;
+use libws::transport::{memory_pair, MemoryTransport};
+use libws::ws_client::WsClient;
+use libws::audit::{AuditSink, TopicAuditEvent, TopicAuditKind};
+use libws::connections::ConnectionRegistry;
+use libws::history::MessageHistory;
+use libws::interceptor::{Decision, Envelope, MessageInterceptor, NoopInterceptor, ServerTimestamp};
+use libws::metrics::Metrics;
+use libws::rate_limit::RateLimit;
+use libws::send_queue::{OverflowPolicy, SendQueueLimit};
+use libws::subscribers::{ConnectionId, Subscriber};
+use libws::subprotocol::{EncodedFrame, WireFormat};
+use libws::{publish_to_topic, ConnectionConfig, SessionBus, Subscribers, TopicPolicy, UnknownCommandPolicy, WsServerBuilder, STICKY_COOKIE_NAME};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_tungstenite::{connect_async, connect_async_tls_with_config, Connector, MaybeTlsStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use std::collections::BTreeSet;
+use crate::test_server::{connect_raw, recv_topic, recv_type, spawn_ws_server, spawn_wss_server, sync_raw, RawSocket};
+use crate::tls::load_rustls_config;
+
+/// Runs server behaviour tests against dedicated test servers.
+pub async fn run_server_tests() -> Result<(), Box<dyn Error>> {
+    test_sticky_cookie().await?;
+    test_subprotocol_negotiation().await?;
+    test_origin_allow_list().await?;
+    test_tls_endpoint().await?;
+    test_negotiated_heartbeat().await?;
+    test_pong_timeout_reaps_silent_client().await?;
+    test_subscribe_only_topic().await?;
+    test_subscription_transfer().await?;
+    test_in_memory_round_trip().await?;
+    test_overload_retry_after().await?;
+    test_connection_limit().await?;
+    test_max_connection_lifetime().await?;
+    test_close_codes().await?;
+    test_graceful_shutdown().await?;
+    test_simultaneous_close().await?;
+    test_unknown_command_policy().await?;
+    test_max_message_size().await?;
+    test_session_bus_publish().await?;
+    test_publish_to_topic().await?;
+    test_subscriber_metadata().await?;
+    test_resume_token_replay().await?;
+    test_replay_on_subscribe().await?;
+    test_topic_audit_events().await?;
+    test_publish_prunes_dead_subscribers().await?;
+    test_fan_out_limit().await?;
+    test_min_subscribers().await?;
+    test_publish_rate_limit().await?;
+    test_direct_message_rate_limit().await?;
+    test_send_queue_depth().await?;
+    test_send_queue_overflow().await?;
+    test_direct_message().await?;
+    test_wildcard_subscriptions().await?;
+    test_retained_tombstone().await?;
+    test_retained_last_value().await?;
+    test_last_will().await?;
+    test_parked_session_reattach().await?;
+    test_parked_session_expiry().await?;
+    test_negotiated_features().await?;
+    test_presence_roster().await?;
+    test_command_id_acks().await?;
+    test_message_interceptors().await?;
+    test_typed_commands().await?;
+    test_list_subscriptions().await?;
+    test_admin_token_and_live_counts().await?;
+    test_admin_subscriber_peers().await?;
+    test_server_builder().await?;
+    test_concurrent_fan_out().await?;
+    test_admin_counts_for_large_topic().await?;
+    Ok(())
+}
+
+// A wss:// endpoint loaded from PEM files carries the protocol, and unusable files are named in the error
+async fn test_tls_endpoint() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] TLS endpoint test...");
+
+    let dir = std::env::temp_dir().join(format!("rusty_ws_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let (cert_path, key_path, bad_path) = (dir.join("cert.pem"), dir.join("key.pem"), dir.join("bad.pem"));
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    std::fs::write(&cert_path, certified.cert.pem())?;
+    std::fs::write(&key_path, certified.key_pair.serialize_pem())?;
+    std::fs::write(&bad_path, "not a key")?;
+
+    for (cert, key, expected) in [(&dir.join("missing.pem"), &key_path, "missing.pem"), (&cert_path, &bad_path, "bad.pem")] {
+        match load_rustls_config(cert, key) {
+            Ok(_) => return Err(format!("loading {} succeeded", expected).into()),
+            Err(e) if e.contains(expected) => println!("[server_tests] Refused: {}", e),
+            Err(e) => return Err(format!("error does not name {}: {}", expected, e).into()),
+        }
+    }
+
+    let tls_config = load_rustls_config(&cert_path, &key_path)?;
+    std::fs::remove_dir_all(&dir)?;
+    let server = spawn_wss_server(ConnectionConfig::default(), tls_config).await?;
+    // Trust the self-signed certificate for this connection only
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(native_tls::Certificate::from_pem(certified.cert.pem().as_bytes())?)
+        .build()?;
+    let (mut socket, _) = connect_async_tls_with_config(server.ws_url.as_str(), None, false, Some(Connector::NativeTls(connector))).await?;
+
+    socket.send(Message::Text("subscribe:SecureTopic".to_string())).await?;
+    sync_raw(&mut socket).await?;
+    socket.send(Message::Text(json!({"op": "publish", "topic": "SecureTopic", "payload": "over tls"}).to_string())).await?;
+    let delivered = recv_topic(&mut socket, "SecureTopic", Duration::from_secs(2)).await
+        .ok_or("publish over wss was not delivered")?;
+    if delivered["payload"] != "over tls" {
+        return Err(format!("unexpected delivery: {}", delivered).into());
+    }
+    println!("[server_tests] Round trip over {}", server.ws_url);
+
+    // Without trusting the certificate the handshake is refused
+    if connect_async(server.ws_url.as_str()).await.is_ok() {
+        return Err("connected without trusting the self-signed certificate".into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// The upgrade pins the client to this instance and a cookie for another instance is re-routed
+async fn test_sticky_cookie() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Sticky session cookie test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        instance_id: Some("instance-a".to_string()),
+        ..Default::default()
+    }).await?;
+
+    let (_socket, response) = connect_async(server.ws_url.as_str()).await?;
+    let cookie = response.headers().get(header::SET_COOKIE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or("upgrade response did not set a sticky cookie")?;
+    if !cookie.starts_with(&format!("{}=instance-a", STICKY_COOKIE_NAME)) {
+        return Err(format!("unexpected sticky cookie: {}", cookie).into());
+    }
+    println!("[server_tests] Sticky cookie issued: {}", cookie);
+
+    let mut request = server.ws_url.as_str().into_client_request()?;
+    request.headers_mut().insert(header::COOKIE, format!("{}=instance-b", STICKY_COOKIE_NAME).parse()?);
+    match connect_async(request).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response))
+            if response.status() == StatusCode::MISDIRECTED_REQUEST => {
+            println!("[server_tests] Mismatched instance re-routed with {}", response.status());
+        }
+        Err(e) => return Err(format!("unexpected error for mismatched instance: {}", e).into()),
+        Ok(_) => return Err("reconnect pinned to another instance was accepted".into()),
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// The upgrade echoes the first offered subprotocol the client asked for, and unknown ones fall back to the default
+async fn test_subprotocol_negotiation() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Subprotocol negotiation test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    for (requested, expected) in [(Some("mqtt.v5, json.v1"), Some("json.v1")), (Some("mqtt.v5"), None), (None, None)] {
+        let mut request = server.ws_url.as_str().into_client_request()?;
+        if let Some(requested) = requested {
+            request.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, requested.parse()?);
+        }
+        let (mut socket, response) = connect_async(request).await?;
+        let chosen = response.headers().get(header::SEC_WEBSOCKET_PROTOCOL).map(|value| value.to_str()).transpose()?;
+        if chosen != expected {
+            return Err(format!("requested {:?}, expected {:?} in the handshake, got {:?}", requested, expected, chosen).into());
+        }
+
+        // Every connection is served, and reports the format it was given
+        socket.send(Message::Text("whoami".to_string())).await?;
+        let identity = recv_type(&mut socket, "identity", Duration::from_secs(2)).await
+            .ok_or("negotiated connection did not answer whoami")?;
+        if identity["protocol"] != "json.v1" {
+            return Err(format!("unexpected protocol in identity: {}", identity["protocol"]).into());
+        }
+        println!("[server_tests] Requested {:?}, negotiated {:?}", requested, chosen);
+    }
+
+    // A MessagePack connection sends commands and receives everything as binary frames
+    let mut request = server.ws_url.as_str().into_client_request()?;
+    request.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, "msgpack.v1".parse()?);
+    let (mut socket, response) = connect_async(request).await?;
+    if response.headers().get(header::SEC_WEBSOCKET_PROTOCOL).map(|value| value.to_str()).transpose()? != Some("msgpack.v1") {
+        return Err("msgpack.v1 was not negotiated".into());
+    }
+    let EncodedFrame::Binary(subscribe) = WireFormat::MessagePack.encode(json!({"op": "subscribe", "topic": "packed", "id": "1"}).to_string()) else {
+        return Err("a JSON command did not encode as a binary frame".into());
+    };
+    socket.send(Message::Binary(subscribe)).await?;
+    let ack = loop {
+        let frame = tokio::time::timeout(Duration::from_secs(2), socket.next()).await?
+            .ok_or("connection ended before the ack")??;
+        let decoded = match &frame {
+            Message::Binary(bytes) => WireFormat::MessagePack.decode(bytes),
+            Message::Text(text) => return Err(format!("MessagePack connection got a text frame: {}", text).into()),
+            _ => None,
+        };
+        let Some(decoded) = decoded else { continue };
+        let value: Value = serde_json::from_str(&decoded)?;
+        if value["type"] == "ack" {
+            break value;
+        }
+    };
+    if ack["op"] != "subscribe" || ack["topic"] != "packed" || ack["id"] != "1" {
+        return Err(format!("unexpected ack over MessagePack: {}", ack).into());
+    }
+    // Non-JSON text stays text, and binary protocol frames are not mistaken for MessagePack
+    if WireFormat::MessagePack.encode("pong".to_string()) != EncodedFrame::Text("pong".to_string())
+        || WireFormat::MessagePack.decode(&[0x01, 0x00]).is_some()
+        || WireFormat::Json.encode("{}".to_string()) != EncodedFrame::Text("{}".to_string())
+    {
+        return Err("MessagePack codec mishandled a non-JSON frame".into());
+    }
+    println!("[server_tests] MessagePack ack: {}", ack);
+
+    server.stop();
+    Ok(())
+}
+
+// Upgrades from origins outside the allow-list are refused before the socket opens
+async fn test_origin_allow_list() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Origin allow-list test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        allowed_origins: Some(vec!["https://app.example.com".to_string()]),
+        ..Default::default()
+    }).await?;
+    let open = spawn_ws_server(ConnectionConfig::default()).await?;
+    let cases = [
+        (&server, Some("https://app.example.com"), true),
+        (&server, Some("HTTPS://App.Example.com/"), true),
+        (&server, Some("https://evil.example.com"), false),
+        (&server, Some("null"), false),
+        (&server, None, true),
+        (&open, Some("https://evil.example.com"), true),
+    ];
+    for (target, origin, allowed) in cases {
+        let mut request = target.ws_url.as_str().into_client_request()?;
+        if let Some(origin) = origin {
+            request.headers_mut().insert(header::ORIGIN, origin.parse()?);
+        }
+        match connect_async(request).await {
+            Ok((mut socket, _)) if allowed => {
+                socket.send(Message::Text("whoami".to_string())).await?;
+                recv_type(&mut socket, "identity", Duration::from_secs(2)).await
+                    .ok_or(format!("connection from origin {:?} was not served", origin))?;
+            }
+            Ok(_) => return Err(format!("origin {:?} was accepted", origin).into()),
+            Err(tokio_tungstenite::tungstenite::Error::Http(response))
+                if !allowed && response.status() == StatusCode::FORBIDDEN => {}
+            Err(e) => return Err(format!("unexpected error for origin {:?}: {}", origin, e).into()),
+        }
+        println!("[server_tests] Origin {:?} allowed: {}", origin, allowed);
+    }
+
+    server.stop();
+    open.stop();
+    Ok(())
+}
+
+// The server pings at the interval the client negotiated and clamps out-of-range requests
+async fn test_negotiated_heartbeat() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Negotiated heartbeat test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        heartbeat_min_interval: Duration::from_millis(100),
+        heartbeat_max_interval: Duration::from_secs(10),
+        ..Default::default()
+    }).await?;
+
+    // Out-of-range requests are clamped to the server's bounds
+    let mut clamped = connect_raw(&server.ws_url).await?;
+    clamped.send(Message::Text("register-heartbeat:10".to_string())).await?;
+    let reply = recv_type(&mut clamped, "heartbeat", Duration::from_secs(2)).await
+        .ok_or("no heartbeat confirmation")?;
+    if reply["interval_ms"] != 100 {
+        return Err(format!("expected interval clamped to 100ms, got {}", reply).into());
+    }
+
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("register-heartbeat:300".to_string())).await?;
+    let reply = recv_type(&mut socket, "heartbeat", Duration::from_secs(2)).await
+        .ok_or("no heartbeat confirmation")?;
+    if reply["interval_ms"] != 300 {
+        return Err(format!("expected 300ms interval, got {}", reply).into());
+    }
+
+    // Count pings over 1.5s; at 300ms that is about five
+    let window = Duration::from_millis(1500);
+    let start = Instant::now();
+    let mut pings = 0;
+    while let Ok(Some(Ok(msg))) = tokio::time::timeout_at(start + window, socket.next()).await {
+        if let Message::Ping(_) = msg {
+            pings += 1;
+        }
+    }
+    println!("[server_tests] Received {} pings in {:?}", pings, window);
+    if !(3..=7).contains(&pings) {
+        return Err(format!("expected about 5 pings at 300ms, got {}", pings).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// A client that stops answering pings is disconnected and its subscriptions removed
+async fn test_pong_timeout_reaps_silent_client() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Pong timeout test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        heartbeat_interval: Some(Duration::from_millis(100)),
+        heartbeat_min_interval: Duration::from_millis(50),
+        pong_timeout: Some(Duration::from_millis(400)),
+        ..Default::default()
+    }).await?;
+
+    // Pongs are only written while the socket is read, so a socket nobody reads goes silent
+    let mut silent = connect_raw(&server.ws_url).await?;
+    silent.send(Message::Text("subscribe:ZombieTopic".to_string())).await?;
+    sync_raw(&mut silent).await?;
+
+    let mut live = connect_raw(&server.ws_url).await?;
+    live.send(Message::Text("subscribe:LiveTopic".to_string())).await?;
+    sync_raw(&mut live).await?;
+    let reader = tokio::spawn(async move { while let Some(Ok(_)) = live.next().await {} });
+
+    let started = Instant::now();
+    while server.subscribers.contains_topic("ZombieTopic") {
+        if started.elapsed() > Duration::from_secs(3) {
+            reader.abort();
+            return Err("silent client was never reaped".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    println!("[server_tests] Silent client reaped after {:?}", started.elapsed());
+    if !server.subscribers.contains_topic("LiveTopic") {
+        reader.abort();
+        return Err("a client answering pings was reaped".into());
+    }
+
+    reader.abort();
+    drop(silent);
+    server.stop();
+    Ok(())
+}
+
+// Clients may subscribe to a subscribe-only topic but not publish to it
+async fn test_subscribe_only_topic() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Subscribe-only topic test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        topic_policies: vec![TopicPolicy::subscribe_only("system/announcements")],
+        ..Default::default()
+    }).await?;
+
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("subscribe:system/announcements".to_string())).await?;
+    sync_raw(&mut socket).await?;
+    if !server.subscribers.contains_topic("system/announcements") {
+        return Err("subscribe to a subscribe-only topic was refused".into());
+    }
+
+    let publish = json!({
+        "publisher_name": "PolicyClient",
+        "topic": "system/announcements",
+        "payload": "not allowed",
+        "timestamp": "",
+    });
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    let error = recv_type(&mut socket, "error", Duration::from_secs(2)).await
+        .ok_or("publish to a subscribe-only topic was not rejected")?;
+    if error["code"] != "publish_not_allowed" {
+        return Err(format!("unexpected error frame: {}", error).into());
+    }
+    println!("[server_tests] Publish denied: {}", error);
+
+    server.stop();
+    Ok(())
+}
+
+// Reads frames until a control frame of the given type arrives, collecting payloads published on `topic`
+async fn collect_until(socket: &mut RawSocket, topic: &str, msg_type: &str, seen: &mut BTreeSet<u32>)
+    -> Result<serde_json::Value, Box<dyn Error>>
+{
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(3), socket.next()).await
+            .map_err(|_| format!("timed out waiting for {}", msg_type))?
+            .ok_or("socket closed")??;
+        if let Message::Text(text) = msg {
+            let value: serde_json::Value = match serde_json::from_str(&text) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            if value["topic"] == topic {
+                seen.insert(value["payload"].as_str().unwrap_or("").parse()?);
+            } else if value["type"] == msg_type {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+// Collects payloads published on `topic` until the socket is quiet for the given duration or closes
+async fn collect_while_open(socket: &mut RawSocket, topic: &str, quiet: Duration, seen: &mut BTreeSet<u32>) {
+    while let Ok(Some(Ok(msg))) = tokio::time::timeout(quiet, socket.next()).await {
+        if let Message::Text(text) = msg {
+            if let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) {
+                if value["topic"] == topic {
+                    if let Ok(seq) = value["payload"].as_str().unwrap_or("").parse() {
+                        seen.insert(seq);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A standby connection adopts a subscription with a transfer token and no message is lost
+async fn test_subscription_transfer() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Subscription transfer test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let topic = "FailoverEvent";
+    let total = 40;
+
+    let mut primary = connect_raw(&server.ws_url).await?;
+    primary.send(Message::Text("register-session:session-failover".to_string())).await?;
+    primary.send(Message::Text(format!("subscribe:{}", topic))).await?;
+    sync_raw(&mut primary).await?;
+
+    let mut standby = connect_raw(&server.ws_url).await?;
+    standby.send(Message::Text("register-session:session-failover".to_string())).await?;
+    sync_raw(&mut standby).await?;
+
+    // Publish a numbered stream throughout the handoff
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    let publisher_task = tokio::spawn(async move {
+        for seq in 0..total {
+            let publish = json!({
+                "publisher_name": "FailoverPublisher",
+                "topic": topic,
+                "payload": seq.to_string(),
+                "timestamp": "",
+                "session_id": "session-failover",
+            });
+            publisher.send(Message::Text(format!("publish-json:{}", publish))).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        publisher
+    });
+
+    let mut seen = BTreeSet::new();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    primary.send(Message::Text("transfer-token".to_string())).await?;
+    let token = collect_until(&mut primary, topic, "transfer_token", &mut seen).await?;
+    let token = token["token"].as_str().ok_or("transfer token missing")?;
+
+    standby.send(Message::Text(format!("transfer-subscription:{}", token))).await?;
+    let complete = collect_until(&mut standby, topic, "transfer_complete", &mut seen).await?;
+    println!("[server_tests] Transfer complete: {}", complete);
+
+    // The primary keeps receiving until it closes
+    primary.send(Message::Close(None)).await?;
+    collect_while_open(&mut primary, topic, Duration::from_millis(500), &mut seen).await;
+
+    let _publisher = publisher_task.await?;
+    collect_while_open(&mut standby, topic, Duration::from_millis(500), &mut seen).await;
+
+    let expected: BTreeSet<u32> = (0..total).collect();
+    let missing: Vec<_> = expected.difference(&seen).collect();
+    if !missing.is_empty() {
+        return Err(format!("messages lost across handoff: {:?}", missing).into());
+    }
+    println!("[server_tests] All {} messages delivered across the handoff", total);
+
+    // A token can only be used once
+    standby.send(Message::Text(format!("transfer-subscription:{}", token))).await?;
+    recv_type(&mut standby, "error", Duration::from_secs(2)).await
+        .ok_or("transfer token was accepted twice")?;
+
+    server.stop();
+    Ok(())
+}
+
+// Serves a connection over the in-process transport and returns the client's end
+fn serve_in_memory(subscribers: &Subscribers, config: &Arc<ConnectionConfig>) -> MemoryTransport {
+    let (client, server) = memory_pair();
+    tokio::spawn(libws::serve_transport(server, subscribers.clone(), None, config.clone()));
+    client
+}
+
+// Returns the next text frame after the server_hello, or None if the connection closes or stays silent
+async fn next_text(transport: &mut MemoryTransport) -> Option<String> {
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = transport.next().await {
+            if let FrameMessage::Text(text) = msg {
+                if text.contains("\"type\":\"server_hello\"") {
+                    continue;
+                }
+                return Some(text);
+            }
+        }
+        None
+    }).await.ok().flatten()
+}
+
+// A full subscribe/publish round trip served entirely in memory, with no listener bound
+async fn test_in_memory_round_trip() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] In-memory transport round trip test...");
+
+    let subscribers: Subscribers = Subscribers::default();
+    let config = Arc::new(ConnectionConfig::default());
+    let mut subscriber = serve_in_memory(&subscribers, &config);
+    let mut publisher = serve_in_memory(&subscribers, &config);
+
+    subscriber.send(FrameMessage::Text("register-session:session-memory".to_string())).await?;
+    subscriber.send(FrameMessage::Text("subscribe:MemoryTopic".to_string())).await?;
+    // Frames are handled in order, so the pong means the subscription is registered
+    subscriber.send(FrameMessage::Text("ping".to_string())).await?;
+    if next_text(&mut subscriber).await.as_deref() != Some("pong") {
+        return Err("in-memory subscriber did not get a pong".into());
+    }
+
+    let publish = json!({
+        "publisher_name": "MemoryPublisher",
+        "topic": "MemoryTopic",
+        "payload": "no sockets involved",
+        "timestamp": "",
+        "session_id": "session-memory"
+    });
+    publisher.send(FrameMessage::Text(format!("publish-json:{}", publish))).await?;
+
+    let delivered: Value = serde_json::from_str(&next_text(&mut subscriber).await
+        .ok_or("publish was not delivered over the in-memory transport")?)?;
+    if delivered["payload"] != "no sockets involved" || delivered["publisher_name"] != "MemoryPublisher" {
+        return Err(format!("unexpected delivery: {}", delivered).into());
+    }
+    println!("[server_tests] Delivered in memory: {}", delivered);
+
+    // Closing the client end ends the connection and drops its subscription
+    subscriber.close().await?;
+    drop(subscriber);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    if subscribers.contains_topic("MemoryTopic") {
+        return Err("subscription outlived the in-memory connection".into());
+    }
+
+    Ok(())
+}
+
+// A full endpoint refuses new connections with Retry-After and the client waits that long
+async fn test_overload_retry_after() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Overload Retry-After test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        max_connections: Some(1),
+        overload_retry_after: Duration::from_secs(1),
+        ..Default::default()
+    }).await?;
+
+    let mut holder = connect_raw(&server.ws_url).await?;
+    sync_raw(&mut holder).await?;
+
+    match connect_async(server.ws_url.as_str()).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response))
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE => {
+            let retry_after = response.headers().get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .ok_or("overload rejection did not include Retry-After")?;
+            if retry_after != "1" {
+                return Err(format!("expected Retry-After 1, got {}", retry_after).into());
+            }
+            println!("[server_tests] Rejected at capacity with Retry-After {}", retry_after);
+        }
+        Err(e) => return Err(format!("unexpected error at capacity: {}", e).into()),
+        Ok(_) => return Err("connection beyond max_connections was accepted".into()),
+    }
+
+    // Free the slot well before Retry-After elapses; the client must still wait it out
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(holder);
+    });
+    let started = Instant::now();
+    let client = WsClient::connect_with_retry("RetryClient", "session-retry", &server.ws_url, 3).await?;
+    let waited = started.elapsed();
+    if waited < Duration::from_secs(1) {
+        return Err(format!("client retried after {:?}, before Retry-After elapsed", waited).into());
+    }
+    println!("[server_tests] Client connected after waiting {:?}", waited);
+
+    drop(client);
+    server.stop();
+    Ok(())
+}
+
+// Connections up to the limit are accepted, the next is refused, and a closed one frees its slot
+async fn test_connection_limit() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Connection limit test...");
+
+    let metrics = Arc::new(Metrics::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        max_connections: Some(3),
+        metrics: metrics.clone(),
+        ..Default::default()
+    }).await?;
+
+    let mut open = Vec::new();
+    for _ in 0..3 {
+        let mut socket = connect_raw(&server.ws_url).await?;
+        sync_raw(&mut socket).await?;
+        open.push(socket);
+    }
+    match connect_async(server.ws_url.as_str()).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response))
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE => {}
+        Err(e) => return Err(format!("unexpected error at capacity: {}", e).into()),
+        Ok(_) => return Err("a fourth connection was accepted with max_connections 3".into()),
+    }
+
+    // Dropped without a close frame, so the slot is freed on the error path too
+    drop(open.pop());
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while metrics.active_connections() > 2 {
+        if Instant::now() > deadline {
+            return Err("closing a connection did not free its slot".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut replacement = connect_raw(&server.ws_url).await
+        .map_err(|e| format!("connection refused after a slot was freed: {}", e))?;
+    sync_raw(&mut replacement).await?;
+    println!("[server_tests] Refused at 3 connections, accepted again after one closed");
+
+    server.stop();
+    Ok(())
+}
+
+// A connection is cycled once its maximum lifetime elapses, even while it is active
+async fn test_max_connection_lifetime() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Maximum connection lifetime test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        max_connection_lifetime: Some(Duration::from_millis(500)),
+        ..Default::default()
+    }).await?;
+
+    let started = Instant::now();
+    let mut socket = connect_raw(&server.ws_url).await?;
+
+    // Stay busy with text pings until the server gives notice
+    let notice = loop {
+        if started.elapsed() > Duration::from_secs(3) {
+            return Err("connection outlived its maximum lifetime".into());
+        }
+        socket.send(Message::Text("ping".to_string())).await?;
+        if let Some(notice) = recv_type(&mut socket, "lifetime_exceeded", Duration::from_millis(100)).await {
+            break notice;
+        }
+    };
+    let lived = started.elapsed();
+    if lived < Duration::from_millis(500) {
+        return Err(format!("connection closed after {:?}, before its lifetime elapsed", lived).into());
+    }
+    if notice["reconnect"] != true {
+        return Err(format!("lifetime notice did not ask the client to reconnect: {}", notice).into());
+    }
+    println!("[server_tests] Lifetime notice after {:?}: {}", lived, notice);
+
+    let closed = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return true,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }).await.unwrap_or(false);
+    if !closed {
+        return Err("server did not close the connection after the lifetime notice".into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Server code publishes through SessionBus and a connected client receives it
+async fn test_session_bus_publish() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] SessionBus publish test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let bus = SessionBus::new(server.subscribers.clone());
+
+    let mut client = WsClient::connect_with_session("BusSubscriber", "session-bus", &server.ws_url).await?;
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    client.on_message("JobFinished", move |payload| {
+        received_clone.lock().unwrap().push(payload);
+    });
+    client.subscribe("BusSubscriber", "JobFinished", "no-payload").await?;
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while bus.subscriber_count("JobFinished", "session-bus") == 0 {
+        if Instant::now() > deadline {
+            return Err("subscription never became visible through SessionBus".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    if bus.topics() != vec!["JobFinished".to_string()] {
+        return Err(format!("unexpected topics: {:?}", bus.topics()).into());
+    }
+    if bus.publish("JobFinished", "other-session", "wrong session") != 0 {
+        return Err("SessionBus delivered across sessions".into());
+    }
+
+    let delivered = bus.publish("JobFinished", "session-bus", "report ready");
+    if delivered != 1 {
+        return Err(format!("expected delivery to 1 subscriber, got {}", delivered).into());
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let received = received.lock().unwrap().clone();
+    if received != vec!["report ready".to_string()] {
+        return Err(format!("subscriber received {:?}", received).into());
+    }
+    println!("[server_tests] Subscriber received server-side publish: {:?}", received);
+
+    drop(client);
+    server.stop();
+    Ok(())
+}
+
+// Server-initiated closes carry a close code to on_close; client closes clean up subscriptions
+async fn test_close_codes() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Close code test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        max_connection_lifetime: Some(Duration::from_millis(300)),
+        ..Default::default()
+    }).await?;
+
+    let mut client = WsClient::connect_with_session("CloseWatcher", "session-close", &server.ws_url).await?;
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+    let closed_tx = Mutex::new(Some(closed_tx));
+    client.on_close(move |code, reason| {
+        if let Some(closed_tx) = closed_tx.lock().unwrap().take() {
+            let _ = closed_tx.send((code, reason));
+        }
+    });
+    let (code, reason) = tokio::time::timeout(Duration::from_secs(2), closed_rx).await
+        .map_err(|_| "on_close was not called after the server closed the connection")??;
+    if code != Some(1001) || !reason.contains("lifetime") {
+        return Err(format!("unexpected close: code={:?}, reason={}", code, reason).into());
+    }
+    println!("[server_tests] on_close received code={:?}, reason={}", code, reason);
+
+    // A handler registered after the close runs straight away
+    let late = Arc::new(Mutex::new(None));
+    let late_clone = late.clone();
+    client.on_close(move |code, _| *late_clone.lock().unwrap() = Some(code));
+    if *late.lock().unwrap() != Some(Some(1001)) {
+        return Err("late on_close handler was not called".into());
+    }
+
+    // A client close frame ends the connection and removes its subscriptions
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("subscribe:ClosingTopic".to_string())).await?;
+    sync_raw(&mut socket).await?;
+    socket.send(Message::Close(Some(tokio_tungstenite::tungstenite::protocol::CloseFrame {
+        code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Normal,
+        reason: "done".into(),
+    }))).await?;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while server.subscribers.contains_topic("ClosingTopic") {
+        if Instant::now() > deadline {
+            return Err("subscriptions were not cleaned up after a client close".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    drop(client);
+    server.stop();
+    Ok(())
+}
+
+// When both peers send a close frame at once, each side sees a completed handshake rather than an error
+async fn test_simultaneous_close() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Simultaneous close test...");
+
+    let metrics = Arc::new(Metrics::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        unknown_command_policy: UnknownCommandPolicy::DisconnectAfter(1),
+        metrics: metrics.clone(),
+        ..Default::default()
+    }).await?;
+
+    // The unknown command makes the server close while the client's close frame is already on its way
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.feed(Message::Text("bogus".to_string())).await?;
+    socket.feed(Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: "done".into() }))).await?;
+    socket.flush().await?;
+    let mut server_close = None;
+    while let Some(msg) = tokio::time::timeout(Duration::from_secs(3), socket.next()).await? {
+        if let Message::Close(frame) = msg.map_err(|e| format!("client saw a transport error during the close: {}", e))? {
+            server_close = Some(frame);
+        }
+    }
+    let server_close = server_close.ok_or("client never saw the server's close frame")?;
+    println!("[server_tests] Raw client finished the handshake with {:?}", server_close);
+
+    // WsClient::close racing the server closing a connection at the end of its lifetime
+    let lifetime_server = spawn_ws_server(ConnectionConfig {
+        max_connection_lifetime: Some(Duration::from_millis(300)),
+        metrics: metrics.clone(),
+        ..Default::default()
+    }).await?;
+    let mut client = WsClient::connect("BothClose", &lifetime_server.ws_url).await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let code = client.close().await.map_err(|e| format!("client close reported an error: {}", e))?;
+    println!("[server_tests] WsClient closed cleanly with code {:?}", code);
+
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while metrics.clean_closes() + metrics.abnormal_closes() < 2 {
+        if Instant::now() > deadline {
+            return Err("server did not finish closing both connections".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    if metrics.clean_closes() != 2 || metrics.abnormal_closes() != 0 {
+        return Err(format!("server saw {} clean and {} abnormal closes", metrics.clean_closes(), metrics.abnormal_closes()).into());
+    }
+    println!("[server_tests] Server reported both closes as clean");
+
+    drop(client);
+    lifetime_server.stop();
+    server.stop();
+    Ok(())
+}
+
+// Unknown commands get a structured error, and repeated ones can end the connection
+async fn test_unknown_command_policy() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Unknown command policy test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        unknown_command_policy: UnknownCommandPolicy::ErrorReply,
+        ..Default::default()
+    }).await?;
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("subscibe:Typo|session-typo".to_string())).await?;
+    let error = recv_type(&mut socket, "error", Duration::from_secs(2)).await
+        .ok_or("unknown command got no error reply")?;
+    if error["code"] != "unknown_command" || error["command"] != "subscibe" {
+        return Err(format!("unexpected error frame: {}", error).into());
+    }
+    println!("[server_tests] Unknown command reported: {}", error);
+    server.stop();
+
+    let server = spawn_ws_server(ConnectionConfig {
+        unknown_command_policy: UnknownCommandPolicy::DisconnectAfter(2),
+        ..Default::default()
+    }).await?;
+    let mut socket = connect_raw(&server.ws_url).await?;
+    for command in ["bogus", "bogus-again"] {
+        socket.send(Message::Text(command.to_string())).await?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let code = loop {
+        match tokio::time::timeout_at(deadline, socket.next()).await {
+            Ok(Some(Ok(Message::Close(frame)))) => break frame.map(|frame| u16::from(frame.code)),
+            Ok(Some(Ok(_))) => continue,
+            _ => return Err("connection was not closed after repeated unknown commands".into()),
+        }
+    };
+    if code != Some(1008) {
+        return Err(format!("expected close code 1008, got {:?}", code).into());
+    }
+    println!("[server_tests] Closed with {:?} after repeated unknown commands", code);
+
+    server.stop();
+    Ok(())
+}
+
+// Shutdown tells every connection the server is going away, then closes each with 1001
+async fn test_graceful_shutdown() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Graceful shutdown test...");
+
+    let connections = Arc::new(ConnectionRegistry::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        connections: connections.clone(),
+        ..Default::default()
+    }).await?;
+    let mut sockets = Vec::new();
+    for _ in 0..3 {
+        let mut socket = connect_raw(&server.ws_url).await?;
+        sync_raw(&mut socket).await?;
+        sockets.push(socket);
+    }
+    if connections.len() != 3 {
+        return Err(format!("expected 3 registered connections, got {}", connections.len()).into());
+    }
+
+    let notified = connections.shutdown();
+    if notified != 3 {
+        return Err(format!("expected 3 connections notified, got {}", notified).into());
+    }
+    for socket in &mut sockets {
+        recv_type(socket, "server_shutdown", Duration::from_secs(2)).await
+            .ok_or("connection was not told about the shutdown")?;
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let code = loop {
+            match tokio::time::timeout_at(deadline, socket.next()).await {
+                Ok(Some(Ok(Message::Close(frame)))) => break frame.map(|frame| u16::from(frame.code)),
+                Ok(Some(Ok(_))) => continue,
+                _ => return Err("connection was not closed on shutdown".into()),
+            }
+        };
+        if code != Some(1001) {
+            return Err(format!("expected close code 1001, got {:?}", code).into());
+        }
+    }
+    tokio::time::timeout(Duration::from_secs(2), connections.wait_until_empty()).await
+        .map_err(|_| "connections were still registered after closing")?;
+    println!("[server_tests] Notified and closed {} connections", notified);
+
+    server.stop();
+    Ok(())
+}
+
+// Frames over max_message_bytes are refused before parsing, and optionally close the connection
+async fn test_max_message_size() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Max message size test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        max_message_bytes: Some(256),
+        ..Default::default()
+    }).await?;
+    let mut socket = connect_raw(&server.ws_url).await?;
+    let oversized_publish = format!("publish-json:{}", json!({"topic": "BigTopic", "payload": "x".repeat(300)}));
+    socket.send(Message::Text(oversized_publish.clone())).await?;
+    let error = recv_type(&mut socket, "error", Duration::from_secs(2)).await
+        .ok_or("oversized publish got no error reply")?;
+    if error["code"] != "message_too_large" || error["size"] != oversized_publish.len() || error["max_message_bytes"] != 256 {
+        return Err(format!("unexpected error frame: {}", error).into());
+    }
+
+    // An oversized name is refused rather than stored, and the connection stays usable
+    socket.send(Message::Text(format!("register-session:{}", "s".repeat(300)))).await?;
+    recv_type(&mut socket, "error", Duration::from_secs(2)).await
+        .ok_or("oversized register-session got no error reply")?;
+    socket.send(Message::Text("whoami".to_string())).await?;
+    let identity = recv_type(&mut socket, "identity", Duration::from_secs(2)).await
+        .ok_or("connection stopped answering after an oversized frame")?;
+    if identity["session_id"] != "default" {
+        return Err(format!("oversized session id was stored: {}", identity["session_id"]).into());
+    }
+    println!("[server_tests] Oversized frames refused: {}", error);
+    server.stop();
+
+    let server = spawn_ws_server(ConnectionConfig {
+        max_message_bytes: Some(256),
+        close_on_oversized_message: true,
+        ..Default::default()
+    }).await?;
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text(oversized_publish)).await?;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let code = loop {
+        match tokio::time::timeout_at(deadline, socket.next()).await {
+            Ok(Some(Ok(Message::Close(frame)))) => break frame.map(|frame| u16::from(frame.code)),
+            Ok(Some(Ok(_))) => continue,
+            _ => return Err("connection was not closed after an oversized frame".into()),
+        }
+    };
+    if code != Some(1009) {
+        return Err(format!("expected close code 1009, got {:?}", code).into());
+    }
+    println!("[server_tests] Closed with {:?} after an oversized frame", code);
+
+    server.stop();
+    Ok(())
+}
+
+// Server code publishes straight into the subscriber map, and dead senders are pruned
+async fn test_publish_to_topic() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] publish_to_topic test...");
+
+    let subscribers = Subscribers::default();
+    let (live, mut live_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (dead, dead_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (wildcard, mut wildcard_rx) = tokio::sync::mpsc::unbounded_channel();
+    drop(dead_rx);
+    subscribers.write("jobs.report").entry("jobs.report".to_string()).or_default()
+        .entry("session-jobs".to_string()).or_default()
+        .extend([Subscriber::new(live, ConnectionId::generate()), Subscriber::new(dead, ConnectionId::generate())]);
+    subscribers.write("jobs.*").entry("jobs.*".to_string()).or_default()
+        .entry("session-jobs".to_string()).or_default()
+        .push(Subscriber::new(wildcard, ConnectionId::generate()));
+
+    let delivered = publish_to_topic(&subscribers, "session-jobs", "jobs.report", json!({"rows": 42}));
+    if delivered != 2 {
+        return Err(format!("expected delivery to 2 subscribers, got {}", delivered).into());
+    }
+    for receiver in [&mut live_rx, &mut wildcard_rx] {
+        let envelope: Value = serde_json::from_str(&receiver.try_recv()?)?;
+        if envelope["publisher_name"] != "server" || envelope["payload"] != json!({"rows": 42}) || envelope["topic"] != "jobs.report" {
+            return Err(format!("unexpected envelope: {}", envelope).into());
+        }
+    }
+    if subscribers.subscriber_count("jobs.report", "session-jobs") != 1 {
+        return Err("closed sender was not pruned".into());
+    }
+    if publish_to_topic(&subscribers, "other-session", "jobs.report", json!("ignored")) != 0 {
+        return Err("publish_to_topic delivered across sessions".into());
+    }
+    println!("[server_tests] Delivered to {} subscribers and pruned the closed one", delivered);
+    Ok(())
+}
+
+// Subscribers of a topic in a session, as stored in the registry
+fn registered(subscribers: &Subscribers, topic: &str, session_id: &str) -> Vec<Subscriber> {
+    subscribers.read(topic).get(topic).and_then(|sessions| sessions.get(session_id)).cloned().unwrap_or_default()
+}
+
+// Subscribe, publish, resubscribe, unsubscribe and disconnect keep the registry entries and their metadata consistent
+async fn test_subscriber_metadata() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Subscriber metadata test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut first = connect_raw(&server.ws_url).await?;
+    first.send(Message::Text("subscribe:Meta|session-meta|unordered,group=workers".to_string())).await?;
+    sync_raw(&mut first).await?;
+    let mut second = connect_raw(&server.ws_url).await?;
+    second.send(Message::Text("subscribe:Meta|session-meta".to_string())).await?;
+    sync_raw(&mut second).await?;
+
+    let entries = registered(&server.subscribers, "Meta", "session-meta");
+    let [first_entry, second_entry] = entries.as_slice() else {
+        return Err(format!("expected two subscribers, got {:?}", entries).into());
+    };
+    if first_entry.options != ["unordered", "group=workers"] || !second_entry.options.is_empty()
+        || first_entry.group.as_deref() != Some("workers") || second_entry.group.is_some()
+        || first_entry.connection_id == second_entry.connection_id {
+        return Err(format!("unexpected subscriber metadata: {:?}", entries).into());
+    }
+    let (first_id, second_id) = (first_entry.connection_id, second_entry.connection_id);
+
+    // A group option naming no group is refused and leaves the entry alone
+    first.send(Message::Text("subscribe:Meta|session-meta|group=".to_string())).await?;
+    let error = recv_type(&mut first, "error", Duration::from_secs(2)).await.ok_or("empty group was not refused")?;
+    if error["code"] != "invalid_subscription_option" {
+        return Err(format!("unexpected error for an empty group: {}", error).into());
+    }
+
+    // Deliveries record the sequence number each subscriber last received
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    publish_raw(&mut publisher, "Meta", "session-meta", "one").await?;
+    let seq = recv_topic(&mut first, "Meta", Duration::from_secs(2)).await.ok_or("first subscriber got nothing")?["seq"].clone();
+    recv_topic(&mut second, "Meta", Duration::from_secs(2)).await.ok_or("second subscriber got nothing")?;
+    if registered(&server.subscribers, "Meta", "session-meta").iter().any(|entry| json!(entry.last_seq()) != seq) {
+        return Err(format!("last_seq not recorded as {}", seq).into());
+    }
+
+    // A resubscribe replaces the connection's entry rather than adding one
+    first.send(Message::Text("subscribe:Meta|session-meta|delta".to_string())).await?;
+    sync_raw(&mut first).await?;
+    let entries = registered(&server.subscribers, "Meta", "session-meta");
+    if entries.len() != 2 || !entries.iter().any(|entry| entry.connection_id == first_id && entry.options == ["delta"] && entry.group.is_none()) {
+        return Err(format!("resubscribe did not replace the entry: {:?}", entries).into());
+    }
+
+    first.send(Message::Text("unsubscribe:Meta|session-meta".to_string())).await?;
+    sync_raw(&mut first).await?;
+    let entries = registered(&server.subscribers, "Meta", "session-meta");
+    if entries.len() != 1 || entries[0].connection_id != second_id {
+        return Err(format!("unsubscribe removed the wrong entry: {:?}", entries).into());
+    }
+
+    second.close(None).await?;
+    for _ in 0..20 {
+        if !server.subscribers.contains_topic("Meta") {
+            println!("[server_tests] Registry entries followed subscribe, publish, unsubscribe and disconnect");
+            server.stop();
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Err("disconnect did not remove the last subscriber".into())
+}
+
+// Subscribes and collects the `topic:payload` of every message sent before the pong to a trailing ping
+async fn subscribe_and_collect(socket: &mut RawSocket, subscribe: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    socket.send(Message::Text(subscribe.to_string())).await?;
+    socket.send(Message::Text("ping".to_string())).await?;
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(Message::Text(text))) = socket.next().await {
+            if text == "pong" {
+                break;
+            }
+            if let Ok(value) = serde_json::from_str::<Value>(&text) {
+                if let (Some(topic), Some(payload)) = (value["topic"].as_str(), value["payload"].as_str()) {
+                    received.push(format!("{}:{}", topic, payload));
+                }
+            }
+        }
+    }).await.map_err(|_| format!("timed out collecting replay after {:?}", received))?;
+    Ok(received)
+}
+
+// A late subscriber is sent the last messages of its topic in its session, oldest first
+async fn test_replay_on_subscribe() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Replay on subscribe test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        history: Arc::new(MessageHistory::default().with_topic_retention(3)),
+        ..Default::default()
+    }).await?;
+
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    for (topic, payload) in [("replay.a", "a1"), ("replay.b", "b1"), ("replay.a", "a2"), ("replay.a", "a3"), ("replay.a", "a4"), ("replay.b", "b2")] {
+        publish_raw(&mut publisher, topic, "session-replay", payload).await?;
+    }
+    publish_raw(&mut publisher, "replay.a", "session-elsewhere", "elsewhere").await?;
+    sync_raw(&mut publisher).await?;
+
+    // Only the newest three of replay.a are kept, and they arrive in publish order
+    let mut late = connect_raw(&server.ws_url).await?;
+    let replayed = subscribe_and_collect(&mut late, "subscribe:replay.a|session-replay").await?;
+    if replayed != ["replay.a:a2", "replay.a:a3", "replay.a:a4"] {
+        return Err(format!("unexpected replay for replay.a: {:?}", replayed).into());
+    }
+
+    // A wildcard subscriber gets every matching topic's messages interleaved by sequence
+    let mut wildcard = connect_raw(&server.ws_url).await?;
+    let replayed = subscribe_and_collect(&mut wildcard, "subscribe:replay.*|session-replay").await?;
+    if replayed != ["replay.b:b1", "replay.a:a2", "replay.a:a3", "replay.a:a4", "replay.b:b2"] {
+        return Err(format!("unexpected replay for replay.*: {:?}", replayed).into());
+    }
+    println!("[server_tests] Wildcard subscriber replayed {:?}", replayed);
+
+    // Live messages follow the replay, and another session's history is not replayed
+    publish_raw(&mut publisher, "replay.a", "session-replay", "a5").await?;
+    let live = recv_topic(&mut late, "replay.a", Duration::from_secs(2)).await.ok_or("live message after replay was missed")?;
+    if live["payload"] != "a5" {
+        return Err(format!("unexpected live message: {}", live).into());
+    }
+    let mut other = connect_raw(&server.ws_url).await?;
+    let replayed = subscribe_and_collect(&mut other, "subscribe:replay.b|session-elsewhere").await?;
+    if !replayed.is_empty() {
+        return Err(format!("replayed another topic or session's history: {:?}", replayed).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Publishes a payload on a topic in a session from a raw socket
+async fn publish_raw(socket: &mut RawSocket, topic: &str, session_id: &str, payload: &str) -> Result<(), Box<dyn Error>> {
+    let publish = json!({
+        "publisher_name": "ResumePublisher",
+        "topic": topic,
+        "payload": payload,
+        "timestamp": "",
+        "session_id": session_id
+    });
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    Ok(())
+}
+
+// A publish reaching more subscribers than max_fan_out is refused and audited; smaller ones go through
+async fn test_fan_out_limit() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Fan-out limit test...");
+
+    let sink = Arc::new(RecordingAuditSink::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        max_fan_out: Some(2),
+        audit_sink: Some(sink.clone()),
+        ..Default::default()
+    }).await?;
+
+    let mut big = Vec::new();
+    for _ in 0..3 {
+        let mut socket = connect_raw(&server.ws_url).await?;
+        socket.send(Message::Text("subscribe:BigTopic|session-fan".to_string())).await?;
+        sync_raw(&mut socket).await?;
+        big.push(socket);
+    }
+    let mut small = connect_raw(&server.ws_url).await?;
+    small.send(Message::Text("subscribe:SmallTopic|session-fan".to_string())).await?;
+    sync_raw(&mut small).await?;
+
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    publisher.send(Message::Text("register-name:FanPublisher".to_string())).await?;
+    publish_raw(&mut publisher, "BigTopic", "session-fan", "too many").await?;
+    let error = recv_type(&mut publisher, "error", Duration::from_secs(2)).await
+        .ok_or("publish exceeding the fan-out cap was not refused")?;
+    if error["code"] != "fan_out_too_large" || error["fan_out"] != 3 || error["max_fan_out"] != 2 {
+        return Err(format!("unexpected error frame: {}", error).into());
+    }
+    println!("[server_tests] Publish refused: {}", error);
+    if recv_topic(&mut big[0], "BigTopic", Duration::from_millis(300)).await.is_some() {
+        return Err("refused publish was still delivered".into());
+    }
+
+    publish_raw(&mut publisher, "SmallTopic", "session-fan", "fits").await?;
+    let delivered = recv_topic(&mut small, "SmallTopic", Duration::from_secs(2)).await
+        .ok_or("publish within the fan-out cap was not delivered")?;
+    if delivered["payload"] != "fits" {
+        return Err(format!("unexpected delivery: {}", delivered).into());
+    }
+
+    let rejected: Vec<TopicAuditEvent> = sink.events.lock().unwrap().iter()
+        .filter(|event| event.kind == TopicAuditKind::FanOutRejected)
+        .cloned()
+        .collect();
+    if rejected.len() != 1 || rejected[0].topic != "BigTopic" || rejected[0].actor != "FanPublisher" {
+        return Err(format!("expected one fan-out audit event, got {:?}", rejected).into());
+    }
+    println!("[server_tests] Capacity event audited for {}", rejected[0].actor);
+
+    server.stop();
+    Ok(())
+}
+
+// A publish asking for more subscribers than its session has is refused and delivered to nobody
+async fn test_min_subscribers() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Minimum subscribers test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = connect_raw(&server.ws_url).await?;
+    subscriber.send(Message::Text("subscribe:QuorumTopic|session-quorum".to_string())).await?;
+    sync_raw(&mut subscriber).await?;
+    // A subscriber in another session does not count towards the quorum
+    let mut outsider = connect_raw(&server.ws_url).await?;
+    outsider.send(Message::Text("subscribe:QuorumTopic|session-other".to_string())).await?;
+    sync_raw(&mut outsider).await?;
+
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    let publish = |payload: &str, min_subscribers: usize| json!({
+        "op": "publish", "topic": "QuorumTopic", "session_id": "session-quorum",
+        "payload": payload, "min_subscribers": min_subscribers
+    }).to_string();
+    publisher.send(Message::Text(publish("needs two", 2))).await?;
+    let error = recv_type(&mut publisher, "error", Duration::from_secs(2)).await
+        .ok_or("publish below its quorum was not refused")?;
+    if error["code"] != "insufficient_subscribers" || error["present"] != 1 || error["required"] != 2 {
+        return Err(format!("unexpected error frame: {}", error).into());
+    }
+    println!("[server_tests] Publish refused: {}", error);
+    if let Some(delivered) = recv_topic(&mut subscriber, "QuorumTopic", Duration::from_millis(300)).await {
+        return Err(format!("refused publish was still delivered: {}", delivered).into());
+    }
+
+    publisher.send(Message::Text(publish("needs one", 1))).await?;
+    let delivered = recv_topic(&mut subscriber, "QuorumTopic", Duration::from_secs(2)).await
+        .ok_or("publish meeting its quorum was not delivered")?;
+    if delivered["payload"] != "needs one" {
+        return Err(format!("unexpected delivery: {}", delivered).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// A publish burst beyond the bucket is refused, and a client that keeps flooding is disconnected
+async fn test_publish_rate_limit() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Publish rate limit test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        publish_rate_limit: Some(RateLimit::new(0.5, 3).disconnect_after(5)),
+        ..Default::default()
+    }).await?;
+    let mut subscriber = connect_raw(&server.ws_url).await?;
+    subscriber.send(Message::Text("subscribe:FloodTopic".to_string())).await?;
+    sync_raw(&mut subscriber).await?;
+
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    for n in 0..10 {
+        let publish = json!({"op": "publish", "topic": "FloodTopic", "payload": n, "id": format!("p{}", n)});
+        publisher.send(Message::Text(publish.to_string())).await?;
+    }
+
+    // Three publishes fit the burst; the next four are refused and the fifth refusal closes the connection
+    let mut refused = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let code = loop {
+        match tokio::time::timeout_at(deadline, publisher.next()).await {
+            Ok(Some(Ok(Message::Close(frame)))) => break frame.map(|frame| u16::from(frame.code)),
+            Ok(Some(Ok(Message::Text(text)))) => {
+                let frame: Value = serde_json::from_str(&text)?;
+                if frame["type"] == "error" {
+                    if frame["code"] != "rate_limited" || frame["retry_after_ms"].as_u64().is_none() {
+                        return Err(format!("unexpected error frame: {}", frame).into());
+                    }
+                    refused.push(frame["id"].clone());
+                }
+            }
+            Ok(Some(Ok(_))) => continue,
+            _ => return Err("flooding publisher was not disconnected".into()),
+        }
+    };
+    if refused != [json!("p3"), json!("p4"), json!("p5"), json!("p6")] || code != Some(1008) {
+        return Err(format!("expected p3..p6 refused then close 1008, got {:?} and {:?}", refused, code).into());
+    }
+    println!("[server_tests] Refused {} publishes, then closed with {:?}", refused.len(), code);
+
+    let mut delivered = Vec::new();
+    while let Some(message) = recv_topic(&mut subscriber, "FloodTopic", Duration::from_millis(300)).await {
+        delivered.push(message["payload"].clone());
+    }
+    if delivered != [json!(0), json!(1), json!(2)] {
+        return Err(format!("expected only the burst to be delivered, got {:?}", delivered).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Direct messages draw on the publish bucket, so a burst of publish-to: is throttled like publishes
+async fn test_direct_message_rate_limit() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Direct message rate limit test...");
+
+    let metrics = Arc::new(Metrics::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        publish_rate_limit: Some(RateLimit::new(0.5, 3)),
+        metrics: metrics.clone(),
+        ..Default::default()
+    }).await?;
+    let mut recipient = connect_raw(&server.ws_url).await?;
+    let address = subscribe_self(&mut recipient).await?;
+
+    let mut sender = connect_raw(&server.ws_url).await?;
+    for n in 0..6 {
+        sender.send(Message::Text(format!("publish-to:{}|burst-{}", address, n))).await?;
+    }
+    let mut throttled = 0;
+    while let Some(error) = recv_type(&mut sender, "error", Duration::from_millis(500)).await {
+        if error["code"] != "rate_limited" || error["retry_after_ms"].as_u64().is_none() {
+            return Err(format!("unexpected error frame: {}", error).into());
+        }
+        throttled += 1;
+    }
+    let topic = format!("@direct/{}", address);
+    let mut delivered = Vec::new();
+    while let Some(message) = recv_topic(&mut recipient, &topic, Duration::from_millis(300)).await {
+        delivered.push(message["payload"].clone());
+    }
+    if throttled != 3 || delivered != [json!("burst-0"), json!("burst-1"), json!("burst-2")] {
+        return Err(format!("expected 3 throttled and 3 delivered, got {} and {:?}", throttled, delivered).into());
+    }
+    if metrics.publish_count("@direct/*") != 3 {
+        return Err(format!("expected 3 direct publishes counted, got {}", metrics.publish_count("@direct/*")).into());
+    }
+    println!("[server_tests] Throttled {} of 6 direct messages", throttled);
+
+    server.stop();
+    Ok(())
+}
+
+// A subscriber that reconnects with its resume token gets exactly the messages it missed
+async fn test_resume_token_replay() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Resume token replay test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        history: Arc::new(MessageHistory::with_retention(100)),
+        resume_token_interval: Some(Duration::from_millis(100)),
+        ..Default::default()
+    }).await?;
+
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    let mut subscriber = connect_raw(&server.ws_url).await?;
+    subscriber.send(Message::Text("subscribe:ResumeTopic|session-resume".to_string())).await?;
+    sync_raw(&mut subscriber).await?;
+
+    for payload in ["m1", "m2", "m3"] {
+        publish_raw(&mut publisher, "ResumeTopic", "session-resume", payload).await?;
+    }
+
+    // Take the first checkpoint issued after the last message arrived
+    let mut seen = Vec::new();
+    let token = tokio::time::timeout(Duration::from_secs(3), async {
+        while let Some(Ok(Message::Text(text))) = subscriber.next().await {
+            let value: Value = serde_json::from_str(&text).unwrap_or_default();
+            if value["topic"] == "ResumeTopic" {
+                seen.push(value["payload"].as_str().unwrap_or_default().to_string());
+            } else if value["type"] == "resume_token" && seen.len() == 3 {
+                return value["token"].as_str().map(|token| token.to_string());
+            }
+        }
+        None
+    }).await.ok().flatten().ok_or("no resume token after the first messages")?;
+    println!("[server_tests] Received {:?} and a resume token", seen);
+
+    drop(subscriber);
+    sync_raw(&mut publisher).await?;
+    for payload in ["m4", "m5"] {
+        publish_raw(&mut publisher, "ResumeTopic", "session-resume", payload).await?;
+    }
+    sync_raw(&mut publisher).await?;
+
+    let mut resumed = connect_raw(&server.ws_url).await?;
+    resumed.send(Message::Text(format!("resume:{}", token))).await?;
+    let mut replayed = Vec::new();
+    let complete = tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(Message::Text(text))) = resumed.next().await {
+            let value: Value = serde_json::from_str(&text).unwrap_or_default();
+            if value["topic"] == "ResumeTopic" {
+                replayed.push(value["payload"].as_str().unwrap_or_default().to_string());
+            } else if value["type"] == "resume_complete" {
+                return Some(value);
+            }
+        }
+        None
+    }).await.ok().flatten().ok_or("resume did not complete")?;
+    println!("[server_tests] Replayed {:?}: {}", replayed, complete);
+    if replayed != ["m4", "m5"] || complete["replayed"] != 2 || complete["truncated"] != false {
+        return Err(format!("expected exactly the missed messages, got {:?} ({})", replayed, complete).into());
+    }
+
+    // The subscription itself was restored
+    publish_raw(&mut publisher, "ResumeTopic", "session-resume", "m6").await?;
+    let live = recv_topic(&mut resumed, "ResumeTopic", Duration::from_secs(2)).await
+        .ok_or("resumed connection did not receive new messages")?;
+    if live["payload"] != "m6" {
+        return Err(format!("unexpected live message after resume: {}", live).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Collects audit events for inspection
+#[derive(Default)]
+struct RecordingAuditSink {
+    events: Mutex<Vec<TopicAuditEvent>>,
+}
+
+impl AuditSink for RecordingAuditSink {
+    fn record(&self, event: &TopicAuditEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+// A brand-new topic is audited once when created and once when its last subscriber leaves
+async fn test_topic_audit_events() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Topic audit events test...");
+
+    let sink = Arc::new(RecordingAuditSink::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        audit_sink: Some(sink.clone()),
+        ..Default::default()
+    }).await?;
+
+    let mut first = connect_raw(&server.ws_url).await?;
+    first.send(Message::Text("register-name:AuditFirst".to_string())).await?;
+    first.send(Message::Text("subscribe:AuditTopic|session-audit".to_string())).await?;
+    sync_raw(&mut first).await?;
+    let mut second = connect_raw(&server.ws_url).await?;
+    second.send(Message::Text("subscribe:AuditTopic|session-audit".to_string())).await?;
+    sync_raw(&mut second).await?;
+
+    let created: Vec<TopicAuditEvent> = sink.events.lock().unwrap().clone();
+    if created.len() != 1 || created[0].kind != TopicAuditKind::Created
+        || created[0].topic != "AuditTopic" || created[0].session_id != "session-audit"
+        || created[0].actor != "AuditFirst" || created[0].at.is_empty() {
+        return Err(format!("expected one creation event by AuditFirst, got {:?}", created).into());
+    }
+    println!("[server_tests] Creation audited: {:?}", created[0]);
+
+    // The topic is only removed once nobody is left on it
+    second.send(Message::Text("unsubscribe:AuditTopic|session-audit".to_string())).await?;
+    sync_raw(&mut second).await?;
+    if sink.events.lock().unwrap().len() != 1 {
+        return Err("topic was audited as removed while still subscribed".into());
+    }
+    drop(first);
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    loop {
+        let events = sink.events.lock().unwrap().clone();
+        if events.len() == 2 && events[1].kind == TopicAuditKind::Removed && events[1].topic == "AuditTopic" {
+            println!("[server_tests] Removal audited: {:?}", events[1]);
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(format!("expected a single removal event, got {:?}", events).into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// A publish that finds a subscriber's receiver gone removes it, collapsing the emptied entries
+async fn test_publish_prunes_dead_subscribers() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Publish prunes dead subscribers test...");
+
+    let sink = Arc::new(RecordingAuditSink::default());
+    let server = spawn_ws_server(ConnectionConfig {
+        audit_sink: Some(sink.clone()),
+        ..Default::default()
+    }).await?;
+
+    // Subscribers whose connections went away without running their own cleanup
+    let (dead, dead_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (dead_wildcard, dead_wildcard_rx) = tokio::sync::mpsc::unbounded_channel();
+    drop((dead_rx, dead_wildcard_rx));
+    server.subscribers.write("prune/topic").entry("prune/topic".to_string()).or_default()
+        .entry("session-prune".to_string()).or_default()
+        .push(Subscriber::new(dead, ConnectionId::generate()));
+    server.subscribers.write("prune/*").entry("prune/*".to_string()).or_default()
+        .entry("session-prune".to_string()).or_default()
+        .push(Subscriber::new(dead_wildcard, ConnectionId::generate()));
+
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    publisher.send(Message::Text("register-name:Pruner".to_string())).await?;
+    publisher.send(Message::Text("register-session:session-prune".to_string())).await?;
+    let publish = json!({"publisher_name": "Pruner", "topic": "prune/topic", "payload": "anyone?", "timestamp": ""});
+    publisher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    sync_raw(&mut publisher).await?;
+
+    if server.subscribers.contains_topic("prune/topic") || server.subscribers.contains_topic("prune/*") {
+        return Err("dead subscribers were left in the registry after a publish".into());
+    }
+    let removed: Vec<String> = sink.events.lock().unwrap().iter()
+        .filter(|event| event.kind == TopicAuditKind::Removed && event.actor == "Pruner")
+        .map(|event| event.topic.clone())
+        .collect();
+    if removed != ["prune/topic", "prune/*"] {
+        return Err(format!("expected both emptied keys to be audited as removed, got {:?}", removed).into());
+    }
+    println!("[server_tests] Publish pruned and audited {:?}", removed);
+
+    server.stop();
+    Ok(())
+}
+
+// Holds back writes on a transport while closed, so messages pile up in the send queue
+#[derive(Default)]
+struct WriteGate {
+    closed: AtomicBool,
+    stalled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl WriteGate {
+    fn set_closed(&self, closed: bool) {
+        self.closed.store(closed, Ordering::SeqCst);
+        if !closed {
+            self.waker.wake();
+        }
+    }
+}
+
+// Server end of an in-memory connection whose writes wait on a gate
+struct GatedTransport {
+    inner: MemoryTransport,
+    gate: Arc<WriteGate>,
+}
+
+impl Stream for GatedTransport {
+    type Item = Result<FrameMessage, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Sink<FrameMessage> for GatedTransport {
+    type Error = axum::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.gate.waker.register(cx.waker());
+        if self.gate.closed.load(Ordering::SeqCst) {
+            self.gate.stalled.store(true, Ordering::SeqCst);
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: FrameMessage) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+// Next payload published to `topic`, from a JSON envelope or, for a binary subscription, a
+// publish frame whose bytes are read back as JSON where they parse
+async fn next_payload(transport: &mut MemoryTransport, topic: &str, binary: bool) -> Option<Value> {
+    if !binary {
+        loop {
+            let frame: Value = serde_json::from_str(&next_text(transport).await?).ok()?;
+            if frame["topic"] == topic {
+                return Some(frame["payload"].clone());
+            }
+        }
+    }
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = transport.next().await {
+            if let FrameMessage::Binary(bytes) = msg {
+                let frame = BinaryFrame::decode(&bytes).ok()?;
+                let text = String::from_utf8(frame.payload).ok()?;
+                return Some(serde_json::from_str(&text).unwrap_or(Value::String(text)));
+            }
+        }
+        None
+    }).await.ok().flatten()
+}
+
+// A stalled reader's full send queue drops the oldest or newest publishes, or disconnects it,
+// and each overflow is counted without blocking the publisher. Binary subscriptions are bound
+// the same way
+async fn test_send_queue_overflow() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Send queue overflow test...");
+
+    let policies = [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest, OverflowPolicy::Disconnect];
+    for (policy, binary) in policies.into_iter().flat_map(|policy| [(policy, false), (policy, true)]) {
+        let subscribers: Subscribers = Subscribers::default();
+        let metrics = Arc::new(Metrics::default());
+        let config = ConnectionConfig {
+            send_queue_limit: Some(SendQueueLimit::new(4, policy)),
+            metrics: metrics.clone(),
+            ..Default::default()
+        };
+        let gate = Arc::new(WriteGate::default());
+        let (mut client, server) = memory_pair();
+        let server = GatedTransport { inner: server, gate: gate.clone() };
+        let connection = tokio::spawn(libws::serve_transport(server, subscribers.clone(), None, Arc::new(config)));
+
+        client.send(FrameMessage::Text("register-session:session-slow".to_string())).await?;
+        let options = if binary { "|session-slow|binary" } else { "" };
+        client.send(FrameMessage::Text(format!("subscribe:SlowTopic{}", options))).await?;
+        client.send(FrameMessage::Text("ping".to_string())).await?;
+        if next_text(&mut client).await.as_deref() != Some("pong") {
+            return Err("subscriber did not get a pong".into());
+        }
+
+        // The first message occupies the writer, four more fill the queue, the rest overflow
+        gate.set_closed(true);
+        publish_to_topic(&subscribers, "session-slow", "SlowTopic", json!("in flight"));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !gate.stalled.load(Ordering::SeqCst) {
+            if Instant::now() > deadline {
+                return Err("writer never stalled on the closed gate".into());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let accepted: usize = (0..10)
+            .map(|i| publish_to_topic(&subscribers, "session-slow", "SlowTopic", json!(i)))
+            .sum();
+
+        if policy == OverflowPolicy::Disconnect {
+            // The stalled write is abandoned, so the connection ends with the gate still closed
+            tokio::time::timeout(Duration::from_secs(3), connection).await
+                .map_err(|_| "slow consumer was not disconnected")???;
+            if accepted != 4 || metrics.send_queue_overflows() != 1 || metrics.abnormal_closes() != 1 {
+                return Err(format!("expected 4 accepted, 1 overflow and an abnormal close, got {}, {} and {}",
+                    accepted, metrics.send_queue_overflows(), metrics.abnormal_closes()).into());
+            }
+            println!("[server_tests] Disconnect (binary: {}): slow consumer closed after {} queued messages", binary, accepted);
+            continue;
+        }
+
+        gate.set_closed(false);
+        let mut delivered = Vec::new();
+        while delivered.len() < 5 {
+            delivered.push(next_payload(&mut client, "SlowTopic", binary).await.ok_or("queued messages were not delivered")?);
+        }
+        let expected = match policy {
+            OverflowPolicy::DropOldest => [json!("in flight"), json!(6), json!(7), json!(8), json!(9)],
+            _ => [json!("in flight"), json!(0), json!(1), json!(2), json!(3)],
+        };
+        let expected_accepted = if policy == OverflowPolicy::DropOldest { 10 } else { 4 };
+        if delivered != expected || accepted != expected_accepted || metrics.send_queue_overflows() != 6 {
+            return Err(format!("{:?} (binary: {}): expected {:?} with {} accepted and 6 overflows, got {:?}, {} and {}",
+                policy, binary, expected, expected_accepted, delivered, accepted, metrics.send_queue_overflows()).into());
+        }
+        println!("[server_tests] {:?} (binary: {}): delivered {:?}", policy, binary, delivered);
+    }
+
+    Ok(())
+}
+
+// Messages stuck behind a stalled write are counted by `queue-depth`
+async fn test_send_queue_depth() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Send queue depth test...");
+
+    let subscribers: Subscribers = Subscribers::default();
+    let gate = Arc::new(WriteGate::default());
+    let (mut client, server) = memory_pair();
+    let server = GatedTransport { inner: server, gate: gate.clone() };
+    tokio::spawn(libws::serve_transport(server, subscribers.clone(), None, Arc::new(ConnectionConfig::default())));
+
+    client.send(FrameMessage::Text("register-session:session-depth".to_string())).await?;
+    client.send(FrameMessage::Text("subscribe:DepthTopic".to_string())).await?;
+    client.send(FrameMessage::Text("ping".to_string())).await?;
+    if next_text(&mut client).await.as_deref() != Some("pong") {
+        return Err("subscriber did not get a pong".into());
+    }
+
+    // The first message occupies the writer; everything after it waits in the queue
+    gate.set_closed(true);
+    let bus = SessionBus::new(subscribers.clone());
+    bus.publish("DepthTopic", "session-depth", "in flight");
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !gate.stalled.load(Ordering::SeqCst) {
+        if Instant::now() > deadline {
+            return Err("writer never stalled on the closed gate".into());
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for i in 0..10 {
+        bus.publish("DepthTopic", "session-depth", &format!("queued {}", i));
+    }
+    client.send(FrameMessage::Text("queue-depth".to_string())).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    gate.set_closed(false);
+
+    // The report is queued behind the backlog it describes
+    let mut delivered = 0;
+    loop {
+        let text = next_text(&mut client).await.ok_or("no queue_depth report received")?;
+        let frame: Value = serde_json::from_str(&text)?;
+        if frame["type"] == "queue_depth" {
+            if frame["depth"] != 10 || delivered != 11 {
+                return Err(format!("expected depth 10 after 11 deliveries, got {} after {}", frame, delivered).into());
+            }
+            println!("[server_tests] Reported: {}", frame);
+            break;
+        }
+        delivered += 1;
+    }
+
+    Ok(())
+}
+
+// Subscribes a raw client to its private topic and returns its direct-message address
+async fn subscribe_self(socket: &mut RawSocket) -> Result<String, Box<dyn Error>> {
+    socket.send(Message::Text("subscribe-self".to_string())).await?;
+    let confirmation = recv_type(socket, "self_subscribed", Duration::from_secs(2)).await
+        .ok_or("no self_subscribed confirmation")?;
+    Ok(confirmation["address"].as_str().ok_or("confirmation carried no address")?.to_string())
+}
+
+// A direct message reaches only its addressee, and private topics cannot be joined directly.
+// It goes through the publish pipeline: interceptors, JSON payloads and the fan-out limit
+async fn test_direct_message() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Direct message test...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        interceptors: vec![Arc::new(RedactField("secret"))],
+        ..Default::default()
+    }).await?;
+    let mut alice = connect_raw(&server.ws_url).await?;
+    let mut bob = connect_raw(&server.ws_url).await?;
+    let mut carol = connect_raw(&server.ws_url).await?;
+    carol.send(Message::Text("register-name:Carol".to_string())).await?;
+
+    let alice_address = subscribe_self(&mut alice).await?;
+    let bob_address = subscribe_self(&mut bob).await?;
+    if alice_address == bob_address {
+        return Err("anonymous connections share a direct address".into());
+    }
+
+    // Eavesdropping on Alice's private topic is refused
+    let alice_topic = format!("@direct/{}", alice_address);
+    carol.send(Message::Text(format!("subscribe:{}", alice_topic))).await?;
+    let refused = recv_type(&mut carol, "error", Duration::from_secs(2)).await
+        .ok_or("subscribing to a private topic was not refused")?;
+    if refused["code"] != "subscribe_not_allowed" {
+        return Err(format!("unexpected refusal: {}", refused).into());
+    }
+
+    carol.send(Message::Text(format!("publish-to:{}|hello alice", alice_address))).await?;
+    let delivered = recv_topic(&mut alice, &alice_topic, Duration::from_secs(2)).await
+        .ok_or("addressee did not receive the direct message")?;
+    if delivered["payload"] != "hello alice" || delivered["publisher_name"] != "Carol" {
+        return Err(format!("unexpected direct message: {}", delivered).into());
+    }
+    println!("[server_tests] Delivered directly: {}", delivered);
+
+    if let Some(leaked) = recv_topic(&mut bob, &alice_topic, Duration::from_millis(300)).await {
+        return Err(format!("direct message leaked to another client: {}", leaked).into());
+    }
+
+    // Nobody is listening at an unknown address
+    carol.send(Message::Text("publish-to:nobody|hello?".to_string())).await?;
+    let unavailable = recv_type(&mut carol, "error", Duration::from_secs(2)).await
+        .ok_or("no error for an unknown address")?;
+    if unavailable["code"] != "recipient_unavailable" {
+        return Err(format!("unexpected error: {}", unavailable).into());
+    }
+
+    // A JSON payload stays JSON, after the interceptors had their say
+    carol.send(Message::Text(format!(r#"publish-to:{}|{{"note":"hi","secret":"s3cr3t"}}"#, alice_address))).await?;
+    let delivered = recv_topic(&mut alice, &alice_topic, Duration::from_secs(2)).await
+        .ok_or("addressee did not receive the JSON direct message")?;
+    if delivered["payload"] != json!({"note": "hi"}) {
+        return Err(format!("direct message skipped the JSON parse or the interceptors: {}", delivered).into());
+    }
+    server.stop();
+
+    // The fan-out limit applies to direct messages too
+    let server = spawn_ws_server(ConnectionConfig { max_fan_out: Some(0), ..Default::default() }).await?;
+    let mut alice = connect_raw(&server.ws_url).await?;
+    let mut carol = connect_raw(&server.ws_url).await?;
+    let alice_address = subscribe_self(&mut alice).await?;
+    carol.send(Message::Text(format!("publish-to:{}|too many", alice_address))).await?;
+    let refused = recv_type(&mut carol, "error", Duration::from_secs(2)).await
+        .ok_or("a direct message over the fan-out limit was not refused")?;
+    if refused["code"] != "fan_out_too_large" {
+        return Err(format!("unexpected error: {}", refused).into());
+    }
+    println!("[server_tests] Direct messages are intercepted, parsed and fan-out limited");
+
+    server.stop();
+    Ok(())
+}
+
+// Wildcard subscriptions match by segment, and overlapping subscriptions deliver once per connection
+async fn test_wildcard_subscriptions() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Wildcard subscription test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut single = connect_raw(&server.ws_url).await?;
+    let mut multi = connect_raw(&server.ws_url).await?;
+    let mut publisher = connect_raw(&server.ws_url).await?;
+
+    // `#` must be the last segment
+    single.send(Message::Text("subscribe:sensor.#.temp".to_string())).await?;
+    let invalid = recv_type(&mut single, "error", Duration::from_secs(2)).await
+        .ok_or("malformed pattern was accepted")?;
+    if invalid["code"] != "invalid_topic_pattern" {
+        return Err(format!("unexpected error frame: {}", invalid).into());
+    }
+
+    single.send(Message::Text("subscribe:sensor.temp.*".to_string())).await?;
+    single.send(Message::Text("subscribe:sensor.temp.kitchen".to_string())).await?;
+    multi.send(Message::Text("subscribe:sensor.#".to_string())).await?;
+    sync_raw(&mut single).await?;
+    sync_raw(&mut multi).await?;
+
+    for topic in ["sensor.temp.kitchen", "sensor.humidity.kitchen", "sensor", "sensor.temp.kitchen.raw", "other.temp.kitchen", "sensor.temp.done"] {
+        let publish = json!({
+            "publisher_name": "Sensors",
+            "topic": topic,
+            "payload": "21.5",
+            "timestamp": "",
+        });
+        publisher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    }
+
+    let single_topics = topics_until(&mut single, "sensor.temp.done").await?;
+    if single_topics != ["sensor.temp.kitchen", "sensor.temp.done"] {
+        return Err(format!("sensor.temp.* with an overlapping exact subscription received {:?}", single_topics).into());
+    }
+    let multi_topics = topics_until(&mut multi, "sensor.temp.done").await?;
+    if multi_topics != ["sensor.temp.kitchen", "sensor.humidity.kitchen", "sensor", "sensor.temp.kitchen.raw", "sensor.temp.done"] {
+        return Err(format!("sensor.# received {:?}", multi_topics).into());
+    }
+    println!("[server_tests] sensor.temp.* received {:?}, sensor.# received {:?}", single_topics, multi_topics);
+
+    // Dropping the exact subscription leaves the wildcard in place
+    single.send(Message::Text("unsubscribe:sensor.temp.kitchen".to_string())).await?;
+    sync_raw(&mut single).await?;
+    let publish = json!({"publisher_name": "Sensors", "topic": "sensor.temp.kitchen", "payload": "22", "timestamp": ""});
+    publisher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    recv_topic(&mut single, "sensor.temp.kitchen", Duration::from_secs(2)).await
+        .ok_or("wildcard subscription stopped matching after the exact unsubscribe")?;
+
+    server.stop();
+    Ok(())
+}
+
+// Clearing a retained message sends current subscribers a tombstone
+async fn test_retained_tombstone() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Retained message tombstone test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut early = connect_raw(&server.ws_url).await?;
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    early.send(Message::Text("subscribe:config.flags".to_string())).await?;
+    sync_raw(&mut early).await?;
+
+    let retained = json!({"publisher_name": "Config", "topic": "config.flags", "payload": "dark-mode", "timestamp": "", "retain": true});
+    publisher.send(Message::Text(format!("publish-json:{}", retained))).await?;
+    recv_topic(&mut early, "config.flags", Duration::from_secs(2)).await
+        .ok_or("subscriber did not receive the retained publish")?;
+
+    // A late subscriber is handed the retained value on subscribe
+    let mut late = connect_raw(&server.ws_url).await?;
+    late.send(Message::Text("subscribe:config.*".to_string())).await?;
+    let value = recv_topic(&mut late, "config.flags", Duration::from_secs(2)).await
+        .ok_or("late subscriber was not sent the retained message")?;
+    if value["payload"] != "dark-mode" || value["retained"] != true {
+        return Err(format!("unexpected retained message: {}", value).into());
+    }
+
+    let clear = json!({"publisher_name": "Config", "topic": "config.flags", "payload": "", "timestamp": "", "retain": true});
+    publisher.send(Message::Text(format!("publish-json:{}", clear))).await?;
+    for subscriber in [&mut early, &mut late] {
+        let tombstone = recv_type(subscriber, "tombstone", Duration::from_secs(2)).await
+            .ok_or("subscriber did not receive a tombstone")?;
+        if tombstone["topic"] != "config.flags" {
+            return Err(format!("unexpected tombstone: {}", tombstone).into());
+        }
+    }
+    println!("[server_tests] Tombstone delivered to both subscribers");
+
+    // Nothing is retained any more
+    let mut after = connect_raw(&server.ws_url).await?;
+    after.send(Message::Text("subscribe:config.flags".to_string())).await?;
+    if let Some(stale) = recv_topic(&mut after, "config.flags", Duration::from_millis(300)).await {
+        return Err(format!("cleared retained message was still delivered: {}", stale).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Only the last retained publish is kept, per session, and a new subscriber gets it before live messages
+async fn test_retained_last_value() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Retained last value test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    publisher.send(Message::Text("register-session:session-retain".to_string())).await?;
+    for (payload, retain) in [("v1", true), ("v2", true), ("live-only", false)] {
+        let publish = json!({"publisher_name": "Gauge", "topic": "gauge.level", "payload": payload, "timestamp": "", "retain": retain});
+        publisher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    }
+    sync_raw(&mut publisher).await?;
+
+    let mut late = connect_raw(&server.ws_url).await?;
+    late.send(Message::Text("subscribe:gauge.level|session-retain".to_string())).await?;
+    // Published on the same connection, so the server handles it after the subscribe
+    let live = json!({"publisher_name": "Gauge", "topic": "gauge.level", "payload": "v3", "timestamp": "", "session_id": "session-retain"});
+    late.send(Message::Text(format!("publish-json:{}", live))).await?;
+    let mut received = Vec::new();
+    while let Some(envelope) = recv_topic(&mut late, "gauge.level", Duration::from_millis(500)).await {
+        received.push((envelope["payload"].clone(), envelope["retained"] == true));
+    }
+    if received != [(json!("v2"), true), (json!("v3"), false)] {
+        return Err(format!("expected the last retained value, then the live one, got {:?}", received).into());
+    }
+
+    // Retained values belong to their session
+    let mut elsewhere = connect_raw(&server.ws_url).await?;
+    elsewhere.send(Message::Text("subscribe:gauge.level|session-other".to_string())).await?;
+    if let Some(leaked) = recv_topic(&mut elsewhere, "gauge.level", Duration::from_millis(300)).await {
+        return Err(format!("retained value leaked into another session: {}", leaked).into());
+    }
+    println!("[server_tests] Late subscriber received {:?}", received);
+
+    server.stop();
+    Ok(())
+}
+
+// A will is published when its connection drops without closing, and discarded by a clean close
+async fn test_last_will() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Last will test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut watcher = connect_raw(&server.ws_url).await?;
+    watcher.send(Message::Text("subscribe:devices.status|session-will".to_string())).await?;
+    sync_raw(&mut watcher).await?;
+
+    // The socket goes away without a close frame, as when the client crashes
+    let mut crashing = connect_raw(&server.ws_url).await?;
+    crashing.send(Message::Text("register-name:Sensor".to_string())).await?;
+    crashing.send(Message::Text("register-session:session-will".to_string())).await?;
+    crashing.send(Message::Text("set-will:devices.status|Sensor offline".to_string())).await?;
+    sync_raw(&mut crashing).await?;
+    drop(crashing);
+    let will = recv_topic(&mut watcher, "devices.status", Duration::from_secs(2)).await
+        .ok_or("the will was not published after the connection dropped")?;
+    if will["payload"] != "Sensor offline" || will["publisher_name"] != "Sensor" || will["will"] != true {
+        return Err(format!("unexpected will envelope: {}", will).into());
+    }
+
+    // A client that closes on purpose takes its will with it
+    let mut leaving = connect_raw(&server.ws_url).await?;
+    leaving.send(Message::Text("register-session:session-will".to_string())).await?;
+    let set_will = json!({"op": "set_will", "topic": "devices.status", "payload": {"state": "gone"}});
+    leaving.send(Message::Text(set_will.to_string())).await?;
+    sync_raw(&mut leaving).await?;
+    leaving.close(None).await?;
+    if let Some(unexpected) = recv_topic(&mut watcher, "devices.status", Duration::from_millis(500)).await {
+        return Err(format!("a will was published after a clean close: {}", unexpected).into());
+    }
+    println!("[server_tests] Will delivered on drop: {}", will);
+
+    server.stop();
+    Ok(())
+}
+
+// Connects to a server with session_grace set and returns the socket with its session token
+async fn connect_with_session_token(url: &str) -> Result<(RawSocket, String), Box<dyn Error>> {
+    let mut socket = connect_raw(url).await?;
+    let notice = recv_type(&mut socket, "session_token", Duration::from_secs(2)).await
+        .ok_or("no session token was sent at connect")?;
+    let token = notice["token"].as_str().ok_or("session token notice without a token")?.to_string();
+    Ok((socket, token))
+}
+
+// A dropped connection's subscriptions are held for the grace period, and a reconnect with the
+// session token takes them over along with everything published in between
+async fn test_parked_session_reattach() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Parked session reattach test...");
+
+    let config = ConnectionConfig { session_grace: Some(Duration::from_secs(5)), ..Default::default() };
+    let parked = config.parked.clone();
+    let server = spawn_ws_server(config).await?;
+    let (mut dropping, token) = connect_with_session_token(&server.ws_url).await?;
+    dropping.send(Message::Text("subscribe:parked.feed|session-park".to_string())).await?;
+    sync_raw(&mut dropping).await?;
+    drop(dropping);
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while parked.is_empty() {
+        if Instant::now() > deadline {
+            return Err("the dropped connection was not parked".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Published while nobody is connected
+    let mut publisher = connect_raw(&server.ws_url).await?;
+    for payload in ["missed-1", "missed-2"] {
+        let publish = json!({"publisher_name": "Feed", "topic": "parked.feed", "payload": payload, "timestamp": "", "session_id": "session-park"});
+        publisher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    }
+    sync_raw(&mut publisher).await?;
+
+    let (mut returning, _) = connect_with_session_token(&server.ws_url).await?;
+    returning.send(Message::Text(format!("reattach:{}", token))).await?;
+    let mut flushed = Vec::new();
+    for _ in 0..2 {
+        let envelope = recv_topic(&mut returning, "parked.feed", Duration::from_secs(2)).await
+            .ok_or("a buffered message was not flushed on reattach")?;
+        flushed.push(envelope["payload"].clone());
+    }
+    let reattached = recv_type(&mut returning, "reattached", Duration::from_secs(2)).await
+        .ok_or("reattach was not answered")?;
+    if flushed != [json!("missed-1"), json!("missed-2")] || reattached["subscriptions"] != 1 || reattached["flushed"] != 2 {
+        return Err(format!("unexpected reattach: flushed {:?}, {}", flushed, reattached).into());
+    }
+
+    // The subscription now delivers live to the new connection, and the token cannot be used twice
+    let live = json!({"publisher_name": "Feed", "topic": "parked.feed", "payload": "live", "timestamp": "", "session_id": "session-park"});
+    publisher.send(Message::Text(format!("publish-json:{}", live))).await?;
+    let envelope = recv_topic(&mut returning, "parked.feed", Duration::from_secs(2)).await
+        .ok_or("the reattached subscription did not deliver live messages")?;
+    if envelope["payload"] != "live" || !parked.is_empty() {
+        return Err(format!("unexpected state after reattach: {}, {} parked", envelope, parked.len()).into());
+    }
+    let mut again = connect_raw(&server.ws_url).await?;
+    again.send(Message::Text(format!("reattach:{}", token))).await?;
+    let error = recv_type(&mut again, "error", Duration::from_secs(2)).await.ok_or("a used session token was accepted")?;
+    if error["code"] != "invalid_resume_token" {
+        return Err(format!("unexpected error for a used token: {}", error).into());
+    }
+    println!("[server_tests] Reattached with {} and flushed {:?}", reattached, flushed);
+
+    server.stop();
+    Ok(())
+}
+
+// A parked session nobody reattaches to is discarded once the grace runs out, and a clean close is never parked
+async fn test_parked_session_expiry() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Parked session expiry test...");
+
+    let config = ConnectionConfig { session_grace: Some(Duration::from_millis(300)), ..Default::default() };
+    let parked = config.parked.clone();
+    let server = spawn_ws_server(config).await?;
+
+    let (mut closing, _) = connect_with_session_token(&server.ws_url).await?;
+    closing.send(Message::Text("subscribe:parked.closed|session-expire".to_string())).await?;
+    sync_raw(&mut closing).await?;
+    closing.close(None).await?;
+
+    let (mut dropping, token) = connect_with_session_token(&server.ws_url).await?;
+    dropping.send(Message::Text("subscribe:parked.expiring|session-expire".to_string())).await?;
+    sync_raw(&mut dropping).await?;
+    drop(dropping);
+
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while server.subscribers.subscriber_count("parked.expiring", "session-expire") > 0 {
+        if Instant::now() > deadline {
+            return Err("the parked subscription outlived its grace period".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    if !parked.is_empty() || server.subscribers.subscriber_count("parked.closed", "session-expire") > 0 {
+        return Err(format!("{} sessions still parked after expiry or a clean close", parked.len()).into());
+    }
+    let mut late = connect_raw(&server.ws_url).await?;
+    late.send(Message::Text(format!("reattach:{}", token))).await?;
+    let error = recv_type(&mut late, "error", Duration::from_secs(2)).await.ok_or("an expired session token was accepted")?;
+    if error["code"] != "invalid_resume_token" {
+        return Err(format!("unexpected error for an expired token: {}", error).into());
+    }
+    println!("[server_tests] Expired session token refused: {}", error);
+
+    server.stop();
+    Ok(())
+}
+
+// The roster lists who is connected in a session, follows renames and drops a name with its last connection
+async fn test_presence_roster() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Presence roster test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut alice = connect_raw(&server.ws_url).await?;
+    alice.send(Message::Text("register-name:Alice".to_string())).await?;
+    alice.send(Message::Text("register-session:room-2".to_string())).await?;
+    alice.send(Message::Text("negotiate:presence".to_string())).await?;
+    recv_type(&mut alice, "negotiated", Duration::from_secs(2)).await.ok_or("negotiate was not answered")?;
+
+    let mut bobs = Vec::new();
+    for _ in 0..2 {
+        let mut bob = connect_raw(&server.ws_url).await?;
+        bob.send(Message::Text("register-name:Bob".to_string())).await?;
+        bob.send(Message::Text("register-session:room-2".to_string())).await?;
+        sync_raw(&mut bob).await?;
+        bobs.push(bob);
+    }
+    // Naming itself after joining replaces the placeholder name on the roster
+    let mut carol = connect_raw(&server.ws_url).await?;
+    carol.send(Message::Text("register-session:room-2".to_string())).await?;
+    carol.send(Message::Text("register-name:Carol".to_string())).await?;
+    sync_raw(&mut carol).await?;
+
+    let roster = list_presence(&mut alice, "list-presence:".to_string()).await?;
+    if roster["session"] != "room-2" || roster["members"] != json!(["Alice", "Bob", "Carol"]) {
+        return Err(format!("unexpected roster: {}", roster).into());
+    }
+    let other = list_presence(&mut alice, json!({"op": "list_presence", "session_id": "room-elsewhere"}).to_string()).await?;
+    if other["members"] != json!([]) {
+        return Err(format!("another session's roster was not empty: {}", other).into());
+    }
+    println!("[server_tests] Roster: {}", roster);
+
+    // Bob stays listed until his second connection goes
+    for expected in [json!(["Alice", "Bob", "Carol"]), json!(["Alice", "Carol"])] {
+        let mut bob = bobs.remove(0);
+        bob.send(Message::Close(None)).await?;
+        // Join events may still be queued ahead of the leave
+        loop {
+            let event = recv_type(&mut alice, "presence", Duration::from_secs(2)).await.ok_or("no leave event")?;
+            if event["left"] == "Bob" {
+                break;
+            }
+        }
+        let roster = list_presence(&mut alice, "list-presence:".to_string()).await?;
+        if roster["members"] != expected {
+            return Err(format!("expected {} after a Bob left, got {}", expected, roster).into());
+        }
+    }
+    println!("[server_tests] Roster updated as connections closed");
+
+    server.stop();
+    Ok(())
+}
+
+async fn list_presence(socket: &mut RawSocket, command: String) -> Result<Value, Box<dyn Error>> {
+    socket.send(Message::Text(command)).await?;
+    Ok(recv_type(socket, "presence_list", Duration::from_secs(2)).await.ok_or("presence query was not answered")?)
+}
+
+// Features negotiated by one connection apply to it alone
+async fn test_negotiated_features() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Negotiated features test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut plain = connect_raw(&server.ws_url).await?;
+    plain.send(Message::Text("register-session:room-1".to_string())).await?;
+    sync_raw(&mut plain).await?;
+
+    let mut watcher = connect_raw(&server.ws_url).await?;
+    watcher.send(Message::Text("register-name:Watcher".to_string())).await?;
+    watcher.send(Message::Text("register-session:room-1".to_string())).await?;
+    watcher.send(Message::Text("negotiate:acks,presence,teleport".to_string())).await?;
+    let negotiated = recv_type(&mut watcher, "negotiated", Duration::from_secs(2)).await
+        .ok_or("negotiation was not confirmed")?;
+    if negotiated["features"] != json!(["acks", "presence"]) || negotiated["unsupported"] != json!(["teleport"]) {
+        return Err(format!("unexpected negotiation result: {}", negotiated).into());
+    }
+
+    // Acks
+    watcher.send(Message::Text("subscribe:RoomTopic".to_string())).await?;
+    let ack = recv_type(&mut watcher, "ack", Duration::from_secs(2)).await.ok_or("subscribe was not acknowledged")?;
+    if ack["op"] != "subscribe" || ack["topic"] != "RoomTopic" {
+        return Err(format!("unexpected subscribe ack: {}", ack).into());
+    }
+    let publish = json!({"publisher_name": "Watcher", "topic": "RoomTopic", "payload": "hi", "timestamp": ""});
+    watcher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    let ack = recv_type(&mut watcher, "ack", Duration::from_secs(2)).await.ok_or("publish was not acknowledged")?;
+    if ack["op"] != "publish" || ack["delivered"] != 1 {
+        return Err(format!("unexpected publish ack: {}", ack).into());
+    }
+
+    // Presence
+    let mut guest = connect_raw(&server.ws_url).await?;
+    guest.send(Message::Text("register-name:Guest".to_string())).await?;
+    guest.send(Message::Text("register-session:room-1".to_string())).await?;
+    let joined = recv_type(&mut watcher, "presence", Duration::from_secs(2)).await.ok_or("no join event")?;
+    if joined["joined"] != "Guest" || joined["session"] != "room-1" {
+        return Err(format!("unexpected join event: {}", joined).into());
+    }
+    guest.send(Message::Close(None)).await?;
+    let left = recv_type(&mut watcher, "presence", Duration::from_secs(2)).await.ok_or("no leave event")?;
+    if left["left"] != "Guest" {
+        return Err(format!("unexpected leave event: {}", left).into());
+    }
+    println!("[server_tests] Watcher saw {} then {}", joined, left);
+
+    // The connection that did not negotiate keeps the defaults
+    plain.send(Message::Text("subscribe:RoomTopic".to_string())).await?;
+    plain.send(Message::Text("ping".to_string())).await?;
+    let frames = tokio::time::timeout(Duration::from_secs(2), async {
+        let mut frames = Vec::new();
+        while let Some(Ok(Message::Text(text))) = plain.next().await {
+            if text == "pong" {
+                break;
+            }
+            frames.push(text);
+        }
+        frames
+    }).await?;
+    if let Some(unexpected) = frames.iter().find(|frame| frame.contains("\"ack\"") || frame.contains("\"presence\"")) {
+        return Err(format!("connection without negotiation received {}", unexpected).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Removes a field from object payloads
+struct RedactField(&'static str);
+
+impl MessageInterceptor for RedactField {
+    fn on_publish(&self, msg: &mut Envelope) -> Decision {
+        if let Some(fields) = msg.payload.as_object_mut() {
+            fields.remove(self.0);
+        }
+        Decision::Forward
+    }
+}
+
+// Drops publishes to topics under a prefix
+struct DropTopics(&'static str);
+
+impl MessageInterceptor for DropTopics {
+    fn on_publish(&self, msg: &mut Envelope) -> Decision {
+        if msg.topic().starts_with(self.0) { Decision::Drop } else { Decision::Forward }
+    }
+}
+
+// Records the topic of every publish it sees
+struct SeenTopics(Arc<Mutex<Vec<String>>>);
+
+impl MessageInterceptor for SeenTopics {
+    fn on_publish(&self, msg: &mut Envelope) -> Decision {
+        self.0.lock().unwrap().push(msg.topic().to_string());
+        Decision::Forward
+    }
+}
+
+// Interceptors run in order before fan-out; a drop stops the chain and the delivery
+async fn test_message_interceptors() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Message interceptor test...");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let server = spawn_ws_server(ConnectionConfig {
+        interceptors: vec![
+            Arc::new(NoopInterceptor),
+            Arc::new(ServerTimestamp),
+            Arc::new(RedactField("password")),
+            Arc::new(DropTopics("blocked.")),
+            Arc::new(SeenTopics(seen.clone())),
+        ],
+        ..Default::default()
+    }).await?;
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("register-session:session-intercept".to_string())).await?;
+    for topic in ["open.events", "blocked.events"] {
+        socket.send(Message::Text(format!("subscribe:{}|session-intercept", topic))).await?;
+    }
+    sync_raw(&mut socket).await?;
+
+    // Forwarded: the timestamp is the server's and the redacted field is gone
+    let publish = json!({
+        "publisher_name": "Interceptor", "topic": "open.events",
+        "payload": {"user": "ann", "password": "hunter2"}, "timestamp": "1999-01-01T00:00:00Z"
+    });
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    let envelope = recv_topic(&mut socket, "open.events", Duration::from_secs(2)).await.ok_or("forwarded publish was not delivered")?;
+    if envelope["payload"] != json!({"user": "ann"}) || envelope["timestamp"] == "1999-01-01T00:00:00Z" {
+        return Err(format!("interceptors did not rewrite the envelope: {}", envelope).into());
+    }
+
+    // Dropped: acknowledged with no deliveries, never sent and unseen by later interceptors
+    let publish = json!({"id": "dropped", "publisher_name": "Interceptor", "topic": "blocked.events", "payload": "hidden"});
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    let ack = recv_type(&mut socket, "ack", Duration::from_secs(2)).await.ok_or("dropped publish was not acknowledged")?;
+    if ack["id"] != "dropped" || ack["delivered"] != 0 {
+        return Err(format!("unexpected ack for a dropped publish: {}", ack).into());
+    }
+    if recv_topic(&mut socket, "blocked.events", Duration::from_millis(300)).await.is_some() {
+        return Err("dropped publish was delivered".into());
+    }
+    if *seen.lock().unwrap() != ["open.events"] {
+        return Err(format!("chain did not stop at the drop: {:?}", seen.lock().unwrap()).into());
+    }
+    println!("[server_tests] Forwarded {}", envelope);
+
+    server.stop();
+    Ok(())
+}
+
+// A command carrying an id is acknowledged with that id even without negotiated acks
+async fn test_command_id_acks() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Command id acks test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("register-session:session-ids".to_string())).await?;
+
+    socket.send(Message::Text("subscribe:IdTopic|session-ids||sub-1".to_string())).await?;
+    let ack = recv_type(&mut socket, "ack", Duration::from_secs(2)).await.ok_or("subscribe with an id was not acknowledged")?;
+    if ack["id"] != "sub-1" || ack["op"] != "subscribe" || ack["topic"] != "IdTopic" {
+        return Err(format!("unexpected subscribe ack: {}", ack).into());
+    }
+
+    let publish = json!({"id": "pub-1", "publisher_name": "Ids", "topic": "IdTopic", "payload": "hi", "timestamp": ""});
+    socket.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    let ack = recv_type(&mut socket, "ack", Duration::from_secs(2)).await.ok_or("publish with an id was not acknowledged")?;
+    if ack["id"] != "pub-1" || ack["op"] != "publish" || ack["delivered"] != 1 {
+        return Err(format!("unexpected publish ack: {}", ack).into());
+    }
+
+    socket.send(Message::Text("unsubscribe:IdTopic|session-ids|unsub-1".to_string())).await?;
+    let ack = recv_type(&mut socket, "ack", Duration::from_secs(2)).await.ok_or("unsubscribe with an id was not acknowledged")?;
+    if ack["id"] != "unsub-1" || ack["op"] != "unsubscribe" {
+        return Err(format!("unexpected unsubscribe ack: {}", ack).into());
+    }
+
+    // Errors echo the id so the waiting command fails instead of timing out
+    socket.send(Message::Text("subscribe:sensor.#.temp|session-ids||sub-2".to_string())).await?;
+    let error = recv_type(&mut socket, "error", Duration::from_secs(2)).await.ok_or("refused subscribe got no error")?;
+    if error["id"] != "sub-2" || error["code"] != "invalid_topic_pattern" {
+        return Err(format!("unexpected subscribe error: {}", error).into());
+    }
+    println!("[server_tests] Acknowledged ids: sub-1, pub-1, unsub-1; refused sub-2 with {}", error["code"]);
+
+    server.stop();
+    Ok(())
+}
+
+// JSON commands carry topics and sessions containing the legacy delimiters intact
+async fn test_typed_commands() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Typed commands test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let topic = "typed|topic:with,delimiters";
+    let session = "typed|session";
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text(json!({"op": "register_session", "session_id": session}).to_string())).await?;
+    socket.send(Message::Text(json!({"op": "subscribe", "topic": topic, "id": "typed-sub"}).to_string())).await?;
+    let ack = recv_type(&mut socket, "ack", Duration::from_secs(2)).await.ok_or("typed subscribe was not acknowledged")?;
+    if ack["topic"] != topic || ack["session_id"] != session || ack["id"] != "typed-sub" {
+        return Err(format!("unexpected subscribe ack: {}", ack).into());
+    }
+
+    // The client sends typed commands, so its publish reaches the same topic and session
+    let mut client = WsClient::connect_with_session("TypedClient", session, &server.ws_url).await?;
+    client.publish("TypedClient", topic, "through the delimiters", "").await?;
+    let delivered = recv_topic(&mut socket, topic, Duration::from_secs(2)).await
+        .ok_or("typed publish was not delivered")?;
+    if delivered["payload"] != "through the delimiters" || delivered["session_id"] != session {
+        return Err(format!("unexpected delivery: {}", delivered).into());
+    }
+    println!("[server_tests] Delivered on '{}': {}", topic, delivered["payload"]);
+
+    // A legacy command still works, splitting on its delimiters
+    socket.send(Message::Text("unsubscribe:typed|typed|session|legacy-unsub".to_string())).await?;
+    let ack = recv_type(&mut socket, "ack", Duration::from_secs(2)).await.ok_or("legacy unsubscribe was not acknowledged")?;
+    if ack["topic"] != "typed" || ack["session_id"] != "typed" || ack["id"] != "session" {
+        return Err(format!("unexpected legacy unsubscribe ack: {}", ack).into());
+    }
+
+    // An unknown op or malformed command is refused as bad JSON
+    for frame in [json!({"op": "explode"}).to_string(), "{\"op\": \"subscribe\"".to_string()] {
+        socket.send(Message::Text(frame.clone())).await?;
+        let error = recv_type(&mut socket, "error", Duration::from_secs(2)).await
+            .ok_or_else(|| format!("no error for {}", frame))?;
+        if error["code"] != "bad_json" {
+            return Err(format!("unexpected error for {}: {}", frame, error).into());
+        }
+    }
+
+    client.close().await?;
+    server.stop();
+    Ok(())
+}
+
+// A connection can ask which (topic, session) pairs it is subscribed to
+async fn test_list_subscriptions() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] List subscriptions test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut socket = connect_raw(&server.ws_url).await?;
+    socket.send(Message::Text("register-session:session-list".to_string())).await?;
+    for command in ["subscribe:ListA", "subscribe:ListB|session-other", "subscribe:ListC", "unsubscribe:ListC"] {
+        socket.send(Message::Text(command.to_string())).await?;
+    }
+    socket.send(Message::Text("list-subscriptions".to_string())).await?;
+    let listed = recv_type(&mut socket, "subscriptions", Duration::from_secs(2)).await
+        .ok_or("list-subscriptions got no reply")?;
+    let expected = json!([
+        {"topic": "ListA", "session_id": "session-list"},
+        {"topic": "ListB", "session_id": "session-other"}
+    ]);
+    if listed["subscriptions"] != expected {
+        return Err(format!("unexpected subscriptions: {}", listed).into());
+    }
+    println!("[server_tests] Listed {}", listed["subscriptions"]);
+
+    server.stop();
+    Ok(())
+}
+
+// The admin endpoints need the token when one is set, and only count connections that are still open
+async fn test_admin_token_and_live_counts() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Admin token and live counts test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut sockets = Vec::new();
+    for (i, session) in ["session-a", "session-a", "session-b"].iter().enumerate() {
+        let mut socket = connect_raw(&server.ws_url).await?;
+        socket.send(Message::Text(format!("subscribe:Watched|{}||w{}", session, i))).await?;
+        recv_type(&mut socket, "ack", Duration::from_secs(2)).await.ok_or("subscribe was not acknowledged")?;
+        sockets.push(socket);
+    }
+    // Sinks whose connection has gone, not yet pruned
+    let (dead, receiver) = tokio::sync::mpsc::unbounded_channel();
+    drop(receiver);
+    for (topic, session) in [("Watched", "session-c"), ("Abandoned", "session-a")] {
+        server.subscribers.write(topic).entry(topic.to_string()).or_default()
+            .entry(session.to_string()).or_default().push(Subscriber::new(dead.clone(), ConnectionId::generate()));
+    }
+
+    let app: axum::Router = libws::admin_api_route::admin_api_router_with_token(server.subscribers.clone(), "admin-secret");
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let client = reqwest::Client::new();
+
+    for auth in [None, Some("Bearer wrong-secret"), Some("admin-secret")] {
+        let mut request = client.get(format!("{}/admin/topics?summary=true", base));
+        if let Some(auth) = auth {
+            request = request.header("Authorization", auth);
+        }
+        let status = request.send().await?.status();
+        if status != reqwest::StatusCode::UNAUTHORIZED {
+            return Err(format!("expected 401 for authorization {:?}, got {}", auth, status).into());
+        }
+    }
+
+    let summary: Value = client.get(format!("{}/admin/topics?summary=true", base))
+        .bearer_auth("admin-secret").send().await?.json().await?;
+    if summary != json!({"topics": 1, "sessions": 2, "subscribers": 3}) {
+        return Err(format!("unexpected summary: {}", summary).into());
+    }
+    let page: Value = client.get(format!("{}/admin/sessions?topic=Watched", base))
+        .bearer_auth("admin-secret").send().await?.json().await?;
+    let expected = json!([{"session_id": "session-a", "subscribers": 2}, {"session_id": "session-b", "subscribers": 1}]);
+    if page["items"] != expected {
+        return Err(format!("unexpected session page: {}", page).into());
+    }
+    println!("[server_tests] Admin summary {} and sessions {}", summary, page["items"]);
+
+    handle.abort();
+    server.stop();
+    Ok(())
+}
+
+// Collects the topics of published messages up to and including the marker topic
+async fn topics_until(socket: &mut RawSocket, marker: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut topics = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = socket.next().await {
+            let Message::Text(text) = msg else { continue };
+            let Ok(value) = serde_json::from_str::<Value>(&text) else { continue };
+            let Some(topic) = value["topic"].as_str().filter(|_| value.get("payload").is_some()) else { continue };
+            topics.push(topic.to_string());
+            if topic == marker {
+                break;
+            }
+        }
+    }).await.map_err(|_| format!("timed out waiting for {} after {:?}", marker, topics))?;
+    Ok(topics)
+}
+
+// Concurrent publishers fan out to 1000 subscribers while other topics churn subscriptions
+async fn test_concurrent_fan_out() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Concurrent fan-out test...");
+
+    const SUBSCRIBERS: usize = 1000;
+    const PUBLISHERS: usize = 8;
+    const MESSAGES_PER_PUBLISHER: usize = 25;
+
+    let subscribers = Subscribers::default();
+    let mut receivers = Vec::with_capacity(SUBSCRIBERS);
+    for _ in 0..SUBSCRIBERS {
+        let (sink, receiver) = tokio::sync::mpsc::unbounded_channel();
+        subscribers.write("FanOut")
+            .entry("FanOut".to_string()).or_default()
+            .entry("session-fan-out".to_string()).or_default()
+            .push(Subscriber::new(sink, ConnectionId::generate()));
+        receivers.push(receiver);
+    }
+
+    let bus = SessionBus::new(subscribers.clone());
+    let started = Instant::now();
+    let mut tasks = Vec::new();
+    for publisher in 0..PUBLISHERS {
+        let bus = bus.clone();
+        tasks.push(tokio::spawn(async move {
+            for n in 0..MESSAGES_PER_PUBLISHER {
+                bus.publish("FanOut", "session-fan-out", &format!("{}:{}", publisher, n));
+            }
+        }));
+    }
+    // Subscribing to unrelated topics only takes their shards' write locks
+    let churn_subscribers = subscribers.clone();
+    tasks.push(tokio::spawn(async move {
+        for n in 0..200 {
+            let topic = format!("Churn/{}", n);
+            let (sink, _receiver) = tokio::sync::mpsc::unbounded_channel();
+            churn_subscribers.write(&topic).entry(topic.clone()).or_default()
+                .entry("session-churn".to_string()).or_default().push(Subscriber::new(sink, ConnectionId::generate()));
+            churn_subscribers.write(&topic).remove(&topic);
+        }
+    }));
+    for task in tasks {
+        task.await?;
+    }
+    println!("[server_tests] Published {} messages to {} subscribers in {:?}",
+        PUBLISHERS * MESSAGES_PER_PUBLISHER, SUBSCRIBERS, started.elapsed());
+
+    // Every subscriber gets every message, each publisher's in order
+    for receiver in &mut receivers {
+        let mut next = [0usize; PUBLISHERS];
+        while let Ok(envelope) = receiver.try_recv() {
+            let envelope: Value = serde_json::from_str(&envelope)?;
+            let (publisher, n) = envelope["payload"].as_str().and_then(|p| p.split_once(':'))
+                .ok_or("malformed payload")?;
+            let (publisher, n): (usize, usize) = (publisher.parse()?, n.parse()?);
+            if next[publisher] != n {
+                return Err(format!("publisher {} message {} arrived out of order", publisher, n).into());
+            }
+            next[publisher] += 1;
+        }
+        if next.iter().any(|&count| count != MESSAGES_PER_PUBLISHER) {
+            return Err(format!("subscriber missed messages: {:?}", next).into());
+        }
+    }
+    if bus.topics() != vec!["FanOut".to_string()] {
+        return Err(format!("unexpected topics after churn: {:?}", bus.topics()).into());
+    }
+
+    Ok(())
+}
+
+// The admin subscriber listing reports each subscription's connection id and peer address
+async fn test_admin_subscriber_peers() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Admin subscriber peers test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut local_addrs = Vec::new();
+    let mut sockets = Vec::new();
+    for session in ["session-a", "session-b"] {
+        let mut socket = connect_raw(&server.ws_url).await?;
+        let MaybeTlsStream::Plain(tcp) = socket.get_ref() else {
+            return Err("expected a plain TCP connection".into());
+        };
+        local_addrs.push(tcp.local_addr()?.to_string());
+        socket.send(Message::Text(format!("subscribe:Traced|{}|unordered", session))).await?;
+        sync_raw(&mut socket).await?;
+        sockets.push(socket);
+    }
+
+    let app: axum::Router = libws::admin_api_route::admin_api_router(server.subscribers.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let client = reqwest::Client::new();
+
+    let page: Value = client.get(format!("{}/admin/subscribers?topic=Traced", base)).send().await?.json().await?;
+    let items = page["items"].as_array().ok_or("subscriber listing has no items")?;
+    let peers: Vec<&str> = items.iter().filter_map(|item| item["peer"].as_str()).collect();
+    if peers != local_addrs {
+        return Err(format!("expected peers {:?}, got {}", local_addrs, page).into());
+    }
+    if items[0]["connection_id"] == items[1]["connection_id"] || items[0]["options"] != json!(["unordered"]) {
+        return Err(format!("unexpected subscriber entries: {}", page).into());
+    }
+    let filtered: Value = client.get(format!("{}/admin/subscribers?topic=Traced&session_id=session-b", base))
+        .send().await?.json().await?;
+    if filtered["total"] != 1 || filtered["items"][0]["peer"] != local_addrs[1].as_str() {
+        return Err(format!("unexpected listing for session-b: {}", filtered).into());
+    }
+    let status = client.get(format!("{}/admin/subscribers", base)).send().await?.status();
+    if status != reqwest::StatusCode::BAD_REQUEST {
+        return Err(format!("expected 400 without a topic, got {}", status).into());
+    }
+    println!("[server_tests] Subscribers listed with peers {:?}", peers);
+
+    handle.abort();
+    server.stop();
+    Ok(())
+}
+
+// A router assembled by WsServerBuilder serves pub/sub at its path, tokens from its JWT API, and CORS
+async fn test_server_builder() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Server builder test...");
+
+    let subscribers = Subscribers::default();
+    let mut jwt = libws::jwt_api_route::create_default_jwt_state();
+    jwt.secret_key = Arc::new(zeroize::Zeroizing::new([0x24; 32]));
+    let app = WsServerBuilder::new()
+        .with_path("/pubsub")
+        .with_subscribers(subscribers.clone())
+        .with_jwt(jwt)
+        .with_cors(tower_http::cors::CorsLayer::permissive())
+        .build();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await.unwrap();
+    });
+    let client = reqwest::Client::new();
+
+    let response = client.post(format!("http://{}/auth/token", addr))
+        .header("Origin", "http://app.example")
+        .json(&json!({"username": "frank", "password": "pw", "session_id": "session-built"}))
+        .send().await?;
+    if !response.headers().contains_key("access-control-allow-origin") {
+        return Err("the CORS policy was not applied to the JWT API".into());
+    }
+    let token = response.json::<Value>().await?["token"].as_str().ok_or("no token issued")?.to_string();
+    let status = client.get(format!("http://{}/enc/public-key", addr)).send().await?.status();
+    if status != reqwest::StatusCode::NOT_FOUND {
+        return Err(format!("the encryption API was served without being enabled: {}", status).into());
+    }
+
+    // The endpoint validates tokens with the builder's JWT key and shares the caller's subscriber map
+    let url = format!("ws://{}/pubsub", addr);
+    let mut subscriber = connect_raw(&format!("{}?token={}", url, token)).await?;
+    subscriber.send(Message::Text("subscribe:built.topic|session-built".to_string())).await?;
+    sync_raw(&mut subscriber).await?;
+    if subscribers.subscriber_count("built.topic", "session-built") != 1 {
+        return Err("the subscription is missing from the shared subscriber map".into());
+    }
+    let mut publisher = connect_raw(&url).await?;
+    let publish = json!({"publisher_name": "Builder", "topic": "built.topic", "payload": "hi", "timestamp": "", "session_id": "session-built"});
+    publisher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    let envelope = recv_topic(&mut subscriber, "built.topic", Duration::from_secs(2)).await
+        .ok_or("the publish did not reach the subscriber")?;
+    if envelope["payload"] != "hi" {
+        return Err(format!("unexpected envelope: {}", envelope).into());
+    }
+    if connect_raw(&format!("ws://{}/ws", addr)).await.is_ok() {
+        return Err("the endpoint was also served at the default path".into());
+    }
+    println!("[server_tests] Builder router delivered {}", envelope);
+
+    handle.abort();
+    Ok(())
+}
+
+// Admin summaries report counts for a huge topic without listing its subscribers
+async fn test_admin_counts_for_large_topic() -> Result<(), Box<dyn Error>> {
+    println!("[server_tests] Admin counts for a large topic test...");
+
+    const CROWD: usize = 200_000;
+    let subscribers = Subscribers::default();
+    let (sink, _receiver) = tokio::sync::mpsc::unbounded_channel();
+    {
+        let mut shard = subscribers.write("Crowded");
+        let sessions = shard.entry("Crowded".to_string()).or_default();
+        for i in 0..CROWD {
+            sessions.entry(format!("session-{}", i % 3)).or_default().push(Subscriber::new(sink.clone(), ConnectionId::generate()));
+        }
+    }
+    for topic in ["Quiet/a", "Quiet/b"] {
+        subscribers.write(topic).entry(topic.to_string()).or_default()
+            .entry("session-0".to_string()).or_default().push(Subscriber::new(sink.clone(), ConnectionId::generate()));
+    }
+
+    let app: axum::Router = libws::admin_api_route::admin_api_router(subscribers.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+    let client = reqwest::Client::new();
+
+    // Summaries are a handful of numbers however many subscribers there are
+    let body = client.get(format!("{}/admin/topics?summary=true", base)).send().await?.text().await?;
+    println!("[server_tests] Endpoint summary: {}", body);
+    let summary: Value = serde_json::from_str(&body)?;
+    if summary != json!({"topics": 3, "sessions": 5, "subscribers": CROWD + 2}) {
+        return Err(format!("unexpected endpoint summary: {}", summary).into());
+    }
+    let body = client.get(format!("{}/admin/sessions?topic=Crowded&summary=true", base)).send().await?.text().await?;
+    println!("[server_tests] Topic summary: {}", body);
+    let summary: Value = serde_json::from_str(&body)?;
+    if summary != json!({"topic": "Crowded", "sessions": 3, "subscribers": CROWD}) || body.len() > 128 {
+        return Err(format!("unexpected topic summary: {}", body).into());
+    }
+
+    // Listings are paged
+    let page: Value = client.get(format!("{}/admin/topics?offset=1&limit=1", base)).send().await?.json().await?;
+    if page != json!({"total": 3, "offset": 1, "limit": 1, "items": [{"topic": "Quiet/a", "sessions": 1, "subscribers": 1}]}) {
+        return Err(format!("unexpected topic page: {}", page).into());
+    }
+    let page: Value = client.get(format!("{}/admin/sessions?topic=Crowded&limit=2", base)).send().await?.json().await?;
+    let expected = json!([
+        {"session_id": "session-0", "subscribers": CROWD.div_ceil(3)},
+        {"session_id": "session-1", "subscribers": CROWD.div_ceil(3)}
+    ]);
+    if page["total"] != 3 || page["items"] != expected {
+        return Err(format!("unexpected session page: {}", page).into());
+    }
+
+    let missing = client.get(format!("{}/admin/sessions", base)).send().await?;
+    if missing.status() != reqwest::StatusCode::BAD_REQUEST {
+        return Err(format!("expected 400 without a topic, got {}", missing.status()).into());
+    }
+
+    handle.abort();
+    Ok(())
+}
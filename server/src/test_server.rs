@@ -0,0 +1,289 @@
+// src/test_server.rs
+use axum::{
+    Router,
+    routing::get,
+    extract::{
+        connect_info::ConnectInfo,
+        ws::WebSocketUpgrade,
+        Query,
+    },
+    http::HeaderMap,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use libws::command::ClientCommand;
+use libws::timestamp::now_rfc3339;
+use libws::ws_client::WsClient;
+use libws::{ConnectionConfig, Subscribers, WebSocketParams};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// A raw protocol-level client used to observe server frames that `WsClient` does not surface.
+pub type RawSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A WebSocket server started on an ephemeral port for a single test scenario.
+pub struct TestServer {
+    pub ws_url: String,
+    pub subscribers: Subscribers,
+    handle: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Stops the server task. Connections that were already upgraded keep running until closed.
+    pub fn stop(&self) {
+        self.handle.abort();
+    }
+}
+
+/// Starts a `/ws` endpoint with the given configuration on 127.0.0.1 and a random port.
+pub async fn spawn_ws_server(config: ConnectionConfig) -> Result<TestServer, Box<dyn Error>> {
+    spawn_ws_server_at("127.0.0.1:0", config).await
+}
+
+/// Starts a `/ws` endpoint with the given configuration on the given address.
+pub async fn spawn_ws_server_at(addr: &str, config: ConnectionConfig) -> Result<TestServer, Box<dyn Error>> {
+    let subscribers: Subscribers = Subscribers::default();
+    let app = ws_app(subscribers.clone(), config);
+
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    });
+
+    Ok(TestServer {
+        ws_url: format!("ws://{}/ws", addr),
+        subscribers,
+        handle,
+    })
+}
+
+/// Starts a `/ws` endpoint served over TLS on 127.0.0.1 and a random port. `ws_url` is a
+/// `wss://localhost` URL, so the certificate must be issued for `localhost`.
+pub async fn spawn_wss_server(config: ConnectionConfig, tls_config: RustlsConfig) -> Result<TestServer, Box<dyn Error>> {
+    let subscribers: Subscribers = Subscribers::default();
+    let app = ws_app(subscribers.clone(), config);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let handle = tokio::spawn(async move {
+        // Stopped by aborting the task rather than by a shutdown signal
+        crate::tls::serve_tls(listener, tls_config, app, std::future::pending(), Duration::ZERO).await.unwrap();
+    });
+
+    Ok(TestServer {
+        ws_url: format!("wss://localhost:{}/ws", port),
+        subscribers,
+        handle,
+    })
+}
+
+// The `/ws` route, handing every connection the same configuration
+fn ws_app(subscribers_inner: Subscribers, config: ConnectionConfig) -> Router {
+    let config = Arc::new(config);
+
+    Router::new().route(
+        "/ws",
+        get(move |ws: WebSocketUpgrade,
+                  ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                  query_params: Option<Query<WebSocketParams>>,
+                  headers: HeaderMap| {
+            let subscribers = subscribers_inner.clone();
+            let config = config.clone();
+            async move {
+                libws::handle_socket_with_config(ws, ConnectInfo(addr), query_params, headers, subscribers, config).await
+            }
+        }),
+    )
+}
+
+/// A test server on a runtime of its own, so it can be killed together with its open connections.
+pub struct KillableServer {
+    pub ws_url: String,
+    pub subscribers: Subscribers,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl KillableServer {
+    /// Drops the server and every connection it holds, as if the process had died.
+    pub fn kill(self) {}
+}
+
+// Also kills the server when a test returns early; a runtime cannot be dropped normally inside another
+impl Drop for KillableServer {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Starts a killable `/ws` endpoint on the given address; pass port 0 for a random one.
+pub async fn spawn_killable_server(addr: &str, config: ConnectionConfig) -> Result<KillableServer, Box<dyn Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build()?;
+    let addr = addr.to_string();
+    // Started on the server's runtime so its listener and connection tasks die with it
+    let server = runtime.spawn(async move {
+        spawn_ws_server_at(&addr, config).await
+            .map(|server| (server.ws_url, server.subscribers))
+            .map_err(|e| e.to_string())
+    }).await??;
+    Ok(KillableServer { ws_url: server.0, subscribers: server.1, runtime: Some(runtime) })
+}
+
+/// Starts a `/ws` endpoint that reads the client's registration frames and then closes the socket.
+pub async fn spawn_closing_server() -> Result<TestServer, Box<dyn Error>> {
+    let app = Router::new().route(
+        "/ws",
+        get(|ws: WebSocketUpgrade| async move {
+            ws.on_upgrade(|mut socket| async move {
+                // Wait for register-name and register-session before closing
+                let _ = socket.recv().await;
+                let _ = socket.recv().await;
+                let _ = socket.close().await;
+            })
+        }),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+
+    Ok(TestServer {
+        ws_url: format!("ws://{}/ws", addr),
+        subscribers: Subscribers::default(),
+        handle,
+    })
+}
+
+/// Text frames a recording server has received, in arrival order across its connections.
+pub type RecordedFrames = Arc<Mutex<Vec<String>>>;
+
+/// Starts a `/ws` endpoint that records every text frame it receives and never answers.
+pub async fn spawn_recording_server() -> Result<(TestServer, RecordedFrames), Box<dyn Error>> {
+    let frames = RecordedFrames::default();
+    let recorded = frames.clone();
+    let app = Router::new().route(
+        "/ws",
+        get(move |ws: WebSocketUpgrade| async move {
+            ws.on_upgrade(move |mut socket| async move {
+                while let Some(Ok(message)) = socket.recv().await {
+                    if let axum::extract::ws::Message::Text(text) = message {
+                        recorded.lock().unwrap().push(text);
+                    }
+                }
+            })
+        }),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.unwrap();
+    });
+
+    Ok((
+        TestServer {
+            ws_url: format!("ws://{}/ws", addr),
+            subscribers: Subscribers::default(),
+            handle,
+        },
+        frames,
+    ))
+}
+
+/// Waits until a recording server has received a frame containing `marker`, returning every
+/// frame received up to and including it. Returns `None` on timeout.
+pub async fn frames_until(frames: &RecordedFrames, marker: &str, timeout: Duration) -> Option<Vec<String>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tokio::time::Instant::now() < deadline {
+        {
+            let frames = frames.lock().unwrap();
+            if let Some(end) = frames.iter().position(|frame| frame.contains(marker)) {
+                return Some(frames[..=end].to_vec());
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    None
+}
+
+/// The commands a client sent a recording server before its next publish: publishes a marker
+/// and returns everything received ahead of it.
+pub async fn handshake_commands(client: &mut WsClient, frames: &RecordedFrames) -> Result<Vec<ClientCommand>, Box<dyn Error>> {
+    client.publish(&client.name.clone(), "HandshakeMarker", "marker", &now_rfc3339()).await?;
+    let mut received = frames_until(frames, "HandshakeMarker", Duration::from_secs(2)).await
+        .ok_or("recording server never received the marker")?;
+    received.pop();
+    received.iter()
+        .map(|frame| match ClientCommand::parse(frame) {
+            Some(Ok(command)) => Ok(command),
+            _ => Err(format!("client sent an unrecognised frame: {}", frame).into()),
+        })
+        .collect()
+}
+
+/// Opens a raw WebSocket connection to the given URL.
+pub async fn connect_raw(url: &str) -> Result<RawSocket, Box<dyn Error>> {
+    let (socket, _) = connect_async(url).await?;
+    Ok(socket)
+}
+
+/// Waits for the next JSON text frame whose `type` field matches, skipping anything else.
+/// Returns `None` on timeout or when the socket closes first.
+pub async fn recv_type(socket: &mut RawSocket, msg_type: &str, timeout: Duration) -> Option<Value> {
+    tokio::time::timeout(timeout, async {
+        while let Some(Ok(msg)) = socket.next().await {
+            if let Message::Text(text) = msg {
+                if let Ok(value) = serde_json::from_str::<Value>(&text) {
+                    if value["type"] == msg_type {
+                        return Some(value);
+                    }
+                }
+            }
+        }
+        None
+    }).await.ok().flatten()
+}
+
+/// Waits for the next published message on the given topic, skipping anything else.
+pub async fn recv_topic(socket: &mut RawSocket, topic: &str, timeout: Duration) -> Option<Value> {
+    tokio::time::timeout(timeout, async {
+        while let Some(Ok(msg)) = socket.next().await {
+            if let Message::Text(text) = msg {
+                if let Ok(value) = serde_json::from_str::<Value>(&text) {
+                    if value["topic"] == topic {
+                        return Some(value);
+                    }
+                }
+            }
+        }
+        None
+    }).await.ok().flatten()
+}
+
+/// Round-trips a text `ping` so every frame sent before it has been processed by the server.
+pub async fn sync_raw(socket: &mut RawSocket) -> Result<(), Box<dyn Error>> {
+    socket.send(Message::Text("ping".to_string())).await?;
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = socket.next().await {
+            if msg == Message::Text("pong".to_string()) {
+                return Ok(());
+            }
+        }
+        Err("socket closed before pong".into())
+    }).await.map_err(|_| "timed out waiting for pong")?
+}
@@ -0,0 +1,58 @@
+// src/tls.rs
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Loads a PEM certificate chain and private key for serving `wss://`.
+/// The error names the file that could not be read or parsed.
+pub fn load_rustls_config(cert_path: &Path, key_path: &Path) -> Result<RustlsConfig, String> {
+    let cert_pem = std::fs::read(cert_path)
+        .map_err(|e| format!("cannot read certificate file {}: {}", cert_path.display(), e))?;
+    let key_pem = std::fs::read(key_path)
+        .map_err(|e| format!("cannot read private key file {}: {}", key_path.display(), e))?;
+
+    let certs = CertificateDer::pem_slice_iter(&cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid PEM in certificate file {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_slice(&key_pem)
+        .map_err(|e| format!("no usable private key in {}: {}", key_path.display(), e))?;
+
+    // ring is the only crypto provider compiled in, so it is chosen here rather than process-wide
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("unsupported TLS configuration: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("certificate {} does not match key {}: {}", cert_path.display(), key_path.display(), e))?;
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Serves the app over TLS on an already bound listener, passing peer addresses to handlers
+/// the way `axum::serve` does for plain `ws://`. Once `shutdown` resolves, no new connections
+/// are accepted and requests in flight get `grace` to finish.
+pub async fn serve_tls<F>(listener: std::net::TcpListener, config: RustlsConfig, app: Router, shutdown: F, grace: Duration) -> std::io::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    listener.set_nonblocking(true)?;
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        shutdown_handle.graceful_shutdown(Some(grace));
+    });
+    axum_server::from_tcp_rustls(listener, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+}
@@ -1,7 +1,27 @@
 // src/ws_tests.rs
-use libws::ws_client::WsClient;
-use tokio::time::{sleep, Duration};
-use chrono::Utc;
+use libws::ws_client::{ConnectOptions, IncomingMessage, RetryPolicy, WsClient, WsError, RECONNECTING_ERROR};
+use tokio::time::{sleep, Duration, Instant};
+use chrono::{DateTime, SecondsFormat, Utc};
+use libws::timestamp::{format_rfc3339, now_rfc3339};
+use std::error::Error;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use libws::binary_proto::{BinaryFrame, FrameError, Opcode};
+use libws::blocking::BlockingWsClient;
+use libws::capabilities::{Capability, CapabilityError};
+use libws::command::ClientCommand;
+use libws::compression::compress_payload;
+use libws::error_frame::{ErrorCode, ServerError};
+use libws::publisher_filter::PublisherFilter;
+use libws::subprotocol::WireFormat;
+use libws::{ConnectionConfig, DeliveryOrder, UnknownCommandPolicy};
+use futures_util::SinkExt;
+use serde_json::json;
+use tokio_tungstenite::tungstenite::Message;
+use crate::test_server::{
+    connect_raw, recv_topic, recv_type, spawn_closing_server, spawn_killable_server, spawn_recording_server,
+    handshake_commands, spawn_ws_server, sync_raw, TestServer,
+};
 
 /// Runs a series of client tests to simulate WebSocket interactions.
 pub async fn run_client_tests() {
@@ -13,7 +33,7 @@ pub async fn run_client_tests() {
     let registration_event = "RegistrationCompleteEvent";
 
     // Generate a timestamp for the test
-    let timestamp = Utc::now().to_rfc3339();
+    let timestamp = now_rfc3339();
 
     println!("[test] Connecting clients...");
 
@@ -60,17 +80,17 @@ pub async fn run_client_tests() {
     println!("[test] Subscribing clients to topics...");
 
     // Subscribe clients to specific topics
-    client1.subscribe("Client1", detect_event, "no-payload").await;
-    client1.subscribe("Client1", connect_event, "no-payload").await;
+    client1.subscribe("Client1", detect_event, "no-payload").await.unwrap();
+    client1.subscribe("Client1", connect_event, "no-payload").await.unwrap();
 
-    client2.subscribe("Client2", detect_event, "no-payload").await;
-    client2.subscribe("Client2", registration_event, "no-payload").await;
+    client2.subscribe("Client2", detect_event, "no-payload").await.unwrap();
+    client2.subscribe("Client2", registration_event, "no-payload").await.unwrap();
 
-    client3.subscribe("Client3", detect_event, "no-payload").await;
-    client3.subscribe("Client3", connect_event, "no-payload").await;
+    client3.subscribe("Client3", detect_event, "no-payload").await.unwrap();
+    client3.subscribe("Client3", connect_event, "no-payload").await.unwrap();
     
-    client4.subscribe("Client4", registration_event, "no-payload").await;
-    client4.subscribe("Client4", connect_event, "no-payload").await;
+    client4.subscribe("Client4", registration_event, "no-payload").await.unwrap();
+    client4.subscribe("Client4", connect_event, "no-payload").await.unwrap();
 
     // Allow some time for subscriptions to propagate
     sleep(Duration::from_millis(300)).await;
@@ -100,4 +120,1292 @@ pub async fn run_client_tests() {
     sleep(Duration::from_secs(3)).await;
 
     println!("[test] Test complete. Messages were only delivered within their respective sessions.");
-}
\ No newline at end of file
+}
+
+/// Runs client error-path tests against dedicated test servers.
+pub async fn run_client_error_tests() -> Result<(), Box<dyn Error>> {
+    test_subscribe_on_closed_connection().await?;
+    test_errors_after_server_teardown().await?;
+    test_error_variants().await?;
+    test_connect_timeout().await?;
+    test_drop_releases_connection().await?;
+    test_required_capabilities().await?;
+    test_on_error_receives_server_errors().await?;
+    Ok(())
+}
+
+// Subscribing after the server closed the socket must return an error instead of silently succeeding
+async fn test_subscribe_on_closed_connection() -> Result<(), Box<dyn Error>> {
+    println!("[test] Subscribe on closed connection...");
+
+    let server = spawn_closing_server().await?;
+    let mut client = WsClient::connect("ClosedClient", &server.ws_url).await?;
+
+    // Give the client time to observe the close frame
+    sleep(Duration::from_millis(300)).await;
+
+    match client.subscribe("ClosedClient", "DetectCustomerEvent", "no-payload").await {
+        Ok(_) => Err("subscribe on a closed connection returned Ok".into()),
+        Err(e) => {
+            println!("[test] Subscribe failed as expected: {}", e);
+            server.stop();
+            Ok(())
+        }
+    }
+}
+
+// Once the server is gone, subscribe, unsubscribe, publish and connect all report an error
+async fn test_errors_after_server_teardown() -> Result<(), Box<dyn Error>> {
+    println!("[test] Errors after server teardown...");
+
+    let server = spawn_killable_server("127.0.0.1:0", ConnectionConfig::default()).await?;
+    let ws_url = server.ws_url.clone();
+    let mut client = WsClient::connect("TornDownClient", &ws_url).await?;
+    client.subscribe("TornDownClient", "TeardownEvent", "").await?;
+    server.kill();
+
+    let started = std::time::Instant::now();
+    while client.is_connected() {
+        if started.elapsed() > Duration::from_secs(3) {
+            return Err("client did not notice the server going away".into());
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    match client.subscribe("TornDownClient", "OtherEvent", "").await {
+        Err(WsError::NotConnected) => {}
+        other => return Err(format!("subscribe after teardown returned {:?}", other).into()),
+    }
+    match client.unsubscribe("TeardownEvent").await {
+        Err(WsError::NotConnected) => {}
+        other => return Err(format!("unsubscribe after teardown returned {:?}", other).into()),
+    }
+    match client.publish("TornDownClient", "TeardownEvent", "lost", &now_rfc3339()).await {
+        Err(e @ WsError::NotConnected) => println!("[test] Publish after teardown failed: {}", e),
+        other => return Err(format!("publish after teardown returned {:?}", other).into()),
+    }
+    match WsClient::connect("TornDownClient", &ws_url).await {
+        Err(e @ WsError::Connect(_)) => println!("[test] Connect after teardown failed: {}", e),
+        Err(e) => return Err(format!("unexpected connect error: {}", e).into()),
+        Ok(_) => return Err("connected to a server that was torn down".into()),
+    }
+    Ok(())
+}
+
+// Each kind of failure comes back as its own WsError variant
+async fn test_error_variants() -> Result<(), Box<dyn Error>> {
+    println!("[test] Error variants...");
+
+    match WsClient::connect("VariantClient", "not a url").await {
+        Err(WsError::InvalidUrl(e)) => println!("[test] Bad URL refused: {}", e),
+        other => return Err(format!("bad URL returned {:?}", other.map(|_| ())).into()),
+    }
+
+    // A port that was just released has nothing listening on it
+    let closed_port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let closed_url = format!("ws://127.0.0.1:{}/ws", closed_port);
+    match WsClient::connect("VariantClient", &closed_url).await {
+        Err(WsError::Connect(e)) => println!("[test] Closed port refused: {}", e),
+        other => return Err(format!("closed port returned {:?}", other.map(|_| ())).into()),
+    }
+    let auth_url = format!("http://127.0.0.1:{}/token", closed_port);
+    match WsClient::connect_with_auth("VariantClient", &closed_url, &auth_url, "user", "pw", None).await {
+        Err(WsError::Auth(e)) => println!("[test] Token request failed: {}", e),
+        other => return Err(format!("unreachable auth endpoint returned {:?}", other.map(|_| ())).into()),
+    }
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut client = WsClient::connect("VariantClient", &server.ws_url).await?;
+    match client.publish_encrypted("VariantClient", "VariantEvent", &json!({"secret": 1}), &now_rfc3339()).await {
+        Err(WsError::Encryption(e)) => println!("[test] Encrypted publish without a key refused: {}", e),
+        other => return Err(format!("encrypted publish without a key returned {:?}", other).into()),
+    }
+    let long_topic = "t".repeat(usize::from(u16::MAX) + 1);
+    match client.publish_binary(&long_topic, b"payload").await {
+        Err(WsError::Serialization(e)) => println!("[test] Oversized binary frame refused: {}", e),
+        other => return Err(format!("oversized binary frame returned {:?}", other).into()),
+    }
+    server.stop();
+    Ok(())
+}
+
+// A handshake the server never completes fails once the connect timeout runs out
+async fn test_connect_timeout() -> Result<(), Box<dyn Error>> {
+    println!("[test] Connect timeout...");
+
+    // Accepts TCP connections but never answers the upgrade request
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let ws_url = format!("ws://{}/ws", listener.local_addr()?);
+    let stalled = tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+
+    let options = ConnectOptions::default().with_connect_timeout(Duration::from_millis(200));
+    let started = std::time::Instant::now();
+    let result = WsClient::connect_with_options("StalledClient", "session-stalled", &ws_url, options).await;
+    stalled.abort();
+    match result {
+        Err(WsError::Timeout(e)) if started.elapsed() < Duration::from_secs(2) => println!("[test] Connect timed out: {}", e),
+        other => return Err(format!("stalled handshake returned {:?} after {:?}", other.map(|_| ()), started.elapsed()).into()),
+    }
+    Ok(())
+}
+
+// Connecting with a capability the server does not advertise fails with a clear error
+async fn test_required_capabilities() -> Result<(), Box<dyn Error>> {
+    println!("[test] Required capabilities...");
+
+    // The default configuration issues no resume tokens and no server offers encryption yet
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let result = WsClient::connect("CapableClient", &server.ws_url).await?
+        .require_capabilities(&[Capability::BinaryFrames, Capability::Resume, Capability::Encryption]).await;
+    match result {
+        Err(WsError::Capabilities(e)) if e == CapabilityError::Missing(vec![Capability::Resume, Capability::Encryption]) => {
+            println!("[test] Connect failed as expected: {}", e);
+            if e.to_string() != "server lacks required capabilities: resume, encryption" {
+                return Err(format!("unclear capability error: {}", e).into());
+            }
+        }
+        Err(e) => return Err(format!("unexpected capability error: {}", e).into()),
+        Ok(_) => return Err("connect succeeded without required capabilities".into()),
+    }
+
+    let client = WsClient::connect("CapableClient", &server.ws_url).await?
+        .require_capabilities(&[Capability::BinaryFrames, Capability::Delta]).await?;
+    if !client.server_capabilities().is_some_and(|advertised| advertised.contains(&Capability::QueueDepth)) {
+        return Err(format!("unexpected advertised capabilities: {:?}", client.server_capabilities()).into());
+    }
+    server.stop();
+
+    // A server that never says hello cannot satisfy any requirement
+    let silent = spawn_closing_server().await?;
+    match WsClient::connect("CapableClient", &silent.ws_url).await?.require_capabilities(&[Capability::Delta]).await {
+        Err(WsError::Capabilities(CapabilityError::NotAdvertised)) => {}
+        Err(e) => return Err(format!("unexpected error from a silent server: {}", e).into()),
+        Ok(_) => return Err("silent server satisfied a requirement".into()),
+    }
+    silent.stop();
+    Ok(())
+}
+
+// Commands the server cannot handle come back as structured errors instead of only being logged
+async fn test_on_error_receives_server_errors() -> Result<(), Box<dyn Error>> {
+    println!("[test] Server errors reach on_error...");
+
+    let server = spawn_ws_server(ConnectionConfig {
+        unknown_command_policy: UnknownCommandPolicy::ErrorReply,
+        ..Default::default()
+    }).await?;
+    let mut client = WsClient::connect("ErrorClient", &server.ws_url).await?;
+    let errors: Arc<Mutex<Vec<ServerError>>> = Arc::new(Mutex::new(Vec::new()));
+    let errors_clone = errors.clone();
+    client.on_error(move |error| errors_clone.lock().unwrap().push(error));
+
+    client.ws_channel.lock().await.send(Message::Text("publish-json:{\"topic\": ".to_string())).await?;
+    client.publish("ErrorClient", "", "no topic", &now_rfc3339()).await?;
+    client.ws_channel.lock().await.send(Message::Text("subscibe:Typo".to_string())).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let errors = errors.lock().unwrap().clone();
+    let codes: Vec<Option<ErrorCode>> = errors.iter().map(ServerError::error_code).collect();
+    if codes != vec![Some(ErrorCode::BadJson), Some(ErrorCode::MissingTopic), Some(ErrorCode::UnknownCommand)] {
+        return Err(format!("unexpected errors: {:?}", errors).into());
+    }
+    if errors[0].detail.is_none() || errors[2].extra.get("command") != Some(&json!("subscibe")) {
+        return Err(format!("errors lack their details: {:?}", errors).into());
+    }
+    println!("[test] Received {:?}", codes);
+
+    server.stop();
+    Ok(())
+}
+
+/// Runs client message delivery tests against dedicated test servers.
+pub async fn run_client_delivery_tests() -> Result<(), Box<dyn Error>> {
+    test_handler_registered_after_publish().await?;
+    test_on_subscribes_with_handler().await?;
+    test_multiple_handlers_per_topic().await?;
+    test_full_message_handler().await?;
+    test_catch_all_handler().await?;
+    test_awaitable_subscribe().await?;
+    test_subscriber_count().await?;
+    test_codec_round_trip(WireFormat::Json).await?;
+    test_codec_round_trip(WireFormat::MessagePack).await?;
+    test_publisher_filter().await?;
+    test_subscription_guard().await?;
+    test_server_assigned_correlation_id().await?;
+    test_request_reply().await?;
+    test_delivery_order(DeliveryOrder::Ordered).await?;
+    test_delivery_order(DeliveryOrder::Unordered).await?;
+    test_timestamp_format()?;
+    test_delta_subscription().await?;
+    test_structured_payloads().await?;
+    test_compressed_round_trip().await?;
+    test_binary_frame_codec()?;
+    test_binary_round_trip().await?;
+    test_reconnect_replays_subscriptions().await?;
+    test_handshake_frames().await?;
+    Ok(())
+}
+
+// connect registers the name and session; ConnectOptions can leave either out
+async fn test_handshake_frames() -> Result<(), Box<dyn Error>> {
+    println!("[test] Handshake frames...");
+
+    let (server, frames) = spawn_recording_server().await?;
+    let mut client = WsClient::connect_with_session("Registered", "session-registered", &server.ws_url).await?;
+    let expected = vec![
+        ClientCommand::RegisterName { name: "Registered".to_string() },
+        ClientCommand::RegisterSession { session_id: "session-registered".to_string() },
+    ];
+    let sent = handshake_commands(&mut client, &frames).await?;
+    if sent != expected {
+        return Err(format!("default connect sent {:?}", sent).into());
+    }
+    drop(client);
+
+    frames.lock().unwrap().clear();
+    let options = ConnectOptions::default().with_register_name(false);
+    let mut client = WsClient::connect_with_options("Unnamed", "session-unnamed", &server.ws_url, options).await?;
+    let sent = handshake_commands(&mut client, &frames).await?;
+    if sent != [ClientCommand::RegisterSession { session_id: "session-unnamed".to_string() }] {
+        return Err(format!("connect without name registration sent {:?}", sent).into());
+    }
+    drop(client);
+
+    frames.lock().unwrap().clear();
+    let options = ConnectOptions::default().with_register_name(false).with_register_session(false);
+    let mut client = WsClient::connect_with_options("Silent", "session-silent", &server.ws_url, options).await?;
+    let sent = handshake_commands(&mut client, &frames).await?;
+    if !sent.is_empty() {
+        return Err(format!("connect without registrations sent {:?}", sent).into());
+    }
+    println!("[test] Registrations sent only as configured");
+    server.stop();
+    Ok(())
+}
+
+/// Drives `BlockingWsClient` from synchronous code; must not be called on an async thread.
+pub fn run_blocking_client_tests() -> Result<(), Box<dyn Error>> {
+    println!("[test] Blocking client...");
+
+    // The server gets its own runtime; the clients bring theirs
+    let server_runtime = tokio::runtime::Runtime::new()?;
+    let server = server_runtime.block_on(spawn_ws_server(ConnectionConfig::default()))?;
+
+    let mut subscriber = BlockingWsClient::connect_with_session("BlockingSub", "session-blocking", &server.ws_url)?;
+    subscriber.subscribe("BlockingSub", "BlockingEvent", "")?;
+    let mut publisher = BlockingWsClient::connect_with_session("BlockingPub", "session-blocking", &server.ws_url)?;
+    publisher.publish("BlockingPub", "BlockingEvent", "hello", &now_rfc3339())?;
+    publisher.publish("BlockingPub", "UnsubscribedEvent", "ignored", &now_rfc3339())?;
+
+    let message = subscriber.recv_timeout(Duration::from_secs(2))?;
+    if message.topic != "BlockingEvent" || message.payload != "hello" || message.publisher_name != "BlockingPub" {
+        return Err(format!("unexpected message: {:?}", message).into());
+    }
+    if let Ok(message) = subscriber.recv_timeout(Duration::from_millis(300)) {
+        return Err(format!("received a message on a topic it never subscribed to: {:?}", message).into());
+    }
+    println!("[test] Blocking subscriber received {:?}", message.payload);
+
+    // Once the connection ends, recv stops waiting
+    subscriber.close()?;
+    if subscriber.recv_timeout(Duration::from_secs(2)) != Err(RecvTimeoutError::Disconnected) {
+        return Err("recv kept waiting after the connection closed".into());
+    }
+    match publisher.publish("BlockingPub", "BlockingEvent", "after close", &now_rfc3339()) {
+        Ok(()) => {}
+        Err(e) => return Err(format!("publisher failed after another client closed: {}", e).into()),
+    }
+    drop(publisher);
+    drop(subscriber);
+
+    server.stop();
+    server_runtime.shutdown_background();
+    match BlockingWsClient::connect("BlockingSub", &server.ws_url) {
+        Err(WsError::Connect(e)) => println!("[test] Blocking connect to a stopped server failed: {}", e),
+        Err(e) => return Err(format!("unexpected blocking connect error: {}", e).into()),
+        Ok(_) => return Err("blocking client connected to a stopped server".into()),
+    }
+    Ok(())
+}
+
+// A message that arrives between subscribe and on_message must still reach the handler
+async fn test_handler_registered_after_publish() -> Result<(), Box<dyn Error>> {
+    println!("[test] Late handler registration...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("LateSubscriber", "session-late", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("EarlyPublisher", "session-late", &server.ws_url).await?;
+
+    subscriber.subscribe("LateSubscriber", "RaceEvent", "no-payload").await?;
+    sleep(Duration::from_millis(200)).await;
+    publisher.publish("EarlyPublisher", "RaceEvent", "first message", &now_rfc3339()).await?;
+
+    // Let the message arrive before any handler exists
+    sleep(Duration::from_millis(300)).await;
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on_message("RaceEvent", move |msg| {
+        received_clone.lock().unwrap().push(msg);
+    });
+
+    let received = received.lock().unwrap().clone();
+    if received != vec!["first message".to_string()] {
+        return Err(format!("expected the buffered message, got {:?}", received).into());
+    }
+    println!("[test] Buffered message delivered to late handler");
+
+    server.stop();
+    Ok(())
+}
+
+// `on` registers the handler and subscribes in one call; `off` undoes both
+async fn test_on_subscribes_with_handler() -> Result<(), Box<dyn Error>> {
+    println!("[test] Combined handler and subscribe...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("OnSubscriber", "session-on", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("OnPublisher", "session-on", &server.ws_url).await?;
+
+    // `on` returns only after the server has the subscription
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    let handle = subscriber.on("OnEvent", move |msg| {
+        received_clone.lock().unwrap().push(msg);
+    }).await?;
+    if handle.topic() != "OnEvent" {
+        return Err(format!("unexpected handle {:?}", handle).into());
+    }
+
+    publisher.publish("OnPublisher", "OnEvent", "via on", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    subscriber.off(handle).await?;
+    sleep(Duration::from_millis(200)).await;
+    publisher.publish("OnPublisher", "OnEvent", "after off", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let received = received.lock().unwrap().clone();
+    if received != vec!["via on".to_string()] {
+        return Err(format!("expected only the message sent while subscribed, got {:?}", received).into());
+    }
+    println!("[test] Handler received messages without a separate subscribe");
+
+    server.stop();
+    Ok(())
+}
+
+// Every handler registered for a topic sees each message, in registration order, until cleared
+async fn test_multiple_handlers_per_topic() -> Result<(), Box<dyn Error>> {
+    println!("[test] Multiple handlers per topic...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("MultiSubscriber", "session-multi", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("MultiPublisher", "session-multi", &server.ws_url).await?;
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    for name in ["first", "second"] {
+        let received = received.clone();
+        subscriber.on_message("MultiEvent", move |msg| received.lock().unwrap().push(format!("{}:{}", name, msg)));
+    }
+    subscriber.subscribe("MultiSubscriber", "MultiEvent", "").await?;
+    publisher.publish("MultiPublisher", "MultiEvent", "one", &now_rfc3339()).await?;
+    publisher.publish("MultiPublisher", "MultiEvent", "two", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let seen = received.lock().unwrap().clone();
+    if seen != ["first:one", "second:one", "first:two", "second:two"] {
+        return Err(format!("expected both handlers to see each message in order, got {:?}", seen).into());
+    }
+    println!("[test] Both handlers fired: {:?}", seen);
+
+    // Cleared handlers no longer fire
+    subscriber.clear_handlers("MultiEvent");
+    received.lock().unwrap().clear();
+    publisher.publish("MultiPublisher", "MultiEvent", "three", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(300)).await;
+    if !received.lock().unwrap().is_empty() {
+        return Err(format!("cleared handlers still fired: {:?}", received.lock().unwrap()).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// on_message_full hands the handler the envelope's metadata alongside the payload
+async fn test_full_message_handler() -> Result<(), Box<dyn Error>> {
+    println!("[test] Full message handler...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("FullSubscriber", "session-full", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("FullPublisher", "session-full", &server.ws_url).await?;
+
+    let received: Arc<Mutex<Vec<IncomingMessage>>> = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on_message_full("FullEvent", move |message| received_clone.lock().unwrap().push(message));
+    // A payload-only handler on the same topic still works
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let payloads_clone = payloads.clone();
+    subscriber.on_message("FullEvent", move |payload| payloads_clone.lock().unwrap().push(payload));
+    subscriber.subscribe("FullSubscriber", "FullEvent", "").await?;
+
+    let timestamp = now_rfc3339();
+    publisher.publish("FullPublisher", "FullEvent", "with metadata", &timestamp).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let received = received.lock().unwrap().clone();
+    let [message] = received.as_slice() else {
+        return Err(format!("expected one message, got {:?}", received).into());
+    };
+    if message.topic != "FullEvent"
+        || message.payload != "with metadata"
+        || message.publisher_name != "FullPublisher"
+        || message.timestamp != timestamp
+        || message.session_id != "session-full"
+        || message.correlation_id.is_none()
+    {
+        return Err(format!("metadata was not delivered intact: {:?}", message).into());
+    }
+    if *payloads.lock().unwrap() != ["with metadata"] {
+        return Err(format!("payload-only handler got {:?}", payloads.lock().unwrap()).into());
+    }
+    println!("[test] Full message delivered: {:?}", message);
+
+    server.stop();
+    Ok(())
+}
+
+// on_any sees every message: after the topic's handler where there is one, and on its own where not
+async fn test_catch_all_handler() -> Result<(), Box<dyn Error>> {
+    println!("[test] Catch-all handler...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("AnySubscriber", "session-any", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("AnyPublisher", "session-any", &server.ws_url).await?;
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let calls_clone = calls.clone();
+    subscriber.on_message("HandledEvent", move |payload| calls_clone.lock().unwrap().push(format!("specific:{}", payload)));
+    let calls_clone = calls.clone();
+    subscriber.on_any(move |message| {
+        calls_clone.lock().unwrap().push(format!("any:{}:{}:{}", message.topic, message.publisher_name, message.payload));
+    });
+    subscriber.subscribe_many(&["HandledEvent", "UnhandledEvent"]).await?.detach();
+
+    publisher.publish("AnyPublisher", "HandledEvent", "one", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(200)).await;
+    publisher.publish("AnyPublisher", "UnhandledEvent", "two", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let seen = calls.lock().unwrap().clone();
+    if seen != ["specific:one", "any:HandledEvent:AnyPublisher:one", "any:UnhandledEvent:AnyPublisher:two"] {
+        return Err(format!("unexpected handler calls: {:?}", seen).into());
+    }
+    println!("[test] Catch-all ran after the specific handler and for the unhandled topic: {:?}", seen);
+
+    // The unhandled message is still buffered for a handler registered later
+    let calls_clone = calls.clone();
+    subscriber.on_message("UnhandledEvent", move |payload| calls_clone.lock().unwrap().push(format!("late:{}", payload)));
+    if calls.lock().unwrap().last().map(String::as_str) != Some("late:two") {
+        return Err(format!("buffered message was not delivered to the late handler: {:?}", calls.lock().unwrap()).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// subscriber_count counts live subscribers in the session, wildcards included, and drops closed ones
+async fn test_subscriber_count() -> Result<(), Box<dyn Error>> {
+    println!("[test] Subscriber count...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut publisher = WsClient::connect_with_session("CountPublisher", "session-count", &server.ws_url).await?;
+    if publisher.subscriber_count("count.events").await? != 0 {
+        return Err("a topic nobody subscribed to has subscribers".into());
+    }
+
+    let mut first = WsClient::connect_with_session("CountFirst", "session-count", &server.ws_url).await?;
+    first.subscribe("CountFirst", "count.events", "").await?;
+    if publisher.subscriber_count("count.events").await? != 1 {
+        return Err("expected one subscriber".into());
+    }
+
+    let mut second = WsClient::connect_with_session("CountSecond", "session-count", &server.ws_url).await?;
+    second.subscribe("CountSecond", "count.events", "").await?;
+    let mut wildcard = WsClient::connect_with_session("CountWildcard", "session-count", &server.ws_url).await?;
+    wildcard.subscribe("CountWildcard", "count.*", "").await?;
+    let mut elsewhere = WsClient::connect_with_session("CountElsewhere", "session-elsewhere", &server.ws_url).await?;
+    elsewhere.subscribe("CountElsewhere", "count.events", "").await?;
+    let many = publisher.subscriber_count("count.events").await?;
+    if many != 3 {
+        return Err(format!("expected 3 subscribers in the session, got {}", many).into());
+    }
+
+    // A disconnected subscriber stops counting
+    second.close().await?;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut after_close = publisher.subscriber_count("count.events").await?;
+    while after_close != 2 && Instant::now() < deadline {
+        sleep(Duration::from_millis(20)).await;
+        after_close = publisher.subscriber_count("count.events").await?;
+    }
+    if after_close != 2 {
+        return Err(format!("expected 2 subscribers after a disconnect, got {}", after_close).into());
+    }
+    println!("[test] Counted 0, 1, {} and {} subscribers", many, after_close);
+
+    server.stop();
+    Ok(())
+}
+
+// Commands, envelopes and acknowledgements survive the trip in either codec
+async fn test_codec_round_trip(codec: WireFormat) -> Result<(), Box<dyn Error>> {
+    println!("[test] {} round trip...", codec.protocol());
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let options = ConnectOptions::default().with_codec(codec);
+    let mut subscriber = WsClient::connect_with_options("CodecSubscriber", "session-codec", &server.ws_url, options.clone()).await?;
+    let mut publisher = WsClient::connect_with_options("CodecPublisher", "session-codec", &server.ws_url, options).await?;
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on_message_full("codec.events", move |message| received_clone.lock().unwrap().push(message));
+    subscriber.subscribe("CodecSubscriber", "codec.events", "").await?;
+    if publisher.subscriber_count("codec.events").await? != 1 {
+        return Err(format!("{}: expected one subscriber", codec.protocol()).into());
+    }
+
+    let timestamp = now_rfc3339();
+    publisher.publish_value("CodecPublisher", "codec.events", json!({"reading": 21.5, "tags": ["a", "b"]}), &timestamp).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let received = received.lock().unwrap().clone();
+    let [message] = received.as_slice() else {
+        return Err(format!("{}: expected one message, got {:?}", codec.protocol(), received).into());
+    };
+    let payload: serde_json::Value = serde_json::from_str(&message.payload)?;
+    if payload != json!({"reading": 21.5, "tags": ["a", "b"]})
+        || message.publisher_name != "CodecPublisher"
+        || message.timestamp != timestamp
+    {
+        return Err(format!("{}: message was not delivered intact: {:?}", codec.protocol(), message).into());
+    }
+    println!("[test] {} delivered {}", codec.protocol(), message.payload);
+
+    server.stop();
+    Ok(())
+}
+
+// A subscription's publisher filter decides which publishers' messages it receives
+async fn test_publisher_filter() -> Result<(), Box<dyn Error>> {
+    println!("[test] Publisher filter...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut publisher = WsClient::connect_with_session("FilterPublisher", "session-filter", &server.ws_url).await?;
+    let mut subscribers = Vec::new();
+    let filters = [
+        ("OnlyAlpha", Some(PublisherFilter::Allow(vec!["Alpha".to_string()]))),
+        ("NotAlpha", Some(PublisherFilter::Deny(vec!["Alpha".to_string()]))),
+        ("AlphaOrBeta", Some(PublisherFilter::Allow(vec!["Alpha".to_string(), "Beta".to_string()]))),
+        ("Everyone", None),
+    ];
+    for (name, filter) in &filters {
+        let mut subscriber = WsClient::connect_with_session(name, "session-filter", &server.ws_url).await?;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        subscriber.on_message_full("filter.events", move |message| received_clone.lock().unwrap().push(message.publisher_name));
+        match filter {
+            Some(filter) => subscriber.subscribe_filtered(name, "filter.events", "", filter).await?,
+            None => subscriber.subscribe(name, "filter.events", "").await?,
+        }
+        subscribers.push((name, subscriber, received));
+    }
+
+    for sender in ["Alpha", "Beta", "Gamma"] {
+        publisher.publish(sender, "filter.events", "hello", &now_rfc3339()).await?;
+    }
+    sleep(Duration::from_millis(300)).await;
+    let expected: [&[&str]; 4] = [&["Alpha"], &["Beta", "Gamma"], &["Alpha", "Beta"], &["Alpha", "Beta", "Gamma"]];
+    for ((name, _, received), expected) in subscribers.iter().zip(expected) {
+        let received = received.lock().unwrap().clone();
+        if received != expected {
+            return Err(format!("{} expected messages from {:?}, got {:?}", name, expected, received).into());
+        }
+        println!("[test] {} received from {:?}", name, received);
+    }
+
+    // The legacy form takes the filter as the last option; a filter naming nobody is refused
+    let mut raw = connect_raw(&server.ws_url).await?;
+    raw.send(Message::Text("subscribe:filter.events|session-filter|ordered,from=Beta,Gamma|1".to_string())).await?;
+    recv_type(&mut raw, "ack", Duration::from_secs(2)).await.ok_or("legacy filtered subscribe was not acknowledged")?;
+    publisher.publish("Alpha", "filter.events", "skipped", &now_rfc3339()).await?;
+    publisher.publish("Gamma", "filter.events", "kept", &now_rfc3339()).await?;
+    let delivered = recv_topic(&mut raw, "filter.events", Duration::from_secs(2)).await
+        .ok_or("legacy filtered subscription received nothing")?;
+    if delivered["publisher_name"] != "Gamma" {
+        return Err(format!("legacy filter let through {}", delivered).into());
+    }
+    raw.send(Message::Text("subscribe:filter.events|session-filter|from=|2".to_string())).await?;
+    let error = recv_type(&mut raw, "error", Duration::from_secs(2)).await.ok_or("empty filter was not refused")?;
+    if error["code"] != "invalid_subscription_option" {
+        return Err(format!("unexpected error for an empty filter: {}", error).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Subscribe resolves on the server's ack, so a publish sent right after it is delivered,
+// and a refused subscribe fails with the server's error instead of timing out
+async fn test_awaitable_subscribe() -> Result<(), Box<dyn Error>> {
+    println!("[test] Awaitable subscribe...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("AckSubscriber", "session-ack", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("AckPublisher", "session-ack", &server.ws_url).await?;
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on_message("AckEvent", move |msg| received_clone.lock().unwrap().push(msg));
+
+    for round in 0..5 {
+        subscriber.subscribe("AckSubscriber", "AckEvent", "").await?;
+        publisher.publish("AckPublisher", "AckEvent", &format!("round {}", round), &now_rfc3339()).await?;
+        sleep(Duration::from_millis(100)).await;
+        subscriber.unsubscribe("AckEvent").await?;
+        sleep(Duration::from_millis(100)).await;
+    }
+    let received = received.lock().unwrap().clone();
+    if received.len() != 5 {
+        return Err(format!("expected every publish after an acknowledged subscribe, got {:?}", received).into());
+    }
+
+    let started = std::time::Instant::now();
+    match subscriber.subscribe("AckSubscriber", "sensor.#.temp", "").await {
+        Ok(()) => return Err("subscribe to an invalid pattern succeeded".into()),
+        Err(e @ WsError::Refused(_)) => {
+            if e.server_error().and_then(ServerError::error_code) != Some(ErrorCode::InvalidTopicPattern) {
+                return Err(format!("unexpected subscribe error: {}", e).into());
+            }
+            if started.elapsed() >= Duration::from_secs(1) {
+                return Err("refused subscribe waited for the ack timeout".into());
+            }
+            println!("[test] Subscribe refused: {}", e);
+        }
+        Err(e) => return Err(format!("unexpected subscribe error: {}", e).into()),
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// subscribe_many subscribes in one batch, and dropping its guard unsubscribes on the server
+// while the client stays connected
+async fn test_subscription_guard() -> Result<(), Box<dyn Error>> {
+    println!("[test] Subscription guard...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut client = WsClient::connect_with_session("GuardClient", "session-guard", &server.ws_url).await?;
+    let subscribed = |topic: &str| server.subscribers.subscriber_count(topic, "session-guard");
+
+    let guard = client.subscribe_many(&["GuardA", "GuardB", "GuardC"]).await?;
+    if guard.topics() != ["GuardA", "GuardB", "GuardC"] || ["GuardA", "GuardB", "GuardC"].iter().any(|topic| subscribed(topic) != 1) {
+        return Err(format!("batch subscribe did not register every topic: {:?}", guard.topics()).into());
+    }
+    let kept = client.subscribe_guarded("GuardKept").await?;
+    kept.detach();
+
+    drop(guard);
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while ["GuardA", "GuardB", "GuardC"].iter().any(|topic| subscribed(topic) != 0) {
+        if std::time::Instant::now() > deadline {
+            return Err("dropping the guard did not unsubscribe on the server".into());
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    if subscribed("GuardKept") != 1 || !client.is_connected() {
+        return Err("a detached guard's subscription or the connection was lost".into());
+    }
+    println!("[test] Guard dropped, server released its topics");
+
+    // A refused topic fails the batch and undoes the topics acknowledged before it
+    if client.subscribe_many(&["GuardD", "sensor.#.temp"]).await.is_ok() {
+        return Err("batch with an invalid pattern succeeded".into());
+    }
+    sleep(Duration::from_millis(200)).await;
+    if subscribed("GuardD") != 0 {
+        return Err("a failed batch left its acknowledged topics subscribed".into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Dropping a client closes its socket so the server cleans up its subscriptions promptly
+async fn test_drop_releases_connection() -> Result<(), Box<dyn Error>> {
+    println!("[test] Drop releases connection...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut client = WsClient::connect_with_session("DroppedClient", "session-drop", &server.ws_url).await?;
+    client.subscribe("DroppedClient", "DropEvent", "no-payload").await?;
+    sleep(Duration::from_millis(200)).await;
+
+    if !server.subscribers.contains_topic("DropEvent") {
+        return Err("subscription was not registered".into());
+    }
+
+    drop(client);
+
+    // Cleanup removes the empty topic entry once the server sees the disconnect
+    for _ in 0..20 {
+        sleep(Duration::from_millis(50)).await;
+        if !server.subscribers.contains_topic("DropEvent") {
+            println!("[test] Server cleaned up after drop");
+            server.stop();
+            return Ok(());
+        }
+    }
+    Err("server did not clean up the dropped client's subscription within 1s".into())
+}
+
+// Publishes without a correlation id get one from the server; a publisher's own id is kept
+async fn test_server_assigned_correlation_id() -> Result<(), Box<dyn Error>> {
+    println!("[test] Server-assigned correlation id...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("TraceSubscriber", "session-trace", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("TracePublisher", "session-trace", &server.ws_url).await?;
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on_message_with_correlation("TraceEvent", move |payload, correlation_id| {
+        received_clone.lock().unwrap().push((payload, correlation_id));
+    });
+    subscriber.subscribe("TraceSubscriber", "TraceEvent", "no-payload").await?;
+    sleep(Duration::from_millis(200)).await;
+
+    publisher.publish("TracePublisher", "TraceEvent", "untraced", &now_rfc3339()).await?;
+
+    let mut raw_publisher = connect_raw(&server.ws_url).await?;
+    let publish = json!({
+        "publisher_name": "RawPublisher",
+        "topic": "TraceEvent",
+        "payload": "traced",
+        "timestamp": now_rfc3339(),
+        "session_id": "session-trace",
+        "correlation_id": "client-id-42",
+    });
+    raw_publisher.send(Message::Text(format!("publish-json:{}", publish))).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let received = received.lock().unwrap().clone();
+    println!("[test] Received {:?}", received);
+    match received.as_slice() {
+        [(first, Some(generated)), (second, Some(preserved))]
+            if first == "untraced" && !generated.is_empty()
+                && second == "traced" && preserved == "client-id-42" => {}
+        _ => return Err(format!("unexpected correlation ids: {:?}", received).into()),
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Ordered subscriptions see every message in publish order; unordered ones see every message
+// A request resolves with the one reply published to its reply_to topic, and times out cleanly without one
+async fn test_request_reply() -> Result<(), Box<dyn Error>> {
+    println!("[test] Request/reply...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut responder = connect_raw(&server.ws_url).await?;
+    responder.send(Message::Text("register-session:session-rpc".to_string())).await?;
+    responder.send(Message::Text("subscribe:EchoService".to_string())).await?;
+    sync_raw(&mut responder).await?;
+
+    // Echoes each request to its reply_to topic, answering "slow" requests too late
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let requests_seen = requests.clone();
+    let responder_task = tokio::spawn(async move {
+        while let Some(request) = recv_topic(&mut responder, "EchoService", Duration::from_secs(10)).await {
+            requests_seen.lock().unwrap().push(request.clone());
+            if request["payload"] == "slow" {
+                sleep(Duration::from_millis(500)).await;
+            }
+            let reply = json!({
+                "op": "publish",
+                "topic": request["reply_to"],
+                "payload": format!("echo: {}", request["payload"].as_str().unwrap_or_default()),
+                "correlation_id": request["correlation_id"],
+            });
+            if responder.send(Message::Text(reply.to_string())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut requester = WsClient::connect_with_session("Requester", "session-rpc", &server.ws_url).await?;
+    let reply = requester.request("EchoService", "hello", Duration::from_secs(2)).await?;
+    if reply != "echo: hello" {
+        return Err(format!("unexpected reply {:?}", reply).into());
+    }
+    // The server passed reply_to and the correlation id through, and the reply topic was released
+    let request = requests.lock().unwrap().first().cloned().ok_or("responder saw no request")?;
+    let correlation_id = request["correlation_id"].as_str().ok_or("request had no correlation id")?;
+    let reply_to = request["reply_to"].as_str().ok_or("request had no reply_to")?.to_string();
+    if reply_to != libws::request_reply::reply_topic(correlation_id) {
+        return Err(format!("reply_to {} does not match correlation id {}", reply_to, correlation_id).into());
+    }
+    wait_for_reply_topic_released(&server, &reply_to).await?;
+    println!("[test] Echo reply received, reply topic released");
+
+    // A late reply times the request out, is dropped on arrival, and leaves the client usable
+    match requester.request("EchoService", "slow", Duration::from_millis(200)).await {
+        Err(e @ WsError::Timeout(_)) => println!("[test] Slow request timed out: {}", e),
+        other => return Err(format!("expected a timeout, got {:?}", other).into()),
+    }
+    let slow_reply_to = requests.lock().unwrap().last().and_then(|r| r["reply_to"].as_str().map(str::to_string))
+        .ok_or("responder saw no slow request")?;
+    wait_for_reply_topic_released(&server, &slow_reply_to).await?;
+    sleep(Duration::from_millis(500)).await;
+    let reply = requester.request("EchoService", "again", Duration::from_secs(2)).await?;
+    if reply != "echo: again" || !requester.is_connected() {
+        return Err(format!("request after a late reply got {:?}", reply).into());
+    }
+    println!("[test] Late reply dropped, next request answered");
+
+    responder_task.abort();
+    server.stop();
+    Ok(())
+}
+
+async fn wait_for_reply_topic_released(server: &TestServer, topic: &str) -> Result<(), Box<dyn Error>> {
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.subscribers.subscriber_count(topic, "session-rpc") != 0 {
+        if std::time::Instant::now() > deadline {
+            return Err(format!("reply topic {} is still subscribed", topic).into());
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    Ok(())
+}
+
+async fn test_delivery_order(order: DeliveryOrder) -> Result<(), Box<dyn Error>> {
+    println!("[test] Delivery order test ({})...", order.as_str());
+    const MESSAGES: usize = 50;
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut subscriber = WsClient::connect_with_session("OrderSubscriber", "session-order", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("OrderPublisher", "session-order", &server.ws_url).await?;
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on_message("OrderEvent", move |payload| {
+        received_clone.lock().unwrap().push(payload);
+    });
+    subscriber.subscribe_with_order("OrderSubscriber", "OrderEvent", "no-payload", order).await?;
+    sleep(Duration::from_millis(200)).await;
+
+    let expected: Vec<String> = (0..MESSAGES).map(|i| format!("message-{}", i)).collect();
+    for payload in &expected {
+        publisher.publish("OrderPublisher", "OrderEvent", payload, &now_rfc3339()).await?;
+    }
+
+    for _ in 0..20 {
+        if received.lock().unwrap().len() >= MESSAGES {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let mut received = received.lock().unwrap().clone();
+    match order {
+        DeliveryOrder::Ordered => {
+            if received != expected {
+                return Err(format!("ordered subscription received {:?}", received).into());
+            }
+        }
+        DeliveryOrder::Unordered => {
+            received.sort_by_key(|payload| payload.trim_start_matches("message-").parse::<usize>().unwrap_or(usize::MAX));
+            if received != expected {
+                return Err(format!("unordered subscription missed messages: {:?}", received).into());
+            }
+        }
+    }
+    println!("[test] All {} messages delivered ({})", MESSAGES, order.as_str());
+
+    server.stop();
+    Ok(())
+}
+
+// Every timestamp producer agrees on one format: UTC, millisecond precision, `Z` suffix
+fn test_timestamp_format() -> Result<(), Box<dyn Error>> {
+    println!("[test] Timestamp format test...");
+
+    let instant = std::time::UNIX_EPOCH + Duration::from_millis(1_706_091_937_123);
+    let canonical = format_rfc3339(instant);
+    let chrono_style = DateTime::<Utc>::from(instant).to_rfc3339_opts(SecondsFormat::Millis, true);
+    println!("[test] canonical={}, chrono={}", canonical, chrono_style);
+    if canonical != "2024-01-24T10:25:37.123Z" || canonical != chrono_style {
+        return Err(format!("timestamp formats differ: {} vs {}", canonical, chrono_style).into());
+    }
+
+    // Whole seconds still carry three fractional digits
+    let whole = format_rfc3339(std::time::UNIX_EPOCH + Duration::from_secs(1_706_091_937));
+    if whole != "2024-01-24T10:25:37.000Z" {
+        return Err(format!("unexpected whole-second timestamp: {}", whole).into());
+    }
+
+    let now = now_rfc3339();
+    if DateTime::parse_from_rfc3339(&now).is_err() || now.len() != canonical.len() {
+        return Err(format!("now_rfc3339 produced a non-canonical timestamp: {}", now).into());
+    }
+    Ok(())
+}
+
+// A delta subscriber gets only the changed fields on the wire and WsClient rebuilds the object
+async fn test_delta_subscription() -> Result<(), Box<dyn Error>> {
+    println!("[test] Delta subscription test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+
+    let mut raw_subscriber = connect_raw(&server.ws_url).await?;
+    raw_subscriber.send(Message::Text("subscribe:JobState|session-delta|delta".to_string())).await?;
+    sync_raw(&mut raw_subscriber).await?;
+
+    let mut client = WsClient::connect_with_session("DeltaSubscriber", "session-delta", &server.ws_url).await?;
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    client.on_message("JobState", move |payload| {
+        received_clone.lock().unwrap().push(payload);
+    });
+    client.subscribe_delta("DeltaSubscriber", "JobState", "no-payload").await?;
+    sleep(Duration::from_millis(200)).await;
+
+    let mut publisher = WsClient::connect_with_session("DeltaPublisher", "session-delta", &server.ws_url).await?;
+    let first = json!({"id": 7, "status": "running", "progress": 10});
+    let second = json!({"id": 7, "status": "running", "progress": 50});
+    publisher.publish("DeltaPublisher", "JobState", &first.to_string(), &now_rfc3339()).await?;
+    publisher.publish("DeltaPublisher", "JobState", &second.to_string(), &now_rfc3339()).await?;
+
+    let full = recv_topic(&mut raw_subscriber, "JobState", Duration::from_secs(2)).await
+        .ok_or("delta subscriber missed the first message")?;
+    let patch = recv_topic(&mut raw_subscriber, "JobState", Duration::from_secs(2)).await
+        .ok_or("delta subscriber missed the second message")?;
+    println!("[test] Wire payloads: {} then {}", full["payload"], patch["payload"]);
+    let full_payload: serde_json::Value = serde_json::from_str(full["payload"].as_str().unwrap_or(""))?;
+    let patch_payload: serde_json::Value = serde_json::from_str(patch["payload"].as_str().unwrap_or(""))?;
+    if full["delta"] != false || full_payload != first {
+        return Err(format!("first delivery was not the full object: {}", full).into());
+    }
+    if patch["delta"] != true || patch_payload != json!({"progress": 50}) {
+        return Err(format!("second delivery was not just the changed field: {}", patch).into());
+    }
+
+    sleep(Duration::from_millis(200)).await;
+    let rebuilt: Vec<serde_json::Value> = received.lock().unwrap().iter()
+        .map(|payload| serde_json::from_str(payload).unwrap_or_default())
+        .collect();
+    if rebuilt != vec![first, second] {
+        return Err(format!("WsClient did not rebuild the full objects: {:?}", rebuilt).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// Frames survive an encode/decode round trip and malformed frames are rejected
+fn test_binary_frame_codec() -> Result<(), Box<dyn Error>> {
+    println!("[test] Binary frame codec...");
+
+    for frame in [
+        BinaryFrame::subscribe("sensor/7/raw", ""),
+        BinaryFrame::unsubscribe("sensor/7/raw", "session-binary"),
+        BinaryFrame::publish("sensor/7/raw", "session-binary", vec![0, 1, 2, 255]),
+        BinaryFrame::publish("empty", "", Vec::new()),
+    ] {
+        let decoded = BinaryFrame::decode(&frame.encode()?)?;
+        if decoded != frame {
+            return Err(format!("round trip changed {:?} into {:?}", frame, decoded).into());
+        }
+    }
+
+    // opcode, topic length 5, "topic", session length 0, payload
+    let wire = [&[0x03, 0x00, 0x05][..], b"topic", &[0x00, 0x00], b"bytes"].concat();
+    let decoded = BinaryFrame::decode(&wire)?;
+    if decoded.opcode != Opcode::Publish || decoded.topic != "topic" || decoded.payload != b"bytes" {
+        return Err(format!("unexpected decode of hand-built frame: {:?}", decoded).into());
+    }
+
+    let cases: [(&[u8], FrameError); 4] = [
+        (&[], FrameError::Truncated),
+        (&[0x09, 0x00, 0x00, 0x00, 0x00], FrameError::UnknownOpcode(0x09)),
+        (&[0x01, 0x00, 0x08, b'a'], FrameError::Truncated),
+        (&[0x01, 0x00, 0x01, 0xff, 0x00, 0x00], FrameError::InvalidUtf8),
+    ];
+    for (bytes, expected) in cases {
+        match BinaryFrame::decode(bytes) {
+            Err(e) if e == expected => {}
+            other => return Err(format!("decoding {:?} gave {:?}, expected {:?}", bytes, other, expected).into()),
+        }
+    }
+    if BinaryFrame::publish(&"t".repeat(70_000), "", Vec::new()).encode() != Err(FrameError::FieldTooLong) {
+        return Err("oversized topic was encoded".into());
+    }
+    Ok(())
+}
+
+// Objects and arrays travel as JSON values, not as strings holding JSON
+async fn test_structured_payloads() -> Result<(), Box<dyn Error>> {
+    println!("[test] Structured payload test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut raw_subscriber = connect_raw(&server.ws_url).await?;
+    raw_subscriber.send(Message::Text("subscribe:Reading|session-values".to_string())).await?;
+    sync_raw(&mut raw_subscriber).await?;
+    let mut raw_delta = connect_raw(&server.ws_url).await?;
+    raw_delta.send(Message::Text("subscribe:Reading|session-values|delta".to_string())).await?;
+    sync_raw(&mut raw_delta).await?;
+
+    let mut subscriber = WsClient::connect_with_session("ValueSubscriber", "session-values", &server.ws_url).await?;
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on("Reading", move |payload| {
+        received_clone.lock().unwrap().push(payload);
+    }).await?;
+
+    let mut publisher = WsClient::connect_with_session("ValuePublisher", "session-values", &server.ws_url).await?;
+    let first = json!({"sensor": "north", "celsius": 21.5, "tags": ["roof", "east"]});
+    let second = json!({"sensor": "north", "celsius": 22.0, "tags": ["roof", "east"]});
+    let list = json!([1, 2, 3]);
+    for payload in [&first, &second, &list] {
+        publisher.publish_value("ValuePublisher", "Reading", payload.clone(), &now_rfc3339()).await?;
+    }
+
+    let envelope = recv_topic(&mut raw_subscriber, "Reading", Duration::from_secs(2)).await
+        .ok_or("raw subscriber missed the object")?;
+    if envelope["payload"] != first {
+        return Err(format!("envelope did not carry the object as a value: {}", envelope).into());
+    }
+    recv_topic(&mut raw_delta, "Reading", Duration::from_secs(2)).await.ok_or("delta subscriber missed the object")?;
+    let patch = recv_topic(&mut raw_delta, "Reading", Duration::from_secs(2)).await
+        .ok_or("delta subscriber missed the change")?;
+    if patch["delta"] != true || patch["payload"] != json!({"celsius": 22.0}) {
+        return Err(format!("object delta was not sent as an object patch: {}", patch).into());
+    }
+    sleep(Duration::from_millis(300)).await;
+
+    let received = received.lock().unwrap().clone();
+    let parsed: Vec<serde_json::Value> = received.iter()
+        .map(|payload| serde_json::from_str(payload))
+        .collect::<Result<_, _>>()?;
+    if parsed != vec![first, second, list] {
+        return Err(format!("handler did not receive the published values: {:?}", received).into());
+    }
+    println!("[test] Handler received {:?}", received);
+
+    server.stop();
+    Ok(())
+}
+
+// A gzipped publish crosses the wire compressed and reaches WsClient handlers inflated
+async fn test_compressed_round_trip() -> Result<(), Box<dyn Error>> {
+    println!("[test] Compressed payload test...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut raw_subscriber = connect_raw(&server.ws_url).await?;
+    raw_subscriber.send(Message::Text("subscribe:Telemetry|session-gzip".to_string())).await?;
+    sync_raw(&mut raw_subscriber).await?;
+
+    let mut subscriber = WsClient::connect_with_session("GzipSubscriber", "session-gzip", &server.ws_url).await?;
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    subscriber.on("Telemetry", move |payload| {
+        received_clone.lock().unwrap().push(payload);
+    }).await?;
+
+    let readings: Vec<serde_json::Value> = (0..200)
+        .map(|i| json!({"sensor": format!("probe-{}", i % 8), "celsius": 20.0 + (i % 10) as f64 / 4.0, "ok": true}))
+        .collect();
+    let telemetry = json!({"site": "north", "readings": readings});
+    let original_len = telemetry.to_string().len();
+    let mut publisher = WsClient::connect_with_session("GzipPublisher", "session-gzip", &server.ws_url).await?;
+    publisher.publish_compressed("GzipPublisher", "Telemetry", &telemetry, &now_rfc3339()).await?;
+
+    let envelope = recv_topic(&mut raw_subscriber, "Telemetry", Duration::from_secs(2)).await
+        .ok_or("raw subscriber missed the compressed message")?;
+    let wire_len = envelope["payload"].as_str().map_or(0, str::len);
+    if envelope["encoding"] != "gzip" || original_len < 4096 || wire_len * 4 > original_len {
+        return Err(format!("expected a marked payload well under {} bytes, got {} bytes: {}", original_len, wire_len, envelope["encoding"]).into());
+    }
+    sleep(Duration::from_millis(300)).await;
+    let inflated: Vec<serde_json::Value> = received.lock().unwrap().iter()
+        .map(|payload| serde_json::from_str(payload))
+        .collect::<Result<_, _>>()?;
+    if inflated != vec![telemetry] {
+        return Err("handler did not receive the inflated payload".into());
+    }
+    println!("[test] {} bytes of telemetry crossed the wire as {}", original_len, wire_len);
+
+    // The server refuses encodings it does not know, payloads that do not inflate, and ones that
+    // would inflate past the largest frame it accepts
+    let bomb = compress_payload(&json!("a".repeat(2 * 1024 * 1024)));
+    for (encoding, payload) in [("br", json!("abc")), ("gzip", json!("not gzip")), ("gzip", bomb)] {
+        raw_subscriber.send(Message::Text(json!({
+            "op": "publish", "topic": "Telemetry", "payload": payload, "encoding": encoding
+        }).to_string())).await?;
+        let error = recv_type(&mut raw_subscriber, "error", Duration::from_secs(2)).await
+            .ok_or_else(|| format!("{} payload was not refused", encoding))?;
+        if error["code"] != "invalid_encoding" {
+            return Err(format!("unexpected error for {}: {}", encoding, error).into());
+        }
+    }
+
+    // Small messages: compression is opt-in, so the plain path is untouched; compare the two
+    let mut plain = Duration::ZERO;
+    let mut compressed = Duration::ZERO;
+    for round in 0..20 {
+        let small = json!({"round": round});
+        for gzip in [false, true] {
+            let started = std::time::Instant::now();
+            if gzip {
+                publisher.publish_compressed("GzipPublisher", "Telemetry", &small, "").await?;
+            } else {
+                publisher.publish_value("GzipPublisher", "Telemetry", small.clone(), "").await?;
+            }
+            recv_topic(&mut raw_subscriber, "Telemetry", Duration::from_secs(2)).await.ok_or("small message was not delivered")?;
+            *if gzip { &mut compressed } else { &mut plain } += started.elapsed();
+        }
+    }
+    println!("[test] Mean small-message round trip: plain {:?}, compressed {:?}", plain / 20, compressed / 20);
+
+    server.stop();
+    Ok(())
+}
+
+// Raw bytes published by one client reach a binary subscriber unchanged and a text subscriber as base64
+async fn test_binary_round_trip() -> Result<(), Box<dyn Error>> {
+    println!("[test] Binary publish round trip...");
+
+    let server = spawn_ws_server(ConnectionConfig::default()).await?;
+    let mut binary_subscriber = WsClient::connect_with_session("BinarySubscriber", "session-binary", &server.ws_url).await?;
+    let mut text_subscriber = WsClient::connect_with_session("TextSubscriber", "session-binary", &server.ws_url).await?;
+    let mut publisher = WsClient::connect_with_session("BinaryPublisher", "session-binary", &server.ws_url).await?;
+
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let frames_clone = frames.clone();
+    binary_subscriber.on_binary("BinaryTopic", move |payload| frames_clone.lock().unwrap().push(payload));
+    binary_subscriber.subscribe_binary("BinaryTopic").await?;
+
+    let texts = Arc::new(Mutex::new(Vec::new()));
+    let texts_clone = texts.clone();
+    text_subscriber.on_message("BinaryTopic", move |payload| texts_clone.lock().unwrap().push(payload));
+    text_subscriber.subscribe("TextSubscriber", "BinaryTopic", "no-payload").await?;
+    sleep(Duration::from_millis(200)).await;
+
+    // Not valid UTF-8, so it could not have travelled as a JSON string
+    let bytes = vec![0x00, 0x9f, 0x92, 0x96, 0xff];
+    publisher.publish_binary("BinaryTopic", &bytes).await?;
+    // Text publishes reach binary subscribers as their UTF-8 bytes
+    publisher.publish("BinaryPublisher", "BinaryTopic", "plain text", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(300)).await;
+
+    let frames = frames.lock().unwrap().clone();
+    println!("[test] Binary subscriber received {:?}", frames);
+    if frames != vec![bytes, b"plain text".to_vec()] {
+        return Err(format!("unexpected binary deliveries: {:?}", frames).into());
+    }
+    let texts = texts.lock().unwrap().clone();
+    println!("[test] Text subscriber received {:?}", texts);
+    if texts != vec!["AJ+Slv8=".to_string(), "plain text".to_string()] {
+        return Err(format!("unexpected text deliveries: {:?}", texts).into());
+    }
+
+    server.stop();
+    Ok(())
+}
+
+// A reconnecting client survives a server restart: it reconnects, resubscribes with the same
+// options and codec, and receives again
+async fn test_reconnect_replays_subscriptions() -> Result<(), Box<dyn Error>> {
+    println!("[test] Reconnect with subscription replay...");
+
+    let server = spawn_killable_server("127.0.0.1:0", ConnectionConfig::default()).await?;
+    let url = server.ws_url.clone();
+    let addr = url.trim_start_matches("ws://").trim_end_matches("/ws").to_string();
+    let policy = RetryPolicy {
+        max_attempts: 20,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+    };
+    let mut client = WsClient::connect_with_reconnect("Reconnector", "session-reconnect", &url, policy).await?;
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = received.clone();
+    client.on("ReconnectEvent", move |msg| received_clone.lock().unwrap().push(msg)).await?;
+    client.subscribe("Reconnector", "ReconnectOther", "").await?;
+    let filtered = Arc::new(Mutex::new(Vec::new()));
+    let filtered_clone = filtered.clone();
+    client.on_message("ReconnectFiltered", move |msg| filtered_clone.lock().unwrap().push(msg));
+    let filter = PublisherFilter::Allow(vec!["Reconnector".to_string()]);
+    client.subscribe_filtered("Reconnector", "ReconnectFiltered", "", &filter).await?;
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let frames_clone = frames.clone();
+    client.on_binary("ReconnectBinary", move |payload| frames_clone.lock().unwrap().push(payload));
+    client.subscribe_binary("ReconnectBinary").await?;
+
+    server.kill();
+    let started = tokio::time::Instant::now();
+    while !client.is_reconnecting() {
+        if started.elapsed() > Duration::from_secs(3) {
+            return Err("client did not notice the server going away".into());
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    match client.publish("Reconnector", "ReconnectEvent", "during the gap", &now_rfc3339()).await {
+        Err(e @ WsError::Reconnecting) if e.to_string() == RECONNECTING_ERROR => {
+            println!("[test] Publish during the gap failed with: {}", e);
+        }
+        other => return Err(format!("expected the reconnecting error, got {:?}", other).into()),
+    }
+
+    // A fresh server on the same address sees the replayed subscriptions
+    let restarted = spawn_killable_server(&addr, ConnectionConfig::default()).await?;
+    let started = tokio::time::Instant::now();
+    while restarted.subscribers.subscriber_count("ReconnectEvent", "session-reconnect") != 1
+        || restarted.subscribers.subscriber_count("ReconnectOther", "session-reconnect") != 1
+        || restarted.subscribers.subscriber_count("ReconnectFiltered", "session-reconnect") != 1
+        || restarted.subscribers.subscriber_count("ReconnectBinary", "session-reconnect") != 1
+        || !client.is_connected()
+    {
+        if started.elapsed() > Duration::from_secs(5) {
+            return Err(format!("topics were not resubscribed: {:?}", restarted.subscribers.topics()).into());
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    println!("[test] Resubscribed after restart: {:?}", restarted.subscribers.topics());
+
+    client.publish("Reconnector", "ReconnectEvent", "after restart", &now_rfc3339()).await?;
+    sleep(Duration::from_millis(300)).await;
+    let received = received.lock().unwrap().clone();
+    if received != vec!["after restart".to_string()] {
+        return Err(format!("handler did not receive the message after reconnecting: {:?}", received).into());
+    }
+
+    // The replayed filter still keeps out other publishers, and binary delivery is still binary
+    let mut stranger = WsClient::connect_with_session("Stranger", "session-reconnect", &url).await?;
+    stranger.publish("Stranger", "ReconnectFiltered", "filtered out", &now_rfc3339()).await?;
+    client.publish("Reconnector", "ReconnectFiltered", "let through", &now_rfc3339()).await?;
+    client.publish_binary("ReconnectBinary", b"raw after restart").await?;
+    sleep(Duration::from_millis(300)).await;
+    let filtered = filtered.lock().unwrap().clone();
+    let frames = frames.lock().unwrap().clone();
+    if filtered != vec!["let through".to_string()] || frames != vec![b"raw after restart".to_vec()] {
+        return Err(format!("replayed subscriptions lost their options: filtered {:?}, binary {:?}", filtered, frames).into());
+    }
+    println!("[test] Filter and binary delivery survived the restart");
+
+    drop(stranger);
+    drop(client);
+    restarted.kill();
+    Ok(())
+}
@@ -224,7 +224,7 @@ async function setupClient(clientName, sessionId, wsUrl, authUrl, username, pass
                 const clientSessionTag = `[${clientName}:${sessionId}]`;
                 
                 // Log received messages
-                log(`${clientSessionTag} Received message: Topic=${data.topic}, Payload=${data.payload}`, 'success');
+                log(`${clientSessionTag} Received message: Topic=${data.topic}, Payload=${typeof data.payload === 'string' ? data.payload : JSON.stringify(data.payload)}`, 'success');
                 log(`${clientSessionTag} Message details: Publisher=${data.publisher_name}, Session=${data.session_id}`, 'info');
             } catch (error) {
                 // Handle non-JSON messages